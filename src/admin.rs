@@ -0,0 +1,162 @@
+use axum::{
+    Json, Router,
+    extract::{Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CacheStats;
+use crate::warcraftlogs::{RateLimitStatus, TokenStatus};
+use crate::{AppState, StreamStats, api_error};
+
+/// Operator routes, nested under `/admin`.
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/cache/flush", post(flush_cache))
+        .route("/status", get(status))
+        .route_layer(middleware::from_fn_with_state(state, require_token))
+}
+
+/// Let a request through only with `Authorization: Bearer <ADMIN_TOKEN>`.
+/// The routes are refused outright while no token is set.
+async fn require_token(State(state): State<AppState>, headers: HeaderMap, request: Request, next: Next) -> Response {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return api_error("admin_disabled", "Admin routes are disabled; set ADMIN_TOKEN to enable them".to_string());
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.trim().as_bytes(), expected.as_bytes()) => next.run(request).await,
+        _ => {
+            let mut response = api_error("unauthorized", "A valid admin token is required".to_string());
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            response
+        }
+    }
+}
+
+/// Compare without returning early on the first differing byte, so the time
+/// taken says nothing about how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Deserialize)]
+struct FlushScope {
+    class:     Option<String>,
+    spec:      Option<String>,
+    encounter: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct FlushSummary {
+    /// Query results evicted from memory and from the persistent store.
+    results:        usize,
+    stored_results: usize,
+    /// Per-report caches; only emptied by an unscoped flush, since their
+    /// entries aren't tied to one spec.
+    talents:        usize,
+    actors:         usize,
+}
+
+/// `POST /admin/cache/flush`: evict cached results, all of them or only those
+/// matching `class`, `spec` and `encounter`, so the next query fetches fresh.
+async fn flush_cache(State(state): State<AppState>, Query(scope): Query<FlushScope>) -> Response {
+    let class = scope.class.as_deref().map(|class| class.replace(' ', "_"));
+    let spec = match (&class, &scope.spec) {
+        (Some(class), Some(spec)) => match state.config.resolve(class, spec) {
+            Ok(resolved) => Some(resolved.spec),
+            Err(e)       => return api_error("invalid_selection", e.to_string()),
+        },
+        (Some(class), None) if !state.config.classes.contains_key(class) => {
+            return api_error("invalid_selection", format!("Unknown class '{}'", class));
+        }
+        (None, Some(_)) => return api_error("invalid_query", "`spec` needs a `class`".to_string()),
+        _ => None,
+    };
+    let scoped = class.is_some() || scope.encounter.is_some();
+
+    let flushed = state
+        .talents
+        .flush_results(|query| {
+            class.as_ref().is_none_or(|class| &query.class == class)
+                && spec.as_ref().is_none_or(|spec| &query.spec == spec)
+                && scope.encounter.is_none_or(|encounter| query.encounter_id == encounter)
+        })
+        .await;
+    let (results, stored_results) = match flushed {
+        Ok(counts) => counts,
+        Err(e) => {
+            tracing::error!("Cache flush failed: {:#}", e);
+            return api_error("internal_error", "Flushing the stored results failed".to_string());
+        }
+    };
+    let (actors, talents) = if scoped { (0, 0) } else { state.wcl.flush_caches() };
+
+    tracing::info!(
+        "Admin cache flush{}: {} results, {} stored, {} talents, {} actors",
+        if scoped { " (scoped)" } else { "" },
+        results,
+        stored_results,
+        talents,
+        actors,
+    );
+    (StatusCode::OK, Json(FlushSummary { results, stored_results, talents, actors })).into_response()
+}
+
+/// Everything worth a look when an instance misbehaves, in one document.
+#[derive(Serialize)]
+struct Status {
+    uptime_secs: u64,
+    /// `None` until the first WarcraftLogs call.
+    token:       Option<TokenStatus>,
+    caches:      CachesStatus,
+    /// `None` until WarcraftLogs has reported usage.
+    rate_limit:  Option<RateLimitStatus>,
+    points_left: Option<f64>,
+    streams:     StreamStats,
+}
+
+#[derive(Serialize)]
+struct CachesStatus {
+    results: CacheStatus,
+    talents: CacheStatus,
+    actors:  CacheStatus,
+}
+
+#[derive(Serialize)]
+struct CacheStatus {
+    #[serde(flatten)]
+    stats:     CacheStats,
+    hit_ratio: Option<f64>,
+}
+
+impl From<CacheStats> for CacheStatus {
+    fn from(stats: CacheStats) -> Self {
+        Self { hit_ratio: stats.hit_ratio(), stats }
+    }
+}
+
+/// `GET /admin/status`: token, cache, API budget and stream state. Only
+/// reads what's already tracked; never calls WarcraftLogs.
+async fn status(State(state): State<AppState>) -> Json<Status> {
+    let (actors, talents) = state.wcl.cache_stats();
+    let rate_limit = state.wcl.rate_limit_status().await;
+    Json(Status {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        token:       state.wcl.token_status().await,
+        caches:      CachesStatus {
+            results: state.talents.cache_stats().into(),
+            talents: talents.into(),
+            actors:  actors.into(),
+        },
+        points_left: rate_limit.as_ref().map(|r| (r.limit_per_hour as f64 - r.points_spent_this_hour).max(0.0)),
+        rate_limit,
+        streams:     StreamStats { open: state.open_streams(), max: state.max_streams },
+    })
+}
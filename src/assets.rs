@@ -0,0 +1,40 @@
+/// A file compiled into the binary and served under `/static/`.
+pub struct Asset {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    pub bytes: &'static [u8],
+}
+
+/// Everything under `/static/`. A new asset only needs an entry here.
+const ASSETS: &[Asset] = &[
+    Asset {
+        name: "app.css",
+        content_type: "text/css; charset=utf-8",
+        bytes: include_bytes!("../static/app.css"),
+    },
+    Asset {
+        name: "app.js",
+        content_type: "text/javascript; charset=utf-8",
+        bytes: include_bytes!("../static/app.js"),
+    },
+    Asset {
+        name: "favicon.ico",
+        content_type: "image/x-icon",
+        bytes: include_bytes!("../static/favicon.ico"),
+    },
+    Asset {
+        name: "favicon.svg",
+        content_type: "image/svg+xml",
+        bytes: include_bytes!("../static/favicon.svg"),
+    },
+];
+
+pub fn get(name: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|a| a.name == name)
+}
+
+/// The URL of an asset in pages. It carries the build's commit, so assets
+/// can be cached indefinitely and still change with a deploy.
+pub fn url(name: &str) -> String {
+    format!("/static/{}?v={}", name, env!("BUILD_GIT_COMMIT"))
+}
@@ -0,0 +1,110 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A bounded in-memory map whose entries expire after a fixed TTL.
+pub struct TtlCache<K, V> {
+    entries: RwLock<HashMap<K, (Instant, V)>>,
+    ttl: Duration,
+    max_entries: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Point-in-time counters for a cache.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of lookups served from the cache; `None` before the first one.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            ttl,
+            max_entries,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The cached value and its age, if present and not expired.
+    pub fn get<Q>(&self, key: &Q) -> Option<(V, Duration)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entries = self.entries.read().unwrap();
+        let found = entries
+            .get(key)
+            .map(|(inserted, value)| (value, inserted.elapsed()))
+            .filter(|(_, age)| *age < self.ttl)
+            .map(|(value, age)| (value.clone(), age));
+
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.read().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drop every entry whose key matches, expired or not; returns how many.
+    pub fn remove_where(&self, matches: impl Fn(&K) -> bool) -> usize {
+        let mut entries = self.entries.write().unwrap();
+        let before = entries.len();
+        entries.retain(|key, _| !matches(key));
+        before - entries.len()
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_aged(key, value, Duration::ZERO);
+    }
+
+    /// Insert a value that was produced `age` ago (e.g. loaded from disk), so
+    /// it expires when the original would have.
+    pub fn insert_aged(&self, key: K, value: V, age: Duration) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap();
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.retain(|_, (inserted, _)| inserted.elapsed() < ttl);
+        }
+
+        // Still full of live entries: drop the oldest.
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (inserted, _))| *inserted)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        let inserted = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        entries.insert(key, (inserted, value));
+    }
+}
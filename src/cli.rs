@@ -0,0 +1,268 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::IsTerminal;
+use std::time::Instant;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{ClassSpecs, Game, Settings};
+use crate::warcraftlogs::{Endpoints, MAX_ENTRIES, TIER_OVERALL, TalentDataWithRank, WclClient, unix_now};
+use crate::{JsonEntry, TalentQuery, TalentsDocument, collect_stream, load_zone_encounters, resolve_query, verify_credentials};
+
+/// Talent builds of the top-ranked WarcraftLogs players.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// `serve` if left out.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the site (the default).
+    Serve,
+    /// Run one talent query and print its entries.
+    Fetch(FetchArgs),
+    /// Check that the WarcraftLogs credentials get a token and the API answers.
+    Check,
+}
+
+/// The parameters of `/api/v1/talents`, as flags.
+#[derive(Args)]
+pub struct FetchArgs {
+    /// Class as classes.toml names it, e.g. `Death_Knight`.
+    #[arg(long)]
+    class:     String,
+    /// Spec as classes.toml names it for the class.
+    #[arg(long)]
+    spec:      String,
+    /// Encounter ID; 0 for the whole tier.
+    #[arg(long, default_value_t = TIER_OVERALL)]
+    encounter: i32,
+    /// Raid tier key from settings.toml; the current season if left out.
+    #[arg(long)]
+    zone:      Option<String>,
+    /// Region code from settings.toml.
+    #[arg(long, default_value = "all")]
+    region:    String,
+    /// e.g. `Heroic` or `Mythic+`; the tier's default difficulty if left out.
+    #[arg(long)]
+    mode:      Option<String>,
+    /// `dps`, `hps`, `tankhps` or `playerscore`; chosen by role and mode if left out.
+    #[arg(long)]
+    metric:    Option<String>,
+    /// Keystone level to rank Mythic+ runs at.
+    #[arg(long)]
+    bracket:   Option<i32>,
+    /// `retail` or `classic`.
+    #[arg(long, default_value = "retail", value_parser = parse_game)]
+    game:      Game,
+    /// Most entries to fetch talents for.
+    #[arg(long, default_value_t = MAX_ENTRIES, value_parser = parse_limit)]
+    limit:     usize,
+    /// How to print the entries.
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    format:    Format,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Aligned columns for reading.
+    Table,
+    /// The document `/api/v1/talents.json` returns.
+    Json,
+}
+
+fn parse_game(value: &str) -> Result<Game, String> {
+    match value {
+        "retail"  => Ok(Game::Retail),
+        "classic" => Ok(Game::Classic),
+        _ => Err("expected `retail` or `classic`".to_string()),
+    }
+}
+
+fn parse_limit(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(limit) if (1..=MAX_ENTRIES).contains(&limit) => Ok(limit),
+        _ => Err(format!("expected a number from 1 to {}", MAX_ENTRIES)),
+    }
+}
+
+/// Log to stderr, warnings and worse unless `RUST_LOG` says otherwise, so
+/// stdout carries only the output.
+fn init_logging() {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")))
+        .compact()
+        .init();
+}
+
+/// A client for both games, from the same environment the server reads.
+fn client() -> anyhow::Result<WclClient> {
+    WclClient::new(Endpoints::from_env()?, &Settings::load(Game::Retail)?, &Settings::load(Game::Classic)?)
+}
+
+/// `fetch`: validate the query against the catalog as the server would, run
+/// it straight against WarcraftLogs, past the result cache and the store, and
+/// print the entries. Any failure of the query as a whole is returned, so the
+/// process exits non-zero; entries whose talents couldn't be loaded are
+/// printed with the reason.
+pub async fn fetch(args: FetchArgs) -> anyhow::Result<()> {
+    init_logging();
+    fetch_with(&client()?, args).await
+}
+
+/// `fetch` through `wcl`.
+async fn fetch_with(wcl: &WclClient, args: FetchArgs) -> anyhow::Result<()> {
+    let config = ClassSpecs::load(args.game)?;
+    let mut settings = Settings::load(args.game)?;
+    load_zone_encounters(&wcl.for_game(args.game, None), &mut settings).await;
+
+    let params = TalentQuery {
+        class:     args.class,
+        spec:      args.spec,
+        encounter: args.encounter,
+        zone:      args.zone,
+        region:    args.region,
+        mode:      args.mode.unwrap_or_default(),
+        metric:    args.metric,
+        bracket:   args.bracket,
+        nocache:   None,
+        allow_unknown_encounter: None,
+        game:      args.game,
+    };
+    let mut prepared = resolve_query(&config, &settings, params).map_err(anyhow::Error::msg)?;
+    prepared.query.limit = args.limit;
+
+    let started   = Instant::now();
+    let receiver  = wcl.fetch_top_talents_stream(&prepared.query).await?;
+    let collected = collect_stream(receiver, None, started).await?;
+
+    match args.format {
+        Format::Json => {
+            let document = TalentsDocument {
+                query:        &prepared.query,
+                encounter:    collected.encounter.as_deref(),
+                generated_at: unix_now(),
+                cached:       false,
+                available:    collected.available,
+                entries:      collected
+                    .entries
+                    .iter()
+                    .map(|e| JsonEntry { rank: e.rank, data: &e.data })
+                    .collect(),
+            };
+            println!("{}", serde_json::to_string_pretty(&document)?);
+        }
+        Format::Table => print!("{}", render_table(&prepared.selection, &prepared.query.metric, &collected.entries)),
+    }
+    Ok(())
+}
+
+/// Entries as aligned columns under the selection. Talents come last, as
+/// import strings are long.
+fn render_table(selection: &str, metric: &str, entries: &[TalentDataWithRank]) -> String {
+    if entries.is_empty() {
+        return format!("No ranked kills exist for {} yet.\n", selection);
+    }
+    let players: Vec<String> = entries
+        .iter()
+        .map(|entry| match &entry.data.realm {
+            Some(realm) => format!("{}-{}", entry.data.name, realm),
+            None        => entry.data.name.clone(),
+        })
+        .collect();
+    let width = players.iter().map(|p| p.chars().count()).max().unwrap_or(0).max("Player".len());
+
+    let mut table = format!("{}\n\n{:>4}  {:<width$}  {:>10}  Talents\n", selection, "Rank", "Player", metric.to_uppercase());
+    for (entry, player) in entries.iter().zip(&players) {
+        let talents = match &entry.data.failure {
+            Some(reason) => format!("({})", reason),
+            None         => entry.data.talent_string.clone(),
+        };
+        table.push_str(&format!("{:>4}  {:<width$}  {:>10.1}  {}\n", entry.rank, player, entry.data.amount, talents));
+    }
+    table
+}
+
+/// `check`: what startup checks, but a GraphQL failure fails too, and
+/// `SKIP_STARTUP_CHECK` doesn't apply.
+pub async fn check() -> anyhow::Result<()> {
+    init_logging();
+
+    if let Some(e) = verify_credentials(&client()?).await? {
+        anyhow::bail!("WarcraftLogs credentials work, but the API didn't answer: {}", e);
+    }
+    println!("WarcraftLogs credentials work");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_wcl::{talent_entry, unreachable_client};
+
+    fn fetch_args(flags: &[&str]) -> FetchArgs {
+        let args = ["talent-trends", "fetch", "--class", "Mage", "--spec", "Frost"].iter().chain(flags);
+        match Cli::try_parse_from(args).unwrap().command {
+            Some(Command::Fetch(args)) => args,
+            _ => panic!("expected fetch"),
+        }
+    }
+
+    #[test]
+    fn limits_must_be_from_one_to_the_maximum() {
+        assert_eq!(parse_limit("1"), Ok(1));
+        assert_eq!(parse_limit(&MAX_ENTRIES.to_string()), Ok(MAX_ENTRIES));
+        for bad in ["0", &(MAX_ENTRIES + 1).to_string(), "-1", "ten", ""] {
+            assert_eq!(parse_limit(bad), Err(format!("expected a number from 1 to {}", MAX_ENTRIES)), "{:?}", bad);
+        }
+        assert!(Cli::try_parse_from(["talent-trends", "fetch", "--class", "Mage", "--spec", "Frost", "--limit", "0"]).is_err());
+    }
+
+    #[test]
+    fn games_are_named_in_lowercase() {
+        assert!(matches!(parse_game("retail"), Ok(Game::Retail)));
+        assert!(matches!(parse_game("classic"), Ok(Game::Classic)));
+        for bad in ["Retail", "wotlk", ""] {
+            assert_eq!(parse_game(bad).err().as_deref(), Some("expected `retail` or `classic`"), "{:?}", bad);
+        }
+        assert!(matches!(fetch_args(&[]).game, Game::Retail));
+    }
+
+    #[test]
+    fn an_empty_table_says_there_are_no_kills() {
+        assert_eq!(render_table("Frost Mage", "dps", &[]), "No ranked kills exist for Frost Mage yet.\n");
+    }
+
+    #[test]
+    fn table_rows_join_realms_and_show_failures() {
+        let mut unrealmed = talent_entry(2, "Wanderer", "", None);
+        unrealmed.data.realm = None;
+        let entries = [
+            talent_entry(1, "Jaina", "Draenor", None),
+            unrealmed,
+            talent_entry(3, "Khadgar", "Silvermoon", Some("log is private")),
+        ];
+
+        assert_eq!(
+            render_table("Frost Mage", "dps", &entries),
+            "Frost Mage\n\
+             \n\
+             Rank  Player                     DPS  Talents\n\
+             \x20  1  Jaina-Draenor          99999.0  TALENTS1\n\
+             \x20  2  Wanderer               99998.0  TALENTS2\n\
+             \x20  3  Khadgar-Silvermoon     99997.0  (log is private)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_queries_fail_before_any_fetch() {
+        let e = fetch_with(&unreachable_client(), fetch_args(&["--metric", "damage"])).await.unwrap_err();
+        assert_eq!(e.to_string(), "Unknown metric. Valid metrics: dps, hps, tankhps, playerscore.");
+
+        let e = fetch_with(&unreachable_client(), fetch_args(&["--zone", "nowhere"])).await.unwrap_err();
+        assert!(e.to_string().contains("nowhere"), "{}", e);
+    }
+}
@@ -0,0 +1,384 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::Instrument;
+
+use crate::cache::{CacheStats, TtlCache};
+use crate::config::env_or;
+use crate::shutdown;
+use crate::store::Store;
+use crate::warcraftlogs::{RankingsQuery, STREAM_CHANNEL_CAPACITY, StreamItem, WclApi};
+
+/// The entries for a query, either streamed from upstream or replayed from
+/// the result cache.
+pub struct TalentStream {
+    pub receiver: mpsc::Receiver<Result<StreamItem>>,
+    /// Age of the cached result being replayed; `None` for a fresh fetch.
+    pub cached_age: Option<Duration>,
+}
+
+/// Everything an in-progress query has produced so far, replayed to each
+/// request that joins it.
+#[derive(Default)]
+struct FlightLog {
+    items: Vec<Result<StreamItem, SharedError>>,
+    done: bool,
+}
+
+/// An upstream error shared by every request subscribed to the same query.
+/// The original error is exposed as the source so callers can still find
+/// typed causes (e.g. `RateLimited`) in the chain.
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<anyhow::Error>);
+
+impl std::fmt::Display for SharedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&**self.0)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref RESUME_GRACE: Duration = Duration::from_secs(env_or("RESUME_GRACE_SECS", 10));
+    static ref RESULT_TTL: Duration = Duration::from_secs(env_or("CACHE_TTL_SECS", 15 * 60));
+}
+
+/// Front for `WclApi::talents` that shares upstream work
+/// between identical queries and caches complete results. Clones share the
+/// same flights and cache.
+#[derive(Clone)]
+pub struct Coalescer {
+    wcl: Arc<dyn WclApi>,
+    /// Where complete results and build snapshots are persisted, if anywhere.
+    store: Option<Store>,
+    in_flight: Arc<Mutex<HashMap<RankingsQuery, Arc<watch::Sender<FlightLog>>>>>,
+    results: Arc<TtlCache<RankingsQuery, Vec<StreamItem>>>,
+}
+
+impl Coalescer {
+    pub fn new(wcl: Arc<dyn WclApi>, store: Option<Store>) -> Self {
+        Self {
+            wcl,
+            store,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            results: Arc::new(TtlCache::new(*RESULT_TTL, env_or("CACHE_MAX_ENTRIES", 500))),
+        }
+    }
+
+    /// Counters of the in-memory result cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.results.stats()
+    }
+
+    /// Drop the cached results of every matching query, in memory and in the
+    /// persistent store; returns how many of each. Queries in flight are left
+    /// to finish.
+    pub async fn flush_results(&self, matches: impl Fn(&RankingsQuery) -> bool) -> Result<(usize, usize)> {
+        let in_memory = self.results.remove_where(&matches);
+        let stored = match &self.store {
+            Some(store) => store.delete_results(matches).await?,
+            None        => 0,
+        };
+        Ok((in_memory, stored))
+    }
+
+    /// Look a query up in memory, then in the persistent store if enabled.
+    async fn cached_results(&self, params: &RankingsQuery) -> Option<(Vec<StreamItem>, Duration)> {
+        if let Some(hit) = self.results.get(params) {
+            return Some(hit);
+        }
+
+        let store = self.store.as_ref()?;
+        match store.load_results(params, *RESULT_TTL).await {
+            Ok(Some((items, age))) => {
+                self.results.insert_aged(params.clone(), items.clone(), age);
+                Some((items, age))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                tracing::warn!("Persistent cache lookup failed: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Recent results are replayed from the cache (unless `bypass_cache`); otherwise the first
+    /// request for a query does the upstream work and identical concurrent
+    /// requests subscribe to it, getting every entry replayed as it arrives.
+    /// Fresh results are written back to the cache either way.
+    pub async fn fetch_top_talents(
+        &self,
+        params: &RankingsQuery,
+        bypass_cache: bool,
+    ) -> Result<TalentStream> {
+        let cached = if bypass_cache { None } else { self.cached_results(params).await };
+
+        if let Some((items, age)) = cached {
+            tracing::info!(
+                "Serving {} {} encounter {} from cache ({}s old)",
+                params.class, params.spec, params.encounter_id, age.as_secs()
+            );
+            let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+            tokio::spawn(async move {
+                for item in items {
+                    if tx.send(Ok(item)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            return Ok(TalentStream { receiver: rx, cached_age: Some(age) });
+        }
+
+        // Subscribing happens under the map lock, so `drive` can't decide a flight
+        // has been abandoned while someone is joining it.
+        let flight = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(params) {
+                Some(flight) => {
+                    tracing::info!(
+                        "Joining in-flight query for {} {} encounter {}",
+                        params.class, params.spec, params.encounter_id
+                    );
+                    flight.subscribe()
+                }
+                None => {
+                    let (tx, rx) = watch::channel(FlightLog::default());
+                    let tx = Arc::new(tx);
+                    in_flight.insert(params.clone(), tx.clone());
+                    // The flight runs under the span of the request that started
+                    // it, so its upstream calls log that request's ID.
+                    tokio::spawn(self.clone().drive(params.clone(), tx).instrument(tracing::Span::current()));
+                    rx
+                }
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        tokio::spawn(replay(flight, tx));
+        Ok(TalentStream { receiver: rx, cached_age: None })
+    }
+
+    /// Run the upstream fetch for a query and record everything it produces.
+    /// Only complete results without errors or failed entries are cached. If
+    /// every subscriber leaves and nobody rejoins within `RESUME_GRACE`, the
+    /// fetch is dropped, which cancels its remaining upstream calls.
+    ///
+    /// The log is read eagerly, so backpressure from one slow subscriber stays
+    /// on its own `replay` channel rather than stalling everyone sharing the
+    /// flight; the log itself is bounded by the entries in one result.
+    async fn drive(self, params: RankingsQuery, log: Arc<watch::Sender<FlightLog>>) {
+        let mut items   = Vec::new();
+        let mut failed  = false;
+
+        match self.wcl.talents(&params).await {
+            Ok(mut receiver) => loop {
+                tokio::select! {
+                    item = receiver.recv() => {
+                        let Some(item) = item else { break };
+                        match &item {
+                            Ok(StreamItem::Entry(entry)) if entry.data.failure.is_some() => failed = true,
+                            Ok(StreamItem::Progress { .. } | StreamItem::Timing { .. }) => {}
+                            Ok(item) => items.push(item.clone()),
+                            Err(_)   => failed = true,
+                        }
+                        let item = item.map_err(|e| SharedError(Arc::new(e)));
+                        log.send_modify(|log| log.items.push(item));
+                    }
+                    _ = log.closed() => {
+                        // EventSource reconnects on its own after a dropped
+                        // connection; give it a moment to rejoin and resume,
+                        // unless the server is shutting down and it can't.
+                        tokio::select! {
+                            _ = tokio::time::sleep(*RESUME_GRACE) => {}
+                            _ = shutdown::token().cancelled_owned() => {}
+                        }
+                        let mut in_flight = self.in_flight.lock().unwrap();
+                        if log.receiver_count() == 0 {
+                            in_flight.remove(&params);
+                            tracing::info!(
+                                "All clients left, cancelling query for {} {} encounter {}",
+                                params.class, params.spec, params.encounter_id
+                            );
+                            return;
+                        }
+                    }
+                }
+            },
+            Err(e) => {
+                failed = true;
+                log.send_modify(|log| log.items.push(Err(SharedError(Arc::new(e)))));
+            }
+        }
+
+        if !failed {
+            self.results.insert(params.clone(), items.clone());
+            if let Some(store) = &self.store
+                && let Err(e) = store.save_results(&params, &items).await
+            {
+                tracing::warn!("Persistent cache write failed: {:#}", e);
+            }
+            if let Some(store) = &self.store
+                && let Err(e) = store.save_snapshot(&params, &items).await
+            {
+                tracing::warn!("Build snapshot write failed: {:#}", e);
+            }
+        }
+
+        // Unregister before marking done: anyone who joined before this point
+        // will still see `done`, anyone after starts a fresh query.
+        self.in_flight.lock().unwrap().remove(&params);
+        log.send_modify(|log| log.done = true);
+    }
+}
+
+/// Forward a flight's log to one subscriber, from the first entry onwards.
+/// Returns (dropping its subscription) as soon as the subscriber goes away.
+async fn replay(mut flight: watch::Receiver<FlightLog>, tx: mpsc::Sender<Result<StreamItem>>) {
+    let mut cursor = 0;
+    loop {
+        let (pending, done) = {
+            let log = flight.borrow_and_update();
+            (log.items[cursor..].to_vec(), log.done)
+        };
+
+        for item in pending {
+            cursor += 1;
+            if tx.send(item.map_err(anyhow::Error::new)).await.is_err() {
+                return;
+            }
+        }
+
+        if done {
+            return;
+        }
+
+        tokio::select! {
+            changed = flight.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            _ = tx.closed() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_wcl::{MockWcl, Step, entry, meta, query};
+
+    /// Held after the rankings, until the test releases it.
+    fn held() -> Arc<MockWcl> {
+        MockWcl::new(vec![meta(2, 2), Step::Hold, entry(1, "Alpha", None), entry(2, "Bravo", None)])
+    }
+
+    async fn next_rank(stream: &mut TalentStream) -> Option<usize> {
+        match stream.receiver.recv().await? {
+            Ok(StreamItem::Meta { .. })  => Some(0),
+            Ok(StreamItem::Entry(entry)) => Some(entry.rank),
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+
+    async fn ranks(stream: &mut TalentStream) -> Vec<usize> {
+        let mut ranks = Vec::new();
+        while let Some(rank) = next_rank(stream).await {
+            ranks.push(rank);
+        }
+        ranks
+    }
+
+    #[tokio::test]
+    async fn identical_concurrent_queries_share_one_upstream_fetch() {
+        let wcl = held();
+        let coalescer = Coalescer::new(wcl.clone(), None);
+
+        let params = query();
+        let mut streams: Vec<TalentStream> =
+            futures::future::join_all((0..5).map(|_| coalescer.fetch_top_talents(&params, false)))
+                .await
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+        wcl.release();
+        for stream in &mut streams {
+            assert_eq!(stream.cached_age, None);
+            assert_eq!(ranks(stream).await, [0, 1, 2]);
+        }
+        assert_eq!(wcl.calls(), 1);
+
+        // Finished: now from the cache, still without going upstream.
+        let mut cached = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert!(cached.cached_age.is_some());
+        assert_eq!(ranks(&mut cached).await, [0, 1, 2]);
+        assert_eq!(wcl.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn late_joiners_get_what_was_sent_before_they_joined() {
+        let wcl = held();
+        let coalescer = Coalescer::new(wcl.clone(), None);
+
+        let mut first = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert_eq!(next_rank(&mut first).await, Some(0));
+
+        let mut late = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert_eq!(next_rank(&mut late).await, Some(0));
+
+        wcl.release();
+        assert_eq!(ranks(&mut first).await, [1, 2]);
+        assert_eq!(ranks(&mut late).await, [1, 2]);
+        assert_eq!(wcl.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_are_not_cached() {
+        let wcl = MockWcl::new(vec![meta(1, 1), Step::Fail(|| anyhow::anyhow!("upstream failed"))]);
+        let coalescer = Coalescer::new(wcl.clone(), None);
+
+        let mut stream = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert!(matches!(stream.receiver.recv().await, Some(Ok(StreamItem::Meta { .. }))));
+        assert!(matches!(stream.receiver.recv().await, Some(Err(_))));
+        assert!(stream.receiver.recv().await.is_none());
+
+        let mut again = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert_eq!(again.cached_age, None);
+        assert!(matches!(again.receiver.recv().await, Some(Ok(StreamItem::Meta { .. }))));
+        assert_eq!(wcl.calls(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn abandoned_fetch_is_kept_for_the_grace_period_then_cancelled() {
+        let wcl = held();
+        let coalescer = Coalescer::new(wcl.clone(), None);
+
+        let mut first = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert_eq!(next_rank(&mut first).await, Some(0));
+        drop(first);
+
+        // A reconnect within the grace period resumes the same fetch.
+        tokio::time::sleep(*RESUME_GRACE / 2).await;
+        assert!(!wcl.cancelled());
+        let mut resumed = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert_eq!(next_rank(&mut resumed).await, Some(0));
+        assert_eq!(wcl.calls(), 1);
+        drop(resumed);
+
+        // Nobody comes back: the upstream fetch is dropped.
+        tokio::time::sleep(*RESUME_GRACE + Duration::from_secs(1)).await;
+        assert!(wcl.cancelled());
+
+        // And the next request starts over.
+        let mut fresh = coalescer.fetch_top_talents(&query(), false).await.unwrap();
+        assert_eq!(next_rank(&mut fresh).await, Some(0));
+        assert_eq!(wcl.calls(), 2);
+    }
+}
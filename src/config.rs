@@ -1,33 +1,340 @@
-use serde::Deserialize;
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Debug, Deserialize)]
+/// Read an optional setting from the environment, falling back to `default`
+/// when unset. A value that doesn't parse is logged and ignored.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(raw) => raw.trim().parse().unwrap_or_else(|_| {
+            tracing::warn!("Ignoring invalid {}={:?}", name, raw);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// A period of whole minutes from the environment, `default_mins` when unset.
+/// Zero is raised to one minute, as timers can't tick with no period.
+pub fn minutes_or(name: &str, default_mins: u64) -> Duration {
+    at_least_a_minute(name, env_or(name, default_mins))
+}
+
+fn at_least_a_minute(name: &str, mins: u64) -> Duration {
+    if mins == 0 {
+        tracing::warn!("{}=0 is too short; using 1 minute", name);
+    }
+    Duration::from_secs(60 * mins.max(1))
+}
+
+/// The address to listen on: `LISTEN` as a whole (e.g. `127.0.0.1:8080` or
+/// `[::1]:3000`), otherwise `BIND_ADDR` and `PORT`, each defaulting to
+/// `0.0.0.0:3000`. Unlike `env_or`, a bad value is a startup error rather
+/// than silently replaced, since serving on the wrong interface isn't a
+/// safe fallback.
+pub fn listen_addr() -> anyhow::Result<SocketAddr> {
+    let var = |name| std::env::var(name).ok().filter(|v: &String| !v.trim().is_empty());
+    parse_listen_addr(var("LISTEN").as_deref(), var("BIND_ADDR").as_deref(), var("PORT").as_deref())
+}
+
+fn parse_listen_addr(listen: Option<&str>, bind: Option<&str>, port: Option<&str>) -> anyhow::Result<SocketAddr> {
+    if let Some(listen) = listen {
+        return listen.trim().parse().map_err(|_| {
+            anyhow::anyhow!("Invalid LISTEN={:?}: expected an address and port like 127.0.0.1:8080 or [::1]:3000", listen)
+        });
+    }
+
+    let ip: IpAddr = match bind {
+        // Accept `[::1]` too, as it would be written in LISTEN.
+        Some(bind) => bind.trim().trim_start_matches('[').trim_end_matches(']').parse().map_err(|_| {
+            anyhow::anyhow!("Invalid BIND_ADDR={:?}: expected an IP address like 127.0.0.1 or ::1", bind)
+        })?,
+        None => IpAddr::from([0, 0, 0, 0]),
+    };
+    let port: u16 = match port {
+        Some(port) => port.trim().parse().map_err(|_| {
+            anyhow::anyhow!("Invalid PORT={:?}: expected a number from 0 to 65535", port)
+        })?,
+        None => 3000,
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Origins allowed to call the API from a browser.
+#[derive(Debug, Clone)]
+pub enum CorsOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+/// `PUBLIC_URL`: where the site is served, for the links in webhook messages
+/// and the feed, without a trailing slash. Unset leaves the links out.
+pub fn public_url() -> Option<String> {
+    std::env::var("PUBLIC_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+}
+
+/// `ADMIN_TOKEN`: the shared secret the `/admin` routes require as a bearer
+/// token. Unset or empty refuses them outright.
+pub fn admin_token() -> Option<String> {
+    std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+/// `CORS_ALLOWED_ORIGINS`: comma-separated exact origins such as
+/// `https://app.example.com`, or `*` for any. Unset means no CORS headers,
+/// so only same-origin pages can read the API.
+pub fn cors_origins() -> anyhow::Result<Option<CorsOrigins>> {
+    let Some(raw) = std::env::var("CORS_ALLOWED_ORIGINS").ok().filter(|v| !v.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let origins: Vec<&str> = raw.split(',').map(str::trim).filter(|o| !o.is_empty()).collect();
+    if origins.contains(&"*") {
+        if origins.len() > 1 {
+            anyhow::bail!("CORS_ALLOWED_ORIGINS: `*` already allows every origin; list it alone");
+        }
+        return Ok(Some(CorsOrigins::Any));
+    }
+
+    for origin in &origins {
+        // Browsers send the bare origin, so anything with a path or a
+        // trailing slash would never match.
+        let rest = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"))
+            .ok_or_else(|| anyhow::anyhow!("CORS_ALLOWED_ORIGINS: {:?} must start with http:// or https://", origin))?;
+        if rest.is_empty() || rest.contains('/') {
+            anyhow::bail!("CORS_ALLOWED_ORIGINS: {:?} must be a bare origin like https://app.example.com", origin);
+        }
+    }
+    Ok(Some(CorsOrigins::List(origins.into_iter().map(String::from).collect())))
+}
+
+/// A unix socket to listen on instead of TCP.
+#[derive(Debug, Clone)]
+pub struct UnixSocket {
+    pub path: std::path::PathBuf,
+    pub mode: u32,
+}
+
+/// `LISTEN_UNIX`, with its permissions from `LISTEN_UNIX_MODE` in octal
+/// (default `660`, so a proxy in the same group can connect).
+pub fn unix_socket() -> anyhow::Result<Option<UnixSocket>> {
+    let var = |name| std::env::var(name).ok().filter(|v: &String| !v.trim().is_empty());
+    let Some(path) = var("LISTEN_UNIX") else {
+        return Ok(None);
+    };
+    let mode = match var("LISTEN_UNIX_MODE") {
+        Some(raw) => {
+            let digits = raw.trim().trim_start_matches("0o");
+            u32::from_str_radix(digits, 8)
+                .ok()
+                .filter(|mode| *mode <= 0o777)
+                .ok_or_else(|| anyhow::anyhow!("Invalid LISTEN_UNIX_MODE={:?}: expected octal permissions like 660", raw))?
+        }
+        None => 0o660,
+    };
+    Ok(Some(UnixSocket { path: path.into(), mode }))
+}
+
+/// PEM files for serving HTTPS directly.
+#[derive(Debug, Clone)]
+pub struct TlsPaths {
+    pub cert: std::path::PathBuf,
+    pub key:  std::path::PathBuf,
+}
+
+/// `TLS_CERT_PATH` and `TLS_KEY_PATH`, when both are set; plain HTTP when
+/// neither is. Setting only one is a startup error.
+pub fn tls_paths() -> anyhow::Result<Option<TlsPaths>> {
+    let var = |name| std::env::var(name).ok().filter(|v: &String| !v.trim().is_empty());
+    match (var("TLS_CERT_PATH"), var("TLS_KEY_PATH")) {
+        (Some(cert), Some(key)) => Ok(Some(TlsPaths { cert: cert.into(), key: key.into() })),
+        (None, None) => Ok(None),
+        (Some(_), None) => anyhow::bail!("TLS_CERT_PATH is set but TLS_KEY_PATH isn't; set both or neither"),
+        (None, Some(_)) => anyhow::bail!("TLS_KEY_PATH is set but TLS_CERT_PATH isn't; set both or neither"),
+    }
+}
+
+/// Which game a query is about. Retail unless `game=classic` is asked for,
+/// so requests that don't say are unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Game {
+    #[default]
+    Retail,
+    Classic,
+}
+
+impl Game {
+    pub fn is_retail(&self) -> bool {
+        *self == Self::Retail
+    }
+
+    /// The modes the form offers. Classic raids stop at Heroic and have no
+    /// Mythic+.
+    pub fn modes(self) -> Vec<Mode> {
+        match self {
+            Self::Retail  => ClassSpecs::get_modes(),
+            Self::Classic => vec![
+                Mode { name: "Normal", difficulty: 3, dungeon: false },
+                Mode { name: "Heroic", difficulty: 4, dungeon: false },
+            ],
+        }
+    }
+}
+
+/// `classes.toml`: a table per class, keyed by class name.
+#[derive(Debug)]
 pub struct ClassSpecs {
-    #[serde(flatten)]
     pub classes: BTreeMap<String, ClassData>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ClassData {
-    pub specs: Vec<String>,
+    pub specs: Vec<Spec>,
     pub color: Vec<String>,
     #[serde(rename = "pretty-color")]
     pub pretty_color: Vec<String>,
+    /// The class name WarcraftLogs expects, when it isn't the key without
+    /// underscores (`Death_Knight` -> `DeathKnight`).
+    #[serde(rename = "wcl-class-name")]
+    pub wcl_class_name: Option<String>,
 }
 
+/// A spec, written in `classes.toml` either as its name or as a table such
+/// as `{ name = "Beast Mastery", wcl-name = "BeastMastery", role = "dps" }`.
 #[derive(Debug, Clone)]
+pub struct Spec {
+    pub name: String,
+    pub wcl_name: Option<String>,
+    /// `None` for the plain-name form.
+    pub role: Option<Role>,
+}
+
+/// What a spec does in a group. Only these values are accepted in
+/// `classes.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Dps,
+    Healer,
+    Tank,
+}
+
+/// The table form of a spec. Unknown keys are rejected so a misspelt
+/// `wcl-name` isn't silently ignored.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct SpecTable {
+    name: String,
+    wcl_name: Option<String>,
+    role: Option<Role>,
+}
+
+impl<'de> Deserialize<'de> for Spec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SpecVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for SpecVisitor {
+            type Value = Spec;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, r#"a spec name, or a table like {{ name = "Beast Mastery", role = "dps" }}"#)
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<Spec, E> {
+                Ok(Spec { name: name.to_string(), wcl_name: None, role: None })
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Spec, A::Error> {
+                let table = SpecTable::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(Spec { name: table.name, wcl_name: table.wcl_name, role: table.role })
+            }
+        }
+
+        deserializer.deserialize_any(SpecVisitor)
+    }
+}
+
+impl Spec {
+    /// The spec name WarcraftLogs expects.
+    pub fn wcl_name(&self) -> &str {
+        self.wcl_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// A valid selection, named both as the config and as WarcraftLogs does.
+#[derive(Debug, Clone)]
+pub struct ResolvedSpec {
+    /// The class key, e.g. `Death_Knight`.
+    pub class: String,
+    pub spec: String,
+    pub wcl_class: String,
+    pub wcl_spec: String,
+}
+
+impl ClassData {
+    /// The class name WarcraftLogs expects for the class under `key`.
+    pub fn wcl_name(&self, key: &str) -> String {
+        self.wcl_class_name.clone().unwrap_or_else(|| key.replace('_', ""))
+    }
+
+    /// The first of the class's colors, which the page uses for it. Checked
+    /// to be a hex color when the config is loaded.
+    pub fn css_color(&self) -> Option<&str> {
+        self.color.first().map(|c| c.trim())
+    }
+}
+
+/// `#RGB` or `#RRGGBB`.
+fn is_hex_color(value: &str) -> bool {
+    let Some(digits) = value.trim().strip_prefix('#') else {
+        return false;
+    };
+    matches!(digits.len(), 3 | 6) && digits.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A region offered on the form, from `settings.toml`.
+#[derive(Debug, Clone, Deserialize)]
 pub struct Region {
-    pub code: &'static str,
-    pub name: &'static str,
+    /// Sent to WarcraftLogs as `serverRegion`, unless `aggregate`.
+    pub code: String,
+    pub name: String,
+    /// Stands for every region: the query goes out without a region.
+    #[serde(default)]
+    pub aggregate: bool,
+    /// The WarcraftLogs site this region's logs live on, when not the main
+    /// one, e.g. `https://cn.warcraftlogs.com`. Its queries, tokens and log
+    /// links all go there.
+    #[serde(default)]
+    pub site: Option<String>,
+}
+
+/// A query watched for build changes, as `[[watch]]` in `settings.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Watch {
+    #[serde(flatten)]
+    pub query:   TrackedQuery,
+    /// Discord webhook URL the changes are posted to.
+    pub webhook: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct Mode {
     pub name: &'static str,
-    // Warcraft Logs difficulty: 3 = Normal, 4 = Heroic, 5 = Mythic
+    // Warcraft Logs difficulty: 3 = Normal, 4 = Heroic, 5 = Mythic, 10 = Mythic+
     pub difficulty: i32,
+    /// Ranks dungeon runs rather than raid kills.
+    pub dungeon: bool,
 }
 
+/// Keystone levels a Mythic+ query can be narrowed to.
+pub const KEY_LEVELS: std::ops::RangeInclusive<i32> = 2..=30;
+
 #[derive(Debug, Clone)]
 pub struct Metric {
     pub name: &'static str,  // display label
@@ -38,20 +345,57 @@ pub struct Metric {
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    /// The WarcraftLogs site this game's logs live on, when not the main
+    /// one; set for Classic.
+    #[serde(default)]
+    pub site: Option<String>,
     pub current_season: CurrentSeason,
+    /// In form order.
+    pub regions: Vec<Region>,
     pub seasons: BTreeMap<String, Season>,
+    /// Queries whose builds are recorded daily for trends.
+    #[serde(default)]
+    pub tracked: Vec<TrackedQuery>,
+    /// Queries whose dominant build is posted to Discord when it changes.
+    #[serde(default)]
+    pub watch: Vec<Watch>,
+    /// Queries refetched in the background to keep their results cached.
+    #[serde(default)]
+    pub prefetch: Vec<TrackedQuery>,
+}
+
+/// A talent query run in the background: recorded daily, as `[[tracked]]` in
+/// `settings.toml`, watched, as part of a `[[watch]]`, or kept cached, as
+/// `[[prefetch]]`. Checked like any other query when it runs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrackedQuery {
+    pub class:     String,
+    pub spec:      String,
+    pub encounter: i32,
+    pub region:    String,
+    pub mode:      String,
+    #[serde(default)]
+    pub zone:      Option<String>,
+    #[serde(default)]
+    pub metric:    Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CurrentSeason {
     pub id: String,
+    /// Informational; the form shows the season's own name.
     pub name: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Season {
+    /// Shown in the form's raid list.
     pub name: String,
+    /// The fallback when `zones` is set, used if WCL can't be reached at startup.
     pub encounters: Vec<SeasonEncounter>,
+    /// WCL zone IDs whose encounters replace `encounters` at startup.
+    #[serde(default)]
+    pub zones: Vec<i32>,
     pub modes: Option<SeasonModes>,
     /// Optional WCL partition number. Set when a mid-season patch splits
     /// rankings (e.g. a prepatch). Omit for new seasons with no partition yet.
@@ -62,6 +406,13 @@ pub struct Season {
 pub struct SeasonEncounter {
     pub id: i32,
     pub name: String,
+    /// The raid the boss is in, for seasons spanning several; the form groups
+    /// bosses by it.
+    #[serde(default)]
+    pub raid: Option<String>,
+    /// A Mythic+ dungeon rather than a raid boss; listed under the Mythic+ mode.
+    #[serde(default)]
+    pub dungeon: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -70,37 +421,330 @@ pub struct SeasonModes {
     pub allowed: Vec<i32>,
 }
 
+/// A problem with `classes.toml` or `settings.toml`, worded so it says what
+/// to change. `file` is the path it was read from, or names the built-in copy.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The file couldn't be read at all.
+    Read { file: String, source: std::io::Error },
+    /// The file isn't valid TOML or doesn't have the expected shape.
+    Parse { file: String, line: Option<usize>, message: String },
+    /// Not a single class is defined.
+    NoClasses { file: String },
+    /// A class lists no specs, so nothing could be queried for it.
+    NoSpecs { file: String, class: String },
+    /// `color` and `pretty-color` describe the same colors and must pair up.
+    ColorMismatch { file: String, class: String, colors: usize, pretty_colors: usize },
+    /// A `wcl-class-name` or `wcl-name` is set but blank.
+    EmptyWclName { file: String, class: String, spec: Option<String> },
+    /// Two classes, or two specs of a class, map to the same WarcraftLogs
+    /// name, so one of them could never be queried. `first` and `second`
+    /// say which, e.g. `[Hunter] spec "Survival"`.
+    DuplicateWclName { file: String, first: String, second: String, name: String },
+    /// `current_season` names a season that isn't defined.
+    UnknownSeason { file: String, season: String },
+    /// The current season has no encounters, so the form would be empty.
+    NoEncounters { file: String, season: String },
+    /// An encounter ID appears twice in one season.
+    DuplicateEncounter { file: String, season: String, id: i32 },
+    /// No regions are defined, so nothing could be queried.
+    NoRegions { file: String },
+    /// A region has a blank code or name.
+    EmptyRegion { file: String, code: String },
+    /// A region code appears twice.
+    DuplicateRegion { file: String, code: String },
+    /// More than one region is marked `aggregate`.
+    SecondAggregate { file: String, first: String, second: String },
+    /// A region's `site` isn't an http or https URL.
+    BadSite { file: String, code: String },
+    /// The top-level `site` isn't an http or https URL.
+    BadGameSite { file: String },
+    /// The aggregate region names a `site`; it covers the main site only.
+    AggregateSite { file: String, code: String },
+    /// An encounter has a blank name.
+    EmptyEncounterName { file: String, season: String, id: i32 },
+    /// A `color` entry isn't a hex color. They end up in CSS, so nothing
+    /// else is let through.
+    BadColor { file: String, class: String, value: String },
+    /// A `[[watch]]` webhook isn't an https URL.
+    BadWebhook { file: String, spec: String, class: String },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read { file, .. } => write!(f, "Could not read {}", file),
+            Self::Parse { file, line: Some(line), message } => write!(f, "{} line {}: {}", file, line, message),
+            Self::Parse { file, line: None, message } => write!(f, "{}: {}", file, message),
+            Self::NoClasses { file } => write!(f, "{}: no classes are defined", file),
+            Self::NoSpecs { file, class } => {
+                write!(f, "{}: [{}] has an empty `specs` list; add at least one spec", file, class)
+            }
+            Self::ColorMismatch { file, class, colors, pretty_colors } => write!(
+                f,
+                "{}: [{}] has {} `color` entries but {} `pretty-color` entries; they must pair up one to one",
+                file, class, colors, pretty_colors
+            ),
+            Self::EmptyWclName { file, class, spec: Some(spec) } => write!(
+                f,
+                "{}: [{}] spec {:?} has an empty `wcl-name`; remove it to use the spec name",
+                file, class, spec
+            ),
+            Self::EmptyWclName { file, class, spec: None } => write!(
+                f,
+                "{}: [{}] has an empty `wcl-class-name`; remove it to use {:?}",
+                file, class, class.replace('_', "")
+            ),
+            Self::DuplicateWclName { file, first, second, name } => write!(
+                f,
+                "{}: {} and {} both map to WarcraftLogs name {:?}; set a different one for one of them",
+                file, first, second, name
+            ),
+            Self::UnknownSeason { file, season } => write!(
+                f,
+                "{}: current_season is {:?}, but there is no [seasons.{}]",
+                file, season, season
+            ),
+            Self::NoEncounters { file, season } => {
+                write!(f, "{}: [seasons.{}] is the current season but has no encounters", file, season)
+            }
+            Self::DuplicateEncounter { file, season, id } => {
+                write!(f, "{}: [seasons.{}] lists encounter {} more than once", file, season, id)
+            }
+            Self::NoRegions { file } => write!(f, "{}: no [[regions]] are defined", file),
+            Self::EmptyRegion { file, code } => {
+                write!(f, "{}: region {:?} needs both a code and a name", file, code)
+            }
+            Self::DuplicateRegion { file, code } => {
+                write!(f, "{}: region {:?} is listed more than once", file, code)
+            }
+            Self::SecondAggregate { file, first, second } => write!(
+                f,
+                "{}: regions {:?} and {:?} are both marked aggregate; only one can stand for all regions",
+                file, first, second
+            ),
+            Self::BadSite { file, code } => {
+                write!(f, "{}: region {:?} needs a site like \"https://cn.warcraftlogs.com\"", file, code)
+            }
+            Self::BadGameSite { file } => {
+                write!(f, "{}: site needs to be a URL like \"https://classic.warcraftlogs.com\"", file)
+            }
+            Self::AggregateSite { file, code } => write!(
+                f,
+                "{}: region {:?} is the aggregate one, which can't have its own site",
+                file, code
+            ),
+            Self::EmptyEncounterName { file, season, id } => {
+                write!(f, "{}: [seasons.{}] encounter {} has an empty name", file, season, id)
+            }
+            Self::BadColor { file, class, value } => write!(
+                f,
+                "{}: [{}] has color {:?}; colors must be hex like \"#3FC7EB\"",
+                file, class, value
+            ),
+            Self::BadWebhook { file, spec, class } => write!(
+                f,
+                "{}: the [[watch]] for {} {} needs a webhook starting with https://",
+                file, spec, class
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// The config file `name` from the path in `env_var`, else from a file of
+/// that name next to the binary, else `built_in`. Returns the file's name
+/// for messages along with its contents.
+fn read_config(
+    env_var: &str,
+    name: &'static str,
+    built_in: &'static str,
+) -> Result<(String, std::borrow::Cow<'static, str>), ConfigError> {
+    let beside_binary = std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(name)))
+        .filter(|path| path.is_file());
+    let path = std::env::var_os(env_var)
+        .filter(|path| !path.is_empty())
+        .map(std::path::PathBuf::from)
+        .or(beside_binary);
+
+    match path {
+        Some(path) => {
+            let file   = path.display().to_string();
+            let source = std::fs::read_to_string(&path)
+                .map_err(|source| ConfigError::Read { file: file.clone(), source })?;
+            Ok((file, source.into()))
+        }
+        None => Ok((format!("the built-in {}", name), built_in.into())),
+    }
+}
+
+/// Whether a `site` is something requests can be sent to.
+fn is_site_url(site: &str) -> bool {
+    reqwest::Url::parse(site).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Deserialize a config file, pointing at the offending line on failure.
+fn parse_toml<T: serde::de::DeserializeOwned>(file: &str, source: &str) -> Result<T, ConfigError> {
+    toml::from_str(source).map_err(|e| ConfigError::Parse {
+        file:    file.to_string(),
+        // 1-based, as editors count.
+        line:    e.span().map(|span| source[..span.start].matches('\n').count() + 1),
+        message: e.message().trim().replace('\n', "; "),
+    })
+}
+
 impl ClassSpecs {
-    pub fn load() -> Self {
-        const CONFIG: &str = include_str!("../classes.toml");
-        toml::from_str(CONFIG).expect("Failed to parse classes.toml")
+    /// The class catalog from `CLASSES_TOML_PATH`, else from a `classes.toml`
+    /// next to the binary, else the copy built into it. A file that exists
+    /// but is invalid is an error rather than a reason to fall back: the
+    /// operator put it there to change something. Classic's is read the
+    /// same way from `CLASSIC_CLASSES_TOML_PATH` or `classes_classic.toml`.
+    pub fn load(game: Game) -> Result<Self, ConfigError> {
+        let (file, source) = match game {
+            Game::Retail  => read_config("CLASSES_TOML_PATH", "classes.toml", include_str!("../classes.toml"))?,
+            Game::Classic => read_config(
+                "CLASSIC_CLASSES_TOML_PATH",
+                "classes_classic.toml",
+                include_str!("../classes_classic.toml"),
+            )?,
+        };
+        let specs = Self::parse(file.clone(), &source)?;
+        tracing::info!("Loaded classes from {}", file);
+        Ok(specs)
+    }
+
+    /// Parse and validate a class catalog. `file` names the source in errors.
+    fn parse(file: String, source: &str) -> Result<Self, ConfigError> {
+        // Parsed as the map itself rather than through `#[serde(flatten)]`,
+        // which would lose the position of errors.
+        let classes = parse_toml(&file, source)?;
+        let specs = Self { classes };
+
+        if specs.classes.is_empty() {
+            return Err(ConfigError::NoClasses { file });
+        }
+        for (class, data) in &specs.classes {
+            if data.specs.is_empty() {
+                return Err(ConfigError::NoSpecs { file, class: class.clone() });
+            }
+            if data.color.len() != data.pretty_color.len() {
+                return Err(ConfigError::ColorMismatch {
+                    file,
+                    class:         class.clone(),
+                    colors:        data.color.len(),
+                    pretty_colors: data.pretty_color.len(),
+                });
+            }
+            if let Some(value) = data.color.iter().find(|c| !is_hex_color(c)) {
+                return Err(ConfigError::BadColor { file, class: class.clone(), value: value.clone() });
+            }
+        }
+        specs.check_wcl_names(file)?;
+        Ok(specs)
+    }
+
+    /// Overrides must not be blank, and no two classes (or two specs of one
+    /// class) may end up with the same WarcraftLogs name.
+    fn check_wcl_names(&self, file: String) -> Result<(), ConfigError> {
+        let mut class_names: HashMap<String, &str> = HashMap::new();
+        for (class, data) in &self.classes {
+            if data.wcl_class_name.as_ref().is_some_and(|n| n.trim().is_empty()) {
+                return Err(ConfigError::EmptyWclName { file, class: class.clone(), spec: None });
+            }
+            let wcl_class = data.wcl_name(class);
+            if let Some(other) = class_names.insert(wcl_class.to_lowercase(), class) {
+                return Err(ConfigError::DuplicateWclName {
+                    file,
+                    first:  format!("[{}]", other),
+                    second: format!("[{}]", class),
+                    name:   wcl_class,
+                });
+            }
+
+            let mut spec_names: HashMap<String, &str> = HashMap::new();
+            for spec in &data.specs {
+                if spec.wcl_name.as_ref().is_some_and(|n| n.trim().is_empty()) {
+                    return Err(ConfigError::EmptyWclName { file, class: class.clone(), spec: Some(spec.name.clone()) });
+                }
+                if let Some(other) = spec_names.insert(spec.wcl_name().to_lowercase(), &spec.name) {
+                    return Err(ConfigError::DuplicateWclName {
+                        file,
+                        first:  format!("[{}] spec {:?}", class, other),
+                        second: format!("[{}] spec {:?}", class, spec.name),
+                        name:   spec.wcl_name().to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The class's color for the page, by key or display name.
+    pub fn class_color(&self, class_name: &str) -> Option<&str> {
+        self.classes.get(&class_name.replace(' ', "_"))?.css_color()
+    }
+
+    /// The role of a class's spec, by config names, when the config gives one.
+    pub fn role_of(&self, class: &str, spec: &str) -> Option<Role> {
+        self.classes
+            .get(&class.replace(' ', "_"))?
+            .specs
+            .iter()
+            .find(|s| s.name == spec)?
+            .role
     }
 
     pub fn class_names(&self) -> Vec<String> {
         self.classes.keys().map(|k| k.replace('_', " ")).collect()
     }
 
-    pub fn get_specs(&self, class_name: &str) -> Option<Vec<String>> {
-        let key = class_name.replace(' ', "_");
-        self.classes.get(&key).map(|c| c.specs.clone())
-    }
+    /// Check that `class` (underscore or space form) exists and has `spec`,
+    /// suggesting the closest known name when it doesn't. A spec's
+    /// WarcraftLogs name is accepted too, so links from before an override
+    /// was added keep working. The WarcraftLogs names only ever come from
+    /// here.
+    pub fn resolve(&self, class: &str, spec: &str) -> Result<ResolvedSpec, SelectionError> {
+        let key = class.replace(' ', "_");
+        let Some(data) = self.classes.get(&key) else {
+            let names = self.class_names();
+            let suggestion = closest_match(class, names.iter().map(String::as_str)).map(String::from);
+            return Err(SelectionError::UnknownClass { class: class.to_string(), suggestion });
+        };
 
-    pub fn get_regions() -> Vec<Region> {
-        vec![
-            Region { code: "all", name: "All Regions" },
-            Region { code: "US",  name: "US & Oceanic" },
-            Region { code: "EU",  name: "Europe" },
-            Region { code: "KR",  name: "Korea" },
-            Region { code: "TW",  name: "Taiwan" },
-            Region { code: "CN",  name: "China" },
-        ]
+        let found = data.specs.iter().find(|s| s.name == spec).or_else(|| data.specs.iter().find(|s| s.wcl_name() == spec));
+        if let Some(found) = found {
+            return Ok(ResolvedSpec {
+                wcl_class: data.wcl_name(&key),
+                class:     key,
+                spec:      found.name.clone(),
+                wcl_spec:  found.wcl_name().to_string(),
+            });
+        }
+        let suggestion = closest_match(spec, data.specs.iter().map(|s| s.name.as_str())).map(String::from);
+        Err(SelectionError::UnknownSpec {
+            spec: spec.to_string(),
+            class: class.replace('_', " "),
+            suggestion,
+        })
     }
 
     pub fn get_modes() -> Vec<Mode> {
         vec![
-            Mode { name: "Normal", difficulty: 3 },
-            Mode { name: "Heroic", difficulty: 4 },
-            Mode { name: "Mythic", difficulty: 5 },
+            Mode { name: "Normal",  difficulty: 3,  dungeon: false },
+            Mode { name: "Heroic",  difficulty: 4,  dungeon: false },
+            Mode { name: "Mythic",  difficulty: 5,  dungeon: false },
+            Mode { name: "Mythic+", difficulty: 10, dungeon: true },
         ]
     }
 
@@ -109,41 +753,651 @@ impl ClassSpecs {
             Metric { name: "Damage",       code: "dps" },
             Metric { name: "Healing",      code: "hps" },
             Metric { name: "Tank Healing", code: "tankhps" },
+            Metric { name: "Score",        code: "playerscore" },
         ]
     }
 }
 
+/// A class or spec that isn't in classes.toml.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SelectionError {
+    UnknownClass { class: String, suggestion: Option<String> },
+    UnknownSpec { spec: String, class: String, suggestion: Option<String> },
+}
+
+impl std::fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let suggestion = match self {
+            Self::UnknownClass { class, suggestion } => {
+                write!(f, "Unknown class '{}'", class)?;
+                suggestion
+            }
+            Self::UnknownSpec { spec, class, suggestion } => {
+                write!(f, "Unknown spec '{}' for {}", spec, class)?;
+                suggestion
+            }
+        };
+        match suggestion {
+            Some(name) => write!(f, " — did you mean '{}'?", name),
+            None       => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for SelectionError {}
+
+/// Lowercased alphanumerics only, so "Beast Mastery", "beast_mastery" and
+/// "BeastMastery" compare equal.
+fn match_key(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// First letters of each word, e.g. "dk" for "Death Knight" or "Death_Knight".
+fn initials(name: &str) -> String {
+    name.split([' ', '_'])
+        .filter_map(|w| w.chars().next())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(ca != *cb)).min(row[j] + 1).min(above + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate `input` most plausibly meant, ignoring case, spaces and
+/// underscores. Initials ("DK") and prefixes either way ("Feral Combat",
+/// "Marks") count as close; otherwise the edit distance has to be small
+/// relative to the name.
+fn closest_match<'a>(input: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let key = match_key(input);
+    if key.is_empty() {
+        return None;
+    }
+
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let other = match_key(candidate);
+            let distance = if key == other || (key.len() > 1 && key == initials(candidate)) {
+                0
+            } else if other.starts_with(&key) || key.starts_with(&other) {
+                1
+            } else {
+                levenshtein(&key, &other)
+            };
+            (distance, other.len(), candidate)
+        })
+        .filter(|(distance, len, _)| *distance <= (*len / 3).max(2))
+        .min_by_key(|(distance, _, _)| *distance)
+        .map(|(_, _, candidate)| candidate)
+}
+
+/// An encounter as served to clients.
+#[derive(Debug, Serialize)]
+pub struct EncounterInfo {
+    pub id: i32,
+    pub name: String,
+    pub raid: Option<String>,
+    pub dungeon: bool,
+    /// Position on the form, from 0.
+    pub order: usize,
+    pub season: String,
+    pub season_name: String,
+}
+
 impl Settings {
-    pub fn load() -> Self {
-        const SETTINGS: &str = include_str!("../settings.toml");
-        toml::from_str(SETTINGS).expect("Failed to parse settings.toml")
+    /// Seasons and their encounters, from `SETTINGS_TOML_PATH` or a file next
+    /// to the binary like `ClassSpecs::load`, so a new tier's bosses don't
+    /// need a rebuild. Classic's come from `CLASSIC_SETTINGS_TOML_PATH` or
+    /// `settings_classic.toml`.
+    pub fn load(game: Game) -> Result<Self, ConfigError> {
+        let (file, source) = match game {
+            Game::Retail  => read_config("SETTINGS_TOML_PATH", "settings.toml", include_str!("../settings.toml"))?,
+            Game::Classic => read_config(
+                "CLASSIC_SETTINGS_TOML_PATH",
+                "settings_classic.toml",
+                include_str!("../settings_classic.toml"),
+            )?,
+        };
+        let settings = Self::parse(file.clone(), &source)?;
+        tracing::info!("Loaded seasons from {}", file);
+        Ok(settings)
+    }
+
+    /// Parse and validate the seasons. `file` names the source in errors.
+    fn parse(file: String, source: &str) -> Result<Self, ConfigError> {
+        let settings: Self = parse_toml(&file, source)?;
+
+        let current = &settings.current_season.id;
+        match settings.seasons.get(current) {
+            None => return Err(ConfigError::UnknownSeason { file, season: current.clone() }),
+            Some(season) if season.encounters.is_empty() => {
+                return Err(ConfigError::NoEncounters { file, season: current.clone() });
+            }
+            Some(_) => {}
+        }
+
+        if let Some(site) = &settings.site
+            && !is_site_url(site)
+        {
+            return Err(ConfigError::BadGameSite { file });
+        }
+        if settings.regions.is_empty() {
+            return Err(ConfigError::NoRegions { file });
+        }
+        let mut aggregate: Option<&str> = None;
+        for (i, region) in settings.regions.iter().enumerate() {
+            if region.code.trim().is_empty() || region.name.trim().is_empty() {
+                return Err(ConfigError::EmptyRegion { file, code: region.code.clone() });
+            }
+            if settings.regions[..i].iter().any(|r| r.code.eq_ignore_ascii_case(&region.code)) {
+                return Err(ConfigError::DuplicateRegion { file, code: region.code.clone() });
+            }
+            if let Some(site) = &region.site {
+                if region.aggregate {
+                    return Err(ConfigError::AggregateSite { file, code: region.code.clone() });
+                }
+                if !is_site_url(site) {
+                    return Err(ConfigError::BadSite { file, code: region.code.clone() });
+                }
+            }
+            if region.aggregate {
+                if let Some(first) = aggregate {
+                    return Err(ConfigError::SecondAggregate { file, first: first.to_string(), second: region.code.clone() });
+                }
+                aggregate = Some(&region.code);
+            }
+        }
+
+        for (id, season) in &settings.seasons {
+            let mut seen = std::collections::HashSet::new();
+            for encounter in &season.encounters {
+                if encounter.name.trim().is_empty() {
+                    return Err(ConfigError::EmptyEncounterName { file, season: id.clone(), id: encounter.id });
+                }
+                if !seen.insert(encounter.id) {
+                    return Err(ConfigError::DuplicateEncounter { file, season: id.clone(), id: encounter.id });
+                }
+            }
+        }
+
+        for watch in &settings.watch {
+            if !watch.webhook.starts_with("https://") {
+                let (spec, class) = (watch.query.spec.clone(), watch.query.class.clone());
+                return Err(ConfigError::BadWebhook { file, spec, class });
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The configured region with this code, ignoring case.
+    pub fn region(&self, code: &str) -> Option<&Region> {
+        self.regions.iter().find(|r| r.code.eq_ignore_ascii_case(code))
+    }
+
+    /// The raid tiers the form offers, the current one first and the rest in
+    /// key order. Seasons without encounters are left out.
+    pub fn tiers(&self) -> Vec<(&str, &Season)> {
+        let current = self.current_season.id.as_str();
+        let mut tiers: Vec<(&str, &Season)> = self
+            .seasons
+            .iter()
+            .filter(|(_, s)| !s.encounters.is_empty())
+            .map(|(id, s)| (id.as_str(), s))
+            .collect();
+        tiers.sort_by_key(|(id, _)| *id != current);
+        tiers
     }
 
-    pub fn current_encounters(&self) -> Vec<SeasonEncounter> {
-        let id = &self.current_season.id;
-        self.seasons.get(id).map(|s| s.encounters.clone()).unwrap_or_default()
+    /// The tier with this key, or the current season when none is given.
+    pub fn tier(&self, id: Option<&str>) -> Option<(&str, &Season)> {
+        let id = id.unwrap_or(&self.current_season.id);
+        self.seasons.get_key_value(id).map(|(id, season)| (id.as_str(), season))
+    }
+
+    /// WCL zones the current season's encounters are read from, if any.
+    pub fn current_zones(&self) -> &[i32] {
+        self.seasons.get(&self.current_season.id).map_or(&[], |s| &s.zones)
+    }
+
+    /// Replace the current season's raid bosses with ones read from WCL. Its
+    /// dungeons are kept, after them.
+    pub fn set_current_encounters(&mut self, encounters: Vec<SeasonEncounter>) {
+        if let Some(season) = self.seasons.get_mut(&self.current_season.id) {
+            let dungeons = season.encounters.drain(..).filter(|e| e.dungeon);
+            season.encounters = encounters.into_iter().chain(dungeons).collect();
+        }
+    }
+}
+
+impl Season {
+    /// A tier's encounters in the order the form lists them, shared by
+    /// the page and `/api/encounters`. `id` is the tier's key.
+    pub fn encounter_catalog(&self, id: &str) -> Vec<EncounterInfo> {
+        self.encounters
+            .iter()
+            .enumerate()
+            .map(|(order, e)| EncounterInfo {
+                id: e.id,
+                name: e.name.clone(),
+                raid: e.raid.clone(),
+                dungeon: e.dungeon,
+                order,
+                season: id.to_string(),
+                season_name: self.name.clone(),
+            })
+            .collect()
     }
 
     pub fn default_difficulty(&self) -> i32 {
-        let id = &self.current_season.id;
-        self.seasons
-            .get(id)
-            .and_then(|s| s.modes.as_ref())
-            .map(|m| m.default)
-            .unwrap_or(5)
+        self.modes.as_ref().map(|m| m.default).unwrap_or(5)
     }
 
     pub fn allowed_difficulties(&self) -> Vec<i32> {
-        let id = &self.current_season.id;
-        self.seasons
-            .get(id)
-            .and_then(|s| s.modes.as_ref())
-            .map(|m| m.allowed.clone())
-            .unwrap_or_else(|| vec![3, 4, 5])
+        self.modes.as_ref().map(|m| m.allowed.clone()).unwrap_or_else(|| vec![3, 4, 5])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Specs {
+        specs: Vec<Spec>,
+    }
+
+    fn specs(source: &str) -> Result<Vec<Spec>, toml::de::Error> {
+        toml::from_str::<Specs>(source).map(|s| s.specs)
+    }
+
+    #[test]
+    fn spec_as_plain_name() {
+        let specs = specs(r#"specs = ["Frost"]"#).unwrap();
+        assert_eq!(specs[0].name, "Frost");
+        assert_eq!(specs[0].wcl_name(), "Frost");
+        assert_eq!(specs[0].role, None);
+    }
+
+    #[test]
+    fn spec_as_table() {
+        let specs = specs(r#"specs = [{ name = "Beast Mastery", wcl-name = "BeastMastery", role = "dps" }]"#).unwrap();
+        assert_eq!(specs[0].name, "Beast Mastery");
+        assert_eq!(specs[0].wcl_name(), "BeastMastery");
+        assert_eq!(specs[0].role, Some(Role::Dps));
+    }
+
+    #[test]
+    fn spec_table_rejects_unknown_keys_and_roles() {
+        let misspelt = specs(r#"specs = [{ name = "Frost", wcl_name = "Frost" }]"#).unwrap_err();
+        assert!(misspelt.message().contains("unknown field `wcl_name`"), "{}", misspelt);
+
+        let role = specs(r#"specs = [{ name = "Frost", role = "support" }]"#).unwrap_err();
+        assert!(role.message().contains("unknown variant `support`"), "{}", role);
+
+        let number = specs("specs = [3]").unwrap_err();
+        assert!(number.message().contains("a spec name, or a table"), "{}", number);
     }
 
-    pub fn current_partition(&self) -> Option<i32> {
-        let id = &self.current_season.id;
-        self.seasons.get(id).and_then(|s| s.partition)
+    #[test]
+    fn levenshtein_counts_edits() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("frost", "frost"), 0);
+        assert_eq!(levenshtein("frost", "forst"), 2);
+        assert_eq!(levenshtein("holy", "holly"), 1);
+        assert_eq!(levenshtein("", "fire"), 4);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn closest_match_ignores_case_spaces_and_underscores() {
+        let specs = ["Beast Mastery", "Marksmanship", "Survival"];
+        assert_eq!(closest_match("BeastMastery", specs), Some("Beast Mastery"));
+        assert_eq!(closest_match("beast_mastery", specs), Some("Beast Mastery"));
+        assert_eq!(closest_match("BEAST MASTERY", specs), Some("Beast Mastery"));
+
+        let classes = ["Death Knight", "Demon Hunter", "Druid"];
+        assert_eq!(closest_match("death_knight", classes), Some("Death Knight"));
+        assert_eq!(closest_match("DeathKnight", classes), Some("Death Knight"));
+    }
+
+    #[test]
+    fn closest_match_takes_initials_prefixes_and_typos() {
+        let classes = ["Death Knight", "Demon Hunter", "Druid"];
+        assert_eq!(closest_match("DK", classes), Some("Death Knight"));
+        assert_eq!(closest_match("dh", classes), Some("Demon Hunter"));
+
+        let specs = ["Balance", "Feral", "Guardian", "Restoration"];
+        assert_eq!(closest_match("Feral Combat", specs), Some("Feral"));
+        assert_eq!(closest_match("Resto", specs), Some("Restoration"));
+        assert_eq!(closest_match("Gaurdian", specs), Some("Guardian"));
+    }
+
+    #[test]
+    fn closest_match_gives_up_on_unrelated_input() {
+        let specs = ["Balance", "Feral", "Guardian", "Restoration"];
+        assert_eq!(closest_match("Arcane", specs), None);
+        assert_eq!(closest_match("", specs), None);
+        assert_eq!(closest_match(" _ ", specs), None);
+    }
+
+    fn listen(listen: Option<&str>, bind: Option<&str>, port: Option<&str>) -> String {
+        match parse_listen_addr(listen, bind, port) {
+            Ok(addr) => addr.to_string(),
+            Err(e)   => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn minute_periods_are_at_least_a_minute() {
+        assert_eq!(at_least_a_minute("WATCH_INTERVAL_MINS", 0), Duration::from_secs(60));
+        assert_eq!(at_least_a_minute("WATCH_INTERVAL_MINS", 1), Duration::from_secs(60));
+        assert_eq!(at_least_a_minute("WATCH_INTERVAL_MINS", 15), Duration::from_secs(900));
+        assert_eq!(minutes_or("TEST_UNSET_INTERVAL_MINS", 0), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn listen_addr_defaults_to_every_interface_on_3000() {
+        assert_eq!(listen(None, None, None), "0.0.0.0:3000");
+    }
+
+    #[test]
+    fn listen_addr_from_bind_addr_and_port() {
+        assert_eq!(listen(None, Some("127.0.0.1"), None), "127.0.0.1:3000");
+        assert_eq!(listen(None, None, Some("8080")), "0.0.0.0:8080");
+        assert_eq!(listen(None, Some("::1"), Some(" 8080 ")), "[::1]:8080");
+        assert_eq!(listen(None, Some("[::1]"), Some("0")), "[::1]:0");
+    }
+
+    #[test]
+    fn listen_takes_precedence() {
+        assert_eq!(listen(Some("[::1]:3000"), None, None), "[::1]:3000");
+        assert_eq!(listen(Some("127.0.0.1:8080"), Some("10.0.0.1"), Some("9000")), "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn listen_addr_rejects_bad_values() {
+        assert_eq!(
+            listen(Some("::1:3000"), None, None),
+            r#"Invalid LISTEN="::1:3000": expected an address and port like 127.0.0.1:8080 or [::1]:3000"#
+        );
+        assert!(listen(Some("127.0.0.1"), None, None).starts_with("Invalid LISTEN="));
+        assert_eq!(
+            listen(None, Some("localhost"), None),
+            r#"Invalid BIND_ADDR="localhost": expected an IP address like 127.0.0.1 or ::1"#
+        );
+        assert_eq!(listen(None, None, Some("70000")), r#"Invalid PORT="70000": expected a number from 0 to 65535"#);
+        assert!(listen(None, None, Some("http")).starts_with("Invalid PORT="));
+    }
+
+    fn resolve_error(game: Game, class: &str, spec: &str) -> String {
+        ClassSpecs::load(game).unwrap().resolve(class, spec).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn resolve_accepts_either_class_form_and_wcl_spec_names() {
+        let config = ClassSpecs::load(Game::Retail).unwrap();
+        let resolved = config.resolve("Death Knight", "Frost").unwrap();
+        assert_eq!((resolved.class.as_str(), resolved.wcl_class.as_str()), ("Death_Knight", "DeathKnight"));
+
+        let resolved = config.resolve("Hunter", "BeastMastery").unwrap();
+        assert_eq!((resolved.spec.as_str(), resolved.wcl_spec.as_str()), ("Beast Mastery", "BeastMastery"));
+    }
+
+    #[test]
+    fn resolve_suggests_classes() {
+        assert_eq!(resolve_error(Game::Retail, "DK", "Frost"), "Unknown class 'DK' \u{2014} did you mean 'Death Knight'?");
+        assert_eq!(resolve_error(Game::Retail, "death knight", "Frost"), "Unknown class 'death knight' \u{2014} did you mean 'Death Knight'?");
+        assert_eq!(resolve_error(Game::Retail, "DemonHunter", "Havoc"), "Unknown class 'DemonHunter' \u{2014} did you mean 'Demon Hunter'?");
+        assert_eq!(resolve_error(Game::Retail, "Nope", "Frost"), "Unknown class 'Nope'");
+    }
+
+    #[test]
+    fn resolve_suggests_specs() {
+        assert_eq!(
+            resolve_error(Game::Retail, "Hunter", "beast_mastery"),
+            "Unknown spec 'beast_mastery' for Hunter \u{2014} did you mean 'Beast Mastery'?"
+        );
+        assert_eq!(
+            resolve_error(Game::Retail, "Hunter", "beastmastery"),
+            "Unknown spec 'beastmastery' for Hunter \u{2014} did you mean 'Beast Mastery'?"
+        );
+        assert_eq!(
+            resolve_error(Game::Retail, "Death_Knight", "Frostt"),
+            "Unknown spec 'Frostt' for Death Knight \u{2014} did you mean 'Frost'?"
+        );
+        assert_eq!(
+            resolve_error(Game::Classic, "Druid", "Feral Combat"),
+            "Unknown spec 'Feral Combat' for Druid \u{2014} did you mean 'Feral'?"
+        );
+        assert_eq!(resolve_error(Game::Retail, "Mage", "Shadow"), "Unknown spec 'Shadow' for Mage");
+    }
+
+    const CLASSES: &str = r##"
+        [Mage]
+        specs = [{ name = "Frost", role = "dps" }, "Fire"]
+        color = ["#3FC7EB"]
+        pretty-color = ["light-blue"]
+
+        [Death_Knight]
+        specs = ["Unholy"]
+        color = ["#C41E3A"]
+        pretty-color = ["red"]
+    "##;
+
+    fn classes(source: &str) -> Result<ClassSpecs, ConfigError> {
+        ClassSpecs::parse("classes.toml".to_string(), source)
+    }
+
+    #[test]
+    fn valid_classes_parse() {
+        let config = classes(CLASSES).unwrap();
+        assert_eq!(config.class_names(), ["Death Knight", "Mage"]);
+        assert_eq!(config.class_color("Death Knight"), Some("#C41E3A"));
+        assert_eq!(config.role_of("Mage", "Frost"), Some(Role::Dps));
+        assert_eq!(config.role_of("Mage", "Fire"), None);
+    }
+
+    #[test]
+    fn classes_parse_errors_point_at_the_line() {
+        let error = classes(&CLASSES.replace(r#"pretty-color = ["red"]"#, r#"pretty-color = "red"#)).unwrap_err();
+        assert!(matches!(error, ConfigError::Parse { line: Some(10), .. }), "{:?}", error);
+        assert!(error.to_string().starts_with("classes.toml line 10: "), "{}", error);
+
+        let error = classes(&CLASSES.replace("specs = [\"Unholy\"]", "spec = [\"Unholy\"]")).unwrap_err();
+        assert!(error.to_string().contains("missing field `specs`"), "{}", error);
+    }
+
+    #[test]
+    fn classes_errors() {
+        assert!(matches!(classes("# nothing yet"), Err(ConfigError::NoClasses { .. })));
+
+        let cases = [
+            (CLASSES.replace("specs = [\"Unholy\"]", "specs = []"), "[Death_Knight] has an empty `specs` list; add at least one spec"),
+            (
+                CLASSES.replace(r##"color = ["#3FC7EB"]"##, r##"color = ["#3FC7EB", "#FFFFFF"]"##),
+                "[Mage] has 2 `color` entries but 1 `pretty-color` entries; they must pair up one to one",
+            ),
+            (
+                CLASSES.replace(r##""#C41E3A""##, r#""red; background: url(x)""#),
+                r##"[Death_Knight] has color "red; background: url(x)"; colors must be hex like "#3FC7EB""##,
+            ),
+            (CLASSES.replace(r##""#C41E3A""##, r##""#C41E3""##), r##"[Death_Knight] has color "#C41E3""##),
+        ];
+        for (source, expected) in cases {
+            let error = classes(&source).unwrap_err().to_string();
+            assert!(error.starts_with("classes.toml: "), "{}", error);
+            assert!(error.contains(expected), "expected {:?} in {:?}", expected, error);
+        }
+        assert!(matches!(
+            classes(&CLASSES.replace(r##""#C41E3A""##, r#""red""#)),
+            Err(ConfigError::BadColor { class, value, .. }) if class == "Death_Knight" && value == "red"
+        ));
+    }
+
+    #[test]
+    fn wcl_names_default_to_the_key_and_spec_name() {
+        let config = ClassSpecs::load(Game::Retail).unwrap();
+        let wcl = |class: &str, spec: &str| {
+            let resolved = config.resolve(class, spec).unwrap();
+            (resolved.wcl_class, resolved.wcl_spec)
+        };
+        assert_eq!(wcl("Death_Knight", "Unholy"), ("DeathKnight".to_string(), "Unholy".to_string()));
+        assert_eq!(wcl("Demon Hunter", "Vengeance"), ("DemonHunter".to_string(), "Vengeance".to_string()));
+        assert_eq!(wcl("Hunter", "Beast Mastery"), ("Hunter".to_string(), "BeastMastery".to_string()));
+    }
+
+    #[test]
+    fn wcl_name_overrides_replace_the_derived_names() {
+        let source = CLASSES.replace("[Death_Knight]", "[Death_Knight]\nwcl-class-name = \"DK\"").replace(
+            r#""Fire""#,
+            r#"{ name = "Fire Mage", wcl-name = "Fire" }"#,
+        );
+        let config = classes(&source).unwrap();
+        assert_eq!(config.resolve("Death Knight", "Unholy").unwrap().wcl_class, "DK");
+        let resolved = config.resolve("Mage", "Fire Mage").unwrap();
+        assert_eq!((resolved.spec.as_str(), resolved.wcl_spec.as_str()), ("Fire Mage", "Fire"));
+    }
+
+    #[test]
+    fn wcl_name_overrides_must_be_set_and_distinct() {
+        let cases = [
+            (
+                CLASSES.replace("[Death_Knight]", "[Death_Knight]\nwcl-class-name = \" \""),
+                r#"[Death_Knight] has an empty `wcl-class-name`; remove it to use "DeathKnight""#,
+            ),
+            (
+                CLASSES.replace(r#""Fire""#, r#"{ name = "Fire", wcl-name = "" }"#),
+                r#"[Mage] spec "Fire" has an empty `wcl-name`; remove it to use the spec name"#,
+            ),
+            (
+                CLASSES.replace("[Death_Knight]", "[Death_Knight]\nwcl-class-name = \"Mage\""),
+                r#"[Death_Knight] and [Mage] both map to WarcraftLogs name "Mage""#,
+            ),
+            (
+                CLASSES.replace(r#""Fire""#, r#"{ name = "Fire", wcl-name = "frost" }"#),
+                r#"[Mage] spec "Frost" and [Mage] spec "Fire" both map to WarcraftLogs name "frost""#,
+            ),
+        ];
+        for (source, expected) in cases {
+            let error = classes(&source).unwrap_err().to_string();
+            assert!(error.contains(expected), "expected {:?} in {:?}", expected, error);
+        }
+    }
+
+    const SETTINGS: &str = r#"
+        [current_season]
+        id = "s1"
+        name = "Season 1"
+
+        [[regions]]
+        code = "all"
+        name = "All Regions"
+        aggregate = true
+
+        [[regions]]
+        code = "EU"
+        name = "Europe"
+
+        [seasons.s1]
+        name = "Raid"
+        encounters = [{ id = 1, name = "First Boss" }, { id = 2, name = "Second Boss" }]
+    "#;
+
+    fn settings(source: &str) -> Result<Settings, ConfigError> {
+        Settings::parse("settings.toml".to_string(), source)
+    }
+
+    #[test]
+    fn valid_settings_parse() {
+        let settings = settings(SETTINGS).unwrap();
+        assert_eq!(settings.current_season.id, "s1");
+        assert_eq!(settings.region("eu").map(|r| r.name.as_str()), Some("Europe"));
+        assert_eq!(settings.tier(None).map(|(id, _)| id), Some("s1"));
+    }
+
+    #[test]
+    fn tiers_put_the_current_season_first() {
+        let source = format!(
+            "{}\n[seasons.a_old]\nname = \"Old Raid\"\nencounters = [{{ id = 9, name = \"Old Boss\" }}]\n\
+             [seasons.z_empty]\nname = \"Empty\"\nencounters = []",
+            SETTINGS
+        );
+        let settings = settings(&source).unwrap();
+        let tiers: Vec<&str> = settings.tiers().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(tiers, ["s1", "a_old"]);
+        assert_eq!(settings.tier(Some("a_old")).map(|(_, s)| s.name.as_str()), Some("Old Raid"));
+        assert!(settings.tier(Some("missing")).is_none());
+    }
+
+    #[test]
+    fn settings_errors() {
+        let cases = [
+            (SETTINGS.replace(r#"id = "s1""#, r#"id = "s2""#), "current_season is \"s2\", but there is no [seasons.s2]"),
+            (
+                SETTINGS.replace(r#"encounters = [{ id = 1, name = "First Boss" }, { id = 2, name = "Second Boss" }]"#, "encounters = []"),
+                "[seasons.s1] is the current season but has no encounters",
+            ),
+            (SETTINGS.replace("id = 2,", "id = 1,"), "[seasons.s1] lists encounter 1 more than once"),
+            (SETTINGS.replace(r#""Second Boss""#, r#"" ""#), "[seasons.s1] encounter 2 has an empty name"),
+            (SETTINGS.replace(r#"code = "EU""#, r#"code = "all""#), "region \"all\" is listed more than once"),
+            (SETTINGS.replace(r#"name = "Europe""#, r#"name = """#), "region \"EU\" needs both a code and a name"),
+            (
+                SETTINGS.replace(r#"name = "Europe""#, "name = \"Europe\"\naggregate = true"),
+                "regions \"all\" and \"EU\" are both marked aggregate",
+            ),
+            (
+                SETTINGS.replace(r#"name = "Europe""#, "name = \"Europe\"\nsite = \"cn.warcraftlogs.com\""),
+                "region \"EU\" needs a site like",
+            ),
+            (
+                SETTINGS.replace(r#"name = "All Regions""#, "name = \"All Regions\"\nsite = \"https://cn.warcraftlogs.com\""),
+                "region \"all\" is the aggregate one, which can't have its own site",
+            ),
+            (format!("site = \"ftp://example.com\"\n{}", SETTINGS), "site needs to be a URL"),
+            (
+                format!(
+                    "{}\n[[watch]]\nclass = \"Mage\"\nspec = \"Frost\"\nencounter = 1\nregion = \"EU\"\nmode = \"Mythic\"\nwebhook = \"http://example.com\"",
+                    SETTINGS
+                ),
+                "the [[watch]] for Frost Mage needs a webhook starting with https://",
+            ),
+        ];
+        for (source, expected) in cases {
+            let error = settings(&source).unwrap_err().to_string();
+            assert!(error.starts_with("settings.toml: "), "{}", error);
+            assert!(error.contains(expected), "expected {:?} in {:?}", expected, error);
+        }
+    }
+
+    #[test]
+    fn settings_without_regions() {
+        let source = r#"
+            regions = []
+
+            [current_season]
+            id = "s1"
+            name = "Season 1"
+
+            [seasons.s1]
+            name = "Raid"
+            encounters = [{ id = 1, name = "First Boss" }]
+        "#;
+        assert!(matches!(settings(source), Err(ConfigError::NoRegions { .. })));
     }
 }
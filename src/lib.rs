@@ -0,0 +1,13 @@
+//! Talent builds of the top-ranked WarcraftLogs players, without the web
+//! server: the upstream client and the class and season catalogs it is
+//! driven by. The `talent-trends` binary wires these into the site.
+
+/// Bounded in-memory caches with per-entry expiry.
+pub mod cache;
+/// The class/spec catalog and the seasons, regions and modes queries may
+/// ask for, loaded from TOML, plus environment helpers.
+pub mod config;
+/// Upstream call metrics and their Prometheus rendering.
+pub mod metrics;
+/// The WarcraftLogs client: rankings, talents and the API budget.
+pub mod warcraftlogs;
@@ -1,29 +1,75 @@
+use anyhow::Context;
+use clap::Parser;
 use axum::{
-    extract::Query,
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode, header},
     response::{
-        Html,
+        Html, IntoResponse, Json, Response,
         sse::{Event, Sse},
     },
     routing::get,
     Router,
 };
-use futures::stream::Stream;
-use serde::Deserialize;
-use std::{convert::Infallible, net::SocketAddr, time::Duration};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::IntoFuture,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tower_http::{
+    compression::{CompressionLayer, DefaultPredicate, Predicate, predicate::NotForContentType},
+    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-mod config;
-mod style;
+mod admin;
+mod assets;
+mod cli;
+mod coalesce;
+#[cfg(test)]
+mod mock_wcl;
+mod openapi;
+mod prefetch;
+mod ratelimit;
+mod shutdown;
+mod store;
 mod templates;
-mod warcraftlogs;
+mod tls;
+#[cfg(unix)]
+mod uds;
+mod watch;
 
-use config::{ClassSpecs, Settings};
-use warcraftlogs::TalentDataWithRank;
+use talent_trends::{cache, config, metrics, warcraftlogs};
+
+use cache::TtlCache;
+use coalesce::{Coalescer, TalentStream};
+use config::{ClassSpecs, Game, ResolvedSpec, Role, Settings, env_or};
+use metrics::ServerTiming;
+use ratelimit::RateLimiter;
+use store::Store;
+use warcraftlogs::{RankingsQuery, StreamItem, TIER_OVERALL, TalentData, TalentDataWithRank, WclClient};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
 
+    match cli::Cli::parse().command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve       => serve().await,
+        cli::Command::Fetch(args) => cli::fetch(args).await,
+        cli::Command::Check       => cli::check().await,
+    }
+}
+
+/// Run the site until shutdown; what the binary does without a subcommand.
+async fn serve() -> anyhow::Result<()> {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -32,119 +78,3186 @@ async fn main() -> anyhow::Result<()> {
         )
         .init();
 
-    let config = ClassSpecs::load();
+    let addr   = config::listen_addr()?;
+    let unix   = config::unix_socket()?;
+    let tls    = match config::tls_paths()? {
+        Some(_) if unix.is_some() => anyhow::bail!("TLS isn't supported on a unix socket; unset LISTEN_UNIX or the TLS paths"),
+        Some(paths) => Some((tls::load(&paths).await?, paths)),
+        None        => None,
+    };
+    let config = ClassSpecs::load(Game::Retail)?;
 
-    for (class_name, class_data) in &config.classes {
-        let color_len  = class_data.color.len();
-        let pretty_len = class_data.pretty_color.len();
-        assert_eq!(
-            color_len, pretty_len,
-            "Mismatch for class '{}' (color: {}, pretty_color: {})",
-            class_name, color_len, pretty_len
-        );
+    tracing::info!(
+        "talent-trends {} (commit {}, built {})",
+        BUILD_INFO.version, BUILD_INFO.commit, BUILD_INFO.built_at
+    );
+    tracing::info!("Loaded {} classes.", config.classes.len());
+
+    let store = store::from_env()?;
+
+    // Nothing on the page changes while the process runs, so it is rendered
+    // once here rather than on every request.
+    let mut settings = Settings::load(Game::Retail)?;
+    let classic_config       = ClassSpecs::load(Game::Classic)?;
+    let mut classic_settings = Settings::load(Game::Classic)?;
+    let wcl = WclClient::new(warcraftlogs::Endpoints::from_env()?, &settings, &classic_settings)?;
+    check_credentials(&wcl).await?;
+    load_zone_encounters(&wcl, &mut settings).await;
+    load_zone_encounters(&wcl.for_game(Game::Classic, None), &mut classic_settings).await;
+
+    // Only reachable with SKIP_STARTUP_CHECK; queries would all fail, so the
+    // page says how to set things up instead.
+    let missing    = warcraftlogs::missing_credentials();
+    let setup_page = (!missing.is_empty()).then(|| Bytes::from(templates::setup(&missing)));
+
+    let render_started = Instant::now();
+    let home_page      = Bytes::from(templates::home(&config, &settings, Game::Retail));
+    tracing::debug!("Rendered the home page ({} bytes) in {:?}", home_page.len(), render_started.elapsed());
+    let classic = Catalog {
+        home_page: Bytes::from(templates::home(&classic_config, &classic_settings, Game::Classic)),
+        config:    classic_config,
+        settings:  classic_settings,
+    };
+
+    let max_streams = env_or("MAX_OPEN_STREAMS", 100);
+    let state = AppState {
+        talents:    Coalescer::new(Arc::new(wcl.clone()), store.clone()),
+        wcl,
+        config:     Arc::new(config),
+        settings:   Arc::new(settings),
+        home_page,
+        setup_page,
+        classic:    Arc::new(classic),
+        started_at: Instant::now(),
+        store,
+        talent_limiter:   Arc::new(RateLimiter::new(env_or("TALENT_QUERIES_PER_MINUTE", 10))),
+        snapshot_limiter: Arc::new(RateLimiter::new(env_or("SNAPSHOT_QUERIES_PER_MINUTE", 2))),
+        recent_nocache:   Arc::new(TtlCache::new(Duration::from_secs(env_or("NOCACHE_MIN_INTERVAL_SECS", 60)), 10_000)),
+        max_streams,
+        stream_permits:   Arc::new(Semaphore::new(max_streams)),
+        trend_retention:  match env_or("TREND_RETENTION_DAYS", 90) {
+            0    => None,
+            days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+        },
+        admin_token:      config::admin_token().map(Arc::from),
+        public_url:       config::public_url().map(Arc::from),
+    };
+
+    if let Some(store) = state.store.clone() {
+        tokio::spawn(record_trends(state.clone(), store));
+    }
+    if !state.settings.watch.is_empty() {
+        tokio::spawn(watch::run(state.clone()));
+    }
+    if !state.settings.prefetch.is_empty() {
+        tokio::spawn(prefetch::run(state.clone()));
     }
 
-    tracing::info!("Loaded {} classes.", config.classes.len());
+    let app = app(state, config::cors_origins()?);
+
+    if let Some(socket) = unix {
+        #[cfg(unix)]
+        {
+            let listener = uds::bind(&socket.path, socket.mode)?;
+            tracing::info!("Server listening on unix:{}", socket.path.display());
+            run_until_drained(uds::serve(listener, socket.path, app)).await?;
+            return Ok(());
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("LISTEN_UNIX={} is only supported on Unix", socket.path.display());
+    }
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some((tls_config, paths)) => {
+            let listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("Could not listen on {}", addr))?;
+            tracing::info!("Server listening on https://{}", addr);
+            tls::reload_on_sighup(tls_config.clone(), paths);
+
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown::signal().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            run_until_drained(axum_server::from_tcp_rustls(listener, tls_config).handle(handle).serve(app)).await?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Could not listen on {}", addr))?;
+            tracing::info!("Server listening on http://{}", addr);
+            run_until_drained(axum::serve(listener, app).with_graceful_shutdown(shutdown::signal())).await?;
+        }
+    }
 
-    let app = Router::new()
+    Ok(())
+}
+
+/// Every route of the site, with its middleware. `cors_origins` are the
+/// origins besides the site's own allowed to call the API.
+fn app(state: AppState, cors_origins: Option<config::CorsOrigins>) -> Router {
+    let api = Router::new()
+        // Unversioned alias kept for EventSource clients from before /api/v1.
+        .route(
+            "/api/talents",
+            get(get_talents).route_layer(axum::middleware::from_fn_with_state(state.clone(), limit_talent_queries)),
+        )
+        .nest("/api/v1", api_v1(state.clone()));
+    // Only the API is offered to other origins; the page itself isn't.
+    let api = match cors_origins {
+        Some(origins) => api.layer(cors_layer(origins)),
+        None          => api,
+    };
+
+    Router::new()
         .route("/", get(home))
-        .route("/api/talents", get(get_talents_sse));
+        .route("/favicon.ico", get(favicon))
+        .route("/feed.xml", get(feed))
+        .route("/static/*name", get(static_asset))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version))
+        .route("/metrics", get(prometheus_metrics))
+        .nest("/admin", admin::router(state.clone()))
+        .merge(api)
+        .fallback(not_found)
+        .with_state(state)
+        // The default predicate already skips event streams, small bodies and
+        // images. NDJSON is skipped too: the encoder holds lines back until
+        // its buffer fills, which defeats streaming them as they resolve.
+        .layer(CompressionLayer::new().gzip(true).br(true).compress_when(
+            DefaultPredicate::new().and(NotForContentType::const_new("application/x-ndjson")),
+        ))
+        // Layers run bottom-up: the ID is assigned first so the request span
+        // and the response header both carry it.
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(|req: &axum::http::Request<Body>| {
+            tracing::info_span!(
+                "request",
+                id = request_id(req.headers()),
+                method = %req.method(),
+                uri = %req.uri(),
+            )
+        }))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+/// How often the `[[tracked]]` queries are recorded.
+const TREND_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Once at startup and then daily: delete snapshots past the retention, then
+/// fetch every `[[tracked]]` query past the cache, which records its builds.
+/// Runs until shutdown; failures are logged and left for the next round.
+async fn record_trends(state: AppState, store: Store) {
+    let mut interval = tokio::time::interval(TREND_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let shutdown = shutdown::token();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        if let Some(retention) = state.trend_retention {
+            match store.prune_snapshots(retention).await {
+                Ok(0) => {}
+                Ok(deleted) => tracing::info!("Deleted {} expired build snapshots", deleted),
+                Err(e) => tracing::warn!("Failed to delete expired build snapshots: {:#}", e),
+            }
+        }
+
+        for tracked in &state.settings.tracked {
+            // The address only matters for `nocache`, which isn't used here.
+            let prepared = match prepare_query(&state, Ipv4Addr::LOCALHOST.into(), tracked.into()) {
+                Ok(prepared) => PreparedQuery { bypass_cache: true, ..prepared },
+                Err(e) => {
+                    tracing::warn!("Skipping tracked query {} {}: {}", tracked.spec, tracked.class, e);
+                    continue;
+                }
+            };
+            match collect_talents(&state, &prepared).await {
+                Ok(_) => tracing::info!("Recorded builds for {}", prepared.selection),
+                Err(e) => tracing::warn!("Failed to record builds for {}: {:#}", prepared.selection, e),
+            }
+        }
+    }
+}
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    tracing::info!("Server listening on http://{}", addr);
+/// How long startup waits for a WarcraftLogs token before giving up.
+const STARTUP_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+/// Refuse to start without working WarcraftLogs credentials, rather than
+/// serving an OAuth error to every query. Getting the token also caches it
+/// for the first request. A failing GraphQL ping after that only warns, since
+/// the credentials are fine. `SKIP_STARTUP_CHECK=1` skips it all, for offline
+/// development.
+async fn check_credentials(wcl: &WclClient) -> anyhow::Result<()> {
+    if matches!(std::env::var("SKIP_STARTUP_CHECK").as_deref().map(str::trim), Ok("1" | "true")) {
+        tracing::warn!("Skipping the WarcraftLogs credentials check (SKIP_STARTUP_CHECK)");
+        return Ok(());
+    }
 
+    match verify_credentials(wcl).await? {
+        Some(e) => tracing::warn!("WarcraftLogs credentials work, but the API didn't answer: {}", e),
+        None    => tracing::info!("WarcraftLogs credentials work"),
+    }
     Ok(())
 }
 
-#[derive(Deserialize)]
+/// Get a WarcraftLogs token, failing with what to fix if there is none.
+/// Returns why the GraphQL ping after it failed, if it did; the caller
+/// decides whether that matters.
+async fn verify_credentials(wcl: &WclClient) -> anyhow::Result<Option<String>> {
+    let readiness = tokio::time::timeout(STARTUP_CHECK_TIMEOUT, wcl.readiness())
+        .await
+        .map_err(|_| anyhow::anyhow!(
+            "WarcraftLogs didn't issue a token within {:?}; set SKIP_STARTUP_CHECK=1 to start anyway",
+            STARTUP_CHECK_TIMEOUT
+        ))?;
+    if let Some(missing) = readiness.credentials {
+        anyhow::bail!(
+            "{}; set WCL_CLIENT_ID and WCL_CLIENT_SECRET to a WarcraftLogs API client \
+             (https://www.warcraftlogs.com/api/clients), or SKIP_STARTUP_CHECK=1 to start without them",
+            missing
+        );
+    }
+    if let Some(e) = readiness.upstream.token {
+        anyhow::bail!(
+            "No WarcraftLogs token: check WCL_CLIENT_ID and WCL_CLIENT_SECRET, or set SKIP_STARTUP_CHECK=1 \
+             to start anyway ({})",
+            e
+        );
+    }
+    Ok(readiness.upstream.graphql)
+}
+
+/// How long startup waits for the zone list before using the configured
+/// encounters.
+const ZONE_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the current season's encounters from the WCL zones it names, so a new
+/// raid only needs its zone ID in settings.toml. The list is read once and kept
+/// for the life of the process; if WCL can't be reached the encounters listed
+/// in settings.toml are used instead.
+async fn load_zone_encounters(wcl: &WclClient, settings: &mut Settings) {
+    let zone_ids = settings.current_zones().to_vec();
+    if zone_ids.is_empty() {
+        return;
+    }
+
+    let zones = match tokio::time::timeout(ZONE_FETCH_TIMEOUT, wcl.fetch_zones(&zone_ids)).await {
+        Ok(Ok(zones)) => zones,
+        Ok(Err(e)) => {
+            tracing::warn!("Could not read zones {:?} from WarcraftLogs, using the configured encounters: {:#}", zone_ids, e);
+            return;
+        }
+        Err(_) => {
+            tracing::warn!("Reading zones {:?} from WarcraftLogs timed out, using the configured encounters", zone_ids);
+            return;
+        }
+    };
+
+    let mut encounters = Vec::new();
+    for zone in zones {
+        tracing::info!("Loaded {} encounters of {} (zone {})", zone.encounters.len(), zone.name, zone.id);
+        for encounter in zone.encounters {
+            if !encounters.iter().any(|e: &config::SeasonEncounter| e.id == encounter.id) {
+                encounters.push(config::SeasonEncounter {
+                    id:   encounter.id,
+                    name: encounter.name,
+                    raid: Some(zone.name.clone()),
+                    dungeon: false,
+                });
+            }
+        }
+    }
+    if encounters.is_empty() {
+        tracing::warn!("Zones {:?} have no encounters on WarcraftLogs, using the configured encounters", zone_ids);
+        return;
+    }
+    settings.set_current_encounters(encounters);
+}
+
+/// Run the server until its graceful shutdown completes. Streams end
+/// themselves once the shutdown grace period is over; anything still holding
+/// a connection a little after that doesn't delay the exit.
+async fn run_until_drained(server: impl IntoFuture<Output = std::io::Result<()>>) -> std::io::Result<()> {
+    tokio::select! {
+        result = server.into_future() => result,
+        _ = async {
+            shutdown::drained().await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        } => {
+            tracing::warn!("Connections still open after the shutdown grace period, exiting anyway");
+            Ok(())
+        }
+    }
+}
+
+/// CORS for the API routes. Reads only, so just GET, with the request headers
+/// `fetch` and a reconnecting EventSource send, and the response headers a
+/// cross-origin script may want to read.
+fn cors_layer(origins: config::CorsOrigins) -> CorsLayer {
+    let allow_origin = match origins {
+        config::CorsOrigins::Any => AllowOrigin::any(),
+        config::CorsOrigins::List(origins) => AllowOrigin::list(
+            origins.iter().filter_map(|o| header::HeaderValue::from_str(o).ok()),
+        ),
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([axum::http::Method::GET])
+        .allow_headers([
+            header::ACCEPT,
+            header::CACHE_CONTROL,
+            header::IF_NONE_MATCH,
+            header::HeaderName::from_static("last-event-id"),
+        ])
+        .expose_headers([
+            header::CONTENT_DISPOSITION,
+            header::ETAG,
+            header::HeaderName::from_static("server-timing"),
+            header::HeaderName::from_static("x-request-id"),
+        ])
+        .max_age(Duration::from_secs(3600))
+}
+
+/// Version 1 of the HTTP API, mounted at `/api/v1`. A breaking change gets a
+/// new router next to this one rather than changing these routes.
+fn api_v1(state: AppState) -> Router<AppState> {
+    let talents = Router::new()
+        .route("/talents", get(get_talents))
+        .route("/talents.json", get(get_talents_json))
+        .route("/talents.ndjson", get(get_talents_ndjson))
+        .route("/talents.csv", get(get_talents_csv))
+        .route("/talents.md", get(get_talents_markdown))
+        .route("/talents.txt", get(get_talents_text))
+        .route("/talents/compare", get(get_compare))
+        .route("/talents/overview", get(get_overview))
+        .route("/talents/regions", get(get_region_comparison))
+        .route("/talents/snapshot", get(get_snapshot))
+        .route("/talents/trends", get(get_trends))
+        .route("/trends", get(get_trends_json))
+        .route_layer(axum::middleware::from_fn_with_state(state, limit_talent_queries));
+
+    Router::new()
+        .merge(talents)
+        .route("/classes", get(classes))
+        .route("/encounters", get(encounters))
+        .route("/regions", get(regions))
+        .route("/stats", get(stats))
+        .route("/openapi.json", get(openapi_document))
+}
+
+/// Everything handlers share, built once in `main`. Cloning is cheap: each
+/// part is reference-counted.
+#[derive(Clone)]
+struct AppState {
+    wcl:        WclClient,
+    /// Talent queries go through here rather than straight to `wcl`, so
+    /// identical queries share upstream work and cached results.
+    talents:    Coalescer,
+    config:     Arc<ClassSpecs>,
+    settings:   Arc<Settings>,
+    /// The rendered `/` page; cloning only bumps a reference count.
+    home_page:  Bytes,
+    /// Served at `/` instead while WarcraftLogs credentials are missing.
+    setup_page: Option<Bytes>,
+    /// Classic's counterparts of `config`, `settings` and `home_page`.
+    classic:    Arc<Catalog>,
+    started_at: Instant,
+    /// SQLite persistence, when `CACHE_DB_PATH` is set. `talents` persists
+    /// results to the same store.
+    store:      Option<Store>,
+    /// Talent queries per client, so one can't spend the API budget for all.
+    talent_limiter:   Arc<RateLimiter>,
+    /// Snapshots per client, on top of the talent query limit, as each is a
+    /// query for every spec.
+    snapshot_limiter: Arc<RateLimiter>,
+    /// Clients that used `nocache` recently, to stop it being used to drain
+    /// the API budget.
+    recent_nocache:   Arc<TtlCache<IpAddr, ()>>,
+    /// Event streams open at once; each holds one of `stream_permits` until
+    /// it ends.
+    max_streams:      usize,
+    stream_permits:   Arc<Semaphore>,
+    /// Build snapshots older than this are deleted; `None` keeps them forever.
+    trend_retention:  Option<Duration>,
+    /// The bearer token `/admin` requires; the routes are refused without one.
+    admin_token:      Option<Arc<str>>,
+    /// Where the site is served, for links in webhook messages and the feed.
+    public_url:       Option<Arc<str>>,
+}
+
+/// One game's class catalog, seasons and rendered page.
+struct Catalog {
+    config:    ClassSpecs,
+    settings:  Settings,
+    home_page: Bytes,
+}
+
+impl AppState {
+    /// Event streams currently open.
+    fn open_streams(&self) -> usize {
+        self.max_streams - self.stream_permits.available_permits()
+    }
+
+    /// A permit for one more event stream, unless `max_streams` are open.
+    fn stream_permit(&self) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        self.stream_permits.clone().try_acquire_owned().ok()
+    }
+
+    /// Whether this client may bypass the cache now; records the use if so.
+    fn allow_nocache(&self, ip: IpAddr) -> bool {
+        if self.recent_nocache.get(&ip).is_some() {
+            return false;
+        }
+        self.recent_nocache.insert(ip, ());
+        true
+    }
+
+    /// The class catalog and seasons queries of `game` are checked against.
+    fn catalog(&self, game: Game) -> (&ClassSpecs, &Settings) {
+        match game {
+            Game::Retail  => (&self.config, &self.settings),
+            Game::Classic => (&self.classic.config, &self.classic.settings),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
 struct TalentQuery {
     class:    String,
     spec:     String,
     encounter: i32,
+    /// Key of the raid tier in settings.toml; the current season if absent.
+    zone:     Option<String>,
     region:   String,
     mode:     String,
     metric:   Option<String>,
+    /// Keystone level to rank Mythic+ runs at; every level if absent.
+    bracket:  Option<i32>,
+    /// `nocache=1` skips the result cache (rate-limited per IP).
+    nocache:  Option<u8>,
+    /// `allow_unknown_encounter=1` queries encounter IDs outside the selected
+    /// tier, e.g. ones not in settings.toml.
+    allow_unknown_encounter: Option<u8>,
+    /// `game=classic` queries Classic; retail if absent.
+    #[serde(default)]
+    game:     Game,
 }
 
-async fn home() -> Html<String> {
-    let config = ClassSpecs::load();
-    Html(templates::home(&config))
+impl From<&config::TrackedQuery> for TalentQuery {
+    fn from(tracked: &config::TrackedQuery) -> Self {
+        Self {
+            class:     tracked.class.clone(),
+            spec:      tracked.spec.clone(),
+            encounter: tracked.encounter,
+            zone:      tracked.zone.clone(),
+            region:    tracked.region.clone(),
+            mode:      tracked.mode.clone(),
+            metric:    tracked.metric.clone(),
+            bracket:   None,
+            nocache:   None,
+            allow_unknown_encounter: None,
+            game:      Game::Retail,
+        }
+    }
 }
 
-async fn get_talents_sse(
-    Query(params): Query<TalentQuery>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let region_display = if params.region == "all" {
-        "All Regions".to_string()
+/// Longest accepted text parameter; real class, spec and region names are
+/// well under it.
+const MAX_PARAM_LEN: usize = 32;
+
+/// Encounter IDs are positive and, so far, five digits at most.
+const MAX_ENCOUNTER_ID: i32 = 999_999;
+
+impl TalentQuery {
+    /// Sanity checks ahead of any lookup, so oversized or garbled values
+    /// never reach the logs or the page. The reason names the parameter
+    /// rather than echoing its value.
+    fn check(&self) -> Result<(), String> {
+        let text = [
+            ("class",  Some(&self.class)),
+            ("spec",   Some(&self.spec)),
+            ("zone",   self.zone.as_ref()),
+            ("region", Some(&self.region)),
+            ("mode",   Some(&self.mode)),
+            ("metric", self.metric.as_ref()),
+        ];
+        for (name, value) in text {
+            let Some(value) = value else { continue };
+            if value.chars().count() > MAX_PARAM_LEN {
+                return Err(format!("{} is too long (at most {} characters)", name, MAX_PARAM_LEN));
+            }
+            if value.chars().any(char::is_control) {
+                return Err(format!("{} contains control characters", name));
+            }
+        }
+        if self.encounter != TIER_OVERALL && !(1..=MAX_ENCOUNTER_ID).contains(&self.encounter) {
+            return Err(format!("encounter must be 0 (the whole tier) or an ID from 1 to {}", MAX_ENCOUNTER_ID));
+        }
+        Ok(())
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Whether to take the client address from `Forwarded`/`X-Forwarded-For`.
+    static ref TRUST_PROXY: bool = env_or("TRUST_PROXY", false);
+}
+
+/// The client's address: the peer's, or with `TRUST_PROXY=true` the one the
+/// proxy in front reports. Only the last hop counts, being the one the proxy
+/// added itself; anything before it is whatever the client chose to send.
+fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !*TRUST_PROXY {
+        return peer;
+    }
+    let last_hop = |name: header::HeaderName| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .map(str::trim)
+    };
+
+    // Forwarded: for=192.0.2.60;proto=https, or for="[2001:db8::1]:4711"
+    let forwarded = last_hop(header::FORWARDED).and_then(|hop| {
+        hop.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("for").then(|| parse_node(value.trim_matches('"')))?
+        })
+    });
+    let forwarded_for = last_hop(header::HeaderName::from_static("x-forwarded-for")).and_then(parse_node);
+    forwarded.or(forwarded_for).unwrap_or(peer)
+}
+
+/// An address as proxies write it: bare, with a port, or bracketed IPv6.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6.split(']').next()?.parse().ok();
+    }
+    node.parse().ok().or_else(|| node.parse::<SocketAddr>().ok().map(|a| a.ip()))
+}
+
+/// Ahead of every talents route: resolve the client address, then turn away
+/// clients over their query rate with a 429 and `Retry-After`. EventSource
+/// can't read an error status, so the event stream gets a 200 carrying an
+/// error event instead. Handlers see the resolved address as `ConnectInfo`.
+async fn limit_talent_queries(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let ip = client_ip(peer.ip(), request.headers());
+    request.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip, peer.port())));
+
+    let Err(retry_after) = state.talent_limiter.check(ip) else {
+        return next.run(request).await;
+    };
+
+    let secs = (retry_after.as_secs_f64().ceil() as u64).max(1);
+    tracing::info!("Rate limited {} for {}s", ip, secs);
+    let message = format!("Too many queries, please wait {} seconds and try again", secs);
+
+    let headers = request.headers();
+    let path = request.uri().path();
+    let event_stream = path.ends_with("/compare")
+        || path.ends_with("/overview")
+        || path.ends_with("/regions")
+        || path.ends_with("/talents/trends")
+        || path.ends_with("/snapshot")
+        || path.ends_with("/talents") && negotiate(headers.get(header::ACCEPT).and_then(|v| v.to_str().ok())) == Format::Sse;
+    let mut response = if event_stream {
+        error_stream("too_many_requests", message, request_id(headers))
+    } else {
+        api_error("too_many_requests", message)
+    };
+    response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+    response
+}
+
+/// An event stream that is refused outright: a fatal error, then `complete`.
+fn error_stream(code: &'static str, message: String, request_id: &str) -> Response {
+    let events = [
+        error_event(ErrorPayload::fatal(code, message, short_ref(request_id))),
+        Event::default().event("complete").data(complete_data(None, 0)),
+    ];
+    Sse::new(futures::stream::iter(events.map(Ok::<_, Infallible>))).into_response()
+}
+
+/// Unknown paths: a JSON error under `/api/`, the styled page elsewhere.
+async fn not_found(uri: axum::http::Uri) -> Response {
+    if uri.path().starts_with("/api/") {
+        return api_error("not_found", format!("No API route at {}", uri.path()));
+    }
+    (StatusCode::NOT_FOUND, Html(templates::not_found(uri.path()))).into_response()
+}
+
+/// `GET /static/*name`: files compiled into the binary. Pages link them with
+/// the build's commit in the URL, so they can be cached for good.
+async fn static_asset(axum::extract::Path(name): axum::extract::Path<String>) -> Response {
+    asset_response(&name, "public, max-age=31536000, immutable")
+}
+
+/// `GET /favicon.ico`, which browsers try whatever the page links. Its URL
+/// never changes, so it's cached for a day only.
+async fn favicon() -> Response {
+    asset_response("favicon.ico", "public, max-age=86400")
+}
+
+/// Changes listed in `/feed.xml`, the newest ones.
+const FEED_ENTRIES: usize = 50;
+
+/// `GET /feed.xml`: an Atom feed of the `[[watch]]` queries' build changes.
+/// The history lives in the store, so without it the feed is empty.
+async fn feed(State(state): State<AppState>) -> Response {
+    let changes = match &state.store {
+        Some(store) => match store.recent_changes(FEED_ENTRIES).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                tracing::error!("Failed to read build changes: {:#}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Could not read the build changes").into_response();
+            }
+        },
+        None => Vec::new(),
+    };
+    let body = templates::render_feed(&changes, state.public_url.as_deref(), warcraftlogs::unix_now());
+    (
+        [
+            (header::CONTENT_TYPE, "application/atom+xml; charset=utf-8"),
+            (header::CACHE_CONTROL, "public, max-age=300"),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+fn asset_response(name: &str, cache_control: &'static str) -> Response {
+    match assets::get(name) {
+        Some(asset) => (
+            [(header::CONTENT_TYPE, asset.content_type), (header::CACHE_CONTROL, cache_control)],
+            asset.bytes,
+        )
+            .into_response(),
+        None => {
+            let path = format!("/static/{}", name);
+            (StatusCode::NOT_FOUND, Html(templates::not_found(&path))).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HomeQuery {
+    #[serde(default)]
+    game: Game,
+}
+
+async fn home(State(state): State<AppState>, Query(params): Query<HomeQuery>) -> Html<Bytes> {
+    let page = match params.game {
+        Game::Retail  => state.home_page,
+        Game::Classic => state.classic.home_page.clone(),
+    };
+    Html(state.setup_page.unwrap_or(page))
+}
+
+/// What's running, captured at compile time by build.rs.
+#[derive(Serialize)]
+struct BuildInfo {
+    version:  &'static str,
+    commit:   &'static str,
+    /// Unix timestamp of the build.
+    built_at: &'static str,
+}
+
+const BUILD_INFO: BuildInfo = BuildInfo {
+    version:  env!("CARGO_PKG_VERSION"),
+    commit:   env!("BUILD_GIT_COMMIT"),
+    built_at: env!("BUILD_TIMESTAMP"),
+};
+
+/// `GET /version`
+async fn version() -> Json<BuildInfo> {
+    Json(BUILD_INFO)
+}
+
+/// `GET /healthz`: liveness only. Never calls WarcraftLogs, so it stays up
+/// when upstream is slow or credentials are missing.
+async fn healthz(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "status":      "ok",
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+        "version":     env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+/// `GET /readyz`: 200 when WarcraftLogs queries can be served, 503 with the
+/// failing check otherwise.
+async fn readyz(State(state): State<AppState>) -> Response {
+    let readiness = state.wcl.readiness().await;
+    let status = if readiness.is_ready() {
+        StatusCode::OK
     } else {
-        params.region.clone()
+        StatusCode::SERVICE_UNAVAILABLE
     };
+    let body = serde_json::json!({ "ready": readiness.is_ready(), "checks": readiness });
+    (status, Json(body)).into_response()
+}
+
+/// `GET /metrics`: latency histograms for Prometheus.
+async fn prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = metrics::render_prometheus();
+    body.push_str(&format!(
+        "# HELP talent_trends_open_streams Event streams currently open.\n\
+         # TYPE talent_trends_open_streams gauge\n\
+         talent_trends_open_streams {}\n\
+         # HELP talent_trends_max_open_streams Event streams allowed open at once.\n\
+         # TYPE talent_trends_max_open_streams gauge\n\
+         talent_trends_max_open_streams {}\n",
+        state.open_streams(),
+        state.max_streams,
+    ));
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        body,
+    )
+}
+
+#[derive(Serialize)]
+struct Stats {
+    rate_limit: Option<warcraftlogs::RateLimitStatus>,
+    latency:    Vec<metrics::LatencySummary>,
+    streams:    StreamStats,
+}
+
+/// Open event streams against `MAX_OPEN_STREAMS`.
+#[derive(Serialize)]
+struct StreamStats {
+    open: usize,
+    max:  usize,
+}
+
+async fn stats(State(state): State<AppState>) -> Json<Stats> {
+    Json(Stats {
+        rate_limit: state.wcl.rate_limit_status().await,
+        latency:    metrics::summaries(),
+        streams:    StreamStats { open: state.open_streams(), max: state.max_streams },
+    })
+}
+
+/// Response formats of the talent endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Sse,
+    Json,
+    Ndjson,
+    Csv,
+    Markdown,
+    Text,
+}
 
-    let settings  = Settings::load();
-    let partition = settings.current_partition();
+/// Pick a format from an `Accept` header, honouring q-factors and falling back
+/// to SSE (what the page's EventSource sends, and what `*/*` gets) when
+/// nothing more specific is acceptable.
+fn negotiate(accept: Option<&str>) -> Format {
+    let Some(accept) = accept else { return Format::Sse };
 
-    let difficulty = ClassSpecs::get_modes()
+    let mut ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media = parts.next().filter(|m| !m.is_empty())?;
+            let q = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((media, q))
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    // Stable, so equally weighted ranges keep the client's order.
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges
         .into_iter()
-        .find(|m| m.name == params.mode)
-        .map(|m| m.difficulty)
-        .unwrap_or_else(|| settings.default_difficulty());
+        .find_map(|(media, _)| match media.to_ascii_lowercase().as_str() {
+            "text/event-stream" | "text/*" | "*/*" => Some(Format::Sse),
+            "application/json" | "application/*"   => Some(Format::Json),
+            "application/x-ndjson"                 => Some(Format::Ndjson),
+            "text/csv"                             => Some(Format::Csv),
+            "text/markdown"                        => Some(Format::Markdown),
+            "text/plain"                           => Some(Format::Text),
+            _                                      => None,
+        })
+        .unwrap_or(Format::Sse)
+}
+
+/// `GET /api/v1/talents`: the format is chosen by the `Accept` header, SSE by
+/// default. The suffixed routes serve one format each.
+async fn get_talents(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<TalentQuery>,
+    options: Query<TextOptions>,
+) -> Response {
+    let format = negotiate(headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()));
+    let (state, params) = (State(state), Query(params));
+    match format {
+        Format::Sse      => get_talents_sse(state, connect_info, headers, params).await,
+        Format::Json     => get_talents_json(state, connect_info, params).await,
+        Format::Ndjson   => get_talents_ndjson(state, connect_info, params).await,
+        Format::Csv      => get_talents_csv(state, connect_info, params).await,
+        Format::Markdown => get_talents_markdown(state, connect_info, params).await,
+        Format::Text     => get_talents_text(state, connect_info, params, options).await,
+    }
+}
 
-    let metric = params.metric
-        .as_deref()
-        .unwrap_or("dps")
-        .to_string();
+/// A JSON response with an ETag over its body, answering 304 when the client
+/// already has it. For catalog data that only changes with the config.
+fn cacheable_json(headers: &HeaderMap, body: &serde_json::Value) -> Response {
+    use std::hash::{Hash, Hasher};
 
-    tracing::info!(
-        "Fetching talents for {} {} encounter {} (region: {}, mode: {}, difficulty: {}, partition: {:?}, metric: {})",
-        params.class, params.spec, params.encounter,
-        region_display, params.mode, difficulty, partition, metric
-    );
+    let body = body.to_string();
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!(r#""{:016x}""#, hasher.finish());
+
+    let matches = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+
+    let cache_headers = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, "public, max-age=300".to_string()),
+    ];
+    if matches {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (cache_headers, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+/// The `game` a catalog endpoint lists; retail if absent.
+#[derive(Deserialize)]
+struct GameQuery {
+    #[serde(default)]
+    game: Game,
+}
+
+/// `GET /api/v1/classes`: every class with its display name, colors and specs.
+async fn classes(State(state): State<AppState>, Query(params): Query<GameQuery>, headers: HeaderMap) -> Response {
+    let classes: Vec<serde_json::Value> = state
+        .catalog(params.game)
+        .0
+        .classes
+        .iter()
+        .map(|(key, class)| {
+            let specs: Vec<serde_json::Value> = class
+                .specs
+                .iter()
+                .map(|spec| serde_json::json!({ "name": spec.name, "wcl_name": spec.wcl_name(), "role": spec.role }))
+                .collect();
+            serde_json::json!({
+                "key":          key,
+                "name":         key.replace('_', " "),
+                "color":        class.color,
+                "pretty_color": class.pretty_color,
+                "specs":        specs,
+            })
+        })
+        .collect();
+    cacheable_json(&headers, &serde_json::Value::from(classes))
+}
+
+#[derive(Deserialize)]
+struct EncountersQuery {
+    zone: Option<String>,
+    #[serde(default)]
+    game: Game,
+}
+
+/// `GET /api/v1/encounters`: a raid tier's encounters in form order, the
+/// current season's unless `zone` names another.
+async fn encounters(
+    State(state): State<AppState>,
+    Query(params): Query<EncountersQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let settings = state.catalog(params.game).1;
+    let Some((id, tier)) = settings.tier(params.zone.as_deref()) else {
+        return api_error("invalid_selection", unknown_zone_message(settings, params.zone.as_deref().unwrap_or_default()));
+    };
+    cacheable_json(&headers, &serde_json::to_value(tier.encounter_catalog(id)).unwrap_or_default())
+}
+
+/// `GET /api/v1/regions`: region codes and names; the one standing for every
+/// region is flagged as the aggregate.
+async fn regions(State(state): State<AppState>, Query(params): Query<GameQuery>, headers: HeaderMap) -> Response {
+    let regions: Vec<serde_json::Value> = state
+        .catalog(params.game)
+        .1
+        .regions
+        .iter()
+        .map(|r| serde_json::json!({
+            "code":      r.code,
+            "name":      r.name,
+            "aggregate": r.aggregate,
+        }))
+        .collect();
+    cacheable_json(&headers, &serde_json::Value::from(regions))
+}
+
+async fn openapi_document(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(openapi::document(&state.config, &state.settings))
+}
+
+async fn get_talents_sse(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<TalentQuery>,
+) -> Response {
+    // Entry events carry their rank as the event ID, so a reconnecting
+    // EventSource tells us the last rank it received.
+    let resume_after: usize = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0);
+
+    // Refused before any upstream work; the permit is held by the stream.
+    let Some(permit) = state.stream_permit() else {
+        tracing::warn!("Refusing event stream, {} already open", state.max_streams);
+        let message = "The server is busy, please try again shortly".to_string();
+        return error_stream("server_busy", message, request_id(&headers));
+    };
 
-    let region = if params.region == "all" { None } else { Some(params.region.clone()) };
+    let prepared = prepare_query(&state, client.ip(), params);
+    let reference = short_ref(request_id(&headers)).to_string();
+
+    let received = Instant::now();
+
+    // The query is started and read up to its rankings before responding, so
+    // the `Server-Timing` header can cover them. What was read is sent first.
+    let mut timing = ServerTiming::default();
+    let started = match prepared {
+        Ok(prepared) => {
+            // Dropped with the stream if the client leaves before the end.
+            let timer = metrics::Timer::start("talent_stream", "cancelled");
+            let mut talents = state.talents.fetch_top_talents(&prepared.query, prepared.bypass_cache).await;
+            let head = match &mut talents {
+                Ok(talents) => read_to_meta(&mut talents.receiver, &mut timing).await,
+                Err(_)      => Vec::new(),
+            };
+            Ok((prepared, timer, talents, head))
+        }
+        Err(e) => Err(e),
+    };
+    timing.add("total", received.elapsed());
 
     let stream = async_stream::stream! {
-        match warcraftlogs::fetch_top_talents_stream(
-            &params.class,
-            &params.spec,
-            params.encounter,
-            region.as_deref(),
-            difficulty,
-            partition,
-            &metric,
-        ).await {
-            Ok(mut receiver) => {
-                while let Some(result) = receiver.recv().await {
-                    let result: Result<TalentDataWithRank, _> = result;
+        let _permit = permit;
+        let (PreparedQuery { query, selection, unknown_encounter, bracket_notice, class_color, .. }, timer, talents, head) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                yield Ok::<_, Infallible>(error_event(ErrorPayload::fatal(e.code, e.message, &reference)));
+                yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+                return;
+            }
+        };
+
+        if resume_after > 0 {
+            tracing::info!("Resuming stream after rank {}", resume_after);
+        }
+
+        if unknown_encounter && resume_after == 0 {
+            let notice = templates::render_notice(&format!(
+                "Encounter {} is not in the current season, so its name is unknown.",
+                query.encounter_id
+            ));
+            yield Ok(Event::default().data(notice));
+        }
+        if let Some(text) = bracket_notice.filter(|_| resume_after == 0) {
+            yield Ok(Event::default().data(templates::render_notice(&text)));
+        }
+
+        match talents {
+            Ok(TalentStream { mut receiver, cached_age }) => {
+                let mut failed_entries = 0;
+                let mut outcome = if cached_age.is_some() { "cached" } else { "ok" };
+                let mut head = head.into_iter();
+                let draining = shutdown::drained();
+                tokio::pin!(draining);
+                loop {
+                    let next = match head.next() {
+                        Some(result) => Some(result),
+                        None => tokio::select! {
+                            result = receiver.recv() => result,
+                            _ = &mut draining => Some(Err(shutdown::ServerRestarting.into())),
+                        },
+                    };
+                    let Some(result) = next else { break };
+                    let result: Result<StreamItem, _> = result;
                     match result {
-                        Ok(talent_data) => {
-                            let html = templates::render_talent_entry(&talent_data);
-                            yield Ok(Event::default().data(html));
+                        Ok(StreamItem::Meta { encounter, entries, available }) => {
+                            let meta = serde_json::json!({
+                                "encounter": encounter,
+                                "entries":   entries,
+                                "available": available,
+                            });
+                            yield Ok(Event::default().event("meta").data(meta.to_string()));
+                            if resume_after > 0 {
+                                continue;
+                            }
+                            if let Some(notice) = templates::render_count_notice(available, &selection) {
+                                yield Ok(Event::default().data(notice));
+                            }
                         }
+                        Ok(StreamItem::Entry(talent_data)) => {
+                            if talent_data.rank <= resume_after {
+                                continue;
+                            }
+                            let html = templates::render_talent_entry(&talent_data, class_color.as_deref());
+                            yield Ok(Event::default().id(talent_data.rank.to_string()).data(html));
+                            if let Some(reason) = &talent_data.data.failure {
+                                failed_entries += 1;
+                                yield Ok(error_event(ErrorPayload {
+                                    code:    "entry_failed",
+                                    message: reason.clone(),
+                                    rank:    Some(talent_data.rank),
+                                    fatal:   false,
+                                    html:    None,
+                                    side:    None,
+                                }));
+                            }
+                        }
+                        Ok(StreamItem::Progress { resolved, total }) => {
+                            let progress = serde_json::json!({ "resolved": resolved, "total": total });
+                            yield Ok(Event::default().event("progress").data(progress.to_string()));
+                        }
+                        Ok(StreamItem::Timing { .. }) => {}
                         Err(e) => {
                             tracing::error!("Worker error: {:#}", e);
-                            let error_html = format!(r#"<div class="error">Error: {}</div>"#, e);
-                            yield Ok(Event::default().data(error_html));
+                            let (code, message) = describe_error(&e);
+                            yield Ok(error_event(ErrorPayload::fatal(code, message, &reference)));
+                            outcome = "error";
                             break;
                         }
                     }
                 }
-                yield Ok(Event::default().event("complete").data("done"));
+                yield Ok(Event::default().event("complete").data(complete_data(cached_age, failed_entries)));
+                timer.finish(outcome);
             }
             Err(e) => {
                 tracing::error!("Failed to start stream: {:#}", e);
-                let error_html = format!(r#"<div class="error">Error: {}</div>"#, e);
-                yield Ok(Event::default().data(error_html));
-                yield Ok(Event::default().event("complete").data("done"));
+                let (code, message) = describe_error(&e);
+                yield Ok(error_event(ErrorPayload::fatal(code, message, &reference)));
+                yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+                timer.finish("error");
             }
         }
     };
 
-    Sse::new(stream).keep_alive(
+    let sse = Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(1))
             .text("keep-alive"),
-    )
+    );
+    with_server_timing(sse, &timing)
+}
+
+/// The second spec of a comparison; everything else is shared with the first.
+#[derive(Deserialize)]
+struct CompareWith {
+    class2: String,
+    spec2:  String,
+}
+
+/// `GET /api/v1/talents/compare`: the talent query for two specs at once,
+/// side by side. Both run concurrently and their events are interleaved as
+/// they arrive, each tagged with its `side` (1 for `class`/`spec`, 2 for
+/// `class2`/`spec2`); entries come as JSON with the rendered `html`. A side
+/// that fails gets a fatal error for that side only, and `complete` follows
+/// once both have finished.
+async fn get_compare(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<TalentQuery>,
+    Query(with): Query<CompareWith>,
+) -> Response {
+    let Some(permit) = state.stream_permit() else {
+        tracing::warn!("Refusing compare stream, {} already open", state.max_streams);
+        let message = "The server is busy, please try again shortly".to_string();
+        return error_stream("server_busy", message, request_id(&headers));
+    };
+    let reference = short_ref(request_id(&headers)).to_string();
+
+    // `nocache` is spent once, by the first side; the second follows it.
+    let second = TalentQuery { class: with.class2, spec: with.spec2, nocache: None, ..params.clone() };
+    let first  = prepare_query(&state, client.ip(), params);
+    let bypass_cache = first.as_ref().is_ok_and(|p| p.bypass_cache);
+    let second = prepare_query(&state, client.ip(), second).map(|p| PreparedQuery { bypass_cache, ..p });
+
+    let stream = async_stream::stream! {
+        let _permit = permit;
+        let sides = futures::stream::select(
+            compare_side(state.clone(), 1, first, reference.clone()),
+            compare_side(state, 2, second, reference.clone()),
+        );
+        tokio::pin!(sides);
+
+        let draining = shutdown::drained();
+        tokio::pin!(draining);
+        let mut failed_entries = 0;
+        loop {
+            let next = tokio::select! {
+                next = sides.next() => next,
+                _ = &mut draining => {
+                    let (code, message) = describe_error(&shutdown::ServerRestarting.into());
+                    yield Ok::<_, Infallible>(error_event(ErrorPayload::fatal(code, message, &reference)));
+                    break;
+                }
+            };
+            let Some((event, failed)) = next else { break };
+            failed_entries += failed;
+            yield Ok(event);
+        }
+        yield Ok(Event::default().event("complete").data(complete_data(None, failed_entries)));
+    };
+
+    Sse::new(stream)
+        .keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(Duration::from_secs(1))
+                .text("keep-alive"),
+        )
+        .into_response()
+}
+
+/// The events of one side of a comparison, each paired with the number of
+/// failed entries it reports. Ends after the side's last entry or its error.
+fn compare_side(
+    state: AppState,
+    side: u8,
+    prepared: Result<PreparedQuery, Rejection>,
+    reference: String,
+) -> impl futures::Stream<Item = (Event, usize)> {
+    let html_event = move |html: String| {
+        Event::default().data(serde_json::json!({ "side": side, "html": html }).to_string())
+    };
+    let fatal = move |code, message| {
+        error_event(ErrorPayload { side: Some(side), ..ErrorPayload::fatal(code, message, &reference) })
+    };
+
+    async_stream::stream! {
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                yield (fatal(e.code, e.message), 0);
+                return;
+            }
+        };
+        if prepared.unknown_encounter {
+            let notice = templates::render_notice(&format!(
+                "Encounter {} is not in the current season, so its name is unknown.",
+                prepared.query.encounter_id
+            ));
+            yield (html_event(notice), 0);
+        }
+        if let Some(text) = &prepared.bracket_notice {
+            yield (html_event(templates::render_notice(text)), 0);
+        }
+
+        let mut receiver = match state.talents.fetch_top_talents(&prepared.query, prepared.bypass_cache).await {
+            Ok(TalentStream { receiver, .. }) => receiver,
+            Err(e) => {
+                tracing::error!("Failed to start side {} of comparison: {:#}", side, e);
+                let (code, message) = describe_error(&e);
+                yield (fatal(code, message), 0);
+                return;
+            }
+        };
+        while let Some(result) = receiver.recv().await {
+            match result {
+                Ok(StreamItem::Meta { encounter, entries, available }) => {
+                    let meta = serde_json::json!({
+                        "side":      side,
+                        "encounter": encounter,
+                        "entries":   entries,
+                        "available": available,
+                    });
+                    yield (Event::default().event("meta").data(meta.to_string()), 0);
+                    if let Some(notice) = templates::render_count_notice(available, &prepared.selection) {
+                        yield (html_event(notice), 0);
+                    }
+                }
+                Ok(StreamItem::Entry(talent_data)) => {
+                    let html = templates::render_talent_entry(&talent_data, prepared.class_color.as_deref());
+                    yield (html_event(html), 0);
+                    if let Some(reason) = &talent_data.data.failure {
+                        yield (error_event(ErrorPayload {
+                            code:    "entry_failed",
+                            message: reason.clone(),
+                            rank:    Some(talent_data.rank),
+                            fatal:   false,
+                            html:    None,
+                            side:    Some(side),
+                        }), 1);
+                    }
+                }
+                Ok(StreamItem::Progress { resolved, total }) => {
+                    let progress = serde_json::json!({ "side": side, "resolved": resolved, "total": total });
+                    yield (Event::default().event("progress").data(progress.to_string()), 0);
+                }
+                Ok(StreamItem::Timing { .. }) => {}
+                Err(e) => {
+                    tracing::error!("Side {} of comparison failed: {:#}", side, e);
+                    let (code, message) = describe_error(&e);
+                    yield (fatal(code, message), 0);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The second region of a region comparison.
+#[derive(Deserialize)]
+struct RegionCompareWith {
+    region2: String,
+}
+
+/// `GET /api/v1/talents/regions`: one spec's most common build on one boss in
+/// two regions, side by side, saying whether the builds are the same. Both
+/// regions are fetched at once as ordinary talent queries, sharing their
+/// cache; the comparison is a single SSE section, with a region that failed
+/// shown as such next to the other.
+async fn get_region_comparison(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<TalentQuery>,
+    Query(with): Query<RegionCompareWith>,
+) -> Response {
+    let Some(permit) = state.stream_permit() else {
+        tracing::warn!("Refusing region comparison stream, {} already open", state.max_streams);
+        let message = "The server is busy, please try again shortly".to_string();
+        return error_stream("server_busy", message, request_id(&headers));
+    };
+    let reference = short_ref(request_id(&headers)).to_string();
+
+    let region_names = [params.region.as_str(), with.region2.as_str()]
+        .map(|code| state.catalog(params.game).1.region(code).map(|r| r.name.clone()).unwrap_or_default());
+    // As with a spec comparison, `nocache` is spent once and applies to both.
+    let second = TalentQuery { region: with.region2, nocache: None, ..params.clone() };
+    let prepared = prepare_query(&state, client.ip(), params).and_then(|first| {
+        let second = prepare_query(&state, client.ip(), second)?;
+        if first.query.region == second.query.region {
+            return Err("Choose two different regions to compare.".to_string().into());
+        }
+        let bypass_cache = first.bypass_cache;
+        Ok([first, PreparedQuery { bypass_cache, ..second }])
+    });
+
+    let stream = async_stream::stream! {
+        let _permit = permit;
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                yield Ok::<_, Infallible>(error_event(ErrorPayload::fatal(e.code, e.message, &reference)));
+                yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+                return;
+            }
+        };
+
+        let both = futures::future::join(collect_talents(&state, &prepared[0]), collect_talents(&state, &prepared[1]));
+        let draining = shutdown::drained();
+        tokio::pin!(draining);
+        let results = tokio::select! {
+            results = both => results,
+            _ = &mut draining => {
+                let (code, message) = describe_error(&shutdown::ServerRestarting.into());
+                yield Ok(error_event(ErrorPayload::fatal(code, message, &reference)));
+                yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+                return;
+            }
+        };
+
+        let mut failed = 0;
+        let sides: Vec<templates::RegionBuild> = [&results.0, &results.1]
+            .into_iter()
+            .zip(&region_names)
+            .map(|(result, region)| match result {
+                Ok(collected) => templates::RegionBuild {
+                    region,
+                    build:   most_common_build(&collected.entries),
+                    failure: None,
+                },
+                Err(e) => {
+                    tracing::warn!("Region comparison in {} failed: {:#}", region, e);
+                    failed += 1;
+                    templates::RegionBuild { region, build: None, failure: Some(describe_error(e).1) }
+                }
+            })
+            .collect();
+        let query = &prepared[0].query;
+        let heading = format!("{} {} on {}", query.spec, query.class.replace('_', " "), prepared[0].encounter_name);
+        let html = templates::render_region_comparison(&heading, prepared[0].class_color.as_deref(), &sides);
+        yield Ok(Event::default().data(html));
+        yield Ok(Event::default().event("complete").data(complete_data(None, failed)));
+    };
+
+    Sse::new(stream)
+        .keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(Duration::from_secs(1))
+                .text("keep-alive"),
+        )
+        .into_response()
+}
+
+/// `GET /api/v1/talents/trends`: the dominant build of each day a query's
+/// builds were recorded, read from the store, as one SSE section. Nothing is
+/// fetched; with persistence disabled the stream is an error.
+async fn get_trends(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<TalentQuery>,
+) -> Response {
+    let Some(store) = state.store.clone() else {
+        let message = "Build history is not enabled on this server".to_string();
+        return error_stream("trends_disabled", message, request_id(&headers));
+    };
+    let Some(permit) = state.stream_permit() else {
+        tracing::warn!("Refusing trends stream, {} already open", state.max_streams);
+        let message = "The server is busy, please try again shortly".to_string();
+        return error_stream("server_busy", message, request_id(&headers));
+    };
+    let reference = short_ref(request_id(&headers)).to_string();
+    let prepared  = prepare_query(&state, client.ip(), params);
+
+    let stream = async_stream::stream! {
+        let _permit = permit;
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                yield Ok::<_, Infallible>(error_event(ErrorPayload::fatal(e.code, e.message, &reference)));
+                yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+                return;
+            }
+        };
+        match store.load_snapshots(&prepared.query).await {
+            Ok(snapshots) => {
+                let html = templates::render_trends(
+                    &prepared.selection,
+                    prepared.class_color.as_deref(),
+                    &daily_trend(&snapshots),
+                );
+                yield Ok(Event::default().data(html));
+            }
+            Err(e) => {
+                tracing::error!("Failed to read build snapshots: {:#}", e);
+                let message = "Could not read the build history".to_string();
+                yield Ok(error_event(ErrorPayload::fatal("internal", message, &reference)));
+            }
+        }
+        yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+    };
+
+    Sse::new(stream)
+        .keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(Duration::from_secs(1))
+                .text("keep-alive"),
+        )
+        .into_response()
+}
+
+/// Query of `/api/v1/trends`. Only the spec and boss are required: the region
+/// defaults to the aggregate one and the mode to the season's default.
+#[derive(Deserialize)]
+struct TrendsQuery {
+    class:     String,
+    spec:      String,
+    encounter: i32,
+    zone:      Option<String>,
+    region:    Option<String>,
+    mode:      Option<String>,
+    metric:    Option<String>,
+    bracket:   Option<i32>,
+    #[serde(default)]
+    game:      Game,
+}
+
+/// One day of `/api/v1/trends`: its dominant build and the share of the top
+/// players running it.
+#[derive(Serialize)]
+struct TrendPoint<'a> {
+    #[serde(flatten)]
+    day:   &'a templates::TrendDay<'a>,
+    share: f64,
+}
+
+/// A day the dominant build changed, from what to what.
+#[derive(Serialize)]
+struct TrendChange<'a> {
+    date: &'a str,
+    from: &'a str,
+    to:   &'a str,
+}
+
+#[derive(Serialize)]
+struct TrendsDocument<'a> {
+    query:   &'a RankingsQuery,
+    series:  Vec<TrendPoint<'a>>,
+    /// Empty until there are two days to compare.
+    changes: Vec<TrendChange<'a>>,
+}
+
+/// `GET /api/v1/trends`: the dominant build per recorded day as JSON, for
+/// scripts that diff it rather than read the page.
+async fn get_trends_json(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<TrendsQuery>,
+) -> Response {
+    let Some(store) = state.store.clone() else {
+        return api_error("trends_disabled", "Build history is not enabled on this server".to_string());
+    };
+    let region = params.region.unwrap_or_else(|| {
+        let settings = state.catalog(params.game).1;
+        settings.regions.iter().find(|r| r.aggregate).or(settings.regions.first())
+            .map(|r| r.code.clone())
+            .unwrap_or_default()
+    });
+    let query = TalentQuery {
+        class:     params.class,
+        spec:      params.spec,
+        encounter: params.encounter,
+        zone:      params.zone,
+        region,
+        mode:      params.mode.unwrap_or_default(),
+        metric:    params.metric,
+        bracket:   params.bracket,
+        nocache:   None,
+        allow_unknown_encounter: None,
+        game:      params.game,
+    };
+    let prepared = match prepare_query(&state, client.ip(), query) {
+        Ok(prepared) => prepared,
+        Err(e)       => return api_error(e.code, e.message),
+    };
+
+    let snapshots = match store.load_snapshots(&prepared.query).await {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            tracing::error!("Failed to read build snapshots: {:#}", e);
+            return api_error("internal", "Could not read the build history".to_string());
+        }
+    };
+    let days = daily_trend(&snapshots);
+    let changes = days
+        .windows(2)
+        .filter(|pair| pair[1].changed)
+        .map(|pair| TrendChange { date: &pair[1].date, from: pair[0].talent_string, to: pair[1].talent_string })
+        .collect();
+    Json(TrendsDocument {
+        query:  &prepared.query,
+        series: days
+            .iter()
+            .map(|day| TrendPoint { day, share: day.players as f64 / day.considered.max(1) as f64 })
+            .collect(),
+        changes,
+    })
+    .into_response()
+}
+
+/// The dominant build of each UTC day with snapshots, taken from the day's
+/// last one, and whether it differs from the previous day's.
+fn daily_trend(snapshots: &[store::Snapshot]) -> Vec<templates::TrendDay<'_>> {
+    let mut days: Vec<templates::TrendDay> = Vec::new();
+    for snapshot in snapshots {
+        let Some(top) = snapshot.builds.first() else { continue };
+        let date = templates::utc_date(snapshot.taken_at);
+        if days.last().is_some_and(|day| day.date == date) {
+            days.pop();
+        }
+        let changed = days.last().is_some_and(|day| day.talent_string != top.talent_string);
+        days.push(templates::TrendDay {
+            date,
+            talent_string: &top.talent_string,
+            players:       top.players,
+            considered:    snapshot.considered,
+            changed,
+        });
+    }
+    days
+}
+
+#[derive(Deserialize)]
+struct OverviewQuery {
+    class:  String,
+    spec:   String,
+    region: String,
+    mode:   String,
+    zone:   Option<String>,
+    metric: Option<String>,
+    #[serde(default)]
+    game:   Game,
+}
+
+/// Bosses an overview fetches at once. Each boss's query already looks up
+/// several players in parallel through the shared upstream slots, so this
+/// mostly bounds how far the overview runs ahead of its client.
+const OVERVIEW_PARALLELISM: usize = 2;
+
+/// `GET /api/v1/talents/overview`: one spec's most common build on every boss
+/// of a tier (every dungeon for Mythic+), as one SSE section per boss in the
+/// order they complete. Each boss is an ordinary talent query, so it shares
+/// their result cache and coalescing; a boss that fails gets a failed section
+/// and the rest carry on.
+async fn get_overview(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<OverviewQuery>,
+) -> Response {
+    let Some(permit) = state.stream_permit() else {
+        tracing::warn!("Refusing overview stream, {} already open", state.max_streams);
+        let message = "The server is busy, please try again shortly".to_string();
+        return error_stream("server_busy", message, request_id(&headers));
+    };
+    let reference = short_ref(request_id(&headers)).to_string();
+
+    // Every boss is validated before anything is fetched, so a bad class or
+    // region is reported once rather than once per boss.
+    let settings = state.catalog(params.game).1;
+    let prepared: Result<Vec<(usize, PreparedQuery)>, Rejection> = match settings.tier(params.zone.as_deref()) {
+        None => Err(unknown_zone_message(settings, params.zone.as_deref().unwrap_or_default()).into()),
+        Some((_, tier)) => {
+            let dungeon = params.game.modes().iter().any(|m| m.name == params.mode && m.dungeon);
+            let bosses: Vec<i32> = tier.encounters.iter().filter(|e| e.dungeon == dungeon).map(|e| e.id).collect();
+            if bosses.is_empty() {
+                Err("This tier has no Mythic+ dungeons.".to_string().into())
+            } else {
+                bosses
+                    .into_iter()
+                    .enumerate()
+                    .map(|(order, encounter)| {
+                        let query = TalentQuery {
+                            class:     params.class.clone(),
+                            spec:      params.spec.clone(),
+                            encounter,
+                            zone:      params.zone.clone(),
+                            region:    params.region.clone(),
+                            mode:      params.mode.clone(),
+                            metric:    params.metric.clone(),
+                            bracket:   None,
+                            nocache:   None,
+                            allow_unknown_encounter: None,
+                            game:      params.game,
+                        };
+                        prepare_query(&state, client.ip(), query).map(|prepared| (order, prepared))
+                    })
+                    .collect()
+            }
+        }
+    };
+
+    let stream = async_stream::stream! {
+        let _permit = permit;
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                yield Ok::<_, Infallible>(error_event(ErrorPayload::fatal(e.code, e.message, &reference)));
+                yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+                return;
+            }
+        };
+
+        let total = prepared.len();
+        let mut sections = futures::stream::iter(prepared)
+            .map(|(order, prepared)| {
+                let state = state.clone();
+                async move {
+                    let result = collect_talents(&state, &prepared).await;
+                    (order, prepared, result)
+                }
+            })
+            .buffer_unordered(OVERVIEW_PARALLELISM);
+
+        let draining = shutdown::drained();
+        tokio::pin!(draining);
+        let (mut resolved, mut failed) = (0, 0);
+        loop {
+            let next = tokio::select! {
+                next = sections.next() => next,
+                _ = &mut draining => {
+                    let (code, message) = describe_error(&shutdown::ServerRestarting.into());
+                    yield Ok(error_event(ErrorPayload::fatal(code, message, &reference)));
+                    break;
+                }
+            };
+            let Some((order, prepared, result)) = next else { break };
+            resolved += 1;
+
+            let boss = prepared.encounter_name.as_str();
+            let html = match result {
+                Ok(collected) => templates::render_overview_section(
+                    order,
+                    boss,
+                    prepared.class_color.as_deref(),
+                    most_common_build(&collected.entries),
+                ),
+                Err(e) => {
+                    tracing::warn!("Overview of {} failed: {:#}", boss, e);
+                    failed += 1;
+                    let (_, message) = describe_error(&e);
+                    templates::render_failed_overview_section(order, boss, &message)
+                }
+            };
+            yield Ok(Event::default().data(html));
+            let progress = serde_json::json!({ "resolved": resolved, "total": total });
+            yield Ok(Event::default().event("progress").data(progress.to_string()));
+        }
+        yield Ok(Event::default().event("complete").data(complete_data(None, failed)));
+    };
+
+    Sse::new(stream)
+        .keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(Duration::from_secs(1))
+                .text("keep-alive"),
+        )
+        .into_response()
+}
+
+/// The import string shared by the most entries, with how many have it, out
+/// of how many had one at all. Ties go to the build of the better-ranked
+/// player, whose entry is returned.
+fn most_common_build(entries: &[TalentDataWithRank]) -> Option<templates::OverviewBuild<'_>> {
+    let usable: Vec<&TalentDataWithRank> = entries
+        .iter()
+        .filter(|e| e.data.failure.is_none() && !e.data.talent_reconstructed && !e.data.talent_string.is_empty())
+        .collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for entry in &usable {
+        *counts.entry(entry.data.talent_string.as_str()).or_default() += 1;
+    }
+    // `usable` is in rank order and `max_by_key` keeps the last maximum, so
+    // it is searched from the back.
+    let best = usable.iter().rev().max_by_key(|e| counts[e.data.talent_string.as_str()])?;
+    Some(templates::OverviewBuild {
+        entry:      best,
+        players:    counts[best.data.talent_string.as_str()],
+        considered: usable.len(),
+    })
+}
+
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    encounter: i32,
+    zone:      Option<String>,
+    region:    String,
+    mode:      String,
+    metric:    Option<String>,
+    #[serde(default)]
+    game:      Game,
+}
+
+/// Ranked players a snapshot looks at per spec.
+const SNAPSHOT_ENTRIES: usize = 3;
+
+/// Specs a snapshot fetches at once; results are still sent in class order.
+const SNAPSHOT_PARALLELISM: usize = 3;
+
+/// `GET /api/v1/talents/snapshot`: the most common build of every spec of
+/// every class on one boss, from each spec's top `SNAPSHOT_ENTRIES` players.
+/// Sent over SSE as one section per class, in `classes.toml` order. Specs are
+/// ordinary talent queries, sharing their cache and coalescing, but a snapshot
+/// is dozens of them, so it also has its own per-client limit.
+async fn get_snapshot(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<SnapshotQuery>,
+) -> Response {
+    if let Err(retry_after) = state.snapshot_limiter.check(client.ip()) {
+        let secs = (retry_after.as_secs_f64().ceil() as u64).max(1);
+        tracing::info!("Snapshot rate limited {} for {}s", client.ip(), secs);
+        let message = format!("Too many snapshots, please wait {} seconds and try again", secs);
+        let mut response = error_stream("too_many_requests", message, request_id(&headers));
+        response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        return response;
+    }
+    let Some(permit) = state.stream_permit() else {
+        tracing::warn!("Refusing snapshot stream, {} already open", state.max_streams);
+        let message = "The server is busy, please try again shortly".to_string();
+        return error_stream("server_busy", message, request_id(&headers));
+    };
+    let reference = short_ref(request_id(&headers)).to_string();
+
+    // Every spec is validated before anything is fetched, so a bad boss or
+    // region is reported once.
+    let config = state.catalog(params.game).0;
+    let prepared: Result<Vec<(String, String, PreparedQuery)>, Rejection> = if params.encounter == TIER_OVERALL {
+        Err("A snapshot covers a single boss; choose one.".to_string().into())
+    } else {
+        config
+            .classes
+            .iter()
+            .flat_map(|(class, data)| data.specs.iter().map(move |spec| (class, spec)))
+            .map(|(class, spec)| {
+                let query = TalentQuery {
+                    class:     class.clone(),
+                    spec:      spec.name.clone(),
+                    encounter: params.encounter,
+                    zone:      params.zone.clone(),
+                    region:    params.region.clone(),
+                    mode:      params.mode.clone(),
+                    metric:    params.metric.clone(),
+                    bracket:   None,
+                    nocache:   None,
+                    allow_unknown_encounter: None,
+                    game:      params.game,
+                };
+                let mut prepared = prepare_query(&state, client.ip(), query)?;
+                prepared.query.limit = SNAPSHOT_ENTRIES;
+                Ok((class.clone(), spec.name.clone(), prepared))
+            })
+            .collect()
+    };
+
+    let stream = async_stream::stream! {
+        let _permit = permit;
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                yield Ok::<_, Infallible>(error_event(ErrorPayload::fatal(e.code, e.message, &reference)));
+                yield Ok(Event::default().event("complete").data(complete_data(None, 0)));
+                return;
+            }
+        };
+
+        // Specs arrive in order, so a class is done with its last spec.
+        let total = prepared.len();
+        let last_of_class: Vec<bool> = (0..total)
+            .map(|i| prepared.get(i + 1).is_none_or(|(next, _, _)| *next != prepared[i].0))
+            .collect();
+        let mut specs = futures::stream::iter(prepared.into_iter().zip(last_of_class))
+            .map(|((class, spec, prepared), class_done)| {
+                let state = state.clone();
+                async move {
+                    let result = collect_talents(&state, &prepared).await;
+                    (class, spec, class_done, result)
+                }
+            })
+            .buffered(SNAPSHOT_PARALLELISM);
+
+        let draining = shutdown::drained();
+        tokio::pin!(draining);
+        let (mut resolved, mut failed) = (0, 0);
+        let mut class_specs: Vec<(String, anyhow::Result<CollectedTalents>)> = Vec::new();
+        loop {
+            let next = tokio::select! {
+                next = specs.next() => next,
+                _ = &mut draining => {
+                    let (code, message) = describe_error(&shutdown::ServerRestarting.into());
+                    yield Ok(error_event(ErrorPayload::fatal(code, message, &reference)));
+                    break;
+                }
+            };
+            let Some((class, spec, class_done, result)) = next else { break };
+            resolved += 1;
+            if let Err(e) = &result {
+                tracing::warn!("Snapshot of {} {} failed: {:#}", spec, class, e);
+                failed += 1;
+            }
+            class_specs.push((spec, result));
+
+            if class_done {
+                let rows: Vec<templates::SnapshotSpec<'_>> = class_specs
+                    .iter()
+                    .map(|(spec, result)| match result {
+                        Ok(collected) => templates::SnapshotSpec {
+                            name:    spec,
+                            build:   most_common_build(&collected.entries),
+                            failure: None,
+                        },
+                        Err(e) => templates::SnapshotSpec {
+                            name:    spec,
+                            build:   None,
+                            failure: Some(describe_error(e).1),
+                        },
+                    })
+                    .collect();
+                let html = templates::render_snapshot_class(&class, state.catalog(params.game).0.class_color(&class), &rows);
+                yield Ok(Event::default().data(html));
+                class_specs.clear();
+            }
+            let progress = serde_json::json!({ "resolved": resolved, "total": total });
+            yield Ok(Event::default().event("progress").data(progress.to_string()));
+        }
+        yield Ok(Event::default().event("complete").data(complete_data(None, failed)));
+    };
+
+    Sse::new(stream)
+        .keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(Duration::from_secs(1))
+                .text("keep-alive"),
+        )
+        .into_response()
+}
+
+/// Read a talent stream up to and including its `Meta` item, or its first
+/// error, adding stage timings to `timing`. Returns the items read.
+async fn read_to_meta(
+    receiver: &mut tokio::sync::mpsc::Receiver<anyhow::Result<StreamItem>>,
+    timing: &mut ServerTiming,
+) -> Vec<anyhow::Result<StreamItem>> {
+    let mut head = Vec::new();
+    while let Some(result) = receiver.recv().await {
+        let last = matches!(result, Ok(StreamItem::Meta { .. }) | Err(_));
+        if let Ok(StreamItem::Timing { stage, elapsed_ms }) = &result {
+            timing.add(stage.clone(), Duration::from_millis(*elapsed_ms));
+        }
+        head.push(result);
+        if last {
+            break;
+        }
+    }
+    head
+}
+
+/// A talent request that passed validation, ready to run.
+struct PreparedQuery {
+    query: RankingsQuery,
+    /// "<spec> <class> on <boss> in <region>", for notices.
+    selection: String,
+    /// The boss's name from settings.toml, or a stand-in.
+    encounter_name: String,
+    /// The encounter isn't in the selected tier; only allowed with
+    /// `allow_unknown_encounter=1`.
+    unknown_encounter: bool,
+    /// Says the requested keystone level was out of range and which was used.
+    bracket_notice: Option<String>,
+    bypass_cache: bool,
+    /// The queried class's color, for tinting entries on the page.
+    class_color: Option<String>,
+    /// The tier key and region code resolved, for linking back to the query.
+    zone:   String,
+    region: String,
+}
+
+/// Why a query was turned away before any upstream work: the API error code
+/// and the message for the user.
+#[derive(Debug)]
+struct Rejection {
+    code:    &'static str,
+    message: String,
+}
+
+/// Most rejections are of the selection: an unknown name, boss or region.
+impl From<String> for Rejection {
+    fn from(message: String) -> Self {
+        Self { code: "invalid_selection", message }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Validate the query parameters and resolve them into a `RankingsQuery`.
+/// Unknown names and bosses are rejected here rather than spending an
+/// upstream query on them; the error is the message for the user.
+fn prepare_query(state: &AppState, client: IpAddr, params: TalentQuery) -> Result<PreparedQuery, Rejection> {
+    let nocache = params.nocache == Some(1);
+    let (config, settings) = state.catalog(params.game);
+    let prepared = resolve_query(config, settings, params)?;
+
+    let bypass_cache = nocache && {
+        let allowed = state.allow_nocache(client);
+        if !allowed {
+            tracing::info!("nocache from {} ignored, used too recently", client);
+        }
+        allowed
+    };
+    Ok(PreparedQuery { bypass_cache, ..prepared })
+}
+
+/// `prepare_query` against a given catalog, without the cache bypass. The
+/// command line runs queries through this too.
+fn resolve_query(config: &ClassSpecs, settings: &Settings, params: TalentQuery) -> Result<PreparedQuery, Rejection> {
+    if let Err(e) = params.check() {
+        tracing::info!("Rejected parameters: {}", e);
+        return Err(e.into());
+    }
+
+    let Some((zone, tier)) = settings.tier(params.zone.as_deref()) else {
+        tracing::info!("Rejected unknown zone {:?}", params.zone);
+        return Err(unknown_zone_message(settings, params.zone.as_deref().unwrap_or_default()).into());
+    };
+    // Mythic+ ranks dungeon runs; every other mode ranks raid kills.
+    let mode      = params.game.modes().into_iter().find(|m| m.name == params.mode);
+    let dungeon   = mode.as_ref().is_some_and(|m| m.dungeon);
+    let encounter = tier.encounters.iter().find(|e| e.id == params.encounter);
+    let tier_wide = params.encounter == TIER_OVERALL;
+    let known_encounter = tier_wide || encounter.is_some();
+
+    let resolved = match config.resolve(&params.class, &params.spec) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            tracing::info!("Rejected selection: {}", e);
+            return Err(e.to_string().into());
+        }
+    };
+    if let Some(encounter) = encounter
+        && encounter.dungeon != dungeon
+    {
+        tracing::info!("Rejected encounter {} in mode {}", params.encounter, params.mode);
+        return Err(if encounter.dungeon {
+            format!("{} is a dungeon; choose the Mythic+ mode.", encounter.name)
+        } else {
+            format!("{} is a raid boss; Mythic+ only ranks dungeons.", encounter.name)
+        }
+        .into());
+    }
+    if !known_encounter && params.allow_unknown_encounter != Some(1) {
+        tracing::info!("Rejected unknown encounter {}", params.encounter);
+        let listed: Vec<config::SeasonEncounter> =
+            tier.encounters.iter().filter(|e| e.dungeon == dungeon).cloned().collect();
+        return Err(unknown_encounter_message(params.encounter, &listed).into());
+    }
+
+    let Some(region) = settings.region(&params.region) else {
+        tracing::info!("Rejected unknown region {}", params.region);
+        let codes: Vec<&str> = settings.regions.iter().map(|r| r.code.as_str()).collect();
+        return Err(format!("Unknown region '{}'. Valid regions: {}.", params.region, codes.join(", ")).into());
+    };
+
+    let partition = tier.partition;
+
+    let difficulty = mode.map(|m| m.difficulty).unwrap_or_else(|| tier.default_difficulty());
+
+    // The whole tier covers its bosses, or its dungeons for Mythic+.
+    let tier_encounters: Vec<i32> = if tier_wide {
+        tier.encounters.iter().filter(|e| e.dungeon == dungeon).map(|e| e.id).collect()
+    } else {
+        Vec::new()
+    };
+    if tier_wide && tier_encounters.is_empty() {
+        tracing::info!("Rejected tier-wide query of a tier without dungeons");
+        return Err("This tier has no Mythic+ dungeons.".to_string().into());
+    }
+
+    // Out-of-range key levels are clamped rather than rejected, and the
+    // results say so.
+    let (bracket, bracket_notice) = match params.bracket {
+        Some(_) if !dungeon => {
+            tracing::info!("Rejected bracket outside Mythic+");
+            return Err("bracket only applies to the Mythic+ mode.".to_string().into());
+        }
+        Some(level) => {
+            let clamped = level.clamp(*config::KEY_LEVELS.start(), *config::KEY_LEVELS.end());
+            let notice  = (clamped != level).then(|| {
+                format!("Keystone level {} is out of range; showing +{} runs.", level, clamped)
+            });
+            (Some(clamped), notice)
+        }
+        None => (None, None),
+    };
+
+    // Damage rankings of a healer say little about its build; dungeon runs
+    // are ranked by score unless a metric is asked for.
+    let metric = match params.metric.as_deref() {
+        Some("playerscore") if !dungeon => {
+            tracing::info!("Rejected playerscore outside Mythic+");
+            return Err("The playerscore metric only ranks Mythic+ runs.".to_string().into());
+        }
+        // The metric is part of the cache key, so anything WarcraftLogs
+        // doesn't rank by is turned away rather than fetched as "dps".
+        Some(metric) if !ClassSpecs::get_metrics().iter().any(|m| m.code == metric) => {
+            tracing::info!("Rejected unknown metric");
+            let codes: Vec<&str> = ClassSpecs::get_metrics().iter().map(|m| m.code).collect();
+            return Err(Rejection {
+                code:    "invalid_query",
+                message: format!("Unknown metric. Valid metrics: {}.", codes.join(", ")),
+            });
+        }
+        Some(metric) => metric,
+        None if dungeon => "playerscore",
+        None if config.role_of(&resolved.class, &resolved.spec) == Some(Role::Healer) => "hps",
+        None => "dps",
+    }
+    .to_string();
+
+    tracing::info!(
+        "Fetching talents for {} {} encounter {} (region: {}, mode: {}, difficulty: {}, partition: {:?}, bracket: {:?}, metric: {})",
+        resolved.class, resolved.spec, params.encounter,
+        region.name, params.mode, difficulty, partition, bracket, metric
+    );
+
+    let encounter_name = match encounter {
+        Some(encounter) => encounter.name.clone(),
+        None if tier_wide => "the whole tier".to_string(),
+        None => format!("encounter {}", params.encounter),
+    };
+    let selection = selection_label(&resolved, &encounter_name, &region.name);
+    let selection = match bracket {
+        Some(level) => format!("{} at +{}", selection, level),
+        None        => selection,
+    };
+    let region_code = region.code.clone();
+    let region      = if region.aggregate { None } else { Some(region.code.clone()) };
+
+    let query = RankingsQuery {
+        class: resolved.class,
+        spec: resolved.spec,
+        wcl_class: resolved.wcl_class,
+        wcl_spec: resolved.wcl_spec,
+        encounter_id: params.encounter,
+        tier_encounters,
+        region,
+        difficulty,
+        partition,
+        bracket,
+        metric,
+        limit: warcraftlogs::MAX_ENTRIES,
+        game: params.game,
+    };
+
+    let class_color = config.class_color(&query.class).map(String::from);
+
+    Ok(PreparedQuery {
+        query,
+        selection,
+        encounter_name,
+        unknown_encounter: !known_encounter,
+        bracket_notice,
+        bypass_cache: false,
+        class_color,
+        zone: zone.to_string(),
+        region: region_code,
+    })
+}
+
+/// Everything a query produced, for the formats that aren't streamed.
+struct CollectedTalents {
+    encounter: Option<String>,
+    available: usize,
+    entries:   Vec<TalentDataWithRank>,
+    cached_age: Option<Duration>,
+    timing:    ServerTiming,
+}
+
+/// Run a query to completion and gather its entries in rank order.
+async fn collect_talents(state: &AppState, prepared: &PreparedQuery) -> anyhow::Result<CollectedTalents> {
+    let started = Instant::now();
+    let TalentStream { receiver, cached_age } =
+        state.talents.fetch_top_talents(&prepared.query, prepared.bypass_cache).await?;
+    collect_stream(receiver, cached_age, started).await
+}
+
+/// Drain a talent stream, timing it from `started`.
+async fn collect_stream(
+    mut receiver: tokio::sync::mpsc::Receiver<anyhow::Result<StreamItem>>,
+    cached_age: Option<Duration>,
+    started: Instant,
+) -> anyhow::Result<CollectedTalents> {
+    let mut collected = CollectedTalents {
+        encounter: None,
+        available: 0,
+        entries:   Vec::new(),
+        cached_age,
+        timing:    ServerTiming::default(),
+    };
+    while let Some(item) = receiver.recv().await {
+        match item? {
+            StreamItem::Meta { encounter, available, .. } => {
+                collected.encounter = encounter;
+                collected.available = available;
+            }
+            StreamItem::Entry(entry)       => collected.entries.push(entry),
+            StreamItem::Progress { .. }    => {}
+            StreamItem::Timing { stage, elapsed_ms } => {
+                collected.timing.add(stage, Duration::from_millis(elapsed_ms));
+            }
+        }
+    }
+    collected.timing.add("total", started.elapsed());
+    Ok(collected)
+}
+
+/// Attach a `Server-Timing` header to a response.
+fn with_server_timing(response: impl IntoResponse, timing: &ServerTiming) -> Response {
+    let mut response = response.into_response();
+    if let Ok(value) = header::HeaderValue::from_str(&timing.to_string()) {
+        response.headers_mut().insert("server-timing", value);
+    }
+    response
+}
+
+/// One entry in the JSON document: the rank alongside the talent data.
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    rank: usize,
+    #[serde(flatten)]
+    data: &'a TalentData,
+}
+
+/// The whole result of a query as a single JSON document.
+#[derive(Serialize)]
+struct TalentsDocument<'a> {
+    query:        &'a RankingsQuery,
+    encounter:    Option<&'a str>,
+    generated_at: u64,
+    cached:       bool,
+    available:    usize,
+    entries:      Vec<JsonEntry<'a>>,
+}
+
+/// JSON error body with an HTTP status matching the error code.
+fn api_error(code: &'static str, message: String) -> Response {
+    let status = match code {
+        "invalid_selection" | "invalid_query"          => StatusCode::BAD_REQUEST,
+        "not_found"                                    => StatusCode::NOT_FOUND,
+        "too_many_requests"                            => StatusCode::TOO_MANY_REQUESTS,
+        "rate_limited" | "budget_exhausted"            => StatusCode::SERVICE_UNAVAILABLE,
+        "deadline_exceeded" | "upstream_timeout"       => StatusCode::GATEWAY_TIMEOUT,
+        "upstream_auth" | "upstream_error" | "unexpected_response" => StatusCode::BAD_GATEWAY,
+        "server_restarting" | "server_busy"            => StatusCode::SERVICE_UNAVAILABLE,
+        "trends_disabled"                              => StatusCode::NOT_IMPLEMENTED,
+        "unauthorized"                                 => StatusCode::UNAUTHORIZED,
+        "admin_disabled"                               => StatusCode::FORBIDDEN,
+        _                                              => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let body = serde_json::json!({ "error": { "code": code, "message": message } });
+    (status, Json(body)).into_response()
+}
+
+/// `GET /api/v1/talents.json`: the same query as the SSE route, returned as one
+/// document once every entry has resolved.
+async fn get_talents_json(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<TalentQuery>,
+) -> Response {
+    let prepared = match prepare_query(&state, client.ip(), params) {
+        Ok(prepared) => prepared,
+        Err(e)       => return api_error(e.code, e.message),
+    };
+
+    match collect_talents(&state, &prepared).await {
+        Ok(collected) => with_server_timing(Json(TalentsDocument {
+            query:        &prepared.query,
+            encounter:    collected.encounter.as_deref(),
+            generated_at: warcraftlogs::unix_now(),
+            cached:       collected.cached_age.is_some(),
+            available:    collected.available,
+            entries:      collected
+                .entries
+                .iter()
+                .map(|e| JsonEntry { rank: e.rank, data: &e.data })
+                .collect(),
+        }), &collected.timing),
+        Err(e) => {
+            tracing::error!("Talent query failed: {:#}", e);
+            let (code, message) = describe_error(&e);
+            api_error(code, message)
+        }
+    }
+}
+
+/// `GET /api/v1/talents.ndjson`: one JSON object per line as each entry
+/// resolves, then a `summary` line. Errors arrive as `error` lines so the
+/// stream always ends on a complete line.
+async fn get_talents_ndjson(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<TalentQuery>,
+) -> Response {
+    let prepared = match prepare_query(&state, client.ip(), params) {
+        Ok(prepared) => prepared,
+        Err(e)       => return api_error(e.code, e.message),
+    };
+
+    let lines = async_stream::stream! {
+        let line = |value: serde_json::Value| Ok::<_, Infallible>(format!("{}\n", value));
+
+        let mut encounter      = None;
+        let mut available      = 0;
+        let mut entries        = 0;
+        let mut failed_entries = 0;
+        let mut status         = "complete";
+
+        match state.talents.fetch_top_talents(&prepared.query, prepared.bypass_cache).await {
+            Ok(TalentStream { mut receiver, .. }) => {
+                while let Some(item) = receiver.recv().await {
+                    match item {
+                        Ok(StreamItem::Meta { encounter: name, available: n, .. }) => {
+                            encounter = name;
+                            available = n;
+                        }
+                        Ok(StreamItem::Entry(entry)) => {
+                            entries += 1;
+                            if entry.data.failure.is_some() {
+                                failed_entries += 1;
+                            }
+                            let json = JsonEntry { rank: entry.rank, data: &entry.data };
+                            yield line(serde_json::to_value(&json).unwrap_or_default());
+                        }
+                        Ok(StreamItem::Progress { .. } | StreamItem::Timing { .. }) => {}
+                        Err(e) => {
+                            tracing::error!("Worker error: {:#}", e);
+                            let (code, message) = describe_error(&e);
+                            yield line(serde_json::json!({ "error": { "code": code, "message": message } }));
+                            status = "error";
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to start stream: {:#}", e);
+                let (code, message) = describe_error(&e);
+                yield line(serde_json::json!({ "error": { "code": code, "message": message } }));
+                status = "error";
+            }
+        }
+
+        yield line(serde_json::json!({
+            "summary": {
+                "status":         status,
+                "encounter":      encounter,
+                "available":      available,
+                "entries":        entries,
+                "failed_entries": failed_entries,
+            }
+        }));
+    };
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// `GET /api/v1/talents.csv`: the results as a CSV download.
+async fn get_talents_csv(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<TalentQuery>,
+) -> Response {
+    let prepared = match prepare_query(&state, client.ip(), params) {
+        Ok(prepared) => prepared,
+        Err(e)       => return api_error(e.code, e.message),
+    };
+
+    match collect_talents(&state, &prepared).await {
+        Ok(collected) => {
+            let filename = export_filename(&prepared.query, collected.encounter.as_deref(), "csv");
+            let response = (
+                [
+                    (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                    (header::CONTENT_DISPOSITION, format!(r#"attachment; filename="{}""#, filename)),
+                ],
+                templates::render_csv(&collected.entries, &prepared.query.metric),
+            );
+            with_server_timing(response, &collected.timing)
+        }
+        Err(e) => {
+            tracing::error!("Talent query failed: {:#}", e);
+            let (code, message) = describe_error(&e);
+            api_error(code, message)
+        }
+    }
+}
+
+/// `GET /api/v1/talents.md`: the results as a Markdown table.
+async fn get_talents_markdown(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<TalentQuery>,
+) -> Response {
+    let prepared = match prepare_query(&state, client.ip(), params) {
+        Ok(prepared) => prepared,
+        Err(e)       => return api_error(e.code, e.message),
+    };
+
+    match collect_talents(&state, &prepared).await {
+        Ok(collected) => {
+            let heading = format!(
+                "{} — {}",
+                prepared.selection,
+                templates::utc_date(warcraftlogs::unix_now())
+            );
+            let response = (
+                [(header::CONTENT_TYPE, "text/markdown; charset=utf-8")],
+                templates::render_markdown(&heading, &collected.entries),
+            );
+            with_server_timing(response, &collected.timing)
+        }
+        Err(e) => {
+            tracing::error!("Talent query failed: {:#}", e);
+            let (code, message) = describe_error(&e);
+            api_error(code, message)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TextOptions {
+    /// `unique=1` drops repeated strings.
+    unique: Option<u8>,
+}
+
+/// `GET /api/v1/talents.txt`: only the import strings, one per line.
+async fn get_talents_text(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<TalentQuery>,
+    Query(options): Query<TextOptions>,
+) -> Response {
+    let prepared = match prepare_query(&state, client.ip(), params) {
+        Ok(prepared) => prepared,
+        Err(e)       => return api_error(e.code, e.message),
+    };
+
+    match collect_talents(&state, &prepared).await {
+        Ok(collected) => with_server_timing(
+            (
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                templates::render_text(&collected.entries, options.unique == Some(1)),
+            ),
+            &collected.timing,
+        ),
+        Err(e) => {
+            tracing::error!("Talent query failed: {:#}", e);
+            let (code, message) = describe_error(&e);
+            api_error(code, message)
+        }
+    }
+}
+
+/// A download name like `vorasius-fire-mage-eu.csv`.
+fn export_filename(query: &RankingsQuery, encounter: Option<&str>, extension: &str) -> String {
+    let encounter = encounter.map_or_else(|| query.encounter_id.to_string(), String::from);
+    let parts = [
+        encounter.as_str(),
+        query.spec.as_str(),
+        query.class.as_str(),
+        query.region.as_deref().unwrap_or("all"),
+    ];
+    let slug: Vec<String> = parts
+        .iter()
+        .map(|part| {
+            part.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+                .collect::<String>()
+                .split('-')
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect();
+    format!("{}.{}", slug.join("-"), extension)
+}
+
+/// Error for a `zone` that isn't a tier in settings.toml, listing the valid
+/// ones.
+fn unknown_zone_message(settings: &Settings, zone: &str) -> String {
+    let valid: Vec<String> = settings
+        .tiers()
+        .iter()
+        .map(|(id, tier)| format!("{} ({})", id, tier.name))
+        .collect();
+    format!("Unknown zone '{}'. Valid zones: {}.", zone, valid.join(", "))
+}
+
+/// Error for an encounter ID outside the selected tier, listing the valid
+/// bosses.
+fn unknown_encounter_message(id: i32, encounters: &[config::SeasonEncounter]) -> String {
+    let valid: Vec<String> = encounters
+        .iter()
+        .map(|e| format!("{} ({})", e.name, e.id))
+        .collect();
+    format!(
+        "Unknown encounter {}. Valid bosses: {}. Add allow_unknown_encounter=1 to query it anyway.",
+        id,
+        valid.join(", ")
+    )
+}
+
+/// "<spec> <class> on <boss> in <region>", for notices about the selection.
+fn selection_label(resolved: &ResolvedSpec, boss: &str, region: &str) -> String {
+    format!(
+        "{} {} on {} in {}",
+        resolved.spec, resolved.class.replace('_', " "), boss, region
+    )
+}
+
+/// Payload of the final `complete` event: whether the entries came from the
+/// result cache, how old they were, and how many ranks failed to load.
+fn complete_data(cached_age: Option<Duration>, failed_entries: usize) -> String {
+    serde_json::json!({
+        "cached":         cached_age.is_some(),
+        "age_secs":       cached_age.map(|age| age.as_secs()),
+        "failed_entries": failed_entries,
+    })
+    .to_string()
+}
+
+/// Payload of an `error` event. `rank` is set when a single entry failed;
+/// `fatal` means the query as a whole failed and no more entries will follow.
+/// `html` is the rendered message for the page, present for fatal errors.
+#[derive(Serialize)]
+struct ErrorPayload {
+    code:    &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank:    Option<usize>,
+    fatal:   bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html:    Option<String>,
+    /// Which column of a comparison the error belongs to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    side:    Option<u8>,
+}
+
+impl ErrorPayload {
+    /// A query-ending error. The message ends with a prefix of the request ID
+    /// for the user to quote, which finds the matching lines in the logs.
+    fn fatal(code: &'static str, message: String, reference: &str) -> Self {
+        let message = format!("{} [ref {}]", message, reference);
+        let html = Some(templates::render_error(&message));
+        Self { code, message, rank: None, fatal: true, html, side: None }
+    }
+}
+
+/// The ID `SetRequestIdLayer` assigned to this request.
+fn request_id(headers: &HeaderMap) -> &str {
+    headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+}
+
+/// A prefix of a request ID short enough to read out, long enough to grep.
+fn short_ref(request_id: &str) -> &str {
+    request_id.get(..8).unwrap_or(request_id)
+}
+
+fn error_event(payload: ErrorPayload) -> Event {
+    Event::default()
+        .event("error")
+        .data(serde_json::to_string(&payload).unwrap_or_default())
+}
+
+/// A machine-readable code and the message shown to the user for a failed
+/// stream. Rate limiting, an exhausted point budget, the stream deadline,
+/// rejected or malformed rankings and upstream timeouts get their own
+/// wording rather than the outermost context.
+fn describe_error(e: &anyhow::Error) -> (&'static str, String) {
+    for cause in e.chain() {
+        if let Some(rate_limited) = cause.downcast_ref::<warcraftlogs::RateLimited>() {
+            return ("rate_limited", rate_limited.to_string());
+        }
+        if let Some(exhausted) = cause.downcast_ref::<warcraftlogs::BudgetExhausted>() {
+            return ("budget_exhausted", exhausted.to_string());
+        }
+        if let Some(exceeded) = cause.downcast_ref::<warcraftlogs::DeadlineExceeded>() {
+            return ("deadline_exceeded", exceeded.to_string());
+        }
+        if let Some(wcl) = cause.downcast_ref::<warcraftlogs::WclError>() {
+            let code = match wcl {
+                warcraftlogs::WclError::Auth                      => "upstream_auth",
+                warcraftlogs::WclError::InvalidQuery { .. }       => "invalid_query",
+                warcraftlogs::WclError::Upstream                  => "upstream_error",
+                warcraftlogs::WclError::UnexpectedResponse        => "unexpected_response",
+                _                                                 => "upstream_error",
+            };
+            return (code, wcl.to_string());
+        }
+        if let Some(restarting) = cause.downcast_ref::<shutdown::ServerRestarting>() {
+            return ("server_restarting", restarting.to_string());
+        }
+        if cause.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout()) {
+            return ("upstream_timeout", "WarcraftLogs did not respond in time, please try again".to_string());
+        }
+    }
+    ("internal", e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_wcl::{test_state, unreachable_client};
+    use axum::extract::connect_info::MockConnectInfo;
+    use tower::ServiceExt;
+
+    /// Send `request` through the whole app as if from `peer`. Each test uses
+    /// its own address, as the query limits are per address and global.
+    async fn send(state: AppState, peer: [u8; 4], request: axum::http::Request<Body>) -> Response {
+        app(state, None)
+            .layer(MockConnectInfo(SocketAddr::from((peer, 40000))))
+            .oneshot(request)
+            .await
+            .unwrap()
+    }
+
+    fn get(uri: &str) -> axum::http::Request<Body> {
+        axum::http::Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    async fn body_text(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    const FROST_MAGE: &str = "class=Mage&spec=Frost&encounter=3176&region=all&mode=Mythic";
+
+    fn event_stream(uri: &str) -> axum::http::Request<Body> {
+        axum::http::Request::get(uri).header(header::ACCEPT, "text/event-stream").body(Body::empty()).unwrap()
+    }
+
+    /// The events of a finished stream as (event, id, data), keep-alives left
+    /// out. Unnamed events are `message`, as EventSource names them.
+    async fn events(response: Response) -> Vec<(String, Option<String>, String)> {
+        parse_events(&body_text(response).await)
+    }
+
+    fn parse_events(text: &str) -> Vec<(String, Option<String>, String)> {
+        text.split("\n\n")
+            .filter(|block| !block.trim().is_empty() && !block.starts_with(':'))
+            .map(|block| {
+                let (mut event, mut id, mut data) = ("message".to_string(), None, Vec::new());
+                for line in block.lines() {
+                    match line.split_once(':').map(|(k, v)| (k, v.strip_prefix(' ').unwrap_or(v))) {
+                        Some(("event", v)) => event = v.to_string(),
+                        Some(("id", v))    => id = Some(v.to_string()),
+                        Some(("data", v))  => data.push(v),
+                        _ => {}
+                    }
+                }
+                (event, id, data.join("\n"))
+            })
+            .collect()
+    }
+
+    fn names(events: &[(String, Option<String>, String)]) -> Vec<&str> {
+        events.iter().map(|(event, _, _)| event.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn event_stream_sends_progress_and_entries_in_order() {
+        use mock_wcl::{entry, meta, progress};
+        let wcl = mock_wcl::MockWcl::new(vec![
+            meta(2, 2),
+            progress(1, 2),
+            entry(1, "Alpha", None),
+            progress(2, 2),
+            entry(2, "Bravo", Some("Actor 'Bravo' not found in masterData")),
+        ]);
+        let response = send(test_state(wcl.clone()), [198, 51, 100, 10], event_stream(&format!("/api/v1/talents?{}", FROST_MAGE))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        let events = events(response).await;
+        assert_eq!(names(&events), ["meta", "message", "progress", "message", "progress", "message", "error", "complete"]);
+        assert_eq!(events[0].2, r#"{"available":2,"encounter":"Imperator Averzian","entries":2}"#);
+        assert!(events[1].2.contains("Only 2 ranked kills exist for Frost Mage"), "{}", events[1].2);
+        assert_eq!(events[2].2, r#"{"resolved":1,"total":2}"#);
+        assert_eq!(events[3].1.as_deref(), Some("1"));
+        assert!(events[3].2.contains("TALENTS1"), "{}", events[3].2);
+        assert_eq!(events[5].1.as_deref(), Some("2"));
+        assert!(events[5].2.contains("Bravo"), "{}", events[5].2);
+        let error: serde_json::Value = serde_json::from_str(&events[6].2).unwrap();
+        assert_eq!(error["code"], "entry_failed");
+        assert_eq!(error["rank"], 2);
+        assert_eq!(error["fatal"], false);
+        assert_eq!(events[7].2, r#"{"age_secs":null,"cached":false,"failed_entries":1}"#);
+        assert_eq!(wcl.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn event_stream_without_rankings_says_so() {
+        let wcl = mock_wcl::MockWcl::new(vec![mock_wcl::meta(0, 0)]);
+        let response = send(test_state(wcl), [198, 51, 100, 11], event_stream(&format!("/api/v1/talents?{}", FROST_MAGE))).await;
+
+        let events = events(response).await;
+        assert_eq!(names(&events), ["meta", "message", "complete"]);
+        assert_eq!(events[0].2, r#"{"available":0,"encounter":"Imperator Averzian","entries":0}"#);
+        assert!(events[1].2.contains("No ranked kills exist for Frost Mage"), "{}", events[1].2);
+        assert_eq!(events[2].2, r#"{"age_secs":null,"cached":false,"failed_entries":0}"#);
+    }
+
+    #[tokio::test]
+    async fn event_stream_ends_with_the_error_of_a_failed_fetch() {
+        use mock_wcl::{Step, entry, meta, progress};
+        let wcl = mock_wcl::MockWcl::new(vec![
+            meta(2, 2),
+            progress(1, 2),
+            entry(1, "Alpha", None),
+            Step::Fail(|| warcraftlogs::WclError::Upstream.into()),
+            entry(2, "Bravo", None),
+        ]);
+        let response = send(test_state(wcl), [198, 51, 100, 12], event_stream(&format!("/api/v1/talents?{}", FROST_MAGE))).await;
+
+        let events = events(response).await;
+        assert_eq!(names(&events), ["meta", "message", "progress", "message", "error", "complete"]);
+        let error: serde_json::Value = serde_json::from_str(&events[4].2).unwrap();
+        assert_eq!(error["code"], "upstream_error");
+        assert_eq!(error["fatal"], true);
+        let message = error["message"].as_str().unwrap();
+        assert!(message.starts_with("WarcraftLogs is having problems, please try again later [ref "), "{}", message);
+        assert_eq!(events[5].2, r#"{"age_secs":null,"cached":false,"failed_entries":0}"#);
+    }
+
+    #[tokio::test]
+    async fn event_stream_errors_escape_upstream_text() {
+        use mock_wcl::{Step, meta};
+        let wcl = mock_wcl::MockWcl::new(vec![
+            meta(1, 1),
+            Step::Fail(|| {
+                warcraftlogs::WclError::InvalidQuery { messages: vec!["<script>alert(1)</script>".to_string()] }.into()
+            }),
+        ]);
+        let response = send(test_state(wcl), [198, 51, 100, 13], event_stream(&format!("/api/v1/talents?{}", FROST_MAGE))).await;
+
+        let events = events(response).await;
+        // The message is the notice of there being only one ranked kill.
+        assert_eq!(names(&events), ["meta", "message", "error", "complete"]);
+        let error: serde_json::Value = serde_json::from_str(&events[2].2).unwrap();
+        assert_eq!(error["code"], "invalid_query");
+        let html = error["html"].as_str().unwrap();
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(
+            html.starts_with(r#"<div class="error">Error: WarcraftLogs rejected the query: &#60;script&#62;alert(1)&#60;/script&#62; [ref "#),
+            "{}", html
+        );
+    }
+
+    #[test]
+    fn negotiate_picks_the_asked_for_format() {
+        assert_eq!(negotiate(Some("text/event-stream")), Format::Sse);
+        assert_eq!(negotiate(Some("application/json")), Format::Json);
+        assert_eq!(negotiate(Some("application/x-ndjson")), Format::Ndjson);
+        assert_eq!(negotiate(Some("text/csv")), Format::Csv);
+        assert_eq!(negotiate(Some("text/markdown")), Format::Markdown);
+        assert_eq!(negotiate(Some("text/plain")), Format::Text);
+        assert_eq!(negotiate(Some("Application/JSON; charset=utf-8")), Format::Json);
+    }
+
+    #[test]
+    fn negotiate_honours_q_factors() {
+        assert_eq!(negotiate(Some("text/csv;q=0.5, application/json")), Format::Json);
+        assert_eq!(negotiate(Some("application/json;q=0.2, text/csv;q=0.9")), Format::Csv);
+        // q=0 means not acceptable at all.
+        assert_eq!(negotiate(Some("application/json;q=0, text/csv;q=0.1")), Format::Csv);
+        // Equal weights keep the client's order.
+        assert_eq!(negotiate(Some("text/markdown, application/json")), Format::Markdown);
+        assert_eq!(negotiate(Some("application/json, text/markdown")), Format::Json);
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_sse() {
+        assert_eq!(negotiate(None), Format::Sse);
+        assert_eq!(negotiate(Some("")), Format::Sse);
+        assert_eq!(negotiate(Some("*/*")), Format::Sse);
+        assert_eq!(negotiate(Some("image/png")), Format::Sse);
+        assert_eq!(negotiate(Some("application/json;q=0")), Format::Sse);
+        // A browser's navigation header prefers HTML, then anything.
+        assert_eq!(negotiate(Some("text/html,application/xhtml+xml,*/*;q=0.8")), Format::Sse);
+        assert_eq!(negotiate(Some("image/png, application/*;q=0.5")), Format::Json);
+    }
+
+    #[tokio::test]
+    async fn talents_route_serves_the_negotiated_format() {
+        use mock_wcl::{entry, meta};
+        let wcl = mock_wcl::MockWcl::new(vec![meta(1, 1), entry(1, "Alpha", None)]);
+        let state = test_state(wcl);
+        let uri = format!("/api/v1/talents?{}", FROST_MAGE);
+
+        let csv = axum::http::Request::get(&uri).header(header::ACCEPT, "application/json;q=0.5, text/csv").body(Body::empty()).unwrap();
+        let response = send(state.clone(), [198, 51, 100, 14], csv).await;
+        assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/csv"));
+        assert!(body_text(response).await.starts_with("rank,player,"));
+
+        let json = axum::http::Request::get(&uri).header(header::ACCEPT, "application/json").body(Body::empty()).unwrap();
+        let response = send(state, [198, 51, 100, 14], json).await;
+        assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("application/json"));
+        let body: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(body["entries"][0]["rank"], 1);
+        assert_eq!(body["entries"][0]["name"], "Alpha");
+    }
+
+    #[tokio::test]
+    async fn regions_lists_codes_names_and_the_aggregate() {
+        let state = test_state(Arc::new(unreachable_client()));
+
+        let response = send(state.clone(), [198, 51, 100, 15], get("/api/v1/regions")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=300");
+        let etag = response.headers()[header::ETAG].clone();
+        let body: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(body, serde_json::json!([
+            { "code": "all", "name": "All Regions", "aggregate": true },
+            { "code": "US", "name": "US & Oceanic", "aggregate": false },
+            { "code": "EU", "name": "Europe", "aggregate": false },
+            { "code": "KR", "name": "Korea", "aggregate": false },
+            { "code": "TW", "name": "Taiwan", "aggregate": false },
+            { "code": "CN", "name": "China", "aggregate": false },
+        ]));
+
+        let again = axum::http::Request::get("/api/v1/regions").header(header::IF_NONE_MATCH, etag).body(Body::empty()).unwrap();
+        let response = send(state.clone(), [198, 51, 100, 15], again).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Classic has no separate Chinese site.
+        let response = send(state, [198, 51, 100, 15], get("/api/v1/regions?game=classic")).await;
+        let body: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        let codes: Vec<&str> = body.as_array().unwrap().iter().map(|r| r["code"].as_str().unwrap()).collect();
+        assert_eq!(codes, ["all", "US", "EU", "KR", "TW"]);
+    }
+
+    /// Streams of every outcome counted in a `/metrics` exposition.
+    fn talent_streams(exposition: &str) -> u64 {
+        exposition
+            .lines()
+            .filter(|l| l.starts_with(r#"talent_trends_latency_seconds_count{op="talent_stream","#))
+            .map(|l| l.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn metrics_count_finished_streams() {
+        use mock_wcl::{entry, meta};
+        let state = test_state(mock_wcl::MockWcl::new(vec![meta(1, 1), entry(1, "Alpha", None)]));
+
+        let response = send(state.clone(), [198, 51, 100, 16], get("/metrics")).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; version=0.0.4");
+        let before = body_text(response).await;
+        for name in [
+            "# TYPE talent_trends_latency_seconds histogram",
+            "# TYPE talent_trends_open_streams gauge",
+            "# TYPE talent_trends_max_open_streams gauge",
+        ] {
+            assert!(before.contains(name), "no {} in\n{}", name, before);
+        }
+
+        let response = send(state.clone(), [198, 51, 100, 16], event_stream(&format!("/api/v1/talents?{}", FROST_MAGE))).await;
+        events(response).await;
+
+        // Other tests finish streams too, so this only ever grows by more.
+        let after = body_text(send(state, [198, 51, 100, 16], get("/metrics")).await).await;
+        assert!(talent_streams(&after) > talent_streams(&before), "{}", after);
+        assert!(after.contains(r#"talent_trends_latency_seconds_count{op="talent_stream",outcome="ok"}"#), "{}", after);
+    }
+
+    #[tokio::test]
+    async fn talent_responses_carry_server_timing() {
+        use mock_wcl::{Step, entry, meta};
+        let rankings = Step::Send(Box::new(StreamItem::Timing { stage: "wcl-rankings".to_string(), elapsed_ms: 812 }));
+        let talents  = Step::Send(Box::new(StreamItem::Timing { stage: "wcl-talents".to_string(), elapsed_ms: 3541 }));
+        let wcl = mock_wcl::MockWcl::new(vec![rankings, meta(1, 1), entry(1, "Alpha", None), talents]);
+
+        let response = send(test_state(wcl.clone()), [198, 51, 100, 17], get(&format!("/api/v1/talents.json?{}", FROST_MAGE))).await;
+        let timing = response.headers()["server-timing"].to_str().unwrap();
+        assert!(timing.starts_with("wcl-rankings;dur=812, wcl-talents;dur=3541, total;dur="), "{}", timing);
+
+        // The event stream's header is sent before the talents are fetched,
+        // so it covers the rankings and the time up to them.
+        let response = send(test_state(wcl), [198, 51, 100, 17], event_stream(&format!("/api/v1/talents?{}", FROST_MAGE))).await;
+        let timing = response.headers()["server-timing"].to_str().unwrap();
+        assert!(timing.starts_with("wcl-rankings;dur=812, total;dur="), "{}", timing);
+    }
+
+    /// Read more of an open stream until what was read contains `marker`, or
+    /// to its end without one.
+    async fn read_until(body: &mut axum::body::BodyDataStream, text: &mut String, marker: Option<&str>) {
+        while !marker.is_some_and(|marker| text.contains(marker)) {
+            let Some(chunk) = body.next().await else {
+                assert!(marker.is_none(), "stream ended before {:?}: {}", marker, text);
+                return;
+            };
+            text.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+        }
+    }
+
+    /// Start a Frost Mage stream that holds after its first entry, shut down
+    /// once that entry is read, and return the events and the query's mock.
+    /// `then` runs right after the shutdown.
+    async fn stream_through_shutdown(peer: [u8; 4], then: fn(&mock_wcl::MockWcl)) -> Vec<(String, Option<String>, String)> {
+        use mock_wcl::{Step, entry, meta};
+        let token = tokio_util::sync::CancellationToken::new();
+        shutdown::SCOPED
+            .scope(token.clone(), async {
+                let wcl = mock_wcl::MockWcl::new(vec![meta(2, 2), entry(1, "Alpha", None), Step::Hold, entry(2, "Bravo", None)]);
+                let response = send(test_state(wcl.clone()), peer, event_stream(&format!("/api/v1/talents?{}", FROST_MAGE))).await;
+                let mut body = response.into_body().into_data_stream();
+                let mut text = String::new();
+                read_until(&mut body, &mut text, Some("id: 1\n")).await;
+
+                token.cancel();
+                then(&wcl);
+                read_until(&mut body, &mut text, None).await;
+                parse_events(&text)
+            })
+            .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_finishing_within_the_grace_period_complete_normally() {
+        let events = stream_through_shutdown([198, 51, 100, 18], |wcl| wcl.release()).await;
+
+        assert_eq!(names(&events), ["meta", "message", "message", "message", "complete"]);
+        assert_eq!(events[3].1.as_deref(), Some("2"));
+        assert_eq!(events[4].2, r#"{"age_secs":null,"cached":false,"failed_entries":0}"#);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_still_open_after_the_grace_period_end_with_complete() {
+        let started = tokio::time::Instant::now();
+        let events = stream_through_shutdown([198, 51, 100, 19], |_| {}).await;
+
+        assert!(started.elapsed() >= *shutdown::GRACE);
+        assert_eq!(names(&events), ["meta", "message", "message", "error", "complete"]);
+        let error: serde_json::Value = serde_json::from_str(&events[3].2).unwrap();
+        assert_eq!(error["code"], "server_restarting");
+        assert_eq!(events[4].2, r#"{"age_secs":null,"cached":false,"failed_entries":0}"#);
+    }
+
+    fn gzip_request(uri: &str, accept: &str) -> axum::http::Request<Body> {
+        axum::http::Request::get(uri)
+            .header(header::ACCEPT, accept)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pages_are_gzipped_when_asked() {
+        use std::io::Read;
+        let page = "<p>Frost Mage</p>".repeat(100);
+        let mut state = test_state(Arc::new(unreachable_client()));
+        state.home_page = Bytes::from(page.clone());
+
+        let response = send(state.clone(), [198, 51, 100, 20], gzip_request("/", "text/html")).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(response.headers()[header::VARY].to_str().unwrap().contains("accept-encoding"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.len() < page.len());
+        let mut unzipped = String::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut unzipped).unwrap();
+        assert_eq!(unzipped, page);
+
+        // Not without asking.
+        let response = send(state, [198, 51, 100, 20], get("/")).await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body_text(response).await, page);
+    }
+
+    #[tokio::test]
+    async fn streams_are_never_compressed() {
+        use mock_wcl::{entry, meta};
+        let wcl = mock_wcl::MockWcl::new(vec![meta(1, 1), entry(1, "Alpha", None)]);
+
+        let sse = gzip_request(&format!("/api/v1/talents?{}", FROST_MAGE), "text/event-stream");
+        let response = send(test_state(wcl.clone()), [198, 51, 100, 21], sse).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(names(&events(response).await), ["meta", "message", "message", "complete"]);
+
+        let ndjson = gzip_request(&format!("/api/v1/talents.ndjson?{}", FROST_MAGE), "application/x-ndjson");
+        let response = send(test_state(wcl), [198, 51, 100, 21], ndjson).await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(body_text(response).await.contains(r#""name":"Alpha""#));
+    }
+
+    fn frost_mage() -> TalentQuery {
+        TalentQuery {
+            class:     "Mage".to_string(),
+            spec:      "Frost".to_string(),
+            encounter: 3176,
+            zone:      None,
+            region:    "all".to_string(),
+            mode:      "Mythic".to_string(),
+            metric:    None,
+            bracket:   None,
+            nocache:   None,
+            allow_unknown_encounter: None,
+            game:      Game::Retail,
+        }
+    }
+
+    #[test]
+    fn check_accepts_ordinary_queries() {
+        assert_eq!(frost_mage().check(), Ok(()));
+        assert_eq!(TalentQuery { encounter: TIER_OVERALL, ..frost_mage() }.check(), Ok(()));
+        assert_eq!(TalentQuery { encounter: MAX_ENCOUNTER_ID, ..frost_mage() }.check(), Ok(()));
+        // Length is counted in characters, not bytes.
+        assert_eq!(TalentQuery { spec: "\u{e9}".repeat(MAX_PARAM_LEN), ..frost_mage() }.check(), Ok(()));
+    }
+
+    #[test]
+    fn check_rejects_oversized_values_without_echoing_them() {
+        let long = "A".repeat(MAX_PARAM_LEN + 1);
+        assert_eq!(
+            TalentQuery { class: long.clone(), ..frost_mage() }.check(),
+            Err("class is too long (at most 32 characters)".to_string())
+        );
+        assert_eq!(
+            TalentQuery { metric: Some(long.clone()), ..frost_mage() }.check(),
+            Err("metric is too long (at most 32 characters)".to_string())
+        );
+        assert_eq!(
+            TalentQuery { zone: Some(long), ..frost_mage() }.check(),
+            Err("zone is too long (at most 32 characters)".to_string())
+        );
+    }
+
+    #[test]
+    fn check_rejects_control_characters() {
+        assert_eq!(
+            TalentQuery { spec: "Frost\n[INFO] forged".to_string(), ..frost_mage() }.check(),
+            Err("spec contains control characters".to_string())
+        );
+        assert_eq!(
+            TalentQuery { region: "EU\u{1b}[31m".to_string(), ..frost_mage() }.check(),
+            Err("region contains control characters".to_string())
+        );
+    }
+
+    #[test]
+    fn check_rejects_encounters_out_of_range() {
+        let message = Err(format!("encounter must be 0 (the whole tier) or an ID from 1 to {}", MAX_ENCOUNTER_ID));
+        assert_eq!(TalentQuery { encounter: -1, ..frost_mage() }.check(), message);
+        assert_eq!(TalentQuery { encounter: MAX_ENCOUNTER_ID + 1, ..frost_mage() }.check(), message);
+        assert_eq!(TalentQuery { encounter: i32::MIN, ..frost_mage() }.check(), message);
+    }
+
+    #[tokio::test]
+    async fn rejected_parameters_are_a_bad_request() {
+        let state = test_state(Arc::new(unreachable_client()));
+        let uri = format!("/api/v1/talents.json?{}", FROST_MAGE.replace("class=Mage", &format!("class={}", "M".repeat(100))));
+
+        let response = send(state.clone(), [198, 51, 100, 22], get(&uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = body_text(response).await;
+        assert!(body.contains("class is too long") && !body.contains("MMMM"), "{}", body);
+
+        let response = send(state, [198, 51, 100, 22], get(&format!("/api/v1/talents.json?{}", FROST_MAGE.replace("3176", "-5")))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_text(response).await.contains("encounter must be 0"));
+    }
+
+    #[tokio::test]
+    async fn unknown_metrics_are_rejected_before_any_fetch() {
+        // The client fails every request, so reaching it would be a 502.
+        let state = test_state(Arc::new(unreachable_client()));
+
+        for metric in ["a1", "DPS", "dps%20"] {
+            let uri = format!("/api/v1/talents.json?{}&metric={}", FROST_MAGE, metric);
+            let response = send(state.clone(), [198, 51, 100, 26], get(&uri)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", metric);
+            let body: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+            assert_eq!(body["error"]["code"], "invalid_query");
+            assert_eq!(body["error"]["message"], "Unknown metric. Valid metrics: dps, hps, tankhps, playerscore.");
+        }
+
+        let uri = format!("/api/v1/talents?{}&metric=a1", FROST_MAGE);
+        let events = events(send(state, [198, 51, 100, 26], event_stream(&uri)).await).await;
+        assert_eq!(names(&events), ["error", "complete"]);
+        let error: serde_json::Value = serde_json::from_str(&events[0].2).unwrap();
+        assert_eq!(error["code"], "invalid_query");
+    }
+
+    #[tokio::test]
+    async fn unknown_pages_get_the_html_404() {
+        let state = test_state(Arc::new(unreachable_client()));
+
+        let response = send(state.clone(), [198, 51, 100, 23], get("/no/such/page")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+        let body = body_text(response).await;
+        assert!(body.contains("<title>Not Found — Talent Trends</title>"), "{}", body);
+        assert!(body.contains("There is nothing at <code>/no/such/page</code>."), "{}", body);
+
+        // The path is shown escaped.
+        let response = send(state.clone(), [198, 51, 100, 23], get("/%3Cb%3E\"x")).await;
+        let body = body_text(response).await;
+        assert!(body.contains("<code>/%3Cb%3E&#34;x</code>"), "{}", body);
+
+        // `/api` itself isn't under the API.
+        let response = send(state, [198, 51, 100, 23], get("/api")).await;
+        assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+    }
+
+    #[tokio::test]
+    async fn unknown_api_routes_get_a_json_404() {
+        let state = test_state(Arc::new(unreachable_client()));
+
+        for path in ["/api/v1/nope", "/api/v2/talents", "/api/"] {
+            let response = send(state.clone(), [198, 51, 100, 24], get(path)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("application/json"));
+            let body: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+            assert_eq!(body["error"]["code"], "not_found");
+            assert_eq!(body["error"]["message"], format!("No API route at {}", path));
+        }
+    }
+
+    #[tokio::test]
+    async fn talent_queries_over_the_limit_get_429_with_retry_after() {
+        let state = test_state(Arc::new(unreachable_client()));
+        let unknown = "/api/v1/talents.json?class=Nope&spec=Nope&encounter=0&region=all&mode=Mythic";
+
+        // Refused queries still count.
+        for _ in 0..10 {
+            let response = send(state.clone(), [198, 51, 100, 1], get(unknown)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let response = send(state.clone(), [198, 51, 100, 1], get(unknown)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        assert!((1..=6).contains(&retry_after), "{}", retry_after);
+        let body: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(body["error"]["code"], "too_many_requests");
+
+        // The event stream can't read a status, so it gets an error event.
+        let sse = axum::http::Request::get(unknown.replace("talents.json", "talents"))
+            .header(header::ACCEPT, "text/event-stream")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state.clone(), [198, 51, 100, 1], sse).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let events = body_text(response).await;
+        assert!(events.contains("event: error") && events.contains("too_many_requests"), "{}", events);
+        assert!(events.contains("event: complete"), "{}", events);
+
+        // Other clients aren't affected.
+        let response = send(state, [198, 51, 100, 2], get(unknown)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn feed_is_served_as_atom_with_a_cache_lifetime() {
+        let state = test_state(Arc::new(unreachable_client()));
+
+        let response = send(state, [198, 51, 100, 25], get("/feed.xml")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/atom+xml; charset=utf-8");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=300");
+        // Without a store there is no history, so the feed has no entries.
+        let body = body_text(response).await;
+        assert!(body.starts_with("<?xml version=\"1.0\" encoding=\"utf-8\"?>"), "{}", body);
+        assert!(body.contains("<feed xmlns=\"http://www.w3.org/2005/Atom\">"), "{}", body);
+        assert!(!body.contains("<entry>"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn every_documented_path_is_routed() {
+        let state = test_state(Arc::new(unreachable_client()));
+        let response = send(state.clone(), [198, 51, 100, 27], get("/api/v1/openapi.json")).await;
+        let document: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        let server = document["servers"][0]["url"].as_str().unwrap();
+        assert_eq!(server, "/api/v1");
+
+        let paths = document["paths"].as_object().unwrap();
+        assert!(paths.len() > 10, "{:?}", paths.keys());
+        for path in paths.keys() {
+            // Without their parameters, most are rejected; none may be unknown.
+            let response = send(state.clone(), [198, 51, 100, 27], get(&format!("{}{}", server, path))).await;
+            assert_ne!(response.status(), StatusCode::NOT_FOUND, "{}{}", server, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn hung_upstream_requests_end_as_upstream_timeouts() {
+        // Connections are accepted into the backlog but never answered.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v2/client", listener.local_addr().unwrap());
+        let http = reqwest::Client::builder().timeout(Duration::from_millis(50)).build().unwrap();
+        let error = http.post(url).send().await.unwrap_err();
+
+        let error = anyhow::Error::from(error).context("rankings query");
+        let (code, message) = describe_error(&error);
+        assert_eq!(code, "upstream_timeout");
+        assert_eq!(message, "WarcraftLogs did not respond in time, please try again");
+        assert_eq!(api_error(code, message).status(), StatusCode::GATEWAY_TIMEOUT);
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn the_stream_cap_belongs_to_the_state() {
+        let wcl = mock_wcl::MockWcl::new(vec![mock_wcl::meta(0, 0)]);
+        let full = AppState { max_streams: 0, stream_permits: Arc::new(Semaphore::new(0)), ..test_state(wcl.clone()) };
+        let uri = format!("/api/v1/talents?{}", FROST_MAGE);
+
+        let refused = events(send(full, [198, 51, 100, 28], event_stream(&uri)).await).await;
+        assert_eq!(names(&refused), ["error", "complete"]);
+        let error: serde_json::Value = serde_json::from_str(&refused[0].2).unwrap();
+        assert_eq!(error["code"], "server_busy");
+
+        // Another state has its own permits.
+        let served = events(send(test_state(wcl), [198, 51, 100, 28], event_stream(&uri)).await).await;
+        assert!(!names(&served).contains(&"error"), "{:?}", served);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_check_the_state_token() {
+        let state = test_state(Arc::new(unreachable_client()));
+        let status = || axum::http::Request::get("/admin/status").header(header::AUTHORIZATION, "Bearer secret");
+
+        let response = send(state.clone(), [198, 51, 100, 29], status().body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let state = AppState { admin_token: Some(Arc::from("secret")), ..state };
+        let response = send(state.clone(), [198, 51, 100, 29], status().body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(state, [198, 51, 100, 29], get("/admin/status")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn feed_lists_the_changes_in_the_state_store() {
+        let store = Store::open(":memory:").unwrap();
+        let change = store::BuildChange {
+            id:          0,
+            detected_at: 0,
+            selection:   "Frost Mage on Vorasius in All Regions".to_string(),
+            previous:    "OLD".to_string(),
+            current:     "NEW".to_string(),
+            player:      "#1 Frostyboi".to_string(),
+            log_url:     "https://www.warcraftlogs.com/reports/abc".to_string(),
+        };
+        store.record_change(&mock_wcl::query(), change).await.unwrap();
+        let state = AppState {
+            store:      Some(store),
+            public_url: Some(Arc::from("https://talents.example.com")),
+            ..test_state(Arc::new(unreachable_client()))
+        };
+
+        let body = body_text(send(state, [198, 51, 100, 30], get("/feed.xml")).await).await;
+        assert!(body.contains("<id>https://talents.example.com/feed.xml</id>"), "{}", body);
+        assert!(body.contains("<title>New most common build: Frost Mage on Vorasius in All Regions</title>"), "{}", body);
+        assert!(body.contains("<pre>NEW</pre>"), "{}", body);
+    }
 }
@@ -0,0 +1,228 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency buckets, in seconds. Anything slower lands in
+/// the implicit `+Inf` bucket.
+const BUCKETS: [f64; 12] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// A fixed-bucket latency histogram, cumulative in the Prometheus sense only
+/// when rendered.
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let slot = BUCKETS.iter().position(|b| secs <= *b).unwrap_or(BUCKETS.len());
+        self.buckets[slot].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Upper bound of the bucket holding quantile `q`; `None` when empty and
+    /// infinite when it falls past the last bucket.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+        let target = ((count as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target {
+                return Some(BUCKETS.get(i).copied().unwrap_or(f64::INFINITY));
+            }
+        }
+        Some(f64::INFINITY)
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Histograms by (operation, outcome).
+    static ref LATENCY: Mutex<BTreeMap<(&'static str, &'static str), Arc<Histogram>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Record how long one `op` took and how it ended.
+fn observe(op: &'static str, outcome: &'static str, elapsed: Duration) {
+    let histogram = LATENCY
+        .lock()
+        .unwrap()
+        .entry((op, outcome))
+        .or_default()
+        .clone();
+    histogram.observe(elapsed);
+    tracing::debug!(op, outcome, elapsed_ms = elapsed.as_millis() as u64, "latency");
+}
+
+/// Times an operation from `start` until it is finished or dropped. Dropping
+/// without `finish` records `default_outcome`, which covers early returns
+/// and cancelled futures.
+pub struct Timer {
+    op: &'static str,
+    outcome: &'static str,
+    started: Instant,
+}
+
+impl Timer {
+    pub fn start(op: &'static str, default_outcome: &'static str) -> Self {
+        Self { op, outcome: default_outcome, started: Instant::now() }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn finish(mut self, outcome: &'static str) {
+        self.outcome = outcome;
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        observe(self.op, self.outcome, self.started.elapsed());
+    }
+}
+
+/// A `Server-Timing` header value, e.g.
+/// `wcl-rankings;dur=812, wcl-talents;dur=3541, total;dur=4460`. Durations
+/// are whole milliseconds; names must be HTTP tokens.
+#[derive(Debug, Default)]
+pub struct ServerTiming {
+    metrics: Vec<(String, Duration)>,
+}
+
+impl ServerTiming {
+    pub fn add(&mut self, name: impl Into<String>, elapsed: Duration) -> &mut Self {
+        self.metrics.push((name.into(), elapsed));
+        self
+    }
+}
+
+impl std::fmt::Display for ServerTiming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, elapsed)) in self.metrics.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{};dur={}", name, elapsed.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// Estimated percentiles for one (operation, outcome), in seconds. Each is
+/// the upper bound of the bucket the percentile falls in.
+#[derive(Debug, Serialize)]
+pub struct LatencySummary {
+    pub op: &'static str,
+    pub outcome: &'static str,
+    pub count: u64,
+    pub p50: Option<f64>,
+    pub p95: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+pub fn summaries() -> Vec<LatencySummary> {
+    LATENCY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&(op, outcome), h)| LatencySummary {
+            op,
+            outcome,
+            count: h.count.load(Ordering::Relaxed),
+            p50: h.quantile(0.50),
+            p95: h.quantile(0.95),
+            p99: h.quantile(0.99),
+        })
+        .collect()
+}
+
+/// All histograms in the Prometheus text exposition format.
+pub fn render_prometheus() -> String {
+    let mut out = String::from(
+        "# HELP talent_trends_latency_seconds Latency of upstream calls and whole queries.\n\
+         # TYPE talent_trends_latency_seconds histogram\n",
+    );
+    for (&(op, outcome), h) in LATENCY.lock().unwrap().iter() {
+        let labels = format!(r#"op="{}",outcome="{}""#, op, outcome);
+        let mut cumulative = 0;
+        for (i, bucket) in h.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = BUCKETS.get(i).map_or_else(|| "+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(out, r#"talent_trends_latency_seconds_bucket{{{},le="{}"}} {}"#, labels, le, cumulative);
+        }
+        let sum = h.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "talent_trends_latency_seconds_sum{{{}}} {}", labels, sum);
+        let _ = writeln!(out, "talent_trends_latency_seconds_count{{{}}} {}", labels, h.count.load(Ordering::Relaxed));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_timing_lists_whole_milliseconds_in_order() {
+        assert_eq!(ServerTiming::default().to_string(), "");
+
+        let mut timing = ServerTiming::default();
+        timing
+            .add("wcl-rankings", Duration::from_micros(812_900))
+            .add("wcl-talents", Duration::from_millis(3541))
+            .add("total", Duration::from_millis(4460));
+        assert_eq!(timing.to_string(), "wcl-rankings;dur=812, wcl-talents;dur=3541, total;dur=4460");
+    }
+
+    #[test]
+    fn quantiles_are_bucket_upper_bounds() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for ms in [40, 40, 300, 4000] {
+            histogram.observe(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.quantile(0.5), Some(0.05));
+        assert_eq!(histogram.quantile(0.75), Some(0.5));
+        assert_eq!(histogram.quantile(0.99), Some(5.0));
+
+        histogram.observe(Duration::from_secs(90));
+        assert_eq!(histogram.quantile(1.0), Some(f64::INFINITY));
+    }
+
+    #[test]
+    fn exposition_has_cumulative_buckets_sum_and_count() {
+        // The registry is global; this operation is only used here.
+        observe("metrics_test", "ok", Duration::from_millis(300));
+        observe("metrics_test", "ok", Duration::from_millis(700));
+
+        let text = render_prometheus();
+        assert!(text.starts_with("# HELP talent_trends_latency_seconds "), "{}", text);
+        assert!(text.contains("# TYPE talent_trends_latency_seconds histogram\n"), "{}", text);
+        let labels = r#"op="metrics_test",outcome="ok""#;
+        for line in [
+            format!(r#"talent_trends_latency_seconds_bucket{{{},le="0.25"}} 0"#, labels),
+            format!(r#"talent_trends_latency_seconds_bucket{{{},le="0.5"}} 1"#, labels),
+            format!(r#"talent_trends_latency_seconds_bucket{{{},le="1"}} 2"#, labels),
+            format!(r#"talent_trends_latency_seconds_bucket{{{},le="+Inf"}} 2"#, labels),
+            format!("talent_trends_latency_seconds_sum{{{}}} 1", labels),
+            format!("talent_trends_latency_seconds_count{{{}}} 2", labels),
+        ] {
+            assert!(text.lines().any(|l| l == line), "no {} in\n{}", line, text);
+        }
+
+        let summary = summaries().into_iter().find(|s| s.op == "metrics_test").unwrap();
+        assert_eq!((summary.count, summary.p50, summary.p99), (2, Some(0.5), Some(1.0)));
+    }
+}
@@ -0,0 +1,185 @@
+//! A stand-in for WarcraftLogs that plays back a script, for testing the
+//! handlers and the coalescer without the network.
+
+use anyhow::Result;
+use axum::body::Bytes;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, mpsc, watch};
+
+use crate::cache::TtlCache;
+use crate::coalesce::Coalescer;
+use crate::config::{ClassSpecs, Game, Settings};
+use crate::warcraftlogs::{
+    self, RankingsQuery, STREAM_CHANNEL_CAPACITY, StreamItem, TalentDataWithRank, WclApi, WclClient,
+};
+use crate::ratelimit::RateLimiter;
+use crate::{AppState, Catalog};
+
+/// One step of a talent stream.
+#[derive(Clone)]
+pub enum Step {
+    Send(Box<StreamItem>),
+    /// Send the error this makes; errors can't be cloned, so each stream
+    /// makes its own.
+    Fail(fn() -> anyhow::Error),
+    /// Wait for `MockWcl::release`, or for the receiver to go away.
+    Hold,
+}
+
+/// Every query gets the same script, in order, then the stream ends.
+pub struct MockWcl {
+    script:    Vec<Step>,
+    calls:     AtomicUsize,
+    released:  watch::Sender<bool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl MockWcl {
+    pub fn new(script: Vec<Step>) -> Arc<Self> {
+        Arc::new(Self {
+            script,
+            calls:     AtomicUsize::new(0),
+            released:  watch::channel(false).0,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// How many queries were started.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// Let every stream, now and later, past its `Hold`.
+    pub fn release(&self) {
+        self.released.send_replace(true);
+    }
+
+    /// Whether a stream's receiver went away before the script ended.
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl WclApi for MockWcl {
+    fn talents<'a>(&'a self, _params: &'a RankingsQuery) -> BoxFuture<'a, Result<mpsc::Receiver<Result<StreamItem>>>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx)     = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        let script       = self.script.clone();
+        let mut released = self.released.subscribe();
+        let cancelled    = self.cancelled.clone();
+        tokio::spawn(async move {
+            for step in script {
+                let delivered = match step {
+                    Step::Send(item)  => tx.send(Ok(*item)).await.is_ok(),
+                    Step::Fail(error) => tx.send(Err(error())).await.is_ok(),
+                    Step::Hold => tokio::select! {
+                        _ = released.wait_for(|released| *released) => true,
+                        _ = tx.closed() => false,
+                    },
+                };
+                if !delivered {
+                    cancelled.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+        });
+        Box::pin(async { Ok(rx) })
+    }
+}
+
+/// A client whose endpoints refuse connections, so nothing reaches
+/// WarcraftLogs.
+pub fn unreachable_client() -> WclClient {
+    let endpoints = warcraftlogs::Endpoints {
+        oauth:   "http://127.0.0.1:9/oauth/token".parse().unwrap(),
+        graphql: "http://127.0.0.1:9/api/v2/client".parse().unwrap(),
+    };
+    WclClient::new(endpoints, &Settings::load(Game::Retail).unwrap(), &Settings::load(Game::Classic).unwrap()).unwrap()
+}
+
+/// State with the built-in catalogs whose talent queries go to `wcl`.
+pub fn test_state(wcl: Arc<dyn WclApi>) -> AppState {
+    let settings = Settings::load(Game::Retail).unwrap();
+    let classic_settings = Settings::load(Game::Classic).unwrap();
+    AppState {
+        wcl:        unreachable_client(),
+        talents:    Coalescer::new(wcl, None),
+        config:     Arc::new(ClassSpecs::load(Game::Retail).unwrap()),
+        settings:   Arc::new(settings),
+        home_page:  Bytes::from_static(b"<!doctype html><title>home</title>"),
+        setup_page: None,
+        classic:    Arc::new(Catalog {
+            config:    ClassSpecs::load(Game::Classic).unwrap(),
+            settings:  classic_settings,
+            home_page: Bytes::from_static(b"<!doctype html><title>classic</title>"),
+        }),
+        started_at: Instant::now(),
+        store:      None,
+        talent_limiter:   Arc::new(RateLimiter::new(10)),
+        snapshot_limiter: Arc::new(RateLimiter::new(2)),
+        recent_nocache:   Arc::new(TtlCache::new(Duration::from_secs(60), 10_000)),
+        max_streams:      100,
+        stream_permits:   Arc::new(Semaphore::new(100)),
+        trend_retention:  None,
+        admin_token:      None,
+        public_url:       None,
+    }
+}
+
+/// Frost Mage on the first boss of the current season, Mythic, all regions.
+pub fn query() -> RankingsQuery {
+    RankingsQuery {
+        class:           "Mage".to_string(),
+        spec:            "Frost".to_string(),
+        wcl_class:       "Mage".to_string(),
+        wcl_spec:        "Frost".to_string(),
+        encounter_id:    3176,
+        tier_encounters: Vec::new(),
+        region:          None,
+        difficulty:      5,
+        partition:       None,
+        bracket:         None,
+        metric:          "dps".to_string(),
+        limit:           3,
+        game:            Game::Retail,
+    }
+}
+
+pub fn meta(entries: usize, available: usize) -> Step {
+    Step::Send(Box::new(StreamItem::Meta { encounter: Some("Imperator Averzian".to_string()), entries, available }))
+}
+
+pub fn progress(resolved: usize, total: usize) -> Step {
+    Step::Send(Box::new(StreamItem::Progress { resolved, total }))
+}
+
+/// A ranked player with talents, or with `failure` as why there are none.
+pub fn entry(rank: usize, name: &str, failure: Option<&str>) -> Step {
+    Step::Send(Box::new(StreamItem::Entry(talent_entry(rank, name, "Draenor", failure))))
+}
+
+/// A ranked player running `talents`.
+pub fn build(rank: usize, talents: &str) -> Step {
+    let mut entry = talent_entry(rank, "Player", "Draenor", None);
+    entry.data.talent_string = talents.to_string();
+    Step::Send(Box::new(StreamItem::Entry(entry)))
+}
+
+pub fn talent_entry(rank: usize, name: &str, realm: &str, failure: Option<&str>) -> TalentDataWithRank {
+    let data = serde_json::json!({
+        "name":              name,
+        "realm":             realm,
+        "region":            "EU",
+        "amount":            100_000.0 - rank as f64,
+        "talent_string":     if failure.is_some() { String::new() } else { format!("TALENTS{}", rank) },
+        "failure":           failure,
+        "log_url":           format!("https://www.warcraftlogs.com/reports/abc{}#fight=1", rank),
+        "fight_duration_ms": 300_000,
+        "cast_events":       [],
+    });
+    TalentDataWithRank { rank, data: serde_json::from_value(data).unwrap() }
+}
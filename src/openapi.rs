@@ -0,0 +1,447 @@
+use serde_json::{Value, json};
+
+use crate::config::{ClassSpecs, KEY_LEVELS, Settings};
+use crate::warcraftlogs::TIER_OVERALL;
+
+/// The OpenAPI 3 description of the HTTP API. Enumerated parameters are built
+/// from the same config the handlers validate against, so the document can't
+/// drift from what the server accepts.
+pub fn document(config: &ClassSpecs, settings: &Settings) -> Value {
+    let classes: Vec<&String> = config.classes.keys().collect();
+
+    let mut specs: Vec<&str> = config.classes.values().flat_map(|c| &c.specs).map(|s| s.name.as_str()).collect();
+    specs.sort();
+    specs.dedup();
+
+    let tiers = settings.tiers();
+    let zones: Vec<&str> = tiers.iter().map(|(id, _)| *id).collect();
+    let zone_names: Vec<String> = tiers.iter().map(|(id, tier)| format!("{} = {}", id, tier.name)).collect();
+    let encounters: Vec<_> = tiers.iter().flat_map(|(_, tier)| &tier.encounters).collect();
+    let mut encounter_ids: Vec<i32> = std::iter::once(TIER_OVERALL).chain(encounters.iter().map(|e| e.id)).collect();
+    encounter_ids.sort();
+    encounter_ids.dedup();
+    let encounter_names: Vec<String> = encounters
+        .iter()
+        .map(|e| format!("{} = {}", e.id, e.name))
+        .collect();
+    let zone_param = param("zone", false, json!({ "type": "string", "enum": zones }),
+        &format!("Raid tier: {}. Defaults to the current season ({}).", zone_names.join(", "), settings.current_season.id));
+
+    let game_param = param("game", false, json!({ "type": "string", "enum": ["retail", "classic"] }),
+        "`classic` for Classic, with its own classes, tiers, regions and modes (Normal and Heroic); the values listed here are retail's. Defaults to retail.");
+
+    let regions: Vec<&str> = settings.regions.iter().map(|r| r.code.as_str()).collect();
+    let modes: Vec<&str> = ClassSpecs::get_modes().iter().map(|m| m.name).collect();
+    let metrics = ClassSpecs::get_metrics();
+    let metric_codes: Vec<&str> = metrics.iter().map(|m| m.code).collect();
+    let metric_names: Vec<String> = metrics
+        .iter()
+        .map(|m| format!("{} = {}", m.code, m.name))
+        .collect();
+
+    let query_params = json!([
+        param("class", true, json!({ "type": "string", "enum": classes }),
+              "Class key; spaces are accepted in place of underscores."),
+        param("spec", true, json!({ "type": "string", "enum": specs }),
+              "Spec name, as listed for the class."),
+        param("encounter", true, json!({ "type": "integer", "enum": encounter_ids }),
+              &format!("Encounter ID from the selected tier: {}; or 0 for the whole tier, ranking players across its bosses (or dungeons for Mythic+).",
+                       encounter_names.join(", "))),
+        zone_param.clone(),
+        param("region", true, json!({ "type": "string", "enum": regions }),
+              "Region code; the aggregate region (`all` by default) queries every region."),
+        param("mode", true, json!({ "type": "string", "enum": modes }),
+              "Raid difficulty, or Mythic+ for dungeons; unknown values use the season default."),
+        param("metric", false, json!({ "type": "string", "enum": metric_codes }),
+              &format!("Ranking metric: {}. Defaults to playerscore for Mythic+, hps for healer specs and dps otherwise; playerscore is only for Mythic+.", metric_names.join(", "))),
+        param("bracket", false, json!({ "type": "integer" }),
+              &format!("Mythic+ only: the keystone level to rank runs at, clamped to {} to {}.",
+                       KEY_LEVELS.start(), KEY_LEVELS.end())),
+        param("nocache", false, json!({ "type": "integer", "enum": [1] }),
+              "Skip the result cache. Limited per client."),
+        param("allow_unknown_encounter", false, json!({ "type": "integer", "enum": [1] }),
+              "Allow encounter IDs outside the selected tier."),
+        game_param.clone(),
+    ]);
+
+    let errors = json!({
+        "400": error_response("Invalid selection or query rejected by WarcraftLogs"),
+        "429": error_response("Too many queries from this client; see Retry-After"),
+        "502": error_response("WarcraftLogs failed or returned something unexpected"),
+        "503": error_response("Rate limited, out of API points, or the server is restarting"),
+        "504": error_response("WarcraftLogs did not answer in time"),
+    });
+
+    let talents_op = |summary: &str, content: Value| {
+        let mut responses = errors.clone();
+        responses["200"] = json!({ "description": "OK", "content": content });
+        json!({ "get": {
+            "summary": summary,
+            "parameters": query_params,
+            "responses": responses,
+        }})
+    };
+
+    // A comparison takes the talent parameters plus the second spec.
+    let mut compare_params = query_params.clone();
+    if let Some(params) = compare_params.as_array_mut() {
+        params.push(param("class2", true, json!({ "type": "string", "enum": classes }),
+                          "Class key of the second spec."));
+        params.push(param("spec2", true, json!({ "type": "string", "enum": specs }),
+                          "Name of the second spec, as listed for `class2`."));
+    }
+    let mut compare_responses = errors.clone();
+    compare_responses["200"] = json!({
+        "description": "Both specs' entries interleaved, each event tagged with its side (1 or 2)",
+        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+    });
+
+    // A region comparison takes the talent parameters plus the second region.
+    let mut regions_params = query_params.clone();
+    if let Some(params) = regions_params.as_array_mut() {
+        params.push(param("region2", true, json!({ "type": "string", "enum": regions }),
+                          "Region code to compare with; must differ from `region`."));
+    }
+    let mut regions_responses = errors.clone();
+    regions_responses["200"] = json!({
+        "description": "One HTML section with both regions' most common builds and whether they match",
+        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+    });
+
+    let mut trends_responses = errors.clone();
+    trends_responses["200"] = json!({
+        "description": "One HTML section with the dominant build of each recorded day, or an error event when history is disabled",
+        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+    });
+
+    // The JSON trends take the talent parameters, with region and mode
+    // optional.
+    let trends_json_params: Vec<Value> = query_params
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|p| !matches!(p["name"].as_str(), Some("nocache" | "allow_unknown_encounter")))
+        .map(|p| match p["name"].as_str() {
+            Some("region") => param("region", false, p["schema"].clone(), "Region code; defaults to the aggregate region."),
+            Some("mode")   => param("mode", false, p["schema"].clone(), "Raid difficulty, or Mythic+; defaults to the season default."),
+            _              => p.clone(),
+        })
+        .collect();
+
+    let mut text_params = query_params.clone();
+    if let Some(params) = text_params.as_array_mut() {
+        params.push(param("unique", false, json!({ "type": "integer", "enum": [1] }),
+                          "Drop repeated strings."));
+    }
+
+    // The overview takes the talent parameters that don't pick a boss.
+    let overview_params: Vec<Value> = query_params
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|p| matches!(p["name"].as_str(), Some("class" | "spec" | "zone" | "region" | "mode" | "metric" | "game")))
+        .cloned()
+        .collect();
+    let snapshot_params: Vec<Value> = query_params
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|p| matches!(p["name"].as_str(), Some("encounter" | "zone" | "region" | "mode" | "metric" | "game")))
+        .cloned()
+        .collect();
+    let mut snapshot_responses = errors.clone();
+    snapshot_responses["200"] = json!({
+        "description": "One HTML section per class with each spec's most common build, in class order",
+        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+    });
+
+    let mut overview_responses = errors.clone();
+    overview_responses["200"] = json!({
+        "description": "One HTML section per boss with its most common build, in completion order",
+        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Talent Trends",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": "/api/v1" }],
+        "paths": {
+            "/talents": talents_op(
+                "Top talents, as server-sent events by default or per the Accept header",
+                json!({
+                    "text/event-stream": { "schema": { "type": "string" } },
+                    "application/json": { "schema": { "$ref": "#/components/schemas/TalentsDocument" } },
+                    "application/x-ndjson": { "schema": { "type": "string" } },
+                    "text/csv": { "schema": { "type": "string" } },
+                    "text/markdown": { "schema": { "type": "string" } },
+                    "text/plain": { "schema": { "type": "string" } },
+                }),
+            ),
+            "/talents.json": talents_op(
+                "Top talents as one JSON document",
+                json!({ "application/json": { "schema": { "$ref": "#/components/schemas/TalentsDocument" } } }),
+            ),
+            "/talents.ndjson": talents_op(
+                "Top talents, one JSON entry per line, then a summary line",
+                json!({ "application/x-ndjson": { "schema": { "type": "string" } } }),
+            ),
+            "/talents.csv": talents_op(
+                "Top talents as CSV",
+                json!({ "text/csv": { "schema": { "type": "string" } } }),
+            ),
+            "/talents.md": talents_op(
+                "Top talents as a Markdown table",
+                json!({ "text/markdown": { "schema": { "type": "string" } } }),
+            ),
+            "/talents.txt": {
+                "get": {
+                    "summary": "Talent import strings, one per line",
+                    "parameters": text_params,
+                    "responses": {
+                        "200": { "description": "OK", "content": { "text/plain": { "schema": { "type": "string" } } } },
+                    },
+                },
+            },
+            "/talents/compare": {
+                "get": {
+                    "summary": "Top talents of two specs side by side, as server-sent events",
+                    "parameters": compare_params,
+                    "responses": compare_responses,
+                },
+            },
+            "/talents/overview": {
+                "get": {
+                    "summary": "A spec's most common build on every boss of a tier, as server-sent events",
+                    "parameters": overview_params,
+                    "responses": overview_responses,
+                },
+            },
+            "/talents/regions": {
+                "get": {
+                    "summary": "A spec's most common build on one boss in two regions, as server-sent events",
+                    "parameters": regions_params,
+                    "responses": regions_responses,
+                },
+            },
+            "/talents/snapshot": {
+                "get": {
+                    "summary": "Every spec's most common build on one boss, as server-sent events; limited separately per client",
+                    "parameters": snapshot_params,
+                    "responses": snapshot_responses,
+                },
+            },
+            "/talents/trends": {
+                "get": {
+                    "summary": "The dominant build per day from recorded history, as server-sent events",
+                    "parameters": query_params,
+                    "responses": trends_responses,
+                },
+            },
+            "/trends": {
+                "get": {
+                    "summary": "The dominant build per recorded day as JSON, with the days it changed",
+                    "parameters": trends_json_params,
+                    "responses": {
+                        "200": { "description": "OK", "content": { "application/json": { "schema": {
+                            "$ref": "#/components/schemas/TrendsDocument",
+                        } } } },
+                        "400": error_response("Invalid selection"),
+                        "501": error_response("Build history is not enabled on this server"),
+                    },
+                },
+            },
+            "/stats": {
+                "get": {
+                    "summary": "Last observed WarcraftLogs API point usage, latency percentiles and open streams",
+                    "responses": {
+                        "200": { "description": "OK", "content": { "application/json": { "schema": {
+                            "$ref": "#/components/schemas/Stats",
+                        } } } },
+                    },
+                },
+            },
+            "/classes": {
+                "get": {
+                    "summary": "Classes with their display names, colors and specs",
+                    "parameters": [game_param.clone()],
+                    "responses": {
+                        "200": { "description": "OK", "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/Class" },
+                        } } } },
+                        "304": { "description": "Unchanged since the ETag sent in If-None-Match" },
+                    },
+                },
+            },
+            "/encounters": {
+                "get": {
+                    "summary": "A raid tier's encounters, in the order the form shows them",
+                    "parameters": [zone_param, game_param.clone()],
+                    "responses": {
+                        "200": { "description": "OK", "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/Encounter" },
+                        } } } },
+                        "304": { "description": "Unchanged since the ETag sent in If-None-Match" },
+                        "400": error_response("Unknown zone"),
+                    },
+                },
+            },
+            "/regions": {
+                "get": {
+                    "summary": "Region codes and names",
+                    "parameters": [game_param],
+                    "responses": {
+                        "200": { "description": "OK", "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/Region" },
+                        } } } },
+                        "304": { "description": "Unchanged since the ETag sent in If-None-Match" },
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": { "200": { "description": "OK" } },
+                },
+            },
+        },
+        "components": { "schemas": {
+            "TrendsDocument": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "object" },
+                    "series": { "type": "array", "description": "One point per UTC day with snapshots, oldest first", "items": {
+                        "type": "object",
+                        "properties": {
+                            "date": { "type": "string", "format": "date" },
+                            "talent_string": { "type": "string", "description": "The day's most common build" },
+                            "players": { "type": "integer" },
+                            "considered": { "type": "integer", "description": "Top players with an import string" },
+                            "share": { "type": "number", "description": "players / considered" },
+                            "changed": { "type": "boolean", "description": "Differs from the previous day's build" },
+                        },
+                    } },
+                    "changes": { "type": "array", "description": "Days the build changed; empty with fewer than two days", "items": {
+                        "type": "object",
+                        "properties": {
+                            "date": { "type": "string", "format": "date" },
+                            "from": { "type": "string" },
+                            "to": { "type": "string" },
+                        },
+                    } },
+                },
+            },
+            "TalentsDocument": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "object" },
+                    "encounter": { "type": "string", "nullable": true },
+                    "generated_at": { "type": "integer", "description": "Unix timestamp" },
+                    "cached": { "type": "boolean" },
+                    "available": { "type": "integer", "description": "Named ranked kills in total" },
+                    "entries": { "type": "array", "items": { "$ref": "#/components/schemas/Entry" } },
+                },
+            },
+            "Entry": {
+                "type": "object",
+                "properties": {
+                    "rank": { "type": "integer" },
+                    "name": { "type": "string" },
+                    "guild": { "type": "string" },
+                    "realm": { "type": "string" },
+                    "region": { "type": "string" },
+                    "amount": { "type": "number" },
+                    "talent_string": { "type": "string" },
+                    "talent_reconstructed": { "type": "boolean" },
+                    "failure": { "type": "string", "description": "Set when the talents couldn't be fetched" },
+                    "log_url": { "type": "string" },
+                    "fight_duration_ms": { "type": "integer" },
+                    "cast_events": { "type": "array", "items": { "type": "object" } },
+                },
+            },
+            "Class": {
+                "type": "object",
+                "properties": {
+                    "key": { "type": "string", "enum": classes },
+                    "name": { "type": "string" },
+                    "color": { "type": "array", "items": { "type": "string" } },
+                    "pretty_color": { "type": "array", "items": { "type": "string" } },
+                    "specs": { "type": "array", "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "wcl_name": { "type": "string", "description": "The spec's name on WarcraftLogs; also accepted as `spec`" },
+                            "role": { "type": "string", "enum": ["dps", "healer", "tank"], "nullable": true },
+                        },
+                    } },
+                },
+            },
+            "Encounter": {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "integer" },
+                    "name": { "type": "string" },
+                    "raid": { "type": "string", "nullable": true },
+                    "dungeon": { "type": "boolean", "description": "A Mythic+ dungeon, queried with the Mythic+ mode" },
+                    "order": { "type": "integer" },
+                    "season": { "type": "string" },
+                    "season_name": { "type": "string" },
+                },
+            },
+            "Region": {
+                "type": "object",
+                "properties": {
+                    "code": { "type": "string", "enum": regions },
+                    "name": { "type": "string" },
+                    "aggregate": { "type": "boolean", "description": "True for `all`, which covers every region" },
+                },
+            },
+            "Stats": {
+                "type": "object",
+                "properties": {
+                    "rate_limit": { "type": "object", "nullable": true },
+                    "latency": { "type": "array", "items": { "type": "object" } },
+                    "streams": {
+                        "type": "object",
+                        "description": "Event streams open now, and the most allowed at once",
+                        "properties": { "open": { "type": "integer" }, "max": { "type": "integer" } },
+                    },
+                },
+            },
+            "Error": {
+                "type": "object",
+                "properties": {
+                    "error": {
+                        "type": "object",
+                        "properties": {
+                            "code": { "type": "string" },
+                            "message": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        }},
+    })
+}
+
+fn param(name: &str, required: bool, schema: Value, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": required,
+        "schema": schema,
+        "description": description,
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } },
+    })
+}
@@ -0,0 +1,120 @@
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::config::{env_or, minutes_or};
+use crate::warcraftlogs::{BudgetDecision, unix_now};
+use crate::{AppState, PreparedQuery, collect_talents, prepare_query, shutdown};
+
+/// UTC hours of the day prefetching runs in, as `start-end` with `end`
+/// excluded, e.g. `2-8`, or `22-6` across midnight.
+#[derive(Debug, Clone, Copy)]
+struct Hours {
+    start: u64,
+    end:   u64,
+}
+
+impl Hours {
+    const ALL_DAY: Self = Self { start: 0, end: 24 };
+
+    fn contains(&self, hour: u64) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl FromStr for Hours {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (start, end) = s.split_once('-').ok_or(())?;
+        let start: u64 = start.trim().parse().map_err(|_| ())?;
+        let end: u64 = end.trim().parse().map_err(|_| ())?;
+        if start > 23 || end > 24 || start == end {
+            return Err(());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl std::fmt::Display for Hours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:00-{:02}:00 UTC", self.start, self.end)
+    }
+}
+
+lazy_static::lazy_static! {
+    /// `true` leaves the `[[prefetch]]` list alone, e.g. while the API budget
+    /// is needed elsewhere.
+    static ref PAUSED: bool = env_or("PREFETCH_PAUSED", false);
+    static ref HOURS: Hours = env_or("PREFETCH_HOURS", Hours::ALL_DAY);
+    static ref INTERVAL: Duration = minutes_or("PREFETCH_INTERVAL_MINS", 15);
+    /// Pause between two prefetched queries, so a round doesn't spend the
+    /// API budget in one burst.
+    static ref SPACING: Duration = Duration::from_secs(env_or("PREFETCH_SPACING_SECS", 30));
+}
+
+/// Refetch every `[[prefetch]]` query past the cache, once per interval
+/// within the configured hours, so the cache holds fresh results when users
+/// ask. Queries are spaced out, and a round stops early once the API budget
+/// is low enough that user queries would get fewer entries. Runs until
+/// shutdown.
+pub async fn run(state: AppState) {
+    let queries = &state.settings.prefetch;
+    if *PAUSED {
+        tracing::info!("Prefetch paused by PREFETCH_PAUSED; {} queries not prefetched", queries.len());
+        return;
+    }
+    tracing::info!("Prefetching {} queries every {:?} within {}", queries.len(), *INTERVAL, *HOURS);
+
+    let mut interval = tokio::time::interval(*INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let shutdown = shutdown::token();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        let hour = unix_now() / 3_600 % 24;
+        if !HOURS.contains(hour) {
+            tracing::debug!("Prefetch skipped, outside {}", *HOURS);
+            continue;
+        }
+
+        let round = Instant::now();
+        let mut fetched = 0;
+        for (i, query) in queries.iter().enumerate() {
+            if i > 0 {
+                tokio::select! {
+                    _ = tokio::time::sleep(*SPACING) => {}
+                    _ = shutdown.cancelled() => return,
+                }
+            }
+            if state.wcl.budget_decision().await != BudgetDecision::Full {
+                tracing::info!("Prefetch round stopped after {} queries, API budget is low", fetched);
+                break;
+            }
+
+            // The address only matters for `nocache`, which isn't used here.
+            let prepared = match prepare_query(&state, Ipv4Addr::LOCALHOST.into(), query.into()) {
+                Ok(prepared) => PreparedQuery { bypass_cache: true, ..prepared },
+                Err(e) => {
+                    tracing::warn!("Skipping prefetch of {} {}: {}", query.spec, query.class, e);
+                    continue;
+                }
+            };
+            let started = Instant::now();
+            match collect_talents(&state, &prepared).await {
+                Ok(_) => {
+                    fetched += 1;
+                    tracing::info!("Prefetched {} in {:?}", prepared.selection, started.elapsed());
+                }
+                Err(e) => tracing::warn!("Prefetch of {} failed: {:#}", prepared.selection, e),
+            }
+        }
+        tracing::info!("Prefetch round done: {}/{} queries in {:?}", fetched, queries.len(), round.elapsed());
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Past this many tracked clients, full buckets are dropped; a missing
+/// bucket is the same as a full one.
+const MAX_TRACKED: usize = 10_000;
+
+/// A token bucket per client: `per_minute` queries refill evenly over the
+/// minute, and up to that many can be spent at once.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    capacity: f64,
+    refill_per_sec: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// `per_minute` of 0 disables the limit.
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            capacity: per_minute as f64,
+            refill_per_sec: per_minute as f64 / 60.0,
+        }
+    }
+
+    /// Spend one query for `ip`, or say how long until it may run another.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        if self.capacity == 0.0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&ip) {
+            buckets.retain(|_, b| self.refilled(b, now) < self.capacity);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: self.capacity, updated: now });
+        bucket.tokens  = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_up_to_the_limit_then_wait() {
+        let limiter = RateLimiter::new(6);
+        for _ in 0..6 {
+            assert_eq!(limiter.check(A), Ok(()));
+        }
+        // 6 a minute refill one every 10s.
+        assert_eq!(limiter.check(A), Err(Duration::from_secs(10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_over_time() {
+        let limiter = RateLimiter::new(6);
+        for _ in 0..6 {
+            limiter.check(A).unwrap();
+        }
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let wait = limiter.check(A).unwrap_err();
+        assert!((wait.as_secs_f64() - 6.0).abs() < 0.01, "{:?}", wait);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert_eq!(limiter.check(A), Ok(()));
+        assert!(limiter.check(A).is_err());
+
+        // Never past the limit, however long it has been.
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        for _ in 0..6 {
+            assert_eq!(limiter.check(A), Ok(()));
+        }
+        assert!(limiter.check(A).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_have_their_own_buckets() {
+        let limiter = RateLimiter::new(2);
+        limiter.check(A).unwrap();
+        limiter.check(A).unwrap();
+        assert!(limiter.check(A).is_err());
+
+        assert_eq!(limiter.check(B), Ok(()));
+        assert_eq!(limiter.check(B), Ok(()));
+        assert!(limiter.check(B).is_err());
+    }
+
+    #[test]
+    fn zero_disables_the_limit() {
+        let limiter = RateLimiter::new(0);
+        for _ in 0..1000 {
+            assert_eq!(limiter.check(A), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::env_or;
+
+lazy_static::lazy_static! {
+    static ref TOKEN: CancellationToken = CancellationToken::new();
+    /// How long open streams get to finish after SIGTERM/SIGINT before they
+    /// are ended with a `server_restarting` error.
+    pub static ref GRACE: Duration = Duration::from_secs(env_or("SHUTDOWN_GRACE_SECS", 30));
+}
+
+#[cfg(test)]
+tokio::task_local! {
+    /// Stands in for the process's token in a test task, so a test can shut
+    /// down its own streams without ending every other test's.
+    pub static SCOPED: CancellationToken;
+}
+
+/// Cancelled once the process has been asked to stop.
+pub fn token() -> CancellationToken {
+    #[cfg(test)]
+    if let Ok(token) = SCOPED.try_with(CancellationToken::clone) {
+        return token;
+    }
+    TOKEN.clone()
+}
+
+/// Wait for SIGTERM or Ctrl-C, then cancel the shutdown token. Passed to
+/// `with_graceful_shutdown`, so new connections stop being accepted as soon
+/// as this returns.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c    => {}
+        _ = terminate => {}
+    }
+
+    tracing::info!("Shutting down, giving open streams {:?} to finish", *GRACE);
+    TOKEN.cancel();
+}
+
+/// Resolves once shutdown has begun and the grace period has run out.
+pub async fn drained() {
+    token().cancelled().await;
+    tokio::time::sleep(*GRACE).await;
+}
+
+/// The stream was still running when the shutdown grace period ran out.
+#[derive(Debug)]
+pub struct ServerRestarting;
+
+impl std::fmt::Display for ServerRestarting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The server is restarting, please try again in a moment")
+    }
+}
+
+impl std::error::Error for ServerRestarting {}
@@ -0,0 +1,493 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::warcraftlogs::{RankingsQuery, StreamItem, unix_now};
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records how
+/// many have run, so append new steps rather than editing old ones.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE talent_results (
+        query_key  TEXT PRIMARY KEY,
+        fetched_at INTEGER NOT NULL,
+        entries    TEXT NOT NULL
+    )",
+    // Stored results now include the count item; drop rows in the old shape.
+    "DELETE FROM talent_results",
+    "CREATE TABLE build_snapshots (
+        query_key  TEXT NOT NULL,
+        taken_at   INTEGER NOT NULL,
+        considered INTEGER NOT NULL,
+        builds     TEXT NOT NULL
+    );
+    CREATE INDEX build_snapshots_by_query ON build_snapshots (query_key, taken_at)",
+    "CREATE TABLE watched_builds (
+        watch_key     TEXT PRIMARY KEY,
+        talent_string TEXT NOT NULL,
+        updated_at    INTEGER NOT NULL
+    )",
+    "CREATE TABLE build_changes (
+        id          INTEGER PRIMARY KEY,
+        query_key   TEXT NOT NULL,
+        detected_at INTEGER NOT NULL,
+        selection   TEXT NOT NULL,
+        previous    TEXT NOT NULL,
+        current     TEXT NOT NULL,
+        player      TEXT NOT NULL,
+        log_url     TEXT NOT NULL
+    );
+    CREATE INDEX build_changes_by_query ON build_changes (query_key, id)",
+    // Watch keys held the webhook URL, secret token and all; they now hold a
+    // hash of it. The next check records each watch's build afresh.
+    "DELETE FROM watched_builds",
+];
+
+/// How many top players ran one build in a snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildCount {
+    pub talent_string: String,
+    pub players:       usize,
+}
+
+/// A watched query's most common build changing, as the feed lists it.
+#[derive(Debug, Clone)]
+pub struct BuildChange {
+    pub id:          i64,
+    pub detected_at: u64,
+    /// "<spec> <class> on <boss> in <region>".
+    pub selection:   String,
+    pub previous:    String,
+    pub current:     String,
+    /// Rank and name of the best-ranked player with the new build, whose log
+    /// is linked.
+    pub player:      String,
+    pub log_url:     String,
+}
+
+/// The builds of a query's top players at one point in time, most common
+/// first. `considered` is how many entries had an import string at all.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub taken_at:   u64,
+    pub considered: usize,
+    pub builds:     Vec<BuildCount>,
+}
+
+impl Snapshot {
+    /// The distinct builds among a fetch's entries. Failed and reconstructed
+    /// entries have no import string and are left out. Ties keep the order
+    /// of each build's best-ranked player.
+    fn of(items: &[StreamItem]) -> (usize, Vec<BuildCount>) {
+        let mut builds: Vec<BuildCount> = Vec::new();
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        let mut considered = 0;
+        for item in items {
+            let StreamItem::Entry(entry) = item else { continue };
+            let data = &entry.data;
+            if data.failure.is_some() || data.talent_reconstructed || data.talent_string.is_empty() {
+                continue;
+            }
+            considered += 1;
+            match index.get(data.talent_string.as_str()) {
+                Some(&i) => builds[i].players += 1,
+                None => {
+                    index.insert(&data.talent_string, builds.len());
+                    builds.push(BuildCount { talent_string: data.talent_string.clone(), players: 1 });
+                }
+            }
+        }
+        // Stable, so ties stay in rank order.
+        builds.sort_by_key(|build| std::cmp::Reverse(build.players));
+        (considered, builds)
+    }
+}
+
+/// Optional SQLite persistence behind the in-memory caches, so a restart
+/// doesn't throw away everything fetched so far, and the history of builds
+/// that trends are drawn from. Enabled by `CACHE_DB_PATH`.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// The store named by `CACHE_DB_PATH`, migrated, or `None` when the variable
+/// is unset and persistence stays disabled. Called once at startup.
+pub fn from_env() -> Result<Option<Store>> {
+    let Ok(path) = std::env::var("CACHE_DB_PATH") else {
+        return Ok(None);
+    };
+    let store = Store::open(&path)?;
+    tracing::info!("Persistent cache enabled at {}", path);
+    Ok(Some(store))
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path).with_context(|| format!("Failed to open {}", path))?;
+        migrate(&conn).context("Failed to migrate cache database")?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Stored entries for a query and their age, if younger than `ttl`.
+    pub async fn load_results(
+        &self,
+        query: &RankingsQuery,
+        ttl: Duration,
+    ) -> Result<Option<(Vec<StreamItem>, Duration)>> {
+        let key  = serde_json::to_string(query)?;
+        let conn = self.conn.clone();
+
+        let row: Option<(i64, String)> = tokio::task::spawn_blocking(move || {
+            conn.lock().unwrap()
+                .query_row(
+                    "SELECT fetched_at, entries FROM talent_results WHERE query_key = ?1",
+                    params![key],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+        })
+        .await??;
+
+        let Some((fetched_at, entries)) = row else { return Ok(None) };
+
+        let age = Duration::from_secs(unix_now().saturating_sub(fetched_at as u64));
+        if age >= ttl {
+            return Ok(None);
+        }
+
+        let entries = serde_json::from_str(&entries).context("Corrupt cached entries")?;
+        Ok(Some((entries, age)))
+    }
+
+    pub async fn save_results(&self, query: &RankingsQuery, entries: &[StreamItem]) -> Result<()> {
+        let key     = serde_json::to_string(query)?;
+        let entries = serde_json::to_string(entries)?;
+        let conn    = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            conn.lock().unwrap().execute(
+                "INSERT INTO talent_results (query_key, fetched_at, entries) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(query_key) DO UPDATE SET fetched_at = excluded.fetched_at, entries = excluded.entries",
+                params![key, unix_now() as i64, entries],
+            )
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// Delete the stored results of every matching query; returns how many.
+    /// Keys that no longer parse as a query are left alone.
+    pub async fn delete_results(&self, matches: impl Fn(&RankingsQuery) -> bool) -> Result<usize> {
+        let conn = self.conn.clone();
+        let keys: Vec<String> = tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            let mut statement = conn.prepare("SELECT query_key FROM talent_results")?;
+            let rows = statement.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })
+        .await??;
+
+        let doomed: Vec<String> = keys
+            .into_iter()
+            .filter(|key| serde_json::from_str(key).is_ok_and(|query| matches(&query)))
+            .collect();
+        if doomed.is_empty() {
+            return Ok(0);
+        }
+
+        let conn = self.conn.clone();
+        let deleted = tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            let mut deleted = 0;
+            for key in &doomed {
+                deleted += tx.execute("DELETE FROM talent_results WHERE query_key = ?1", params![key])?;
+            }
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(deleted)
+        })
+        .await??;
+
+        Ok(deleted)
+    }
+
+    /// Record the builds of a fresh fetch for the query's history. Fetches
+    /// without a single usable import string are not recorded.
+    pub async fn save_snapshot(&self, query: &RankingsQuery, items: &[StreamItem]) -> Result<()> {
+        let (considered, builds) = Snapshot::of(items);
+        if builds.is_empty() {
+            return Ok(());
+        }
+        let key    = serde_json::to_string(query)?;
+        let builds = serde_json::to_string(&builds)?;
+        let conn   = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            conn.lock().unwrap().execute(
+                "INSERT INTO build_snapshots (query_key, taken_at, considered, builds) VALUES (?1, ?2, ?3, ?4)",
+                params![key, unix_now() as i64, considered as i64, builds],
+            )
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// Every recorded snapshot of a query, oldest first.
+    pub async fn load_snapshots(&self, query: &RankingsQuery) -> Result<Vec<Snapshot>> {
+        let key  = serde_json::to_string(query)?;
+        let conn = self.conn.clone();
+
+        let rows: Vec<(i64, i64, String)> = tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            let mut statement = conn.prepare(
+                "SELECT taken_at, considered, builds FROM build_snapshots WHERE query_key = ?1 ORDER BY taken_at",
+            )?;
+            let rows = statement.query_map(params![key], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await??;
+
+        rows.into_iter()
+            .map(|(taken_at, considered, builds)| {
+                Ok(Snapshot {
+                    taken_at:   taken_at as u64,
+                    considered: considered as usize,
+                    builds:     serde_json::from_str(&builds).context("Corrupt build snapshot")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Delete snapshots taken longer than `retention` ago; returns how many.
+    pub async fn prune_snapshots(&self, retention: Duration) -> Result<usize> {
+        let cutoff = unix_now().saturating_sub(retention.as_secs()) as i64;
+        let conn   = self.conn.clone();
+
+        let deleted = tokio::task::spawn_blocking(move || {
+            conn.lock().unwrap().execute("DELETE FROM build_snapshots WHERE taken_at < ?1", params![cutoff])
+        })
+        .await??;
+
+        Ok(deleted)
+    }
+
+    /// The build last seen, and notified if it changed, for a watch.
+    pub async fn load_watched_build(&self, key: &str) -> Result<Option<String>> {
+        let key  = key.to_string();
+        let conn = self.conn.clone();
+
+        let build = tokio::task::spawn_blocking(move || {
+            conn.lock().unwrap()
+                .query_row(
+                    "SELECT talent_string FROM watched_builds WHERE watch_key = ?1",
+                    params![key],
+                    |row| row.get(0),
+                )
+                .optional()
+        })
+        .await??;
+
+        Ok(build)
+    }
+
+    /// Add a change to the history, unless the query's last recorded change
+    /// was already to this build, as when several watches share a query or a
+    /// change is seen again after its webhook failed. `id` and `detected_at`
+    /// are assigned here.
+    pub async fn record_change(&self, query: &RankingsQuery, change: BuildChange) -> Result<()> {
+        let key  = serde_json::to_string(query)?;
+        let conn = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            let last: Option<String> = conn
+                .query_row(
+                    "SELECT current FROM build_changes WHERE query_key = ?1 ORDER BY id DESC LIMIT 1",
+                    params![key],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if last.as_deref() == Some(change.current.as_str()) {
+                return Ok(0);
+            }
+            conn.execute(
+                "INSERT INTO build_changes (query_key, detected_at, selection, previous, current, player, log_url)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![key, unix_now() as i64, change.selection, change.previous, change.current, change.player, change.log_url],
+            )
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// The latest `limit` changes of every watched query, newest first.
+    pub async fn recent_changes(&self, limit: usize) -> Result<Vec<BuildChange>> {
+        let conn = self.conn.clone();
+
+        let changes = tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            let mut statement = conn.prepare(
+                "SELECT id, detected_at, selection, previous, current, player, log_url
+                 FROM build_changes ORDER BY id DESC LIMIT ?1",
+            )?;
+            let rows = statement.query_map(params![limit as i64], |row| {
+                Ok(BuildChange {
+                    id:          row.get(0)?,
+                    detected_at: row.get::<_, i64>(1)? as u64,
+                    selection:   row.get(2)?,
+                    previous:    row.get(3)?,
+                    current:     row.get(4)?,
+                    player:      row.get(5)?,
+                    log_url:     row.get(6)?,
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await??;
+
+        Ok(changes)
+    }
+
+    pub async fn save_watched_build(&self, key: &str, talent_string: &str) -> Result<()> {
+        let (key, talent_string) = (key.to_string(), talent_string.to_string());
+        let conn = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            conn.lock().unwrap().execute(
+                "INSERT INTO watched_builds (watch_key, talent_string, updated_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(watch_key) DO UPDATE SET talent_string = excluded.talent_string, updated_at = excluded.updated_at",
+                params![key, talent_string, unix_now() as i64],
+            )
+        })
+        .await??;
+
+        Ok(())
+    }
+}
+
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!("Applying cache database migration {}", i + 1);
+        conn.execute_batch(step)?;
+        conn.pragma_update(None, "user_version", i + 1)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_wcl::{query, talent_entry};
+
+    fn store() -> Store {
+        Store::open(":memory:").unwrap()
+    }
+
+    /// An entry at `rank` with build `talents`.
+    fn build(rank: usize, talents: &str) -> StreamItem {
+        let mut entry = talent_entry(rank, "Player", "Draenor", None);
+        entry.data.talent_string = talents.to_string();
+        StreamItem::Entry(entry)
+    }
+
+    fn change(current: &str) -> BuildChange {
+        BuildChange {
+            id:          0,
+            detected_at: 0,
+            selection:   "Frost Mage on Imperator Averzian in All Regions".to_string(),
+            previous:    "OLD".to_string(),
+            current:     current.to_string(),
+            player:      "#1 Player".to_string(),
+            log_url:     "https://www.warcraftlogs.com/reports/abc#fight=1".to_string(),
+        }
+    }
+
+    #[test]
+    fn snapshot_counts_builds_most_common_first_ties_in_rank_order() {
+        let mut failed = talent_entry(2, "Failed", "Draenor", Some("No talentImportCode for this fight"));
+        failed.data.talent_string = "A".to_string();
+        let mut reconstructed = talent_entry(3, "Rebuilt", "Draenor", None);
+        reconstructed.data.talent_reconstructed = true;
+        let items = [
+            StreamItem::Meta { encounter: None, entries: 7, available: 7 },
+            build(1, "B"),
+            StreamItem::Entry(failed),
+            StreamItem::Entry(reconstructed),
+            build(4, "C"),
+            build(5, "A"),
+            build(6, "C"),
+            build(7, "A"),
+        ];
+        let (considered, builds) = Snapshot::of(&items);
+        assert_eq!(considered, 5);
+        let builds: Vec<(&str, usize)> = builds.iter().map(|b| (b.talent_string.as_str(), b.players)).collect();
+        // C and A tie; C's best player ranks higher.
+        assert_eq!(builds, [("C", 2), ("A", 2), ("B", 1)]);
+    }
+
+    #[tokio::test]
+    async fn results_round_trip_until_they_expire() {
+        let store = store();
+        let items = vec![StreamItem::Meta { encounter: Some("Boss".to_string()), entries: 1, available: 1 }, build(1, "A")];
+        store.save_results(&query(), &items).await.unwrap();
+
+        let (loaded, age) = store.load_results(&query(), Duration::from_secs(60)).await.unwrap().unwrap();
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), serde_json::to_string(&items).unwrap());
+        assert!(age < Duration::from_secs(60));
+
+        assert!(store.load_results(&query(), Duration::ZERO).await.unwrap().is_none());
+        let other = RankingsQuery { spec: "Fire".to_string(), ..query() };
+        assert!(store.load_results(&other, Duration::from_secs(60)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn prune_snapshots_keeps_recent_ones() {
+        let store = store();
+        store.save_snapshot(&query(), &[build(1, "NEW")]).await.unwrap();
+        // Fetches without a usable build aren't recorded at all.
+        store.save_snapshot(&query(), &[StreamItem::Meta { encounter: None, entries: 0, available: 0 }]).await.unwrap();
+        {
+            let key = serde_json::to_string(&query()).unwrap();
+            let old = (unix_now() - 10 * 24 * 60 * 60) as i64;
+            store.conn.lock().unwrap().execute(
+                "INSERT INTO build_snapshots (query_key, taken_at, considered, builds) VALUES (?1, ?2, 1, ?3)",
+                params![key, old, r#"[{"talent_string":"OLD","players":1}]"#],
+            ).unwrap();
+        }
+        assert_eq!(store.load_snapshots(&query()).await.unwrap().len(), 2);
+
+        let pruned = store.prune_snapshots(Duration::from_secs(7 * 24 * 60 * 60)).await.unwrap();
+        assert_eq!(pruned, 1);
+        let left = store.load_snapshots(&query()).await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].builds[0].talent_string, "NEW");
+    }
+
+    #[tokio::test]
+    async fn record_change_skips_a_repeat_of_the_last_change() {
+        let store = store();
+        store.record_change(&query(), change("A")).await.unwrap();
+        store.record_change(&query(), change("A")).await.unwrap();
+        store.record_change(&query(), change("B")).await.unwrap();
+        // Back to an earlier build is a change of its own.
+        store.record_change(&query(), change("A")).await.unwrap();
+        // Another query's history is separate.
+        let other = RankingsQuery { spec: "Fire".to_string(), ..query() };
+        store.record_change(&other, change("A")).await.unwrap();
+
+        let changes = store.recent_changes(10).await.unwrap();
+        let current: Vec<&str> = changes.iter().map(|c| c.current.as_str()).collect();
+        assert_eq!(current, ["A", "A", "B", "A"]);
+        assert!(changes.windows(2).all(|w| w[0].id > w[1].id));
+        assert_eq!(store.recent_changes(1).await.unwrap().len(), 1);
+    }
+}
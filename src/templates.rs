@@ -1,299 +1,582 @@
-use crate::config::{ClassSpecs, Settings};
-use crate::style;
-use crate::warcraftlogs::TalentDataWithRank;
-
-pub fn render_talent_entry(data: &TalentDataWithRank) -> String {
-    let talent_string = &data.data.talent_string;
-
-    let cast_json = serde_json::to_string(&data.data.cast_events)
-        .unwrap_or_else(|_| "[]".to_string())
-        .replace('&', "&amp;")
-        .replace('"', "&quot;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;");
-
-    format!(
-        r#"<div class="talent-entry" id="talent-entry-{rank}">
-            <h3># {rank} - {name}</h3>
-            <div class="talent-string">{talent_string}</div>
-
-            <a href="{log_url}" target="_blank" rel="noopener">View Log →</a>
-
-            <div class="entry-buttons">
-                <button class="btn-secondary toggle-iframe-btn">
-                    Show Talent Calculator
-                </button>
-                <button class="btn-secondary toggle-timeline-btn" data-rank="{rank}">
-                    Show Timeline
-                </button>
-            </div>
-
-            <div class="iframe-container" style="display:none; margin-top:12px; overflow:hidden;">
-                <iframe src="https://www.wowhead.com/talent-calc/embed/blizzard/{talent_string}"
-                    width="100%" height="580"
-                    style="border:1px solid #444; border-radius:6px; display:block; min-width:980px;"></iframe>
-            </div>
-
-            <div class="cast-timeline"
-                 id="cast-timeline-{rank}"
-                 style="display:none;"
-                 data-duration="{fight_duration_ms}"
-                 data-events="{cast_json}">
-            </div>
-        </div>"#,
-        rank              = data.rank,
-        name              = data.data.name,
-        talent_string     = talent_string,
-        log_url           = data.data.log_url,
-        fight_duration_ms = data.data.fight_duration_ms,
-        cast_json         = cast_json,
-    )
-}
-
-pub fn home(config: &ClassSpecs) -> String {
-    let settings = Settings::load();
-
-    let class_options: String = config
-        .classes
-        .iter()
-        .map(|(name, _)| {
-            let display_name = name.replace('_', " ");
-            format!(r#"<option value="{}">{}</option>"#, name, display_name)
-        })
-        .collect::<Vec<_>>()
-        .join("\n                ");
+use askama::Template;
+use std::collections::BTreeMap;
+
+use crate::config::{ClassSpecs, Game, Mode, Region, Settings};
+use crate::store::BuildChange;
+use crate::warcraftlogs::{MAX_ENTRIES, TalentDataWithRank};
+
+/// Quote a CSV field when it contains a delimiter, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
 
-    let encounter_options: String = settings
-        .current_encounters()
-        .iter()
-        .map(|enc| format!(r#"<option value="{}">{}</option>"#, enc.id, enc.name))
-        .collect::<Vec<_>>()
-        .join("\n                ");
+/// Results as CSV, one row per entry. `metric` names the amount column.
+pub fn render_csv(entries: &[TalentDataWithRank], metric: &str) -> String {
+    let mut csv = format!("rank,player,guild,realm,region,{},talent_string,log_url\r\n", csv_field(metric));
+    for entry in entries {
+        let data = &entry.data;
+        let row = [
+            entry.rank.to_string(),
+            data.name.clone(),
+            data.guild.clone().unwrap_or_default(),
+            data.realm.clone().unwrap_or_default(),
+            data.region.clone().unwrap_or_default(),
+            format!("{:.1}", data.amount),
+            data.talent_string.clone(),
+            data.log_url.clone(),
+        ];
+        let row: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
 
-    let region_options: String = ClassSpecs::get_regions()
-        .iter()
-        .map(|reg| format!(r#"<option value="{}">{}</option>"#, reg.code, reg.name))
-        .collect::<Vec<_>>()
-        .join("\n                ");
+/// Escape text for a Markdown table cell or link label.
+fn markdown_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '|' | '[' | ']' | '*' | '_' | '`' | '\\' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// `YYYY-MM-DD` (UTC) for a Unix timestamp.
+pub fn utc_date(unix_secs: u64) -> String {
+    // Civil-from-days, after Howard Hinnant's date algorithms.
+    let z   = (unix_secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp  = (5 * doy + 2) / 153;
+    let day   = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year  = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// RFC 3339 UTC time (`YYYY-MM-DDTHH:MM:SSZ`) for a Unix timestamp.
+pub fn utc_timestamp(unix_secs: u64) -> String {
+    let secs = unix_secs % 86_400;
+    format!("{}T{:02}:{:02}:{:02}Z", utc_date(unix_secs), secs / 3_600, secs / 60 % 60, secs % 60)
+}
+
+/// Results as a Markdown table for pasting into Discord or forums. Talent
+/// strings are kept whole in code spans so they can be copied as-is.
+pub fn render_markdown(heading: &str, entries: &[TalentDataWithRank]) -> String {
+    let mut md = format!("**{}**\n\n| Rank | Player | Talents |\n|---:|---|---|\n", markdown_text(heading));
+    for entry in entries {
+        let data    = &entry.data;
+        let talents = match &data.failure {
+            Some(reason) => format!("_{}_", markdown_text(reason)),
+            None         => format!("`{}`", data.talent_string.replace('`', "'").replace('|', "\\|")),
+        };
+        md.push_str(&format!(
+            "| {} | [{}]({}) | {} |\n",
+            entry.rank,
+            markdown_text(&data.name),
+            data.log_url,
+            talents,
+        ));
+    }
+    md
+}
+
+/// Just the import strings, one per line with a trailing newline. Failed and
+/// reconstructed entries have no import string and are skipped; `unique`
+/// drops repeats, keeping the best-ranked occurrence.
+pub fn render_text(entries: &[TalentDataWithRank], unique: bool) -> String {
+    let mut seen = std::collections::HashSet::new();
+    let mut text = String::new();
+    for entry in entries {
+        let data = &entry.data;
+        if data.failure.is_some() || data.talent_reconstructed || data.talent_string.is_empty() {
+            continue;
+        }
+        if unique && !seen.insert(data.talent_string.as_str()) {
+            continue;
+        }
+        text.push_str(&data.talent_string);
+        text.push('\n');
+    }
+    text
+}
+
+/// Render a template. Templates only fail on a formatting error from one of
+/// their values, which would be a bug; that is logged and the piece left out
+/// rather than failing the whole response.
+fn render(template: &impl Template) -> String {
+    template.render().unwrap_or_else(|e| {
+        tracing::error!("Failed to render {}: {}", std::any::type_name_of_val(template), e);
+        String::new()
+    })
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate<'a> {
+    message: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "notice.html")]
+struct NoticeTemplate<'a> {
+    text: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "failed_entry.html")]
+struct FailedEntryTemplate<'a> {
+    rank:    usize,
+    name:    &'a str,
+    reason:  &'a str,
+    log_url: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "entry.html")]
+struct EntryTemplate<'a> {
+    rank:              usize,
+    /// Hex, validated when the config was loaded.
+    class_color:       Option<&'a str>,
+    name:              &'a str,
+    keystone_level:    Option<i32>,
+    talent_string:     &'a str,
+    reconstructed:     bool,
+    log_url:           &'a str,
+    fight_duration_ms: i64,
+    cast_json:         String,
+}
+
+/// A boss's most common build, as an overview section shows it.
+pub struct OverviewBuild<'a> {
+    /// The best-ranked player with the build, whose log is linked.
+    pub entry:      &'a TalentDataWithRank,
+    pub players:    usize,
+    /// Entries that had an import string at all.
+    pub considered: usize,
+}
+
+#[derive(Template)]
+#[template(path = "overview_section.html")]
+struct OverviewSectionTemplate<'a> {
+    order:       usize,
+    boss:        &'a str,
+    class_color: Option<&'a str>,
+    build:       Option<OverviewBuild<'a>>,
+}
+
+#[derive(Template)]
+#[template(path = "failed_overview_section.html")]
+struct FailedOverviewSectionTemplate<'a> {
+    order:   usize,
+    boss:    &'a str,
+    message: &'a str,
+}
+
+/// One spec's row in a snapshot: its most common build, or why its query
+/// failed.
+pub struct SnapshotSpec<'a> {
+    pub name:    &'a str,
+    pub build:   Option<OverviewBuild<'a>>,
+    pub failure: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "snapshot_class.html")]
+struct SnapshotClassTemplate<'a> {
+    class_name:  String,
+    class_color: Option<&'a str>,
+    specs:       &'a [SnapshotSpec<'a>],
+}
+
+/// One region's side of a region comparison: its most common build, or why
+/// its query failed.
+pub struct RegionBuild<'a> {
+    pub region:  &'a str,
+    pub build:   Option<OverviewBuild<'a>>,
+    pub failure: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "region_comparison.html")]
+struct RegionComparisonTemplate<'a> {
+    heading:     &'a str,
+    class_color: Option<&'a str>,
+    sides:       &'a [RegionBuild<'a>],
+    /// Whether both regions' builds are the same import string; `None`
+    /// unless both have one.
+    identical:   Option<bool>,
+}
+
+/// A query's dominant build on one day.
+#[derive(serde::Serialize)]
+pub struct TrendDay<'a> {
+    /// `YYYY-MM-DD`, UTC.
+    pub date:          String,
+    pub talent_string: &'a str,
+    pub players:       usize,
+    pub considered:    usize,
+    /// Differs from the previous day's dominant build.
+    pub changed:       bool,
+}
 
-    let mode_options: String = ClassSpecs::get_modes()
+#[derive(Template)]
+#[template(path = "trends.html")]
+struct TrendsTemplate<'a> {
+    selection:   &'a str,
+    class_color: Option<&'a str>,
+    days:        &'a [TrendDay<'a>],
+}
+
+#[derive(Template)]
+#[template(path = "feed.xml")]
+struct FeedTemplate<'a> {
+    /// The site's address without a trailing slash, if known.
+    site:    Option<&'a str>,
+    updated: String,
+    entries: Vec<FeedEntry<'a>>,
+}
+
+struct FeedEntry<'a> {
+    change:  &'a BuildChange,
+    updated: String,
+}
+
+#[derive(Template)]
+#[template(path = "not_found.html")]
+struct NotFoundTemplate<'a> {
+    path: &'a str,
+}
+
+struct ClassOption<'a> {
+    key:   &'a str,
+    name:  String,
+    color: Option<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "home.html")]
+struct HomeTemplate<'a> {
+    /// Raid tiers as (key, name), the current one first.
+    tiers:      Vec<(&'a str, &'a str)>,
+    classes:    Vec<ClassOption<'a>>,
+    regions:    &'a [Region],
+    modes:      Vec<Mode>,
+    key_levels: Vec<i32>,
+    page_data:  String,
+    /// Adds `game=classic` to every query the form sends.
+    classic:    bool,
+}
+
+/// An error message for the results area. `message` may quote upstream
+/// responses; like every value in a template, it is escaped.
+pub fn render_error(message: &str) -> String {
+    render(&ErrorTemplate { message })
+}
+
+/// An informational line for the results area.
+pub fn render_notice(text: &str) -> String {
+    render(&NoticeTemplate { text })
+}
+
+/// Explanation shown above the entries when fewer ranked kills exist than a
+/// full page would show. `selection` describes what was searched, e.g.
+/// "Frost Mage on Vorasius in Europe".
+pub fn render_count_notice(available: usize, selection: &str) -> Option<String> {
+    let text = match available {
+        0 => format!("No ranked kills exist for {} yet.", selection),
+        1 => format!("Only 1 ranked kill exists for {} yet.", selection),
+        n if n < MAX_ENTRIES => format!("Only {} ranked kills exist for {} yet.", n, selection),
+        _ => return None,
+    };
+    Some(render_notice(&text))
+}
+
+/// Placeholder for a rank whose talent data couldn't be fetched. Keeps the log
+/// link so the user can still check the build manually.
+pub fn render_failed_entry(rank: usize, name: &str, reason: &str, log_url: &str) -> String {
+    render(&FailedEntryTemplate { rank, name, reason, log_url })
+}
+
+/// A resolved entry. `class_color` tints the entry for the queried class.
+pub fn render_talent_entry(data: &TalentDataWithRank, class_color: Option<&str>) -> String {
+    if let Some(reason) = &data.data.failure {
+        return render_failed_entry(data.rank, &data.data.name, reason, &data.data.log_url);
+    }
+
+    // A reconstructed summary can't be loaded into the Wowhead calculator,
+    // so the template leaves the calculator out.
+    render(&EntryTemplate {
+        rank:              data.rank,
+        class_color,
+        name:              &data.data.name,
+        keystone_level:    data.data.keystone_level,
+        talent_string:     &data.data.talent_string,
+        reconstructed:     data.data.talent_reconstructed,
+        log_url:           &data.data.log_url,
+        fight_duration_ms: data.data.fight_duration_ms,
+        cast_json:         serde_json::to_string(&data.data.cast_events).unwrap_or_else(|_| "[]".to_string()),
+    })
+}
+
+/// One boss of an overview. `order` is the boss's place in the tier, which
+/// the page sorts sections by as they arrive in completion order.
+pub fn render_overview_section(
+    order: usize,
+    boss: &str,
+    class_color: Option<&str>,
+    build: Option<OverviewBuild<'_>>,
+) -> String {
+    render(&OverviewSectionTemplate { order, boss, class_color, build })
+}
+
+/// An overview section for a boss whose query failed.
+pub fn render_failed_overview_section(order: usize, boss: &str, message: &str) -> String {
+    render(&FailedOverviewSectionTemplate { order, boss, message })
+}
+
+/// One class of a snapshot, with a row per spec.
+pub fn render_snapshot_class(class: &str, class_color: Option<&str>, specs: &[SnapshotSpec<'_>]) -> String {
+    render(&SnapshotClassTemplate { class_name: class.replace('_', " "), class_color, specs })
+}
+
+/// Two regions' most common builds side by side. Builds are compared as
+/// import strings, so any difference in talents, however small, counts.
+pub fn render_region_comparison(heading: &str, class_color: Option<&str>, sides: &[RegionBuild<'_>]) -> String {
+    let strings: Option<Vec<&str>> = sides
         .iter()
-        .map(|mode| format!(r#"<option value="{}">{}</option>"#, mode.name, mode.name))
-        .collect::<Vec<_>>()
-        .join("\n                ");
+        .map(|side| side.build.as_ref().map(|b| b.entry.data.talent_string.as_str()))
+        .collect();
+    let identical = strings.map(|strings| strings.windows(2).all(|pair| pair[0] == pair[1]));
+    render(&RegionComparisonTemplate { heading, class_color, sides, identical })
+}
+
+/// A query's dominant build per day, oldest first, marking the days it
+/// changed.
+pub fn render_trends(selection: &str, class_color: Option<&str>, days: &[TrendDay<'_>]) -> String {
+    render(&TrendsTemplate { selection, class_color, days })
+}
 
-    let specs_map: String = config
+/// An Atom feed of build changes, given newest first. The feed's `updated`
+/// is the newest change's time, or `now` without any, so the same history
+/// always renders the same document.
+pub fn render_feed(changes: &[BuildChange], site: Option<&str>, now: u64) -> String {
+    render(&FeedTemplate {
+        site,
+        updated: utc_timestamp(changes.first().map_or(now, |c| c.detected_at)),
+        entries: changes
+            .iter()
+            .map(|change| FeedEntry { change, updated: utc_timestamp(change.detected_at) })
+            .collect(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "setup.html")]
+struct SetupTemplate<'a> {
+    missing: &'a [&'static str],
+}
+
+/// The page served at `/` instead of the form while credentials are
+/// missing, saying which variables to set.
+pub fn setup(missing: &[&'static str]) -> String {
+    render(&SetupTemplate { missing })
+}
+
+/// The page for an unknown path, in the site's styling.
+pub fn not_found(path: &str) -> String {
+    render(&NotFoundTemplate { path })
+}
+
+pub fn home(config: &ClassSpecs, settings: &Settings, game: Game) -> String {
+    // Read by app.js: the specs of each class, and the encounters of every
+    // tier (as /api/v1/encounters lists them) the boss options are built
+    // from for the selected raid. `<` is
+    // escaped so no value can close the script element; the template inserts
+    // it unescaped, as JSON is not HTML.
+    let specs: BTreeMap<&str, Vec<serde_json::Value>> = config
         .classes
         .iter()
         .map(|(class_name, class_data)| {
             let specs = class_data
                 .specs
                 .iter()
-                .map(|s| format!(r#""{}""#, s))
-                .collect::<Vec<_>>()
-                .join(", ");
-            format!(r#""{}": [{}]"#, class_name, specs)
+                .map(|s| serde_json::json!({ "name": s.name, "role": s.role }))
+                .collect();
+            (class_name.as_str(), specs)
         })
-        .collect::<Vec<_>>()
-        .join(",\n            ");
-
-    format!(
-        r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>Talent Trends</title>
-
-    <script>
-    {toggle_script}
-    </script>
-
-    <script>
-    {timeline_script}
-    </script>
-
-    <style>
-    {css}
-    </style>
-</head>
-<body>
-    <h1>Talent Trends</h1>
-
-    <div class="form-container">
-        <form id="talent-form">
-            <select name="region" id="region" required>
-                {region_options}
-            </select>
-            <select name="mode" id="mode" required>
-                <option value="">Select Mode</option>
-                {mode_options}
-            </select>
-            <select name="encounter" id="encounter" required>
-                <option value="">Select Boss</option>
-                {encounter_options}
-            </select>
-            <select name="class" id="class" required>
-                <option value="">Select Class</option>
-                {class_options}
-            </select>
-            <select name="spec" id="spec" required>
-                <option value="">Select Spec</option>
-            </select>
-            <br>
-            <input type="hidden" name="metric" id="metric-input" value="dps">
-            <div class="metric-group" role="group" aria-label="Metric">
-                <button type="button" class="metric-btn active" data-metric="dps">Damage</button>
-                <button type="button" class="metric-btn"        data-metric="hps">Healing</button>
-            </div>
-            <button type="submit" id="submit-btn" disabled>Get Talents</button>
-        </form>
-    </div>
-
-    <div id="results"></div>
-
-    <script>
-        const THEMES = {{
-            dps: {{ accent: '#e84040', hover: '#ff5555', text: '#fff' }},
-            hps: {{ accent: '#01C8AA', hover: '#02dfc0', text: '#0f1a18' }},
-        }};
-
-        function setTheme(metric) {{
-            const t    = THEMES[metric] || THEMES.dps;
-            const root = document.documentElement;
-            root.style.setProperty('--accent',       t.accent);
-            root.style.setProperty('--accent-hover', t.hover);
-            root.style.setProperty('--accent-text',  t.text);
-        }}
-
-        const specsData = {{
-            {specs_map}
-        }};
-
-        const regionSelect    = document.getElementById('region');
-        const modeSelect      = document.getElementById('mode');
-        const encounterSelect = document.getElementById('encounter');
-        const classSelect     = document.getElementById('class');
-        const specSelect      = document.getElementById('spec');
-        const submitBtn       = document.getElementById('submit-btn');
-        const resultsDiv      = document.getElementById('results');
-        const metricInput     = document.getElementById('metric-input');
-
-        // Populate spec options for a given class, optionally restoring a saved value.
-        function populateSpecs(className, restoreValue) {{
-            const prevValue = restoreValue !== undefined ? restoreValue : specSelect.value;
-            specSelect.innerHTML = '<option value="">Select Spec</option>';
-            if (className && specsData[className]) {{
-                specsData[className].forEach(spec => {{
-                    const option = document.createElement('option');
-                    option.value = spec;
-                    option.textContent = spec;
-                    specSelect.appendChild(option);
-                }});
-                specSelect.disabled = false;
-                // Restore previously selected spec if it exists in the new list
-                if (prevValue && specsData[className].includes(prevValue)) {{
-                    specSelect.value = prevValue;
-                }}
-            }} else {{
-                specSelect.disabled = true;
-            }}
-        }}
-
-        // On page load: restore spec list for whatever class the browser remembered,
-        // restore metric button state and theme from the hidden input the browser remembered.
-        document.addEventListener('DOMContentLoaded', () => {{
-            // Restore spec dropdown
-            if (classSelect.value) {{
-                populateSpecs(classSelect.value, specSelect.value);
-            }}
-
-            // Restore metric button active state + theme
-            const savedMetric = metricInput.value || 'dps';
-            document.querySelectorAll('.metric-btn').forEach(btn => {{
-                btn.classList.toggle('active', btn.dataset.metric === savedMetric);
-            }});
-            setTheme(savedMetric);
-
-            updateSubmitButton();
-        }});
-
-        // Metric toggle buttons
-        document.querySelectorAll('.metric-btn').forEach(btn => {{
-            btn.addEventListener('click', () => {{
-                document.querySelectorAll('.metric-btn').forEach(b => b.classList.remove('active'));
-                btn.classList.add('active');
-                metricInput.value = btn.dataset.metric;
-                setTheme(btn.dataset.metric);
-            }});
-        }});
-
-        regionSelect.addEventListener('change', updateSubmitButton);
-        modeSelect.addEventListener('change', updateSubmitButton);
-        encounterSelect.addEventListener('change', updateSubmitButton);
-
-        classSelect.addEventListener('change', (e) => {{
-            populateSpecs(e.target.value);
-            updateSubmitButton();
-        }});
-
-        specSelect.addEventListener('change', updateSubmitButton);
-
-        function updateSubmitButton() {{
-            submitBtn.disabled = !(
-                regionSelect.value &&
-                modeSelect.value &&
-                encounterSelect.value &&
-                classSelect.value &&
-                specSelect.value
-            );
-        }}
-
-        document.getElementById('talent-form').addEventListener('submit', async (e) => {{
-            e.preventDefault();
-            const formData = new FormData(e.target);
-            const params   = new URLSearchParams(formData);
-
-            resultsDiv.innerHTML = '<h2>Top 10 Talents</h2><div id="talents-container"></div><div id="loading-spinner" class="spinner"></div>';
-            submitBtn.disabled = true;
-
-            const eventSource = new EventSource('/api/talents?' + params);
-            let firstData = true;
-
-            eventSource.onmessage = (event) => {{
-                if (firstData) {{
-                    const spinner = document.getElementById('loading-spinner');
-                    if (spinner) spinner.remove();
-                    firstData = false;
-                }}
-                document.getElementById('talents-container')
-                    .insertAdjacentHTML('beforeend', event.data);
-            }};
-
-            eventSource.addEventListener('complete', () => {{
-                eventSource.close();
-                updateSubmitButton();
-            }});
-
-            eventSource.onerror = () => {{
-                eventSource.close();
-                if (firstData) {{
-                    resultsDiv.innerHTML = '<div class="error">Connection error. Please try again.</div>';
-                }}
-                updateSubmitButton();
-            }};
-        }});
-    </script>
-</body>
-</html>
-"#,
-        css             = style::css(),
-        toggle_script   = style::toggle_script(),
-        timeline_script = style::timeline_script(),
-        region_options  = region_options,
-        mode_options    = mode_options,
-        encounter_options = encounter_options,
-        class_options   = class_options,
-        specs_map       = specs_map,
-    )
+        .collect();
+    let tiers = settings.tiers();
+    let page_data = serde_json::json!({
+        "specs":      specs,
+        "encounters": tiers.iter().flat_map(|(id, tier)| tier.encounter_catalog(id)).collect::<Vec<_>>(),
+    })
+    .to_string()
+    .replace('<', "\\u003c");
+
+    render(&HomeTemplate {
+        tiers: tiers.iter().map(|(id, tier)| (*id, tier.name.as_str())).collect(),
+        classes: config
+            .classes
+            .iter()
+            .map(|(key, class)| ClassOption { key, name: key.replace('_', " "), color: class.css_color() })
+            .collect(),
+        regions: &settings.regions,
+        modes:   game.modes(),
+        key_levels: crate::config::KEY_LEVELS.collect(),
+        page_data,
+        classic: game == Game::Classic,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_wcl::talent_entry;
+
+    /// Compare `rendered` with the golden file `tests/snapshots/{name}`.
+    /// With `UPDATE_SNAPSHOTS` set, the file is rewritten instead. Asset
+    /// links carry the build's commit, so it reads as `COMMIT` in the files.
+    fn assert_snapshot(name: &str, rendered: &str) {
+        let rendered = rendered.replace(env!("BUILD_GIT_COMMIT"), "COMMIT");
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots").join(name);
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, &rendered).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("{}: {}; run with UPDATE_SNAPSHOTS=1 to create it", path.display(), e));
+        assert_eq!(rendered, expected, "{} differs from the rendered output", path.display());
+    }
+
+    /// A fixed Mythic+ entry with a keystone and two casts.
+    fn snapshot_entry() -> TalentDataWithRank {
+        let mut entry = talent_entry(3, "Frostyboi", "Argent Dawn", None);
+        entry.data.keystone_level = Some(14);
+        entry.data.talent_string = "C4PAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string();
+        entry.data.cast_events = serde_json::from_value(serde_json::json!([
+            { "t": 1_500, "id": 12472, "name": "Icy Veins", "icon": "spell_frost_coldhearted.jpg" },
+            { "t": 92_250, "id": 84714, "name": "Frozen Orb", "icon": "spell_frost_frozenorb.jpg" },
+        ]))
+        .unwrap();
+        entry
+    }
+
+    #[test]
+    fn entries_match_their_snapshots() {
+        let entry = snapshot_entry();
+        assert_snapshot("entry.html", &render_talent_entry(&entry, Some("#3FC7EB")));
+
+        let mut reconstructed = snapshot_entry();
+        reconstructed.data.keystone_level = None;
+        reconstructed.data.talent_reconstructed = true;
+        reconstructed.data.talent_string = "Frozen Orb, Ice Lance, Shatter (2/2)".to_string();
+        assert_snapshot("entry_reconstructed.html", &render_talent_entry(&reconstructed, None));
+
+        let failed = talent_entry(7, "Frostyboi", "Argent Dawn", Some("Report not found"));
+        assert_snapshot("entry_failed.html", &render_talent_entry(&failed, Some("#3FC7EB")));
+    }
+
+    #[test]
+    fn messages_match_their_snapshots() {
+        assert_snapshot("error.html", &render_error("WarcraftLogs is unavailable (HTTP 502)"));
+        let notice = render_count_notice(3, "Frost Mage on Vorasius in Europe").unwrap();
+        assert_snapshot("count_notice.html", &notice);
+    }
+
+    #[test]
+    fn not_found_matches_its_snapshot() {
+        assert_snapshot("not_found.html", &not_found("/talents/frost-mage"));
+    }
+
+    fn change(id: i64, detected_at: u64, selection: &str) -> BuildChange {
+        BuildChange {
+            id,
+            detected_at,
+            selection: selection.to_string(),
+            previous: format!("OLD{}", id),
+            current: format!("NEW{}", id),
+            player: "#1 Frostyboi".to_string(),
+            log_url: format!("https://www.warcraftlogs.com/reports/abc{}?fight=3&type=summary", id),
+        }
+    }
+
+    #[test]
+    fn feed_matches_its_snapshot() {
+        let changes = [
+            change(2, 1_767_312_000, "Frost Mage on Vorasius in Europe"),
+            change(1, 1_767_225_600, "Havoc Demon Hunter on <Plexus> & Co in All Regions"),
+        ];
+        assert_snapshot("feed.xml", &render_feed(&changes, Some("https://talents.example.com"), 1_767_400_000));
+    }
+
+    #[test]
+    fn empty_feed_is_updated_now_and_has_no_links_without_a_site() {
+        assert_snapshot("feed_empty.xml", &render_feed(&[], None, 1_767_400_000));
+    }
+
+    const NAME: &str = r#"<img src=x onerror="alert(1)">&co"#;
+    const REALM: &str = r#"Argent <Dawn> & "Co""#;
+
+    fn hostile_entry(failure: Option<&str>) -> TalentDataWithRank {
+        let mut entry = talent_entry(1, NAME, REALM, failure);
+        if failure.is_none() {
+            entry.data.talent_string = r#"ABC"><script>alert(2)</script>"#.to_string();
+        }
+        entry.data.log_url = r#"https://www.warcraftlogs.com/reports/abc"onmouseover="alert(3)"#.to_string();
+        entry
+    }
+
+    /// Nothing from the entry is left able to open a tag or end an attribute.
+    fn assert_inert(html: &str) {
+        assert!(!html.contains("<img") && !html.contains("<script"), "{}", html);
+        assert!(!html.contains(r#"onerror=""#) && !html.contains(r#"onmouseover=""#), "{}", html);
+    }
+
+    #[test]
+    fn entries_escape_names_talents_and_links() {
+        let html = render_talent_entry(&hostile_entry(None), Some("#3FC7EB"));
+        assert_inert(&html);
+        assert!(html.contains("# 1 - &#60;img src=x onerror=&#34;alert(1)&#34;&#62;&#38;co</h3>"), "{}", html);
+        assert!(html.contains("ABC&#34;&#62;&#60;script&#62;alert(2)&#60;/script&#62;"), "{}", html);
+        assert!(html.contains(r#"href="https://www.warcraftlogs.com/reports/abc&#34;onmouseover=&#34;alert(3)""#), "{}", html);
+    }
+
+    #[test]
+    fn failed_entries_escape_names_and_reasons() {
+        let html = render_talent_entry(&hostile_entry(Some("<b>gone</b> & \"lost\"")), None);
+        assert_inert(&html);
+        assert!(!html.contains("<b>"), "{}", html);
+        assert!(html.contains("&#60;b&#62;gone&#60;/b&#62; &#38; &#34;lost&#34;"), "{}", html);
+    }
+
+    #[test]
+    fn errors_escape_their_message() {
+        let html = render_error(r#"WCL said: <script>alert("x")</script>"#);
+        assert_eq!(
+            html,
+            r#"<div class="error">Error: WCL said: &#60;script&#62;alert(&#34;x&#34;)&#60;/script&#62;</div>"#
+        );
+    }
+
+    #[test]
+    fn text_formats_quote_names_and_realms() {
+        let entries = [hostile_entry(None)];
+
+        let csv = render_csv(&entries, "dps");
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.starts_with(r#"1,"<img src=x onerror=""alert(1)"">&co",,"Argent <Dawn> & ""Co""",EU,"#), "{}", row);
+
+        let md = render_markdown("Frost Mage", &entries);
+        assert!(md.contains(r#"[\<img src=x onerror="alert(1)"\>&co]("#), "{}", md);
+    }
 }
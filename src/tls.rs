@@ -0,0 +1,49 @@
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::config::TlsPaths;
+
+/// Load the certificate chain and key. Unreadable files, malformed PEM and a
+/// key that doesn't belong to the certificate all fail here, at startup,
+/// rather than on the first handshake.
+pub async fn load(paths: &TlsPaths) -> Result<RustlsConfig> {
+    // rustls is built with ring only; make it the process-wide default so
+    // config builders don't have to pick.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    RustlsConfig::from_pem_file(&paths.cert, &paths.key)
+        .await
+        .with_context(|| {
+            format!(
+                "Could not load TLS certificate {} with key {}",
+                paths.cert.display(),
+                paths.key.display()
+            )
+        })
+}
+
+/// Reload the certificate from the same paths on every SIGHUP, e.g. after a
+/// renewal. A failed reload is logged and the current certificate kept.
+pub fn reload_on_sighup(config: RustlsConfig, paths: TlsPaths) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGHUP, TLS reload disabled: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match config.reload_from_pem_file(&paths.cert, &paths.key).await {
+                Ok(()) => tracing::info!("Reloaded TLS certificate from {}", paths.cert.display()),
+                Err(e) => tracing::error!("TLS reload failed, keeping the current certificate: {}", e),
+            }
+        }
+    });
+
+    #[cfg(not(unix))]
+    let _ = (config, paths);
+}
@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use axum::{Extension, Router, extract::ConnectInfo};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::fs::Permissions;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::UnixListener;
+
+use crate::shutdown;
+
+/// Bind a unix socket at `path` with permissions `mode`. A socket left
+/// behind by a previous run is removed first; any other file there is an
+/// error rather than something to delete.
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Could not remove stale socket {}", path.display()))?;
+        }
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Could not listen on {}", path.display()))?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode))
+        .with_context(|| format!("Could not set mode {:o} on {}", mode, path.display()))?;
+    Ok(listener)
+}
+
+/// Serve `app` on a bound socket until shutdown, then wait for open
+/// connections and remove the socket. There is no peer address on a unix
+/// socket, so handlers see every client as 127.0.0.1: the proxy in front is
+/// on this host and is the one that knows the real address.
+pub async fn serve(listener: UnixListener, path: PathBuf, app: Router) -> std::io::Result<()> {
+    let app      = app.layer(Extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0)))));
+    let graceful = GracefulShutdown::new();
+    let signal   = shutdown::signal();
+    tokio::pin!(signal);
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Usually out of file descriptors; give some a chance to close.
+                    tracing::warn!("Unix socket accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+
+        let service    = TowerToHyperService::new(app.clone());
+        let connection = auto::Builder::new(TokioExecutor::new())
+            .serve_connection_with_upgrades(TokioIo::new(stream), service)
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("Unix socket connection ended with an error: {}", e);
+            }
+        });
+    }
+
+    drop(listener);
+    if let Err(e) = std::fs::remove_file(&path) {
+        tracing::warn!("Could not remove socket {}: {}", path.display(), e);
+    }
+    graceful.shutdown().await;
+    Ok(())
+}
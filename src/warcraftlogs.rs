@@ -1,13 +1,124 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock, Semaphore, SemaphorePermit};
+use tracing::Instrument;
+use unicode_normalization::UnicodeNormalization;
 
+use crate::cache::{CacheStats, TtlCache};
+use crate::config::{Game, Settings, env_or};
+use crate::metrics;
+
+const SITE_URL: &str = "https://www.warcraftlogs.com";
 const OAUTH_TOKEN_URL: &str = "https://www.warcraftlogs.com/oauth/token";
 const GRAPHQL_ENDPOINT: &str = "https://www.warcraftlogs.com/api/v2/client";
 
+/// Where tokens and GraphQL queries are sent: WarcraftLogs itself unless
+/// `WCL_OAUTH_URL` or `WCL_GRAPHQL_URL` point elsewhere, e.g. at a mock
+/// server or a caching proxy.
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub oauth:   Url,
+    pub graphql: Url,
+}
+
+impl Endpoints {
+    /// Read both URLs from the environment. Like the listen address, a bad
+    /// value is a startup error rather than replaced by the default.
+    pub fn from_env() -> Result<Self> {
+        let endpoints = Self {
+            oauth:   endpoint_from_env("WCL_OAUTH_URL", OAUTH_TOKEN_URL)?,
+            graphql: endpoint_from_env("WCL_GRAPHQL_URL", GRAPHQL_ENDPOINT)?,
+        };
+        if endpoints.oauth.as_str() != OAUTH_TOKEN_URL || endpoints.graphql.as_str() != GRAPHQL_ENDPOINT {
+            tracing::info!("WarcraftLogs tokens from {}, queries to {}", endpoints.oauth, endpoints.graphql);
+        }
+        Ok(endpoints)
+    }
+
+    /// The same paths on another site.
+    fn on(&self, site: &Url) -> Result<Self> {
+        Ok(Self { oauth: site.join(self.oauth.path())?, graphql: site.join(self.graphql.path())? })
+    }
+}
+
+/// One WarcraftLogs site and what was learned from it. Tokens and API points
+/// aren't shared between sites, so each has its own.
+struct Host {
+    endpoints:  Endpoints,
+    /// Where log links point.
+    site:       Url,
+    token:      RwLock<Option<AccessToken>>,
+    rate_limit: RwLock<Option<RateLimitStatus>>,
+}
+
+impl Host {
+    fn new(endpoints: Endpoints, site: Url) -> Self {
+        Self { endpoints, site, token: RwLock::new(None), rate_limit: RwLock::new(None) }
+    }
+
+    /// The host for `site`: the configured endpoints when `None`, else the
+    /// same paths on that site.
+    fn for_site(endpoints: &Endpoints, site: Option<&str>) -> Result<Self> {
+        match site {
+            None => Ok(Self::new(endpoints.clone(), Url::parse(SITE_URL)?)),
+            Some(site) => {
+                let site = Url::parse(site).with_context(|| format!("Bad site {}", site))?;
+                Ok(Self::new(endpoints.on(&site)?, site))
+            }
+        }
+    }
+}
+
+/// Every site a client can query, picked per request.
+struct Hosts {
+    retail:   Arc<Host>,
+    classic:  Arc<Host>,
+    /// Regions with a site of their own, by game and region code.
+    regional: HashMap<(Game, String), Arc<Host>>,
+}
+
+fn endpoint_from_env(name: &str, default: &str) -> Result<Url> {
+    let raw = std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let url = Url::parse(raw.as_deref().unwrap_or(default).trim()).with_context(|| format!("{} is not a URL", name))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("{} must be an http or https URL, not {}", name, url);
+    }
+    Ok(url)
+}
+
+/// Number of ranked players shown per query.
+pub const MAX_ENTRIES: usize = 10;
+
+/// The encounter ID that asks for a whole tier's rankings rather than one
+/// boss's, and the encounter name reported for it.
+pub const TIER_OVERALL: i32 = 0;
+const TIER_OVERALL_NAME: &str = "Tier overall";
+
+/// Capacity of the channels that carry entries towards the SSE handler. Kept
+/// small so a slow client applies backpressure to the fetch loop (sends are
+/// awaited) instead of it racing ahead with upstream calls nobody may read.
+/// A dropped receiver is noticed via `Sender::closed`, which cancels the
+/// fetch outright rather than waiting for the next send to fail.
+pub const STREAM_CHANNEL_CAPACITY: usize = 2;
+
+/// Total time `send_with_retry` may spend backing off before giving up.
+const RETRY_BUDGET: Duration = Duration::from_secs(5);
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+/// Longest Retry-After we'll sit out in-request on a 429; longer waits are
+/// reported to the user instead.
+const RATE_LIMIT_MAX_WAIT: Duration = Duration::from_secs(3);
+
+/// Backoff between attempts of a single player's talent fetch, for the
+/// failures `send_with_retry` doesn't retry itself (see `is_transient`).
+const TALENT_RETRY_DELAYS: [Duration; 2] = [Duration::from_millis(500), Duration::from_millis(1500)];
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CastEvent {
     pub t: i64,
@@ -16,446 +127,2451 @@ pub struct CastEvent {
     pub icon: String,
 }
 
+/// One ranked player's build. Only the client produces these, and fields
+/// are added as WarcraftLogs exposes more, so it can't be built outside the
+/// crate.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[non_exhaustive]
 pub struct TalentData {
     pub name: String,
+    /// Ranking details; absent from results cached before they were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guild: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub realm: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// The ranked metric's value (DPS, HPS or Mythic+ score).
+    #[serde(default)]
+    pub amount: f64,
+    /// Keystone level, for Mythic+ runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keystone_level: Option<i32>,
     pub talent_string: String,
+    /// True when `talent_string` is a readable summary rebuilt from combatant
+    /// info rather than a Blizzard import string.
+    #[serde(default)]
+    pub talent_reconstructed: bool,
+    /// Why the talent data couldn't be fetched; the entry is then rendered as
+    /// a failed placeholder that still links to the log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
     pub log_url: String,
     pub fight_duration_ms: i64,
     pub cast_events: Vec<CastEvent>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TalentDataWithRank {
     pub rank: usize,
     pub data: TalentData,
 }
 
+/// What a talent stream carries to the handler.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum StreamItem {
+    /// Sent once the rankings are known, before any entry: the encounter name
+    /// as WCL reports it, how many entries will follow, and how many named
+    /// ranked kills exist in total.
+    #[serde(alias = "Count")]
+    Meta {
+        #[serde(default)]
+        encounter: Option<String>,
+        entries: usize,
+        available: usize,
+    },
+    Entry(TalentDataWithRank),
+    /// How many of the entries have been fetched so far, in completion order.
+    /// Transient; never cached.
+    Progress { resolved: usize, total: usize },
+    /// How long one upstream stage of a fresh fetch took, for the
+    /// `Server-Timing` header. Transient; never cached.
+    Timing { stage: String, elapsed_ms: u64 },
+}
+
 #[derive(Debug, Deserialize)]
 struct TokenResponse {
     access_token: String,
+    /// Seconds the token is valid for.
+    #[serde(default)]
+    expires_in: Option<u64>,
 }
 
-lazy_static::lazy_static! {
-    static ref TOKEN_CACHE: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+/// The OAuth token in use and when it was fetched.
+struct AccessToken {
+    value:       String,
+    acquired_at: u64,
+    expires_in:  Option<u64>,
 }
 
-async fn get_access_token() -> Result<String> {
-    {
-        let cache = TOKEN_CACHE.read().await;
-        if let Some(token) = cache.as_ref() {
-            return Ok(token.clone());
+/// A token is replaced this long before it expires, so requests already on
+/// their way don't carry it past expiry.
+const TOKEN_REFRESH_MARGIN_SECS: u64 = 300;
+
+impl AccessToken {
+    /// Whether the token should be replaced at unix time `now`. Tokens WCL
+    /// gave no lifetime for are kept.
+    fn expiring(&self, now: u64) -> bool {
+        self.expires_in
+            .is_some_and(|lifetime| now + TOKEN_REFRESH_MARGIN_SECS >= self.acquired_at.saturating_add(lifetime))
+    }
+}
+
+/// Age and remaining lifetime of the OAuth token, for `/admin/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenStatus {
+    pub age_secs: u64,
+    /// `None` when WCL didn't say; negative once past expiry.
+    pub expires_in_secs: Option<i64>,
+}
+
+/// Hourly API point usage as last reported by WCL's `rateLimitData`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RateLimitStatus {
+    pub limit_per_hour: i64,
+    pub points_spent_this_hour: f64,
+    /// Seconds until the hourly budget resets, as of `observed_at`.
+    pub points_reset_in: i64,
+    /// Unix timestamp (seconds) of the response the numbers came from.
+    pub observed_at: u64,
+}
+
+impl RateLimitStatus {
+    pub fn used_fraction(&self) -> f64 {
+        if self.limit_per_hour <= 0 {
+            return 0.0;
+        }
+        self.points_spent_this_hour / self.limit_per_hour as f64
+    }
+}
+
+/// How to spend what's left of the hourly point budget. Thresholds are
+/// fractions of `limitPerHour` still remaining.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    /// Below this, fetch only `reduced_entries` talent strings per request.
+    pub reduce_below: f64,
+    /// Below this, refuse new queries until the budget resets.
+    pub refuse_below: f64,
+    pub reduced_entries: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BudgetDecision {
+    Full,
+    Reduced(usize),
+    Exhausted { resets_in: Duration },
+}
+
+impl Budget {
+    pub fn from_env() -> Self {
+        Self {
+            reduce_below:    env_or("WCL_BUDGET_REDUCE_BELOW", 0.20),
+            refuse_below:    env_or("WCL_BUDGET_REFUSE_BELOW", 0.05),
+            reduced_entries: env_or("WCL_BUDGET_REDUCED_ENTRIES", 3),
         }
     }
 
-    let client_id = std::env::var("WCL_CLIENT_ID").context("WCL_CLIENT_ID not set in .env?")?;
-    let client_secret =
-        std::env::var("WCL_CLIENT_SECRET").context("WCL_CLIENT_SECRET not set in .env?")?;
+    /// Decide how much work a new request may do, given the last reported
+    /// usage and the current unix time in seconds.
+    pub fn decide(&self, status: Option<&RateLimitStatus>, now: u64) -> BudgetDecision {
+        let Some(status) = status else { return BudgetDecision::Full };
 
-    tracing::info!("Fetching new OAuth token...");
+        // The numbers are stale once the reported reset time has passed.
+        let elapsed   = now.saturating_sub(status.observed_at) as i64;
+        let resets_in = status.points_reset_in - elapsed;
+        if resets_in <= 0 {
+            return BudgetDecision::Full;
+        }
 
-    let client = Client::new();
-    let params = [("grant_type", "client_credentials")];
+        let remaining = 1.0 - status.used_fraction();
+        if remaining < self.refuse_below {
+            BudgetDecision::Exhausted { resets_in: Duration::from_secs(resets_in as u64) }
+        } else if remaining < self.reduce_below {
+            BudgetDecision::Reduced(self.reduced_entries)
+        } else {
+            BudgetDecision::Full
+        }
+    }
+}
 
-    let response = client
-        .post(OAUTH_TOKEN_URL)
-        .basic_auth(client_id, Some(client_secret))
-        .form(&params)
-        .send()
-        .await
-        .context("Failed to request OAuth token")?;
+/// The hourly point budget is too low to start a new query.
+#[derive(Debug)]
+pub struct BudgetExhausted {
+    pub resets_in: Duration,
+}
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        anyhow::bail!("OAuth failed with status {}: {}", status, error_text);
+impl std::fmt::Display for BudgetExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.resets_in.as_secs();
+        write!(
+            f,
+            "WarcraftLogs API budget is nearly used up for this hour, resets in {}m {}s",
+            secs / 60, secs % 60
+        )
     }
+}
 
-    let token_resp: TokenResponse = response
-        .json()
-        .await
-        .context("Failed to parse OAuth token response")?;
+impl std::error::Error for BudgetExhausted {}
 
-    tracing::info!("OAuth token acquired");
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
-    {
-        let mut cache = TOKEN_CACHE.write().await;
-        *cache = Some(token_resp.access_token.clone());
+/// Usage levels that get an info log line the first time they're crossed
+/// within an hour.
+const RATE_LIMIT_THRESHOLDS: [f64; 3] = [0.50, 0.80, 0.95];
+
+/// What a client remembers between requests. Shared by all its clones.
+struct Caches {
+    /// Player actors per report code.
+    actors: TtlCache<String, Arc<Vec<Actor>>>,
+    /// Per-player talent results keyed by (report code, fight ID, actor ID).
+    talents: TtlCache<(String, i64, i64), TalentResult>,
+    /// Last upstream readiness result, so probes don't spend API points.
+    ready: TtlCache<(), UpstreamCheck>,
+}
+
+impl Caches {
+    fn new() -> Self {
+        Self {
+            actors: TtlCache::new(
+                Duration::from_secs(env_or("ACTOR_CACHE_TTL_SECS", 24 * 60 * 60)),
+                env_or("ACTOR_CACHE_MAX_ENTRIES", 2000),
+            ),
+            talents: TtlCache::new(
+                Duration::from_secs(env_or("TALENT_CACHE_TTL_SECS", 7 * 24 * 60 * 60)),
+                env_or("TALENT_CACHE_MAX_ENTRIES", 5000),
+            ),
+            ready: TtlCache::new(
+                Duration::from_secs(env_or("READY_CHECK_TTL_SECS", 120)),
+                1,
+            ),
+        }
     }
+}
 
-    Ok(token_resp.access_token)
+/// Outcome of the readiness checks; `None` means the check passed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub credentials: Option<String>,
+    #[serde(flatten)]
+    pub upstream: UpstreamCheck,
 }
 
-#[derive(Serialize)]
-struct GraphQLRequest {
-    query: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    variables: Option<serde_json::Value>,
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpstreamCheck {
+    pub token: Option<String>,
+    pub graphql: Option<String>,
 }
 
-struct TalentResult {
-    talent_string: String,
-    fight_duration_ms: i64,
-    cast_events: Vec<CastEvent>,
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.credentials.is_none() && self.upstream.token.is_none() && self.upstream.graphql.is_none()
+    }
 }
 
-async fn fetch_talent_and_events(
-    client: &Client,
-    token: &str,
-    report_code: &str,
-    fight_id: i64,
-    player_name: &str,
-) -> Result<TalentResult> {
-    // ── Step 1: resolve actor ID ──────────────────────────────────────────────
-    let actor_json: serde_json::Value = client
-        .post(GRAPHQL_ENDPOINT)
-        .bearer_auth(token)
-        .json(&GraphQLRequest {
-            query: r#"
-            query GetActors($reportCode: String!) {
-              reportData {
-                report(code: $reportCode) {
-                  masterData(translate: true) {
-                    actors(type: "Player") { id name }
-                  }
-                }
-              }
-            }"#.to_string(),
-            variables: Some(serde_json::json!({ "reportCode": report_code })),
-        })
-        .send().await.context("actor lookup send")?
-        .json().await.context("actor lookup parse")?;
-
-    let actors = actor_json
-        .pointer("/data/reportData/report/masterData/actors")
-        .and_then(|v| v.as_array())
-        .context("No actors array in masterData")?;
-
-    let actor_id = actors
-        .iter()
-        .find(|a| {
-            a.get("name").and_then(|n| n.as_str())
-                .map(|n| n == player_name).unwrap_or(false)
-        })
-        .and_then(|a| a.get("id"))
-        .and_then(|id| id.as_i64())
-        .with_context(|| format!("Actor '{}' not found in masterData", player_name))?;
+/// The credential variables that are unset or blank.
+pub fn missing_credentials() -> Vec<&'static str> {
+    ["WCL_CLIENT_ID", "WCL_CLIENT_SECRET"]
+        .into_iter()
+        .filter(|name| std::env::var(name).map_or(true, |v| v.trim().is_empty()))
+        .collect()
+}
 
-    tracing::debug!("Resolved actor '{}' -> ID {}", player_name, actor_id);
+/// Shared handle to the WarcraftLogs API: one pooled HTTP client (so
+/// connections and TLS sessions are reused), the OAuth token and the caches
+/// of upstream results.
+#[derive(Clone)]
+pub struct WclClient {
+    http: Client,
+    /// The site requests go to: retail's main one, or the one a query
+    /// needs.
+    host: Arc<Host>,
+    hosts: Arc<Hosts>,
+    /// Upper bound on a whole talent stream, retries included.
+    stream_deadline: Duration,
+    budget: Budget,
+    /// Caps concurrent upstream requests across all users.
+    upstream: Arc<Semaphore>,
+    caches: Arc<Caches>,
+}
 
-    // ── Step 2: talent + table (name/icon map) + flat cast events ─────────────
-    let combined: serde_json::Value = client
-        .post(GRAPHQL_ENDPOINT)
-        .bearer_auth(token)
-        .json(&GraphQLRequest {
-            query: r#"
-            query GetAll($code: String!, $ids: [Int]!, $src: Int!) {
-              reportData {
-                report(code: $code) {
-                  fights(fightIDs: $ids) {
-                    startTime
-                    endTime
-                    talentImportCode(actorID: $src)
-                  }
-                  table(
-                    fightIDs: $ids
-                    sourceID: $src
-                    dataType: Casts
-                    translate: true
-                  )
-                  events(
-                    fightIDs: $ids
-                    sourceID: $src
-                    dataType: Casts
-                    limit: 10000
-                  ) {
-                    data
-                    nextPageTimestamp
-                  }
-                }
-              }
-            }"#.to_string(),
-            variables: Some(serde_json::json!({
-                "code": report_code,
-                "ids":  [fight_id as i32],
-                "src":  actor_id as i32,
-            })),
+impl WclClient {
+    pub fn new(endpoints: Endpoints, retail: &Settings, classic: &Settings) -> Result<Self> {
+        let http = Client::builder()
+            .connect_timeout(Duration::from_secs(env_or("WCL_CONNECT_TIMEOUT_SECS", 5)))
+            .timeout(Duration::from_secs(env_or("WCL_REQUEST_TIMEOUT_SECS", 15)))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .pool_max_idle_per_host(16)
+            .build()
+            .context("Failed to build HTTP client")?;
+
+        let mut regional = HashMap::new();
+        for (game, settings) in [(Game::Retail, retail), (Game::Classic, classic)] {
+            for region in &settings.regions {
+                let Some(site) = &region.site else { continue };
+                tracing::info!("{:?} region {} queries {}", game, region.code, site);
+                regional.insert((game, region.code.clone()), Arc::new(Host::for_site(&endpoints, Some(site))?));
+            }
+        }
+        let hosts = Hosts {
+            retail:  Arc::new(Host::for_site(&endpoints, retail.site.as_deref())?),
+            classic: Arc::new(Host::for_site(&endpoints, classic.site.as_deref())?),
+            regional,
+        };
+
+        Ok(Self {
+            http,
+            host: hosts.retail.clone(),
+            hosts: Arc::new(hosts),
+            stream_deadline: Duration::from_secs(env_or("STREAM_DEADLINE_SECS", 60)),
+            budget: Budget::from_env(),
+            upstream: Arc::new(Semaphore::new(env_or("WCL_MAX_CONCURRENT_REQUESTS", 10))),
+            caches: Arc::new(Caches::new()),
         })
-        .send().await.context("combined query send")?
-        .json().await.context("combined query parse")?;
+    }
 
-    let report = combined
-        .pointer("/data/reportData/report")
-        .context("No report in combined response")?;
+    /// The HTTP client behind every WarcraftLogs request, for the rest of
+    /// the application to share its connection pool and timeouts.
+    pub fn http(&self) -> Client {
+        self.http.clone()
+    }
 
-    // ── Fight timing + talent string ──────────────────────────────────────────
-    let fight = report.pointer("/fights/0").context("No fight[0]")?;
+    /// This client with talent streams cut off after `deadline` rather than
+    /// `STREAM_DEADLINE_SECS`.
+    pub fn with_stream_deadline(self, deadline: Duration) -> Self {
+        Self { stream_deadline: deadline, ..self }
+    }
 
-    let fight_start       = fight.get("startTime").and_then(|v| v.as_i64()).unwrap_or(0);
-    let fight_end         = fight.get("endTime").and_then(|v| v.as_i64()).unwrap_or(0);
-    let fight_duration_ms = fight_end - fight_start;
+    /// This client with requests going to the game's site, or to the
+    /// region's own if it has one. Caches and the request limit stay shared.
+    pub fn for_game(&self, game: Game, region: Option<&str>) -> Self {
+        let regional = region.and_then(|code| self.hosts.regional.get(&(game, code.to_string())));
+        let host = match (regional, game) {
+            (Some(host), _)     => host,
+            (None, Game::Retail)  => &self.hosts.retail,
+            (None, Game::Classic) => &self.hosts.classic,
+        };
+        Self { host: host.clone(), ..self.clone() }
+    }
 
-    let talent_code = fight
-        .get("talentImportCode")
-        .and_then(|v| v.as_str())
-        .context("No talentImportCode")?;
+    /// Wait for a free upstream request slot. Long waits are logged, since
+    /// they mean the concurrency limit is what's slowing requests down.
+    async fn permit(&self, what: &str) -> SemaphorePermit<'_> {
+        let started = std::time::Instant::now();
+        let permit  = self.upstream.acquire().await.expect("upstream semaphore closed");
 
-    // ── Build guid → (name, icon) map from table entries ─────────────────────
-    let table_raw = report.get("table").cloned().unwrap_or(serde_json::Value::Null);
-    let table_value: serde_json::Value = if table_raw.is_string() {
-        serde_json::from_str(table_raw.as_str().unwrap_or("{}")).unwrap_or(serde_json::Value::Null)
-    } else {
-        table_raw
-    };
+        let waited = started.elapsed();
+        if waited > Duration::from_secs(1) {
+            tracing::warn!("{} waited {:?} for an upstream request slot", what, waited);
+        }
+        permit
+    }
 
-    let entries = table_value
-        .pointer("/data/entries")
-        .and_then(|v| v.as_array())
-        .cloned()
-        .unwrap_or_default();
-
-    let mut ability_map: HashMap<u64, (String, String)> = HashMap::new();
-
-    for entry in &entries {
-        if let (Some(guid), Some(name), Some(icon)) = (
-            entry.get("guid").and_then(|v| v.as_u64()),
-            entry.get("name").and_then(|v| v.as_str()),
-            entry.get("abilityIcon").and_then(|v| v.as_str()),
-        ) {
-            ability_map.entry(guid).or_insert_with(|| (name.to_string(), icon.to_string()));
-        }
-
-        if let Some(subs) = entry.get("subentries").and_then(|v| v.as_array()) {
-            for sub in subs {
-                if let (Some(guid), Some(name), Some(icon)) = (
-                    sub.get("guid").and_then(|v| v.as_u64()),
-                    sub.get("name").and_then(|v| v.as_str()),
-                    sub.get("abilityIcon").and_then(|v| v.as_str()),
-                ) {
-                    ability_map.entry(guid).or_insert_with(|| (name.to_string(), icon.to_string()));
-                }
+    /// The cached token, or a new one once it is missing or near expiry.
+    async fn access_token(&self) -> Result<String> {
+        {
+            let cache = self.host.token.read().await;
+            if let Some(token) = cache.as_ref().filter(|token| !token.expiring(unix_now())) {
+                return Ok(token.value.clone());
             }
         }
+
+        // Checked again under the write lock, so concurrent requests that
+        // found no usable token fetch only one.
+        let mut cache = self.host.token.write().await;
+        if let Some(token) = cache.as_ref().filter(|token| !token.expiring(unix_now())) {
+            return Ok(token.value.clone());
+        }
+        let token = self.fetch_access_token().await?;
+        let value = token.value.clone();
+        *cache = Some(token);
+        Ok(value)
+    }
+
+    /// The main site's OAuth token's age and expiry; `None` until one is
+    /// fetched.
+    pub async fn token_status(&self) -> Option<TokenStatus> {
+        let now = unix_now();
+        self.host.token.read().await.as_ref().map(|token| TokenStatus {
+            age_secs:        now.saturating_sub(token.acquired_at),
+            expires_in_secs: token.expires_in.map(|lifetime| (token.acquired_at + lifetime) as i64 - now as i64),
+        })
     }
 
-    tracing::debug!("Built ability map: {} entries for {}", ability_map.len(), player_name);
+    /// Counters of the per-report player list and talent caches.
+    pub fn cache_stats(&self) -> (CacheStats, CacheStats) {
+        (self.caches.actors.stats(), self.caches.talents.stats())
+    }
 
-    // ── Parse flat cast events, join with ability map ─────────────────────────
-    let events_raw = report
-        .pointer("/events/data")
-        .cloned()
-        .unwrap_or(serde_json::Value::Null);
+    async fn fetch_access_token(&self) -> Result<AccessToken> {
+        let client_id = std::env::var("WCL_CLIENT_ID").context("WCL_CLIENT_ID not set in .env?")?;
+        let client_secret =
+            std::env::var("WCL_CLIENT_SECRET").context("WCL_CLIENT_SECRET not set in .env?")?;
 
-    let events_array: Vec<serde_json::Value> = match events_raw {
-        serde_json::Value::Array(arr) => arr,
-        serde_json::Value::String(s)  => serde_json::from_str(&s).unwrap_or_default(),
-        _                             => vec![],
-    };
+        tracing::info!("Fetching new OAuth token...");
 
-    let cast_events: Vec<CastEvent> = events_array
-        .iter()
-        .filter(|ev| ev.get("type").and_then(|v| v.as_str()) == Some("cast"))
-        .filter_map(|ev| {
-            let timestamp = ev.get("timestamp")?.as_i64()?;
-            let id        = ev.get("abilityGameID")?.as_u64()?;
-            if id < 100 { return None; }
-            let (name, icon) = ability_map.get(&id)?.clone();
-            Some(CastEvent { t: timestamp - fight_start, id, name, icon })
+        let params = [("grant_type", "client_credentials")];
+
+        let response = self.send_with_retry("oauth", || {
+            self.http
+                .post(self.host.endpoints.oauth.clone())
+                .basic_auth(&client_id, Some(&client_secret))
+                .form(&params)
         })
-        .collect();
+        .await
+        .context("Failed to request OAuth token")?;
 
-    tracing::info!(
-        "Parsed {} cast events for {} ({} raw, {} abilities, {}ms)",
-        cast_events.len(), player_name, events_array.len(), ability_map.len(), fight_duration_ms
-    );
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            anyhow::bail!("OAuth failed with status {}: {}", status, error_text);
+        }
 
-    Ok(TalentResult { talent_string: talent_code.to_string(), fight_duration_ms, cast_events })
-}
-
-pub async fn fetch_top_talents_stream(
-    class: &str,
-    spec: &str,
-    encounter_id: i32,
-    region: Option<&str>,
-    difficulty: i32,
-    partition: Option<i32>,
-    metric: &str,
-) -> Result<mpsc::Receiver<Result<TalentDataWithRank>>> {
-    let (tx, rx) = mpsc::channel(10);
-
-    let class   = class.to_string();
-    let spec    = spec.to_string();
-    let region  = region.map(|s| s.to_string());
-    let metric  = metric.to_string();
-
-    tokio::spawn(async move {
-        if let Err(e) = fetch_and_stream_talents(
-            &tx, &class, &spec, encounter_id, region.as_deref(), difficulty, partition, &metric,
-        ).await {
-            tracing::error!("fetch_and_stream_talents failed: {:#}", e);
-            let _ = tx.send(Err(e)).await;
-        }
-    });
-
-    Ok(rx)
-}
-
-async fn fetch_and_stream_talents(
-    tx: &mpsc::Sender<Result<TalentDataWithRank>>,
-    class: &str,
-    spec: &str,
-    encounter_id: i32,
-    region: Option<&str>,
-    difficulty: i32,
-    partition: Option<i32>,
-    metric: &str,
-) -> Result<()> {
-    let token  = get_access_token().await?;
-    let client = Client::new();
-
-    let class_name     = class.replace('_', "");
-    let region_display = region.unwrap_or("all");
-
-    // Validate metric to avoid injecting arbitrary GraphQL
-    let safe_metric = match metric {
-        "hps" | "tankhps" => metric,
-        _                 => "dps",
-    };
+        let token_resp: TokenResponse = response
+            .json()
+            .await
+            .context("Failed to parse OAuth token response")?;
 
-    tracing::info!(
-        "Querying {} {} encounter {} region {} difficulty {} partition {:?} metric {}",
-        class_name, spec, encounter_id, region_display, difficulty, partition, safe_metric
-    );
+        tracing::info!("OAuth token acquired");
 
-    let partition_arg = match partition {
-        Some(p) => format!("partition: {}", p),
-        None    => String::new(),
-    };
+        Ok(AccessToken {
+            value:       token_resp.access_token,
+            acquired_at: unix_now(),
+            expires_in:  token_resp.expires_in,
+        })
+    }
 
-    let query = format!(
-        r#"
-        query Rankings(
-          $encounterId: Int!,
-          $className: String!,
-          $specName: String!,
-          $serverRegion: String,
-          $difficulty: Int!
-        ) {{
-          worldData {{
-            encounter(id: $encounterId) {{
-              name
-              characterRankings(
-                className: $className
-                specName: $specName
-                serverRegion: $serverRegion
-                metric: {metric}
-                difficulty: $difficulty
-                page: 1
-                {partition_arg}
-              )
-            }}
-          }}
-        }}
-        "#,
-        metric        = safe_metric,
-        partition_arg = partition_arg,
-    );
+    /// Whether we can serve real results: credentials are configured, a token
+    /// can be had, and a minimal GraphQL query succeeds. The upstream part is
+    /// cached for `READY_CHECK_TTL_SECS`.
+    pub async fn readiness(&self) -> Readiness {
+        let credentials = missing_credentials().first().map(|name| format!("{} is not set", name));
+        if credentials.is_some() {
+            return Readiness { credentials, upstream: UpstreamCheck::default() };
+        }
+
+        if let Some((upstream, _)) = self.caches.ready.get(&()) {
+            return Readiness { credentials, upstream };
+        }
 
-    let mut variables = serde_json::json!({
-        "encounterId": encounter_id,
-        "className":   class_name,
-        "specName":    spec,
-        "difficulty":  difficulty,
-    });
-    if let Some(r) = region {
-        variables["serverRegion"] = serde_json::Value::String(r.to_string());
+        let upstream = match self.access_token().await {
+            Err(e) => UpstreamCheck { token: Some(format!("{:#}", e)), graphql: None },
+            Ok(token) => UpstreamCheck { token: None, graphql: self.ping(&token).await.err().map(|e| format!("{:#}", e)) },
+        };
+        self.caches.ready.insert((), upstream.clone());
+        Readiness { credentials, upstream }
     }
 
-    let response = client
-        .post(GRAPHQL_ENDPOINT)
-        .bearer_auth(&token)
-        .json(&GraphQLRequest { query, variables: Some(variables) })
-        .send().await.context("rankings send")?;
+    /// The cheapest GraphQL query there is; also refreshes the point usage.
+    async fn ping(&self, token: &str) -> Result<()> {
+        let request = GraphQLRequest {
+            query: "{ rateLimitData { limitPerHour pointsSpentThisHour pointsResetIn } }".to_string(),
+            variables: None,
+        };
+        let json: serde_json::Value = self.send_with_retry("ready check", || {
+            self.http.post(self.host.endpoints.graphql.clone()).bearer_auth(token).json(&request)
+        })
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+        let rate_limit = json.pointer("/data/rateLimitData").context("No rateLimitData in response")?;
+        self.record_rate_limit(rate_limit).await;
+        Ok(())
+    }
 
-    let status        = response.status();
-    let response_text = response.text().await?;
-    if !status.is_success() {
-        anyhow::bail!("GraphQL request failed {}: {}", status, response_text);
+    /// Encounters of the given zones (raid tiers), in `zone_ids` order and
+    /// WCL's boss order within each. Fails if any of the zones is unknown, so a
+    /// mistyped ID isn't silently dropped from the form.
+    pub async fn fetch_zones(&self, zone_ids: &[i32]) -> Result<Vec<Zone>> {
+        let token = self.access_token().await?;
+        let request = GraphQLRequest {
+            query: "{ worldData { zones { id name encounters { id name } } } }".to_string(),
+            variables: None,
+        };
+        let body = self.send_with_retry("zone list", || {
+            self.http.post(self.host.endpoints.graphql.clone()).bearer_auth(&token).json(&request)
+        })
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+        let mut zones = parse_response::<ZonesResponse>("zone list", &body)?.data.world_data.zones;
+        zone_ids
+            .iter()
+            .map(|id| {
+                let i = zones.iter().position(|z| z.id == *id).with_context(|| format!("WarcraftLogs has no zone {}", id))?;
+                Ok(zones.swap_remove(i))
+            })
+            .collect()
     }
 
-    let json: serde_json::Value =
-        serde_json::from_str(&response_text).context("rankings parse")?;
+    /// Empty the per-report caches of player lists and talents; returns how
+    /// many entries each held.
+    pub fn flush_caches(&self) -> (usize, usize) {
+        (self.caches.actors.remove_where(|_| true), self.caches.talents.remove_where(|_| true))
+    }
 
-    if let Some(errors) = json.get("errors") {
-        anyhow::bail!("GraphQL errors: {}", serde_json::to_string_pretty(errors)?);
+    /// Point usage of the site this client queries; for the one in
+    /// `AppState`, the main site's, which is what the API budget goes by.
+    pub async fn rate_limit_status(&self) -> Option<RateLimitStatus> {
+        self.host.rate_limit.read().await.clone()
     }
 
-    let rankings_json = json
-        .pointer("/data/worldData/encounter/characterRankings")
-        .context("No characterRankings field")?;
+    /// How much work a new query may do on this site's remaining budget.
+    pub async fn budget_decision(&self) -> BudgetDecision {
+        self.budget.decide(self.rate_limit_status().await.as_ref(), unix_now())
+    }
 
-    let rankings_value: serde_json::Value = if rankings_json.is_string() {
-        serde_json::from_str(rankings_json.as_str().context("characterRankings unreadable")?)
-            .context("characterRankings parse")?
-    } else {
-        rankings_json.clone()
-    };
+    async fn record_rate_limit(&self, value: &serde_json::Value) {
+        let status = RateLimitStatus {
+            limit_per_hour:         value.get("limitPerHour").and_then(|v| v.as_i64()).unwrap_or(0),
+            points_spent_this_hour: value.get("pointsSpentThisHour").and_then(|v| v.as_f64()).unwrap_or(0.0),
+            points_reset_in:        value.get("pointsResetIn").and_then(|v| v.as_i64()).unwrap_or(0),
+            observed_at:            unix_now(),
+        };
+
+        let mut cache = self.host.rate_limit.write().await;
+
+        // Only log when this update crosses a threshold the previous one hadn't;
+        // a drop in usage means the hour rolled over and the thresholds re-arm.
+        let before = cache.as_ref().map(|s| s.used_fraction()).unwrap_or(0.0);
+        let after  = status.used_fraction();
+        if let Some(threshold) = RATE_LIMIT_THRESHOLDS.iter().rev().find(|t| after >= **t && before < **t) {
+            tracing::info!(
+                "WCL API usage crossed {:.0}%: {:.0}/{} points spent, resets in {}s",
+                threshold * 100.0, status.points_spent_this_hour, status.limit_per_hour, status.points_reset_in
+            );
+        }
 
-    let rankings = match rankings_value.get("rankings").and_then(|v| v.as_array()) {
-        Some(r) => r,
-        None => anyhow::bail!(
-            "No rankings array: {}",
-            serde_json::to_string(&rankings_value).unwrap_or_default()
-        ),
-    };
+        *cache = Some(status);
+    }
 
-    if rankings.is_empty() {
-        tracing::info!("Empty rankings.");
-        return Ok(());
+    /// Forget the cached token so the next call fetches a fresh one.
+    async fn invalidate_token(&self) {
+        *self.host.token.write().await = None;
     }
+}
 
-    tracing::info!("Found {} rankings, fetching data...", rankings.len());
+/// Comparison key for character names: NFC-normalized and lowercased, so the
+/// rankings name and the masterData name match regardless of case or of
+/// composed vs decomposed accents (e.g. "Ásh" vs "A\u{301}sh").
+fn name_key(name: &str) -> String {
+    // Lowercasing can itself produce decomposed sequences (e.g. 'İ'), so
+    // normalize again afterwards.
+    name.nfc().collect::<String>().to_lowercase().nfc().collect()
+}
 
-    let mut rank_number = 1usize;
+/// The `characterRankings` payload. Only the fields we use are declared;
+/// anything else WCL sends is ignored.
+#[derive(Debug, Deserialize)]
+struct CharacterRankings {
+    rankings: Vec<Ranking>,
+}
 
-    for rank in rankings.iter() {
-        if rank_number > 10 { break; }
+/// One ranked parse from `characterRankings`.
+#[derive(Debug, Deserialize)]
+struct Ranking {
+    name: String,
+    #[serde(default)]
+    amount: f64,
+    /// Keystone level of a Mythic+ run; absent for raid kills.
+    #[serde(default, rename = "hardModeLevel")]
+    hard_mode_level: Option<i32>,
+    #[serde(default)]
+    guild: Option<RankingGuild>,
+    #[serde(default)]
+    server: Option<RankingServer>,
+    #[serde(default)]
+    report: Option<RankingReport>,
+}
 
-        let name        = rank.get("name").and_then(|v| v.as_str()).unwrap_or("Unknown");
-        if name == "Anonymous" { continue; }
+#[derive(Debug, Deserialize)]
+struct RankingReport {
+    code: String,
+    #[serde(rename = "fightID")]
+    fight_id: i64,
+}
 
-        let report_code = rank.pointer("/report/code").and_then(|v| v.as_str()).unwrap_or("");
-        let fight_id    = rank.pointer("/report/fightID").and_then(|v| v.as_i64()).unwrap_or(0);
+#[derive(Debug, Deserialize)]
+struct RankingServer {
+    name: String,
+    #[serde(default)]
+    region: Option<String>,
+}
 
-        let log_url = format!(
-            "https://www.warcraftlogs.com/reports/{}#fight={}",
-            report_code, fight_id
-        );
+#[derive(Debug, Deserialize)]
+struct RankingGuild {
+    name: String,
+}
 
-        let (talent_string, fight_duration_ms, cast_events) =
-            if !report_code.is_empty() && fight_id > 0 {
-                match fetch_talent_and_events(&client, &token, report_code, fight_id, name).await {
-                    Ok(r) => (r.talent_string, r.fight_duration_ms, r.cast_events),
-                    Err(e) => {
-                        tracing::warn!("Rank {} {} failed: {:#}", rank_number, name, e);
-                        ("[Talent data unavailable]".to_string(), 0, vec![])
-                    }
-                }
-            } else {
-                ("[Missing report data]".to_string(), 0, vec![])
-            };
+/// Deserialize `characterRankings`, which WCL returns either as an object or
+/// as a JSON-encoded string. Errors name the path of the offending field.
+fn parse_rankings(value: &serde_json::Value) -> Result<CharacterRankings> {
+    let parsed = match value.as_str() {
+        Some(text) => {
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(text))
+        }
+        None => serde_path_to_error::deserialize(value),
+    };
+    parsed.map_err(|e| anyhow::anyhow!("characterRankings field `{}`: {}", e.path(), e.inner()))
+}
 
-        tracing::info!("Rank {} {} — {} cast events", rank_number, name, cast_events.len());
+/// A player actor from a report's masterData.
+#[derive(Debug, Clone, Deserialize)]
+struct Actor {
+    id: i64,
+    name: String,
+    #[serde(default)]
+    server: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ZonesResponse {
+    data: ZonesData,
+}
+
+#[derive(Debug, Deserialize)]
+struct ZonesData {
+    #[serde(rename = "worldData")]
+    world_data: WorldData,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorldData {
+    zones: Vec<Zone>,
+}
+
+/// A raid tier (or dungeon season) as WCL groups its encounters.
+#[derive(Debug, Deserialize)]
+pub struct Zone {
+    pub id: i32,
+    pub name: String,
+    pub encounters: Vec<ZoneEncounter>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ZoneEncounter {
+    pub id: i32,
+    pub name: String,
+}
+
+/// The `data.reportData.report` envelope shared by the per-report queries.
+/// `report` is null when WCL doesn't know the code.
+#[derive(Debug, Deserialize)]
+struct ReportResponse<T> {
+    data: ReportDataField<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReportDataField<T> {
+    #[serde(rename = "reportData")]
+    report_data: ReportField<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReportField<T> {
+    report: Option<T>,
+}
+
+impl<T> ReportResponse<T> {
+    fn into_report(self) -> Option<T> {
+        self.data.report_data.report
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ActorsReport {
+    #[serde(rename = "masterData")]
+    master_data: MasterData,
+}
+
+#[derive(Debug, Deserialize)]
+struct MasterData {
+    actors: Vec<Actor>,
+}
+
+/// The report part of the combined talents/table/events query.
+#[derive(Debug, Deserialize)]
+struct CombinedReport {
+    fights: Vec<Fight>,
+    #[serde(default)]
+    table: serde_json::Value,
+    #[serde(default)]
+    events: Option<EventsPage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Fight {
+    #[serde(rename = "startTime")]
+    start_time: i64,
+    #[serde(rename = "endTime")]
+    end_time: i64,
+    #[serde(rename = "talentImportCode", default)]
+    talent_import_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsPage {
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+/// Why a ranked player's talents couldn't be looked up in their report.
+#[derive(Debug)]
+enum TalentLookupError {
+    /// The player isn't among the report's masterData actors.
+    ActorNotFound { name: String },
+    /// The fight has no talentImportCode for the player.
+    NullTalentCode,
+}
 
-        if tx.send(Ok(TalentDataWithRank {
-            rank: rank_number,
-            data: TalentData { name: name.to_string(), talent_string, log_url, fight_duration_ms, cast_events },
-        })).await.is_err() {
-            break;
+impl std::fmt::Display for TalentLookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ActorNotFound { name } => write!(f, "Actor '{}' not found in masterData", name),
+            Self::NullTalentCode         => write!(f, "No talentImportCode for this fight"),
         }
+    }
+}
+
+impl std::error::Error for TalentLookupError {}
+
+/// Deserialize a GraphQL response body, naming the offending field on failure.
+fn parse_response<T: serde::de::DeserializeOwned>(what: &str, body: &[u8]) -> Result<T> {
+    serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(body))
+        .map_err(|e| anyhow::anyhow!("{} field `{}`: {}", what, e.path(), e.inner()))
+}
 
-        rank_number += 1;
+/// Resolve a player's actor ID from the masterData actors list. When several
+/// actors normalize to the same name key, narrow by server and then fall back
+/// to an exact name match.
+fn find_actor_id(actors: &[Actor], player_name: &str, server: Option<&str>) -> Option<i64> {
+    let key = name_key(player_name);
+
+    let mut candidates: Vec<&Actor> = actors.iter().filter(|a| name_key(&a.name) == key).collect();
+
+    if candidates.len() > 1
+        && let Some(server) = server
+        && candidates.iter().any(|a| a.server.as_deref() == Some(server))
+    {
+        candidates.retain(|a| a.server.as_deref() == Some(server));
     }
 
-    Ok(())
+    let actor = match candidates.as_slice() {
+        [one] => *one,
+        [] => return None,
+        many => *many.iter().find(|a| a.name == player_name)?,
+    };
+
+    Some(actor.id)
+}
+
+/// Per-request counters, logged once the request's entries are all sent.
+#[derive(Debug, Default)]
+struct FetchStats {
+    actor_lookups: AtomicUsize,
+    actor_cache_hits: AtomicUsize,
+    /// Entries this request will produce, once the rankings are known.
+    entries_expected: AtomicUsize,
+    entries_sent: AtomicUsize,
+}
+
+/// The whole stream ran past its deadline; whatever was sent stays on the page.
+#[derive(Debug)]
+pub struct DeadlineExceeded {
+    pub sent: usize,
+    pub expected: usize,
+}
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.expected > 0 {
+            write!(f, "Timed out, showing partial results ({}/{})", self.sent, self.expected)
+        } else {
+            write!(f, "Timed out waiting for WarcraftLogs")
+        }
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// A failed WCL query, by what would fix it. Only `InvalidQuery` carries
+/// upstream wording to the user; everything else stays in the logs.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WclError {
+    /// Our token was rejected even after fetching a new one.
+    Auth,
+    /// WCL rejected the query itself, e.g. an unknown spec name.
+    InvalidQuery { messages: Vec<String> },
+    /// WCL failed on its side.
+    Upstream,
+    /// The response didn't have the shape we expect. The details are logged
+    /// in the span of the request that ran the query.
+    UnexpectedResponse,
+}
+
+impl std::fmt::Display for WclError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auth => write!(f, "Could not authenticate with WarcraftLogs, please try again later"),
+            Self::InvalidQuery { messages } => {
+                write!(f, "WarcraftLogs rejected the query: {}", messages.join("; "))
+            }
+            Self::Upstream => write!(f, "WarcraftLogs is having problems, please try again later"),
+            Self::UnexpectedResponse => write!(f, "Unexpected response from WarcraftLogs (please report this)"),
+        }
+    }
+}
+
+impl std::error::Error for WclError {}
+
+/// One entry of a GraphQL `errors` array.
+#[derive(Debug, Deserialize)]
+struct GraphQLError {
+    message: String,
+    #[serde(default)]
+    extensions: Option<GraphQLErrorExtensions>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphQLErrorExtensions {
+    #[serde(default)]
+    category: Option<String>,
+}
+
+/// Map a GraphQL `errors` array onto a `WclError`. WCL tags most errors with
+/// an `extensions.category`; untagged ones are classified by their message.
+fn classify_graphql_errors(errors: &serde_json::Value) -> WclError {
+    let Ok(errors) = serde_json::from_value::<Vec<GraphQLError>>(errors.clone()) else {
+        return WclError::Upstream;
+    };
+
+    let is_auth = |e: &GraphQLError| {
+        let message = e.message.to_lowercase();
+        matches!(e.extensions.as_ref().and_then(|x| x.category.as_deref()),
+                 Some("authentication" | "authorization"))
+            || message.contains("unauthenticated")
+            || message.contains("unauthorized")
+    };
+    let is_user = |e: &GraphQLError| {
+        let message = e.message.to_lowercase();
+        matches!(e.extensions.as_ref().and_then(|x| x.category.as_deref()),
+                 Some("graphql" | "validation" | "user"))
+            || message.contains("invalid")
+            || message.contains("does not exist")
+    };
+
+    if errors.iter().any(is_auth) {
+        WclError::Auth
+    } else if !errors.is_empty() && errors.iter().all(is_user) {
+        WclError::InvalidQuery { messages: errors.into_iter().map(|e| e.message).collect() }
+    } else {
+        WclError::Upstream
+    }
+}
+
+/// Log a rankings response we couldn't make sense of and turn it into the
+/// user-facing error.
+fn unexpected_response(body: &str, e: anyhow::Error) -> WclError {
+    tracing::error!(
+        "Unexpected rankings response: {:#}; body: {}",
+        e, body.chars().take(2000).collect::<String>()
+    );
+    WclError::UnexpectedResponse
+}
+
+/// WCL answered 429. Carries the parsed Retry-After so the stream can tell
+/// the user when to come back.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.retry_after {
+            Some(d) => write!(f, "Rate limited by WarcraftLogs, try again in {}s", d.as_secs().max(1)),
+            None    => write!(f, "Rate limited by WarcraftLogs, try again shortly"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Parse a Retry-After header value, either delta-seconds or an HTTP-date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(std::time::SystemTime::now()).unwrap_or_default())
+}
+
+#[derive(Serialize)]
+struct GraphQLRequest {
+    query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variables: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
+struct TalentResult {
+    talent_string: String,
+    talent_reconstructed: bool,
+    fight_duration_ms: i64,
+    cast_events: Vec<CastEvent>,
+}
+
+/// Failed requests and 5xx responses are worth another try of a whole talent
+/// fetch; anything else (actor not found, missing talent data, 4xx) will fail
+/// the same way again. Connection errors, timeouts and 502/503/504 are left
+/// out: `send_with_retry` has already retried those for as long as
+/// `RETRY_BUDGET` allows.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| match cause.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_connect() || e.is_timeout() => false,
+        Some(e) => match e.status() {
+            Some(status) => status.is_server_error() && !matches!(status.as_u16(), 502..=504),
+            None => e.is_request(),
+        },
+        None => false,
+    })
+}
+
+/// Everything that identifies a rankings query; also the key for coalescing
+/// identical concurrent requests.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct RankingsQuery {
+    /// The class key and spec as `classes.toml` names them.
+    pub class: String,
+    pub spec: String,
+    /// What WarcraftLogs calls them; the only names sent upstream.
+    pub wcl_class: String,
+    pub wcl_spec: String,
+    /// `TIER_OVERALL` for every encounter in `tier_encounters` at once.
+    pub encounter_id: i32,
+    /// The encounters a tier-wide query covers; empty otherwise.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tier_encounters: Vec<i32>,
+    /// `None` for all regions.
+    pub region: Option<String>,
+    pub difficulty: i32,
+    pub partition: Option<i32>,
+    /// Keystone level of the Mythic+ runs to rank; `None` for every level.
+    pub bracket: Option<i32>,
+    pub metric: String,
+    /// Most entries to fetch talents for, at most `MAX_ENTRIES`.
+    pub limit: usize,
+    /// Left out for retail, so its keys are what they were before Classic.
+    #[serde(default, skip_serializing_if = "Game::is_retail")]
+    pub game: Game,
+}
+
+/// The upstream side of a talent query, so something other than WarcraftLogs
+/// can stand in for it, e.g. canned entries and errors when testing the
+/// handlers.
+pub trait WclApi: Send + Sync {
+    /// Stream a query's top entries with their talents, ending with the
+    /// summary, as `WclClient::fetch_top_talents_stream` does.
+    fn talents<'a>(&'a self, params: &'a RankingsQuery) -> BoxFuture<'a, Result<mpsc::Receiver<Result<StreamItem>>>>;
+}
+
+impl WclApi for WclClient {
+    fn talents<'a>(&'a self, params: &'a RankingsQuery) -> BoxFuture<'a, Result<mpsc::Receiver<Result<StreamItem>>>> {
+        Box::pin(self.fetch_top_talents_stream(params))
+    }
+}
+
+impl WclClient {
+    pub async fn fetch_top_talents_stream(
+        &self,
+        params: &RankingsQuery,
+    ) -> Result<mpsc::Receiver<Result<StreamItem>>> {
+        let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+
+        let wcl    = self.for_game(params.game, params.region.as_deref());
+        let params = params.clone();
+
+        let span = tracing::Span::current();
+        tokio::spawn(async move {
+            let stats    = FetchStats::default();
+            let deadline = wcl.stream_deadline;
+
+            // On timeout, or when the receiver goes away, the fetch future is
+            // dropped, which cancels any upstream calls still in flight.
+            let fetch = tokio::time::timeout(deadline, wcl.fetch_and_stream_talents(&tx, &params, &stats));
+            let result = tokio::select! {
+                result = fetch => result,
+                _ = tx.closed() => {
+                    tracing::info!("Receiver dropped, cancelling fetch for {} {}", params.class, params.spec);
+                    return;
+                }
+            };
+
+            let result = result.unwrap_or_else(|_| {
+                let exceeded = DeadlineExceeded {
+                    sent:     stats.entries_sent.load(Ordering::Relaxed),
+                    expected: stats.entries_expected.load(Ordering::Relaxed),
+                };
+                tracing::warn!(
+                    "Stream deadline of {:?} hit ({}/{} entries)",
+                    deadline, exceeded.sent, exceeded.expected
+                );
+                Err(exceeded.into())
+            });
+
+            if let Err(e) = result {
+                tracing::error!("fetch_and_stream_talents failed: {:#}", e);
+                let _ = tx.send(Err(e)).await;
+            }
+        }.instrument(span));
+
+        Ok(rx)
+    }
+
+    async fn fetch_and_stream_talents(
+        &self,
+        tx: &mpsc::Sender<Result<StreamItem>>,
+        params: &RankingsQuery,
+        stats: &FetchStats,
+    ) -> Result<()> {
+        let max_entries = match self.budget_decision().await {
+            BudgetDecision::Full => MAX_ENTRIES,
+            BudgetDecision::Reduced(n) => {
+                tracing::warn!("WCL point budget low, limiting request to {} entries", n);
+                n.min(MAX_ENTRIES)
+            }
+            BudgetDecision::Exhausted { resets_in } => {
+                tracing::warn!("WCL point budget exhausted, refusing query (resets in {:?})", resets_in);
+                return Err(BudgetExhausted { resets_in }.into());
+            }
+        };
+
+        let max_entries = max_entries.min(params.limit);
+
+        let timer = metrics::Timer::start("rankings_query", "error");
+        let (token, encounter, rankings) = if params.encounter_id == TIER_OVERALL {
+            self.fetch_tier_rankings(params).await?
+        } else {
+            self.fetch_rankings(params, params.encounter_id).await?
+        };
+        let rankings_elapsed = timer.elapsed();
+        timer.finish("ok");
+
+        tracing::info!("Found {} rankings, fetching data...", rankings.len());
+
+        // Ranks are numbered after skipping anonymous players.
+        let named: Vec<&Ranking> = rankings.iter().filter(|r| r.name != "Anonymous").collect();
+        let available = named.len();
+
+        let ranked: Vec<(usize, &Ranking)> = named
+            .into_iter()
+            .take(max_entries)
+            .enumerate()
+            .map(|(i, r)| (i + 1, r))
+            .collect();
+        stats.entries_expected.store(ranked.len(), Ordering::Relaxed);
+
+        let timing = StreamItem::Timing { stage: "wcl-rankings".into(), elapsed_ms: rankings_elapsed.as_millis() as u64 };
+        let meta   = StreamItem::Meta { encounter, entries: ranked.len(), available };
+        if tx.send(Ok(timing)).await.is_err() || tx.send(Ok(meta)).await.is_err() {
+            return Ok(());
+        }
+        let talents_started = std::time::Instant::now();
+
+        // Fetch several players at once. They complete out of rank order:
+        // progress counts completions as they come, while entries wait in
+        // `pending` until every better rank has been sent, so they are always
+        // appended #1 to #N. Every send is made from this loop rather than from
+        // the fetches, which aren't polled while the loop waits on a full
+        // channel and would otherwise hold up the send it waits behind.
+        let token = token.as_str();
+        let total = ranked.len();
+        let fetches: Vec<_> = ranked
+            .into_iter()
+            .map(|(rank_number, rank)| self.fetch_entry(token, rank_number, rank, stats))
+            .collect();
+        let mut fetches = futures::stream::iter(fetches).buffer_unordered(env_or("TALENT_FETCH_PARALLELISM", 4));
+        let mut pending   = BTreeMap::new();
+        let mut next_rank = 1;
+        let mut resolved  = 0;
+        'fetching: while let Some(entry) = fetches.next().await {
+            resolved += 1;
+            if tx.send(Ok(StreamItem::Progress { resolved, total })).await.is_err() {
+                break;
+            }
+            pending.insert(entry.rank, entry);
+            while let Some(entry) = pending.remove(&next_rank) {
+                if tx.send(Ok(StreamItem::Entry(entry))).await.is_err() {
+                    break 'fetching;
+                }
+                stats.entries_sent.fetch_add(1, Ordering::Relaxed);
+                next_rank += 1;
+            }
+        }
+        let elapsed_ms = talents_started.elapsed().as_millis() as u64;
+        let _ = tx.send(Ok(StreamItem::Timing { stage: "wcl-talents".into(), elapsed_ms })).await;
+
+        tracing::info!(
+            "{} of {} actor lookups served from cache",
+            stats.actor_cache_hits.load(Ordering::Relaxed),
+            stats.actor_lookups.load(Ordering::Relaxed)
+        );
+
+        let talent_cache = self.caches.talents.stats();
+        tracing::info!(
+            "Talent cache: {} hits, {} misses, {} entries",
+            talent_cache.hits, talent_cache.misses, talent_cache.entries
+        );
+
+        Ok(())
+    }
+
+    /// Page one of `characterRankings` for one encounter, with the encounter's
+    /// name as WCL reports it and the token that was used, which the talent
+    /// fetches reuse.
+    async fn fetch_rankings(&self, params: &RankingsQuery, encounter_id: i32) -> Result<(String, Option<String>, Vec<Ranking>)> {
+        let spec       = params.wcl_spec.as_str();
+        let region     = params.region.as_deref();
+        let difficulty = params.difficulty;
+        let partition  = params.partition;
+        let metric     = params.metric.as_str();
+
+        let class_name     = params.wcl_class.as_str();
+        let region_display = region.unwrap_or("all");
+
+        // Validate metric to avoid injecting arbitrary GraphQL
+        let safe_metric = match metric {
+            "hps" | "tankhps" | "playerscore" => metric,
+            _                                 => "dps",
+        };
+
+        tracing::info!(
+            "Querying {} {} encounter {} region {} difficulty {} partition {:?} metric {}",
+            class_name, spec, encounter_id, region_display, difficulty, partition, safe_metric
+        );
+
+        let partition_arg = match partition {
+            Some(p) => format!("partition: {}", p),
+            None    => String::new(),
+        };
+        let bracket_arg = match params.bracket {
+            Some(level) => format!("bracket: {}", level),
+            None        => String::new(),
+        };
+
+        let query = format!(
+            r#"
+            query Rankings(
+              $encounterId: Int!,
+              $className: String!,
+              $specName: String!,
+              $serverRegion: String,
+              $difficulty: Int!
+            ) {{
+              worldData {{
+                encounter(id: $encounterId) {{
+                  name
+                  characterRankings(
+                    className: $className
+                    specName: $specName
+                    serverRegion: $serverRegion
+                    metric: {metric}
+                    difficulty: $difficulty
+                    page: 1
+                    {partition_arg}
+                    {bracket_arg}
+                  )
+                }}
+              }}
+              rateLimitData {{
+                limitPerHour
+                pointsSpentThisHour
+                pointsResetIn
+              }}
+            }}
+            "#,
+            metric        = safe_metric,
+            partition_arg = partition_arg,
+            bracket_arg   = bracket_arg,
+        );
+
+        let mut variables = serde_json::json!({
+            "encounterId": encounter_id,
+            "className":   class_name,
+            "specName":    spec,
+            "difficulty":  difficulty,
+        });
+        if let Some(r) = region {
+            variables["serverRegion"] = serde_json::Value::String(r.to_string());
+        }
+
+        let request = GraphQLRequest { query, variables: Some(variables) };
+
+        // A rejected token may just have expired early: fetch a new one and try
+        // the query once more before giving up.
+        let mut refreshed = false;
+        let (token, response_text, json) = loop {
+            let token    = self.access_token().await?;
+            let response = self.send_with_retry("rankings", || {
+                self.http.post(self.host.endpoints.graphql.clone()).bearer_auth(&token).json(&request)
+            })
+            .await
+            .context("rankings send")?;
+
+            let status        = response.status();
+            let response_text = response.text().await?;
+
+            let error = if status == reqwest::StatusCode::UNAUTHORIZED {
+                Some(WclError::Auth)
+            } else if !status.is_success() {
+                tracing::error!("Rankings request failed {}: {}", status, response_text);
+                Some(WclError::Upstream)
+            } else {
+                None
+            };
+
+            let json: serde_json::Value = match error {
+                Some(_) => serde_json::Value::Null,
+                None => serde_json::from_str(&response_text)
+                    .context("rankings parse")
+                    .map_err(|e| unexpected_response(&response_text, e))?,
+            };
+
+            if let Some(rate_limit) = json.pointer("/data/rateLimitData") {
+                self.record_rate_limit(rate_limit).await;
+            }
+
+            let error = error.or_else(|| {
+                let errors = json.get("errors")?;
+                tracing::warn!("Rankings GraphQL errors: {}", errors);
+                Some(classify_graphql_errors(errors))
+            });
+
+            match error {
+                Some(WclError::Auth) if !refreshed => {
+                    tracing::warn!("WCL rejected our token, fetching a new one");
+                    self.invalidate_token().await;
+                    refreshed = true;
+                }
+                Some(e) => return Err(e.into()),
+                None    => break (token, response_text, json),
+            }
+        };
+
+        // An empty array just means nobody has a ranked kill for this selection
+        // yet; a missing or malformed payload is an unexpected response.
+        let rankings = json
+            .pointer("/data/worldData/encounter/characterRankings")
+            .context("No characterRankings field")
+            .and_then(parse_rankings)
+            .map_err(|e| unexpected_response(&response_text, e))?
+            .rankings;
+
+        let encounter = json
+            .pointer("/data/worldData/encounter/name")
+            .and_then(|v| v.as_str())
+            .map(String::from);
+        Ok((token, encounter, rankings))
+    }
+
+    /// Rankings for a whole tier: page one of each of `params.tier_encounters`,
+    /// merged per player. A placing earns points by position, 100 for first
+    /// down to 1 for hundredth, in the spirit of WCL's All Stars; players are
+    /// ordered by their total and each is represented by their best-placed
+    /// kill, whose fight the talents and log link come from.
+    async fn fetch_tier_rankings(&self, params: &RankingsQuery) -> Result<(String, Option<String>, Vec<Ranking>)> {
+        struct Standing {
+            points: usize,
+            best_position: usize,
+            best: Ranking,
+        }
+
+        let pages = futures::future::try_join_all(
+            params.tier_encounters.iter().map(|id| self.fetch_rankings(params, *id)),
+        )
+        .await?;
+        let token = pages.first().map(|(token, _, _)| token.clone()).context("No encounters in the tier")?;
+
+        let mut standings: HashMap<(String, Option<String>), Standing> = HashMap::new();
+        for (_, _, rankings) in pages {
+            let named = rankings.into_iter().filter(|r| r.name != "Anonymous");
+            for (position, ranking) in named.enumerate() {
+                let points = 100usize.saturating_sub(position);
+                let key    = (name_key(&ranking.name), ranking.server.as_ref().map(|s| s.name.clone()));
+                match standings.entry(key) {
+                    std::collections::hash_map::Entry::Occupied(mut entry) => {
+                        let standing = entry.get_mut();
+                        standing.points += points;
+                        if position < standing.best_position {
+                            standing.best_position = position;
+                            standing.best          = ranking;
+                        }
+                    }
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        entry.insert(Standing { points, best_position: position, best: ranking });
+                    }
+                }
+            }
+        }
+
+        let mut standings: Vec<Standing> = standings.into_values().collect();
+        standings.sort_by(|a, b| b.points.cmp(&a.points).then(a.best_position.cmp(&b.best_position)));
+        let rankings = standings.into_iter().map(|s| s.best).collect();
+        Ok((token, Some(TIER_OVERALL_NAME.to_string()), rankings))
+    }
+
+    /// Build the entry for one ranked player. Never fails: if the talent data
+    /// can't be fetched the entry carries a `failure` reason instead, so every
+    /// rank produces exactly one entry.
+    async fn fetch_entry(
+        &self,
+        token: &str,
+        rank_number: usize,
+        rank: &Ranking,
+        stats: &FetchStats,
+    ) -> TalentDataWithRank {
+        let name        = rank.name.as_str();
+        let report_code = rank.report.as_ref().map_or("", |r| r.code.as_str());
+        let fight_id    = rank.report.as_ref().map_or(0, |r| r.fight_id);
+        let server      = rank.server.as_ref().map(|s| s.name.as_str());
+
+        let log_url = format!(
+            "{}reports/{}#fight={}",
+            self.host.site, report_code, fight_id
+        );
+
+        let mut data = TalentData {
+            name: name.to_string(),
+            guild: rank.guild.as_ref().map(|g| g.name.clone()),
+            realm: server.map(String::from),
+            region: rank.server.as_ref().and_then(|s| s.region.clone()),
+            amount: rank.amount,
+            keystone_level: rank.hard_mode_level.filter(|level| *level > 0),
+            talent_string: String::new(),
+            talent_reconstructed: false,
+            failure: None,
+            log_url,
+            fight_duration_ms: 0,
+            cast_events: vec![],
+        };
+
+        if report_code.is_empty() || fight_id <= 0 {
+            tracing::warn!("Rank {} {} has no report data", rank_number, name);
+            data.failure = Some("Missing report data".to_string());
+            return TalentDataWithRank { rank: rank_number, data };
+        }
+
+        let timer = metrics::Timer::start("talent_fetch", "error");
+        match self.fetch_talent_with_retry(token, report_code, fight_id, name, server, stats).await {
+            Ok(r) => {
+                timer.finish("ok");
+                data.talent_string        = r.talent_string;
+                data.talent_reconstructed = r.talent_reconstructed;
+                data.fight_duration_ms    = r.fight_duration_ms;
+                data.cast_events          = r.cast_events;
+            }
+            Err(e) => {
+                tracing::warn!("Rank {} {} failed: {:#}", rank_number, name, e);
+                let reason = match e.downcast_ref::<TalentLookupError>() {
+                    Some(TalentLookupError::ActorNotFound { .. }) => "Player not found in log",
+                    _                                             => "Talent data unavailable",
+                };
+                data.failure = Some(reason.to_string());
+                return TalentDataWithRank { rank: rank_number, data };
+            }
+        }
+
+        let cast_events = &data.cast_events;
+
+        tracing::info!("Rank {} {} — {} cast events", rank_number, name, cast_events.len());
+
+        TalentDataWithRank { rank: rank_number, data }
+    }
+
+    /// `fetch_talent_and_events` with a short retry policy for transient upstream
+    /// failures, so a single 502 doesn't turn a rank into a placeholder.
+    async fn fetch_talent_with_retry(
+        &self,
+        token: &str,
+        report_code: &str,
+        fight_id: i64,
+        player_name: &str,
+        server: Option<&str>,
+        stats: &FetchStats,
+    ) -> Result<TalentResult> {
+        let mut attempt = 0;
+        loop {
+            match self.fetch_talent_and_events(token, report_code, fight_id, player_name, server, stats).await {
+                Ok(r) => {
+                    if attempt > 0 {
+                        tracing::debug!("Talent fetch for {} succeeded after {} retries", player_name, attempt);
+                    }
+                    return Ok(r);
+                }
+                Err(e) if attempt < TALENT_RETRY_DELAYS.len() && is_transient(&e) => {
+                    let delay = TALENT_RETRY_DELAYS[attempt];
+                    attempt += 1;
+                    tracing::debug!(
+                        "Talent fetch for {} failed ({:#}), retry {}/{} in {:?}",
+                        player_name, e, attempt, TALENT_RETRY_DELAYS.len(), delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    if attempt > 0 {
+                        tracing::debug!("Talent fetch for {} gave up after {} retries", player_name, attempt);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn fetch_talent_and_events(
+        &self,
+        token: &str,
+        report_code: &str,
+        fight_id: i64,
+        player_name: &str,
+        server: Option<&str>,
+        stats: &FetchStats,
+    ) -> Result<TalentResult> {
+        // ── Step 1: resolve actor ID ──────────────────────────────────────────────
+        let actors = self.report_actors(token, report_code, stats).await?;
+
+        let actor_id = find_actor_id(&actors, player_name, server)
+            .ok_or_else(|| TalentLookupError::ActorNotFound { name: player_name.to_string() })?;
+
+        tracing::debug!("Resolved actor '{}' -> ID {}", player_name, actor_id);
+
+        // A finished fight's talents and casts never change, so this can be cached
+        // for a long time across unrelated queries.
+        let cache_key = (report_code.to_string(), fight_id, actor_id);
+        if let Some((cached, _)) = self.caches.talents.get(&cache_key) {
+            tracing::debug!("Talent cache hit for {} ({} fight {})", player_name, report_code, fight_id);
+            return Ok(cached);
+        }
+
+        // ── Step 2: talent + table (name/icon map) + flat cast events ─────────────
+        let request = GraphQLRequest {
+            query: r#"
+            query GetAll($code: String!, $ids: [Int]!, $src: Int!) {
+              reportData {
+                report(code: $code) {
+                  fights(fightIDs: $ids) {
+                    startTime
+                    endTime
+                    talentImportCode(actorID: $src)
+                  }
+                  table(
+                    fightIDs: $ids
+                    sourceID: $src
+                    dataType: Casts
+                    translate: true
+                  )
+                  events(
+                    fightIDs: $ids
+                    sourceID: $src
+                    dataType: Casts
+                    limit: 10000
+                  ) {
+                    data
+                    nextPageTimestamp
+                  }
+                }
+              }
+            }"#.to_string(),
+            variables: Some(serde_json::json!({
+                "code": report_code,
+                "ids":  [fight_id as i32],
+                "src":  actor_id as i32,
+            })),
+        };
+        let body = self.send_with_retry("combined query", || {
+            self.http.post(self.host.endpoints.graphql.clone()).bearer_auth(token).json(&request)
+        })
+        .await.context("combined query send")?
+        .error_for_status().context("combined query status")?
+        .bytes().await.context("combined query read")?;
+
+        let report = parse_response::<ReportResponse<CombinedReport>>("combined query", &body)?
+            .into_report()
+            .context("No report in combined response")?;
+
+        // ── Fight timing + talent string ──────────────────────────────────────────
+        let fight = report.fights.first().context("No fight[0]")?;
+
+        let fight_start       = fight.start_time;
+        let fight_duration_ms = fight.end_time - fight.start_time;
+
+        let (talent_string, talent_reconstructed) = match &fight.talent_import_code {
+            Some(code) => {
+                tracing::debug!("Talents for {} from talentImportCode", player_name);
+                (code.clone(), false)
+            }
+            None => {
+                let summary = self.fetch_combatant_talents(token, report_code, fight_id, actor_id)
+                    .await
+                    .context("Combatant info fallback failed")
+                    .context(TalentLookupError::NullTalentCode)?;
+                tracing::info!("Talents for {} reconstructed from combatant info", player_name);
+                (summary, true)
+            }
+        };
+
+        // ── Build guid → (name, icon) map from table entries ─────────────────────
+        let table_raw = report.table;
+        let table_value: serde_json::Value = if table_raw.is_string() {
+            serde_json::from_str(table_raw.as_str().unwrap_or("{}")).unwrap_or(serde_json::Value::Null)
+        } else {
+            table_raw
+        };
+
+        let entries = table_value
+            .pointer("/data/entries")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        let mut ability_map: HashMap<u64, (String, String)> = HashMap::new();
+
+        for entry in &entries {
+            if let (Some(guid), Some(name), Some(icon)) = (
+                entry.get("guid").and_then(|v| v.as_u64()),
+                entry.get("name").and_then(|v| v.as_str()),
+                entry.get("abilityIcon").and_then(|v| v.as_str()),
+            ) {
+                ability_map.entry(guid).or_insert_with(|| (name.to_string(), icon.to_string()));
+            }
+
+            if let Some(subs) = entry.get("subentries").and_then(|v| v.as_array()) {
+                for sub in subs {
+                    if let (Some(guid), Some(name), Some(icon)) = (
+                        sub.get("guid").and_then(|v| v.as_u64()),
+                        sub.get("name").and_then(|v| v.as_str()),
+                        sub.get("abilityIcon").and_then(|v| v.as_str()),
+                    ) {
+                        ability_map.entry(guid).or_insert_with(|| (name.to_string(), icon.to_string()));
+                    }
+                }
+            }
+        }
+
+        tracing::debug!("Built ability map: {} entries for {}", ability_map.len(), player_name);
+
+        // ── Parse flat cast events, join with ability map ─────────────────────────
+        let events_raw = report.events.map(|e| e.data).unwrap_or_default();
+
+        let events_array: Vec<serde_json::Value> = match events_raw {
+            serde_json::Value::Array(arr) => arr,
+            serde_json::Value::String(s)  => serde_json::from_str(&s).unwrap_or_default(),
+            _                             => vec![],
+        };
+
+        let cast_events: Vec<CastEvent> = events_array
+            .iter()
+            .filter(|ev| ev.get("type").and_then(|v| v.as_str()) == Some("cast"))
+            .filter_map(|ev| {
+                let timestamp = ev.get("timestamp")?.as_i64()?;
+                let id        = ev.get("abilityGameID")?.as_u64()?;
+                if id < 100 { return None; }
+                let (name, icon) = ability_map.get(&id)?.clone();
+                Some(CastEvent { t: timestamp - fight_start, id, name, icon })
+            })
+            .collect();
+
+        tracing::info!(
+            "Parsed {} cast events for {} ({} raw, {} abilities, {}ms)",
+            cast_events.len(), player_name, events_array.len(), ability_map.len(), fight_duration_ms
+        );
+
+        let result = TalentResult { talent_string, talent_reconstructed, fight_duration_ms, cast_events };
+        self.caches.talents.insert(cache_key, result.clone());
+        Ok(result)
+    }
+
+    /// Fallback for fights where `talentImportCode` is null: read the player's
+    /// talent tree from the CombatantInfo event and build a readable summary.
+    /// This is not an import string and cannot be loaded into a talent calculator.
+    async fn fetch_combatant_talents(
+        &self,
+        token: &str,
+        report_code: &str,
+        fight_id: i64,
+        actor_id: i64,
+    ) -> Result<String> {
+        let request = GraphQLRequest {
+            query: r#"
+            query GetCombatantInfo($code: String!, $ids: [Int]!, $src: Int!) {
+              reportData {
+                report(code: $code) {
+                  events(
+                    fightIDs: $ids
+                    sourceID: $src
+                    dataType: CombatantInfo
+                  ) {
+                    data
+                  }
+                }
+              }
+            }"#.to_string(),
+            variables: Some(serde_json::json!({
+                "code": report_code,
+                "ids":  [fight_id as i32],
+                "src":  actor_id as i32,
+            })),
+        };
+        let json: serde_json::Value = self.send_with_retry("combatant info", || {
+            self.http.post(self.host.endpoints.graphql.clone()).bearer_auth(token).json(&request)
+        })
+        .await.context("combatant info send")?
+        .error_for_status().context("combatant info status")?
+        .json().await.context("combatant info parse")?;
+
+        let events_raw = json
+            .pointer("/data/reportData/report/events/data")
+            .cloned()
+            .unwrap_or(serde_json::Value::Null);
+
+        let events: Vec<serde_json::Value> = match events_raw {
+            serde_json::Value::Array(arr) => arr,
+            serde_json::Value::String(s)  => serde_json::from_str(&s).unwrap_or_default(),
+            _                             => vec![],
+        };
+
+        let tree = events
+            .iter()
+            .find(|ev| ev.get("type").and_then(|v| v.as_str()) == Some("combatantinfo"))
+            // Classic's point-based talents come as `talents` instead.
+            .and_then(|ev| ev.get("talentTree").or_else(|| ev.get("talents")))
+            .and_then(|v| v.as_array())
+            .filter(|t| !t.is_empty())
+            .context("No talentTree in combatant info")?;
+
+        let nodes: Vec<String> = tree
+            .iter()
+            .filter_map(|t| {
+                let node = t.get("nodeID").or_else(|| t.get("id"))?.as_i64()?;
+                let rank = t.get("rank").and_then(|v| v.as_i64()).unwrap_or(1);
+                Some(format!("{}:{}", node, rank))
+            })
+            .collect();
+
+        Ok(format!(
+            "[Reconstructed from combatant info] {} talent nodes (node:rank): {}",
+            nodes.len(),
+            nodes.join(" ")
+        ))
+    }
+
+    /// The player actors of a report. Reports are immutable once uploaded, so the
+    /// list is cached by report code and shared by every player and query.
+    async fn report_actors(
+        &self,
+        token: &str,
+        report_code: &str,
+        stats: &FetchStats,
+    ) -> Result<Arc<Vec<Actor>>> {
+        stats.actor_lookups.fetch_add(1, Ordering::Relaxed);
+
+        if let Some((actors, _)) = self.caches.actors.get(report_code) {
+            stats.actor_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(actors);
+        }
+
+        let request = GraphQLRequest {
+            query: r#"
+            query GetActors($reportCode: String!) {
+              reportData {
+                report(code: $reportCode) {
+                  masterData(translate: true) {
+                    actors(type: "Player") { id name server }
+                  }
+                }
+              }
+            }"#.to_string(),
+            variables: Some(serde_json::json!({ "reportCode": report_code })),
+        };
+        let body = self.send_with_retry("actor lookup", || {
+            self.http.post(self.host.endpoints.graphql.clone()).bearer_auth(token).json(&request)
+        })
+        .await.context("actor lookup send")?
+        .error_for_status().context("actor lookup status")?
+        .bytes().await.context("actor lookup read")?;
+
+        let report = parse_response::<ReportResponse<ActorsReport>>("actor lookup", &body)?
+            .into_report()
+            .context("Report not found")?;
+        let actors = Arc::new(report.master_data.actors);
+
+        self.caches.actors.insert(report_code.to_string(), actors.clone());
+        Ok(actors)
+    }
+
+    /// Send an idempotent request, retrying connection errors, timeouts and
+    /// 502/503/504 with jittered exponential backoff until `RETRY_BUDGET` is spent.
+    /// A 429 is waited out when its Retry-After is short, otherwise it becomes a
+    /// `RateLimited` error. Any other response (including GraphQL-level errors)
+    /// is returned as-is.
+    async fn send_with_retry<F>(&self, what: &str, build: F) -> Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let started = tokio::time::Instant::now();
+        let mut attempt: u32 = 0;
+
+        loop {
+            // Hold a slot only while the request is out, not while backing off.
+            let outcome = {
+                let _permit = self.permit(what).await;
+                build().send().await
+            };
+
+            if let Ok(resp) = &outcome
+                && resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+            {
+                let retry_after = resp
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_retry_after);
+
+                match retry_after {
+                    Some(wait) if wait <= RATE_LIMIT_MAX_WAIT && started.elapsed() + wait <= RETRY_BUDGET => {
+                        attempt += 1;
+                        tracing::warn!("{} rate limited, retry {} in {:?}", what, attempt, wait);
+                        tokio::time::sleep(wait).await;
+                        continue;
+                    }
+                    _ => {
+                        tracing::warn!("{} rate limited, Retry-After {:?}", what, retry_after);
+                        return Err(RateLimited { retry_after }.into());
+                    }
+                }
+            }
+
+            let reason = match &outcome {
+                Ok(resp) if matches!(resp.status().as_u16(), 502..=504) => resp.status().to_string(),
+                Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+                _ => return Ok(outcome?),
+            };
+
+            let base   = RETRY_BASE_DELAY * 2u32.pow(attempt);
+            let jitter = Duration::from_millis(fastrand::u64(0..=base.as_millis() as u64 / 2));
+            let delay  = base + jitter;
+
+            if started.elapsed() + delay > RETRY_BUDGET {
+                tracing::warn!("{} failed ({}), retry budget exhausted after {} attempts", what, reason, attempt + 1);
+                return Ok(outcome?);
+            }
+
+            attempt += 1;
+            tracing::warn!("{} failed ({}), retry {} in {:?}", what, reason, attempt, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+
+    /// A client whose token and query endpoints are `app`, served on a free
+    /// local port.
+    async fn mock_client(app: axum::Router) -> WclClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let endpoints = Endpoints {
+            oauth:   Url::parse(&format!("{}/oauth/token", base)).unwrap(),
+            graphql: Url::parse(&format!("{}/api/v2/client", base)).unwrap(),
+        };
+        WclClient::new(endpoints, &Settings::load(Game::Retail).unwrap(), &Settings::load(Game::Classic).unwrap()).unwrap()
+    }
+
+    /// A scripted answer from the mock API.
+    #[derive(Clone)]
+    enum Reply {
+        Json(serde_json::Value),
+        RateLimited { retry_after: &'static str },
+        Status(StatusCode),
+    }
+
+    impl IntoResponse for Reply {
+        fn into_response(self) -> axum::response::Response {
+            match self {
+                Self::Json(body) => axum::Json(body).into_response(),
+                Self::RateLimited { retry_after } => {
+                    let mut headers = HeaderMap::new();
+                    headers.insert("retry-after", retry_after.parse().unwrap());
+                    (StatusCode::TOO_MANY_REQUESTS, headers).into_response()
+                }
+                Self::Status(status) => status.into_response(),
+            }
+        }
+    }
+
+    /// Answers every query with `replies` in turn, repeating the last, and
+    /// counts the queries.
+    fn scripted(replies: Vec<Reply>) -> (axum::Router, Arc<AtomicUsize>) {
+        let replies = Arc::new(std::sync::Mutex::new(std::collections::VecDeque::from(replies)));
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let app = axum::Router::new().route(
+            "/api/v2/client",
+            axum::routing::post(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut replies = replies.lock().unwrap();
+                let reply = if replies.len() > 1 { replies.pop_front() } else { replies.front().cloned() };
+                async move { reply.expect("a scripted reply") }
+            }),
+        );
+        (app, count)
+    }
+
+    fn actors() -> Reply {
+        Reply::Json(serde_json::json!({
+            "data": { "reportData": { "report": { "masterData": { "actors": [
+                { "id": 7, "name": "Alpha", "server": "Draenor" },
+            ] } } } }
+        }))
+    }
+
+    fn combined() -> Reply {
+        Reply::Json(serde_json::json!({
+            "data": { "reportData": { "report": {
+                "fights": [{ "startTime": 1000, "endTime": 61000, "talentImportCode": "CODE" }],
+                "table": { "data": { "entries": [] } },
+                "events": { "data": [] },
+            } } }
+        }))
+    }
+
+    #[tokio::test]
+    async fn per_report_queries_wait_out_a_short_rate_limit() {
+        let (app, count) = scripted(vec![Reply::RateLimited { retry_after: "0" }, actors()]);
+        let wcl = mock_client(app).await;
+
+        let actors = wcl.report_actors("token", "abc", &FetchStats::default()).await.unwrap();
+        assert_eq!(actors.iter().map(|a| a.id).collect::<Vec<_>>(), [7]);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn talent_queries_wait_out_a_short_rate_limit() {
+        let (app, count) = scripted(vec![actors(), Reply::RateLimited { retry_after: "0" }, combined()]);
+        let wcl = mock_client(app).await;
+
+        let result = wcl
+            .fetch_talent_and_events("token", "abc", 3, "Alpha", Some("Draenor"), &FetchStats::default())
+            .await
+            .unwrap();
+        assert_eq!(result.talent_string, "CODE");
+        assert_eq!(result.fight_duration_ms, 60000);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    async fn talents_with_retry(wcl: &WclClient) -> Result<TalentResult> {
+        wcl.fetch_talent_with_retry("token", "abc", 3, "Alpha", Some("Draenor"), &FetchStats::default()).await
+    }
+
+    #[tokio::test]
+    async fn a_failed_talent_fetch_is_tried_again() {
+        let (app, count) = scripted(vec![actors(), Reply::Status(StatusCode::INTERNAL_SERVER_ERROR), combined()]);
+        let wcl = mock_client(app).await;
+
+        let started = std::time::Instant::now();
+        assert_eq!(talents_with_retry(&wcl).await.unwrap().talent_string, "CODE");
+        // The actors are cached, so only the combined query is repeated.
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= TALENT_RETRY_DELAYS[0]);
+    }
+
+    #[tokio::test]
+    async fn a_talent_fetch_gives_up_after_its_retries() {
+        let (app, count) = scripted(vec![actors(), Reply::Status(StatusCode::INTERNAL_SERVER_ERROR)]);
+        let wcl = mock_client(app).await;
+
+        let error = talents_with_retry(&wcl).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("500"), "{:#}", error);
+        assert_eq!(count.load(Ordering::SeqCst), 1 + 1 + TALENT_RETRY_DELAYS.len());
+    }
+
+    #[tokio::test]
+    async fn failures_the_request_already_retried_are_not_retried_again() {
+        let (app, count) = scripted(vec![actors(), Reply::Status(StatusCode::SERVICE_UNAVAILABLE)]);
+        let wcl = mock_client(app).await;
+
+        let started = std::time::Instant::now();
+        let error = talents_with_retry(&wcl).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("503"), "{:#}", error);
+        // One request's retry budget, with no talent-level retry on top:
+        // backing off from 250 ms fits at most five tries into it.
+        assert!(started.elapsed() < RETRY_BUDGET + TALENT_RETRY_DELAYS[0], "{:?}", started.elapsed());
+        assert!((3..=6).contains(&count.load(Ordering::SeqCst)), "{}", count.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (app, count) = scripted(vec![actors(), Reply::Status(StatusCode::BAD_REQUEST)]);
+        let wcl = mock_client(app).await;
+
+        talents_with_retry(&wcl).await.unwrap_err();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn per_report_queries_give_up_on_a_long_rate_limit() {
+        let (app, count) = scripted(vec![Reply::RateLimited { retry_after: "120" }, actors()]);
+        let wcl = mock_client(app).await;
+
+        let error = wcl.report_actors("token", "abc", &FetchStats::default()).await.unwrap_err();
+        let limited = error.downcast_ref::<RateLimited>().expect("a RateLimited error");
+        assert_eq!(limited.retry_after, Some(Duration::from_secs(120)));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    /// A client that already holds a token, so queries go straight to the
+    /// mock without an OAuth round trip.
+    async fn with_token(wcl: WclClient) -> WclClient {
+        *wcl.host.token.write().await = Some(AccessToken { value: "token".to_string(), acquired_at: unix_now(), expires_in: None });
+        wcl
+    }
+
+    #[test]
+    fn tokens_are_replaced_before_they_expire() {
+        let token = AccessToken { value: "token".to_string(), acquired_at: 1_000, expires_in: Some(3_600) };
+        assert!(!token.expiring(1_000));
+        assert!(!token.expiring(4_600 - TOKEN_REFRESH_MARGIN_SECS - 1));
+        assert!(token.expiring(4_600 - TOKEN_REFRESH_MARGIN_SECS));
+        assert!(token.expiring(10_000));
+
+        let lasting = AccessToken { expires_in: None, ..token };
+        assert!(!lasting.expiring(u64::MAX / 2));
+    }
+
+    #[tokio::test]
+    async fn an_expired_token_is_not_used() {
+        let wcl = mock_client(axum::Router::new()).await;
+        let fresh = AccessToken { value: "fresh".to_string(), acquired_at: unix_now(), expires_in: Some(3_600) };
+        *wcl.host.token.write().await = Some(fresh);
+        assert_eq!(wcl.access_token().await.unwrap(), "fresh");
+
+        let stale = AccessToken { value: "stale".to_string(), acquired_at: unix_now() - 3_600, expires_in: Some(3_600) };
+        *wcl.host.token.write().await = Some(stale);
+        // The mock has no token endpoint, so the refresh fails rather than
+        // handing out the expired token.
+        wcl.access_token().await.unwrap_err();
+    }
+
+    fn query() -> RankingsQuery {
+        RankingsQuery {
+            class:           "Mage".to_string(),
+            spec:            "Frost".to_string(),
+            wcl_class:       "Mage".to_string(),
+            wcl_spec:        "Frost".to_string(),
+            encounter_id:    3176,
+            tier_encounters: Vec::new(),
+            region:          None,
+            difficulty:      5,
+            partition:       None,
+            bracket:         None,
+            metric:          "dps".to_string(),
+            limit:           MAX_ENTRIES,
+            game:            Game::Retail,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_ends_with_a_deadline_error_when_upstream_never_answers() {
+        let app = axum::Router::new().route("/api/v2/client", axum::routing::post(std::future::pending::<()>));
+        let wcl = with_token(mock_client(app).await.with_stream_deadline(Duration::from_secs(2))).await;
+
+        let mut rx = wcl.fetch_top_talents_stream(&query()).await.unwrap();
+        let error = rx.recv().await.expect("an item").unwrap_err();
+        let exceeded = error.downcast_ref::<DeadlineExceeded>().expect("a DeadlineExceeded error");
+        assert_eq!((exceeded.sent, exceeded.expected), (0, 0));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_ends_with_a_timeout_error_when_a_request_hangs() {
+        let app = axum::Router::new().route("/api/v2/client", axum::routing::post(std::future::pending::<()>));
+        let wcl = with_token(mock_client(app).await).await;
+
+        let started = tokio::time::Instant::now();
+        let mut rx = wcl.fetch_top_talents_stream(&query()).await.unwrap();
+        let error = rx.recv().await.expect("an item").unwrap_err();
+        let timed_out = error.chain().any(|cause| cause.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout()));
+        assert!(timed_out, "{:#}", error);
+        // The request timeout ends it, well before the stream deadline would.
+        assert!(started.elapsed() < wcl.stream_deadline, "{:?}", started.elapsed());
+        assert!(rx.recv().await.is_none());
+    }
+
+    /// Rankings of `players` in order, each from a report of their own named
+    /// after them.
+    fn rankings(players: &[&str]) -> serde_json::Value {
+        let rankings: Vec<serde_json::Value> = players
+            .iter()
+            .enumerate()
+            .map(|(i, name)| serde_json::json!({
+                "name":   name,
+                "amount": 100_000 - i,
+                "server": { "name": "Draenor", "region": "EU" },
+                "report": { "code": name, "fightID": 1 },
+            }))
+            .collect();
+        serde_json::json!({
+            "data": { "worldData": { "encounter": {
+                "name": "Imperator Averzian",
+                "characterRankings": { "rankings": rankings },
+            } } }
+        })
+    }
+
+    /// An API ranking `players`, whose per-report queries about a player are
+    /// answered after `delay(player)`. Counts the per-report queries.
+    fn upstream(players: &'static [&'static str], delay: fn(&str) -> Duration) -> (axum::Router, Arc<AtomicUsize>) {
+        let count   = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let app = axum::Router::new().route(
+            "/api/v2/client",
+            axum::routing::post(move |body: String| {
+                let counter = counter.clone();
+                async move {
+                    if body.contains("characterRankings") {
+                        return axum::Json(rankings(players));
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let player = *players.iter().find(|p| body.contains(&format!(":\"{}\"", p))).expect("a known report");
+                    tokio::time::sleep(delay(player)).await;
+                    axum::Json(if body.contains("masterData") {
+                        serde_json::json!({
+                            "data": { "reportData": { "report": { "masterData": { "actors": [
+                                { "id": 7, "name": player, "server": "Draenor" },
+                            ] } } } }
+                        })
+                    } else {
+                        serde_json::json!({
+                            "data": { "reportData": { "report": {
+                                "fights": [{ "startTime": 0, "endTime": 60000, "talentImportCode": format!("CODE-{}", player) }],
+                                "table": { "data": { "entries": [] } },
+                                "events": { "data": [] },
+                            } } }
+                        })
+                    })
+                }
+            }),
+        );
+        (app, count)
+    }
+
+    const TEN: &[&str] = &["Alpha", "Bravo", "Charlie", "Delta", "Echo", "Foxtrot", "Golf", "Hotel", "India", "Juliett"];
+
+    #[tokio::test]
+    async fn dropping_the_stream_stops_upstream_queries() {
+        let (app, count) = upstream(TEN, |_| Duration::from_millis(50));
+        let wcl = with_token(mock_client(app).await).await;
+
+        let mut rx = wcl.fetch_top_talents_stream(&query()).await.unwrap();
+        while !matches!(rx.recv().await, Some(Ok(StreamItem::Entry(_)))) {}
+        drop(rx);
+
+        // Let queries already on their way in land, then nothing more may.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let after_drop = count.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(count.load(Ordering::SeqCst), after_drop);
+        // Two queries a player: far fewer than all ten were asked about.
+        assert!(after_drop < 2 * TEN.len(), "{} queries", after_drop);
+    }
+
+    #[tokio::test]
+    async fn a_stalled_receiver_holds_back_upstream_queries() {
+        let (app, count) = upstream(TEN, |_| Duration::ZERO);
+        let wcl = with_token(mock_client(app).await).await;
+
+        // Nothing read: the fetch stops once the channel and the players in
+        // flight are full, well short of every player.
+        let mut rx = wcl.fetch_top_talents_stream(&query()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let stalled = count.load(Ordering::SeqCst);
+        assert!(stalled < 2 * TEN.len(), "{} queries", stalled);
+
+        // Reading lets it carry on to the end.
+        let mut entries = 0;
+        while let Some(item) = rx.recv().await {
+            entries += matches!(item, Ok(StreamItem::Entry(_))) as usize;
+        }
+        assert_eq!(entries, TEN.len());
+        assert_eq!(count.load(Ordering::SeqCst), 2 * TEN.len());
+    }
+
+    #[tokio::test]
+    async fn dropping_a_stalled_receiver_ends_the_fetch() {
+        let (app, count) = upstream(TEN, |_| Duration::ZERO);
+        let wcl = with_token(mock_client(app).await).await;
+
+        let rx = wcl.fetch_top_talents_stream(&query()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let stalled = count.load(Ordering::SeqCst);
+
+        // The producer is waiting on a full channel; going away must free it
+        // rather than let the waiting send through.
+        drop(rx);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(count.load(Ordering::SeqCst), stalled);
+    }
+
+    #[tokio::test]
+    async fn entries_keep_rank_order_when_later_ranks_resolve_first() {
+        // #3 answers at once, #2 after a while and #1 last.
+        let (app, _) = upstream(&["Alpha", "Bravo", "Charlie"], |player| match player {
+            "Alpha" => Duration::from_millis(200),
+            "Bravo" => Duration::from_millis(100),
+            _       => Duration::ZERO,
+        });
+        let wcl = with_token(mock_client(app).await).await;
+
+        let mut rx = wcl.fetch_top_talents_stream(&query()).await.unwrap();
+        let mut seen = Vec::new();
+        while let Some(item) = rx.recv().await {
+            match item.unwrap() {
+                StreamItem::Progress { resolved, .. } => seen.push(format!("progress {}", resolved)),
+                StreamItem::Entry(entry) => {
+                    assert_eq!(entry.data.talent_string, format!("CODE-{}", entry.data.name));
+                    seen.push(format!("#{} {}", entry.rank, entry.data.name));
+                }
+                _ => {}
+            }
+        }
+        // #1 is only sent once it resolves, last, and the rest follow it.
+        assert_eq!(seen, [
+            "progress 1", "progress 2", "progress 3",
+            "#1 Alpha", "#2 Bravo", "#3 Charlie",
+        ]);
+    }
+
+    /// Everything a stream sends, in order, stopping at an error.
+    async fn drain(mut rx: mpsc::Receiver<Result<StreamItem>>) -> Result<Vec<StreamItem>> {
+        let mut items = Vec::new();
+        while let Some(item) = rx.recv().await {
+            items.push(item?);
+        }
+        Ok(items)
+    }
+
+    fn meta(items: &[StreamItem]) -> (usize, usize) {
+        items
+            .iter()
+            .find_map(|item| match item {
+                StreamItem::Meta { entries, available, .. } => Some((*entries, *available)),
+                _ => None,
+            })
+            .expect("a Meta item")
+    }
+
+    #[tokio::test]
+    async fn no_rankings_yet_is_an_empty_stream() {
+        let (app, _) = scripted(vec![Reply::Json(rankings(&[]))]);
+        let wcl = with_token(mock_client(app).await).await;
+
+        let items = drain(wcl.fetch_top_talents_stream(&query()).await.unwrap()).await.unwrap();
+        assert_eq!(meta(&items), (0, 0));
+        assert!(!items.iter().any(|item| matches!(item, StreamItem::Entry(_))));
+    }
+
+    #[tokio::test]
+    async fn missing_rankings_are_an_unexpected_response() {
+        let body = serde_json::json!({ "data": { "worldData": { "encounter": { "name": "Imperator Averzian" } } } });
+        let (app, _) = scripted(vec![Reply::Json(body)]);
+        let wcl = with_token(mock_client(app).await).await;
+
+        let error = drain(wcl.fetch_top_talents_stream(&query()).await.unwrap()).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<WclError>(), Some(WclError::UnexpectedResponse)), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn fewer_rankings_than_asked_for_are_all_sent() {
+        let (app, _) = upstream(&["Alpha", "Bravo"], |_| Duration::ZERO);
+        let wcl = with_token(mock_client(app).await).await;
+
+        let items = drain(wcl.fetch_top_talents_stream(&query()).await.unwrap()).await.unwrap();
+        assert_eq!(meta(&items), (2, 2));
+        let ranks: Vec<usize> = items
+            .iter()
+            .filter_map(|item| match item {
+                StreamItem::Entry(entry) => Some(entry.rank),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ranks, [1, 2]);
+    }
+
+    /// A recorded WCL response from `tests/fixtures/wcl`.
+    macro_rules! fixture {
+        ($name:literal) => {
+            serde_json::from_str::<serde_json::Value>(include_str!(concat!("../tests/fixtures/wcl/", $name))).unwrap()
+        };
+    }
+
+    /// Answers the actor, combined and combatant info queries with `actors`,
+    /// `combined` and `combatant`.
+    fn per_report(actors: serde_json::Value, combined: serde_json::Value, combatant: serde_json::Value) -> axum::Router {
+        axum::Router::new().route(
+            "/api/v2/client",
+            axum::routing::post(move |body: String| async move {
+                axum::Json(if body.contains("masterData") {
+                    actors
+                } else if body.contains("CombatantInfo") {
+                    combatant
+                } else {
+                    combined
+                })
+            }),
+        )
+    }
+
+    async fn talents_of(app: axum::Router, player: &str, server: &str) -> Result<TalentResult> {
+        mock_client(app)
+            .await
+            .fetch_talent_and_events("token", "aBcD1234", 5, player, Some(server), &FetchStats::default())
+            .await
+    }
+
+    #[test]
+    fn master_data_actors_parse() {
+        let body = serde_json::to_vec(&fixture!("actors.json")).unwrap();
+        let actors = parse_response::<ReportResponse<ActorsReport>>("actor lookup", &body)
+            .unwrap()
+            .into_report()
+            .unwrap()
+            .master_data
+            .actors;
+        let actors: Vec<(i64, &str, Option<&str>)> =
+            actors.iter().map(|a| (a.id, a.name.as_str(), a.server.as_deref())).collect();
+        assert_eq!(actors, [
+            (3, "Frostbyte", Some("Draenor")),
+            (4, "Ashwen", Some("Silvermoon")),
+            (9, "Frostbyte", Some("Kazzak")),
+            (12, "\u{d1}eria", None),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn talents_and_casts_come_from_the_combined_query() {
+        let app = per_report(fixture!("actors.json"), fixture!("combined.json"), fixture!("report_not_found.json"));
+        let result = talents_of(app, "Frostbyte", "Draenor").await.unwrap();
+
+        assert_eq!(
+            result.talent_string,
+            "CgEAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAgZmZmxMjZmxMzMzwMAAAAAAAMzMmZZGYGzYmZmxMGAAAAAMzMAAmZmNbzMzMAA"
+        );
+        assert!(!result.talent_reconstructed);
+        assert_eq!(result.fight_duration_ms, 286_756);
+        // Casts only, of known abilities, timed from the start of the fight.
+        let casts: Vec<(i64, u64, &str, &str)> = result
+            .cast_events
+            .iter()
+            .map(|c| (c.t, c.id, c.name.as_str(), c.icon.as_str()))
+            .collect();
+        assert_eq!(casts, [
+            (2330, 116, "Frostbolt", "spell_frost_frostbolt02.jpg"),
+            (2839, 44614, "Flurry", "ability_warlock_burningembersblue.jpg"),
+            (3449, 30455, "Ice Lance", "spell_frost_frostblast.jpg"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn a_null_talent_code_is_rebuilt_from_combatant_info() {
+        let app = per_report(fixture!("actors.json"), fixture!("combined_null_talent.json"), fixture!("combatant_info.json"));
+        let result = talents_of(app, "Frostbyte", "Draenor").await.unwrap();
+
+        assert!(result.talent_reconstructed);
+        assert_eq!(
+            result.talent_string,
+            "[Reconstructed from combatant info] 3 talent nodes (node:rank): 80190:1 80220:2 80239:1"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_null_talent_code_without_combatant_info_is_reported_as_such() {
+        let app = per_report(fixture!("actors.json"), fixture!("combined_null_talent.json"), fixture!("report_not_found.json"));
+        let error = talents_of(app, "Frostbyte", "Draenor").await.unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<TalentLookupError>(), Some(TalentLookupError::NullTalentCode)), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn a_player_missing_from_master_data_is_not_found() {
+        let app = per_report(fixture!("actors.json"), fixture!("combined.json"), fixture!("report_not_found.json"));
+        let error = talents_of(app, "Nobody", "Draenor").await.unwrap_err();
+
+        let Some(TalentLookupError::ActorNotFound { name }) = error.downcast_ref::<TalentLookupError>() else {
+            panic!("not an ActorNotFound: {:#}", error);
+        };
+        assert_eq!(name, "Nobody");
+    }
+
+    #[tokio::test]
+    async fn an_unknown_report_has_no_actors() {
+        let app = per_report(fixture!("report_not_found.json"), fixture!("combined.json"), fixture!("report_not_found.json"));
+        let error = talents_of(app, "Frostbyte", "Draenor").await.unwrap_err();
+
+        assert_eq!(error.to_string(), "Report not found");
+    }
+
+    fn classify(payload: serde_json::Value) -> WclError {
+        classify_graphql_errors(&payload["errors"])
+    }
+
+    #[test]
+    fn token_errors_are_auth() {
+        assert!(matches!(classify(fixture!("errors_unauthenticated.json")), WclError::Auth));
+        // Untagged, by the message.
+        let untagged = serde_json::json!({ "errors": [{ "message": "Unauthorized" }] });
+        assert!(matches!(classify(untagged), WclError::Auth));
+    }
+
+    #[test]
+    fn rejected_queries_are_invalid_with_their_messages() {
+        let WclError::InvalidQuery { messages } = classify(fixture!("errors_validation.json")) else {
+            panic!("not an InvalidQuery");
+        };
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("Variable \"$difficulty\" got invalid value"), "{}", messages[0]);
+
+        let WclError::InvalidQuery { messages } = classify(fixture!("errors_unknown_spec.json")) else {
+            panic!("not an InvalidQuery");
+        };
+        assert_eq!(messages, ["Invalid spec name Frostt for class Mage."]);
+    }
+
+    #[test]
+    fn everything_else_is_upstream() {
+        assert!(matches!(classify(fixture!("errors_internal.json")), WclError::Upstream));
+        // One server error among user errors isn't the user's fault.
+        assert!(matches!(classify(fixture!("errors_mixed.json")), WclError::Upstream));
+        assert!(matches!(classify_graphql_errors(&serde_json::json!("Internal error")), WclError::Upstream));
+        assert!(matches!(classify_graphql_errors(&serde_json::json!([])), WclError::Upstream));
+        // The details stay in the logs.
+        assert!(!WclError::Upstream.to_string().contains("Internal server error"));
+    }
+
+    #[tokio::test]
+    async fn rankings_errors_end_the_stream_classified() {
+        let (app, _) = scripted(vec![Reply::Json(fixture!("errors_unknown_spec.json"))]);
+        let wcl = with_token(mock_client(app).await).await;
+
+        let error = drain(wcl.fetch_top_talents_stream(&query()).await.unwrap()).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<WclError>(), Some(WclError::InvalidQuery { .. })), "{:#}", error);
+        assert_eq!(error.to_string(), "WarcraftLogs rejected the query: Invalid spec name Frostt for class Mage.");
+    }
+
+    const BUDGET: Budget = Budget { reduce_below: 0.20, refuse_below: 0.05, reduced_entries: 3 };
+
+    /// `spent` of 1000 points used, reported at t=1000 with a reset 600s later.
+    fn usage(spent: f64) -> RateLimitStatus {
+        RateLimitStatus { limit_per_hour: 1000, points_spent_this_hour: spent, points_reset_in: 600, observed_at: 1000 }
+    }
+
+    #[test]
+    fn budget_full_until_reduce_threshold() {
+        assert_eq!(BUDGET.decide(None, 1000), BudgetDecision::Full);
+        assert_eq!(BUDGET.decide(Some(&usage(0.0)), 1000), BudgetDecision::Full);
+        assert_eq!(BUDGET.decide(Some(&usage(799.0)), 1000), BudgetDecision::Full);
+    }
+
+    #[test]
+    fn budget_reduced_below_reduce_threshold() {
+        assert_eq!(BUDGET.decide(Some(&usage(801.0)), 1000), BudgetDecision::Reduced(3));
+        assert_eq!(BUDGET.decide(Some(&usage(950.0)), 1000), BudgetDecision::Reduced(3));
+    }
+
+    #[test]
+    fn budget_refused_below_refuse_threshold() {
+        assert_eq!(
+            BUDGET.decide(Some(&usage(951.0)), 1000),
+            BudgetDecision::Exhausted { resets_in: Duration::from_secs(600) }
+        );
+        // The time to reset counts down from when the usage was reported.
+        assert_eq!(
+            BUDGET.decide(Some(&usage(1000.0)), 1400),
+            BudgetDecision::Exhausted { resets_in: Duration::from_secs(200) }
+        );
+    }
+
+    #[test]
+    fn budget_full_again_once_reset_has_passed() {
+        assert_eq!(
+            BUDGET.decide(Some(&usage(1000.0)), 1599),
+            BudgetDecision::Exhausted { resets_in: Duration::from_secs(1) }
+        );
+        assert_eq!(BUDGET.decide(Some(&usage(1000.0)), 1600), BudgetDecision::Full);
+        assert_eq!(BUDGET.decide(Some(&usage(1000.0)), 5000), BudgetDecision::Full);
+    }
+
+    #[test]
+    fn budget_ignores_an_unknown_limit() {
+        let status = RateLimitStatus { limit_per_hour: 0, ..usage(1000.0) };
+        assert_eq!(BUDGET.decide(Some(&status), 1000), BudgetDecision::Full);
+    }
+
+    fn actor(id: i64, name: &str, server: Option<&str>) -> Actor {
+        Actor { id, name: name.to_string(), server: server.map(String::from) }
+    }
+
+    #[test]
+    fn name_key_matches_composed_and_decomposed_accents() {
+        // "Ásh" as one code point and as 'A' plus a combining acute accent.
+        assert_eq!(name_key("\u{c1}sh"), name_key("A\u{301}sh"));
+        assert_eq!(name_key("M\u{e9}ta"), name_key("Me\u{301}ta"));
+        assert_eq!(name_key("Pen\u{303}a"), name_key("pe\u{f1}a"));
+        assert_eq!(name_key("\u{c1}SH"), "\u{e1}sh");
+    }
+
+    #[test]
+    fn name_key_keeps_different_letters_apart() {
+        assert_ne!(name_key("\u{c1}sh"), name_key("Ash"));
+        assert_ne!(name_key("Pe\u{f1}a"), name_key("Pena"));
+    }
+
+    #[test]
+    fn find_actor_id_ignores_case_and_normalization() {
+        let actors = [actor(1, "Other", None), actor(2, "A\u{301}sh", None)];
+        assert_eq!(find_actor_id(&actors, "\u{e1}sh", None), Some(2));
+        assert_eq!(find_actor_id(&actors, "\u{c1}SH", None), Some(2));
+        assert_eq!(find_actor_id(&actors, "Ash", None), None);
+    }
+
+    #[test]
+    fn find_actor_id_narrows_same_key_by_server() {
+        let actors = [actor(1, "M\u{e9}ta", Some("Draenor")), actor(2, "me\u{301}ta", Some("Illidan"))];
+        assert_eq!(find_actor_id(&actors, "M\u{e9}ta", Some("Illidan")), Some(2));
+        assert_eq!(find_actor_id(&actors, "M\u{e9}ta", Some("Draenor")), Some(1));
+    }
+
+    #[test]
+    fn find_actor_id_falls_back_to_exact_name() {
+        let actors = [actor(1, "M\u{e9}ta", Some("Draenor")), actor(2, "me\u{301}ta", Some("Draenor"))];
+        assert_eq!(find_actor_id(&actors, "me\u{301}ta", Some("Draenor")), Some(2));
+        assert_eq!(find_actor_id(&actors, "M\u{e9}ta", None), Some(1));
+        // Same key twice and neither spelt this way: no guess.
+        assert_eq!(find_actor_id(&actors, "META", None), None);
+    }
 }
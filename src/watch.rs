@@ -0,0 +1,364 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::config::{Game, Watch, minutes_or};
+use crate::store::{BuildChange, Store};
+use crate::{AppState, PreparedQuery, collect_talents, most_common_build, prepare_query, shutdown};
+
+lazy_static::lazy_static! {
+    /// How often every `[[watch]]` is checked.
+    static ref WATCH_INTERVAL: Duration = minutes_or("WATCH_INTERVAL_MINS", 60);
+}
+
+/// Tries at posting one message before it is left for the next check.
+const DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry; doubled after each failed attempt.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Check every `[[watch]]` now and then every `WATCH_INTERVAL_MINS`, until
+/// shutdown. Each check goes through the normal cached fetch; when the most
+/// common build differs from the one last seen, the change is posted to the
+/// watch's webhook. The first check of a watch only records its build.
+///
+/// Builds last seen are kept in the store when enabled, so a restart doesn't
+/// forget them, and in memory otherwise. With the store, each change is also
+/// added to the history `/feed.xml` is built from. Failures are logged and
+/// the watch checked again next round.
+pub async fn run(state: AppState) {
+    let http = state.wcl.http();
+    let mut seen: HashMap<String, String> = HashMap::new();
+
+    let mut interval = tokio::time::interval(*WATCH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let shutdown = shutdown::token();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        for watch in &state.settings.watch {
+            if let Err(e) = check(&state, state.store.as_ref(), &http, watch, &mut seen).await {
+                tracing::warn!("Build watch for {} {} failed: {:#}", watch.query.spec, watch.query.class, e);
+            }
+        }
+    }
+}
+
+/// Compare one watch's most common build with the one last seen, posting and
+/// recording it if it changed.
+async fn check(
+    state: &AppState,
+    store: Option<&Store>,
+    http: &reqwest::Client,
+    watch: &Watch,
+    seen: &mut HashMap<String, String>,
+) -> Result<()> {
+    // The address only matters for `nocache`, which isn't used here.
+    let prepared = prepare_query(state, Ipv4Addr::LOCALHOST.into(), (&watch.query).into())
+        .map_err(anyhow::Error::msg)?;
+    let collected = collect_talents(state, &prepared).await?;
+    let Some(build) = most_common_build(&collected.entries) else {
+        tracing::info!("No builds to watch yet for {}", prepared.selection);
+        return Ok(());
+    };
+    let current = build.entry.data.talent_string.as_str();
+
+    // Two watches of one query can post to different channels. The webhook
+    // URL carries its secret token, so only a hash of it is kept.
+    let key = format!("{}\n{}", serde_json::to_string(&prepared.query)?, webhook_digest(&watch.webhook));
+    let previous = match store {
+        Some(store) => store.load_watched_build(&key).await?,
+        None        => seen.get(&key).cloned(),
+    };
+
+    match previous {
+        Some(previous) if previous == current => return Ok(()),
+        Some(previous) => {
+            tracing::info!("Most common build changed for {}", prepared.selection);
+            let message = discord_message(&prepared, &previous, &build, state.public_url.as_deref());
+            // Neither recorded nor added to the feed unless delivered, so the
+            // next check tries again and the change is only listed once.
+            deliver(http, &watch.webhook, &message).await?;
+            if let Some(store) = store {
+                store
+                    .record_change(&prepared.query, BuildChange {
+                        id:          0,
+                        detected_at: 0,
+                        selection:   prepared.selection.clone(),
+                        previous:    previous.clone(),
+                        current:     current.to_string(),
+                        player:      format!("#{} {}", build.entry.rank, build.entry.data.name),
+                        log_url:     build.entry.data.log_url.clone(),
+                    })
+                    .await?;
+            }
+        }
+        None => tracing::info!("Watching builds for {}", prepared.selection),
+    }
+
+    match store {
+        Some(store) => store.save_watched_build(&key, current).await?,
+        None        => {
+            seen.insert(key, current.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// SHA-256 of a webhook URL in hex: stands in for the URL where it's stored.
+fn webhook_digest(webhook: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, webhook.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// A Discord webhook payload announcing the new build, linking to the query's
+/// results when the site's public URL is known.
+fn discord_message(
+    prepared: &PreparedQuery,
+    previous: &str,
+    build: &crate::templates::OverviewBuild<'_>,
+    public_url: Option<&str>,
+) -> serde_json::Value {
+    let entry = build.entry;
+    let mut embed = json!({
+        "title": format!("New most common build: {}", prepared.selection),
+        "description": format!("{} of the top {} now run it.", build.players, build.considered),
+        "fields": [
+            { "name": "Before", "value": format!("```{}```", previous) },
+            { "name": "Now",    "value": format!("```{}```", entry.data.talent_string) },
+            { "name": "Log",    "value": format!("[#{} {}]({})", entry.rank, entry.data.name, entry.data.log_url) },
+        ],
+    });
+    if let Some(url) = public_url.and_then(|base| query_url(base, prepared)) {
+        embed["url"] = json!(url);
+    }
+    json!({ "username": "Talent Trends", "embeds": [embed] })
+}
+
+/// The query's results as Markdown, under the site's public URL.
+fn query_url(base: &str, prepared: &PreparedQuery) -> Option<String> {
+    let query = &prepared.query;
+    let mut url = reqwest::Url::parse(&format!("{}/api/v1/talents.md", base)).ok()?;
+    {
+        let mut pairs = url.query_pairs_mut();
+        pairs
+            .append_pair("class", &query.class)
+            .append_pair("spec", &query.spec)
+            .append_pair("encounter", &query.encounter_id.to_string())
+            .append_pair("zone", &prepared.zone)
+            .append_pair("region", &prepared.region);
+        if let Some(mode) = query.game.modes().into_iter().find(|m| m.difficulty == query.difficulty) {
+            pairs.append_pair("mode", mode.name);
+        }
+        pairs.append_pair("metric", &query.metric);
+        if let Some(bracket) = query.bracket {
+            pairs.append_pair("bracket", &bracket.to_string());
+        }
+        if query.game == Game::Classic {
+            pairs.append_pair("game", "classic");
+        }
+    }
+    Some(url.into())
+}
+
+/// Post a message, retrying with backoff. A 429 waits as long as Discord's
+/// `Retry-After` asks, if it says. Gives up early on shutdown.
+async fn deliver(http: &reqwest::Client, webhook: &str, message: &serde_json::Value) -> Result<()> {
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let failure = match http.post(webhook).json(message).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let retry_after = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<f64>().ok())
+                    .map(Duration::from_secs_f64);
+                if let Some(retry_after) = retry_after {
+                    delay = delay.max(retry_after);
+                }
+                format!("webhook answered {}", response.status())
+            }
+            Err(e) => format!("webhook unreachable: {}", e),
+        };
+        if attempt == DELIVERY_ATTEMPTS {
+            return Err(anyhow::anyhow!(failure)).context(format!("Gave up after {} attempts", attempt));
+        }
+        tracing::warn!("Build change not delivered ({}), retrying in {:?}", failure, delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown::token().cancelled_owned() => anyhow::bail!("Server shutting down"),
+        }
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TrackedQuery;
+    use crate::mock_wcl::{MockWcl, build, meta, test_state, talent_entry};
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    type Posted = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    /// A webhook answering with whatever `status` holds, keeping every
+    /// message posted to it.
+    async fn webhook(status: Arc<AtomicU16>) -> (String, Posted) {
+        let posted = Posted::default();
+        let app = axum::Router::new()
+            .route(
+                "/hook",
+                axum::routing::post(
+                    |State((status, posted)): State<(Arc<AtomicU16>, Posted)>,
+                     axum::Json(message): axum::Json<serde_json::Value>| async move {
+                        posted.lock().unwrap().push(message);
+                        StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap()
+                    },
+                ),
+            )
+            .with_state((status, posted.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, posted)
+    }
+
+    /// Without idle pooled connections, which keep paused time from
+    /// advancing on its own.
+    fn http() -> reqwest::Client {
+        reqwest::Client::builder().pool_max_idle_per_host(0).build().unwrap()
+    }
+
+    fn watch(webhook: &str) -> Watch {
+        Watch {
+            query:   TrackedQuery {
+                class:     "Mage".to_string(),
+                spec:      "Frost".to_string(),
+                encounter: 3176,
+                region:    "all".to_string(),
+                mode:      "Mythic".to_string(),
+                zone:      None,
+                metric:    None,
+            },
+            webhook: webhook.to_string(),
+        }
+    }
+
+    /// State whose top three run `talents`, most common first.
+    fn state_with(talents: [&str; 3]) -> AppState {
+        let [first, second, third] = talents;
+        test_state(MockWcl::new(vec![meta(3, 3), build(1, first), build(2, second), build(3, third)]))
+    }
+
+    #[tokio::test]
+    async fn posts_a_changed_build_once_and_retries_failed_deliveries() {
+        let store  = Store::open(":memory:").unwrap();
+        let status = Arc::new(AtomicU16::new(204));
+        let (url, posted) = webhook(status.clone()).await;
+        let http   = http();
+        let watch  = watch(&url);
+        let mut seen = HashMap::new();
+
+        // The first check only records the build, and an unchanged one is
+        // left alone.
+        let before = state_with(["A", "A", "B"]);
+        check(&before, Some(&store), &http, &watch, &mut seen).await.unwrap();
+        check(&before, Some(&store), &http, &watch, &mut seen).await.unwrap();
+        assert!(posted.lock().unwrap().is_empty());
+        assert!(store.recent_changes(10).await.unwrap().is_empty());
+
+        // A change the webhook refuses is neither recorded nor remembered.
+        let after = state_with(["B", "A", "B"]);
+        status.store(500, Ordering::SeqCst);
+        tokio::time::pause();
+        let failed = check(&after, Some(&store), &http, &watch, &mut seen).await;
+        tokio::time::resume();
+        assert!(failed.unwrap_err().to_string().contains("Gave up after 5 attempts"));
+        assert_eq!(posted.lock().unwrap().len(), DELIVERY_ATTEMPTS as usize);
+        assert!(store.recent_changes(10).await.unwrap().is_empty());
+
+        // So the next check posts it again, and records it once.
+        status.store(204, Ordering::SeqCst);
+        check(&after, Some(&store), &http, &watch, &mut seen).await.unwrap();
+        check(&after, Some(&store), &http, &watch, &mut seen).await.unwrap();
+        let posted = posted.lock().unwrap().clone();
+        assert_eq!(posted.len(), DELIVERY_ATTEMPTS as usize + 1);
+        let fields = &posted.last().unwrap()["embeds"][0]["fields"];
+        assert_eq!(fields[0]["value"], "```A```");
+        assert_eq!(fields[1]["value"], "```B```");
+
+        let changes = store.recent_changes(10).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].previous.as_str(), changes[0].current.as_str()), ("A", "B"));
+        assert_eq!(changes[0].player, "#1 Player");
+    }
+
+    #[tokio::test]
+    async fn without_a_store_builds_are_remembered_in_memory() {
+        let (url, posted) = webhook(Arc::new(AtomicU16::new(204))).await;
+        let http  = http();
+        let watch = watch(&url);
+        let mut seen = HashMap::new();
+
+        check(&state_with(["A", "A", "B"]), None, &http, &watch, &mut seen).await.unwrap();
+        assert_eq!(seen.values().collect::<Vec<_>>(), ["A"]);
+        assert!(posted.lock().unwrap().is_empty());
+
+        check(&state_with(["B", "B", "A"]), None, &http, &watch, &mut seen).await.unwrap();
+        assert_eq!(seen.values().collect::<Vec<_>>(), ["B"]);
+        assert_eq!(posted.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn webhook_urls_are_stored_only_as_a_hash() {
+        let store = Store::open(":memory:").unwrap();
+        let (url, _) = webhook(Arc::new(AtomicU16::new(204))).await;
+        let secret_url = format!("{}?token=s3cret", url);
+        let state = state_with(["A", "A", "B"]);
+        let mut seen = HashMap::new();
+
+        check(&state, Some(&store), &http(), &watch(&secret_url), &mut seen).await.unwrap();
+        let prepared = prepare_query(&state, Ipv4Addr::LOCALHOST.into(), (&watch("").query).into()).unwrap();
+        let query = serde_json::to_string(&prepared.query).unwrap();
+
+        let hashed = format!("{}\n{}", query, webhook_digest(&secret_url));
+        assert_eq!(store.load_watched_build(&hashed).await.unwrap().as_deref(), Some("A"));
+        let plain = format!("{}\n{}", query, secret_url);
+        assert_eq!(store.load_watched_build(&plain).await.unwrap(), None);
+
+        assert_eq!(webhook_digest(&secret_url).len(), 64);
+        assert_ne!(webhook_digest(&secret_url), webhook_digest(&url));
+    }
+
+    #[test]
+    fn message_links_to_the_query() {
+        let state    = state_with(["A", "A", "B"]);
+        let prepared = prepare_query(&state, Ipv4Addr::LOCALHOST.into(), (&watch("").query).into()).unwrap();
+        let entry    = talent_entry(1, "Player", "Draenor", None);
+        let build    = crate::templates::OverviewBuild { entry: &entry, players: 2, considered: 3 };
+
+        let message = discord_message(&prepared, "OLD", &build, Some("https://trends.example"));
+        assert_eq!(
+            message["embeds"][0]["url"],
+            "https://trends.example/api/v1/talents.md?class=Mage&spec=Frost&encounter=3176&zone=midnight_s1&region=all&mode=Mythic&metric=dps"
+        );
+        assert_eq!(message["embeds"][0]["description"], "2 of the top 3 now run it.");
+
+        let message = discord_message(&prepared, "OLD", &build, None);
+        assert!(message["embeds"][0].get("url").is_none());
+    }
+}
@@ -0,0 +1,662 @@
+document.addEventListener('click', (e) => {
+
+    if (e.target.matches('.toggle-iframe-btn')) {
+        const btn       = e.target;
+        const entry     = btn.closest('.talent-entry');
+        const container = entry.querySelector('.iframe-container');
+        if (container.style.display === 'none' || container.style.display === '') {
+            container.style.display    = 'block';
+            container.style.height     = '0px';
+            const iframe               = container.querySelector('iframe');
+            const targetHeight         = iframe.offsetHeight + 'px';
+            container.style.transition = 'height 0.3s ease';
+            requestAnimationFrame(() => { container.style.height = targetHeight; });
+            btn.textContent = 'Hide Talent Calculator';
+        } else {
+            const currentHeight        = container.offsetHeight + 'px';
+            container.style.height     = currentHeight;
+            requestAnimationFrame(() => {
+                container.style.transition = 'height 0.3s ease';
+                container.style.height     = '0px';
+            });
+            setTimeout(() => { container.style.display = 'none'; }, 300);
+            btn.textContent = 'Show Talent Calculator';
+        }
+    }
+
+    if (e.target.matches('.toggle-timeline-btn')) {
+        const btn       = e.target;
+        const container = btn.closest('.talent-entry').querySelector('.cast-timeline');
+        if (!container) return;
+        const showing = container.style.display === 'block';
+        if (!showing) {
+            container.style.display = 'block';
+            btn.textContent = 'Hide Timeline';
+            if (!container.dataset.rendered) {
+                container.dataset.rendered = '1';
+                try {
+                    const events   = JSON.parse(container.dataset.events || '[]');
+                    const duration = parseInt(container.dataset.duration || '0', 10);
+                    renderTimeline(container.id, events, duration);
+                } catch (err) {
+                    container.innerHTML =
+                        '<div class="ct-empty">Failed to parse timeline data: ' + err.message + '</div>';
+                }
+            }
+        } else {
+            container.style.display = 'none';
+            btn.textContent = 'Show Timeline';
+        }
+    }
+
+});
+
+function sliderLabel(v) {
+    return +v >= 100 ? 'All' : String(v);
+}
+
+function renderTimeline(id, events, durationMs) {
+    const el = document.getElementById(id);
+    if (!el) return;
+
+    if (!events || !events.length || !durationMs) {
+        el.innerHTML = '<div class="ct-empty">No cast data available.</div>';
+        return;
+    }
+
+    const byId = {};
+    for (const ev of events) {
+        if (!byId[ev.id]) byId[ev.id] = { name: ev.name, icon: ev.icon, times: [] };
+        byId[ev.id].times.push(ev.t);
+    }
+
+    const allRows = Object.values(byId).sort((a, b) => a.times[0] - b.times[0]);
+
+    function esc(s) {
+        return String(s).replace(/[<>&"]/g, c =>
+            ({ '<': '&lt;', '>': '&gt;', '&': '&amp;', '"': '&quot;' }[c]));
+    }
+
+    function fmt(ms) {
+        const s = Math.floor(ms / 1000);
+        return Math.floor(s / 60) + ':' + String(s % 60).padStart(2, '0');
+    }
+
+    function build(maxCasts) {
+        const showAll = +maxCasts >= 100;
+        const rows    = showAll ? allRows : allRows.filter(a => a.times.length <= +maxCasts);
+
+        if (!rows.length) {
+            return '<div class="ct-empty">No abilities match this filter.</div>';
+        }
+
+        const durSec   = durationMs / 1000;
+        const interval = durSec > 300 ? 60 : 30;
+
+        let ticks = '';
+        for (let s = 0; s <= Math.ceil(durSec); s += interval) {
+            const pct = (s / durSec * 100).toFixed(2);
+            ticks += '<span class="ct-tick" style="left:' + pct + '%">'
+                   + Math.floor(s/60) + ':' + String(s%60).padStart(2,'0')
+                   + '</span>';
+        }
+
+        let rowsHtml = '';
+        for (const a of rows) {
+            const iconUrl = 'https://assets.rpglogs.com/img/warcraft/abilities/' + a.icon;
+            let marks = '';
+            for (const t of a.times) {
+                const pct = (t / durationMs * 100).toFixed(2);
+                marks += '<div class="ct-mark" style="left:' + pct + '%" title="'
+                       + esc(a.name) + ' @ ' + fmt(t) + '"></div>';
+            }
+            rowsHtml += '<div class="ct-row">'
+                + '<div class="ct-label">'
+                + '<img class="ct-icon" src="' + iconUrl + '" alt="" onerror="this.style.display=\'none\'">'
+                + '<span class="ct-name">' + esc(a.name) + '</span>'
+                + '<span class="ct-count">x' + a.times.length + '</span>'
+                + '</div>'
+                + '<div class="ct-bar">' + marks + '</div>'
+                + '</div>';
+        }
+
+        return '<div class="ct-inner">'
+             + '<div class="ct-axis-row">'
+             + '<div class="ct-label-col"></div>'
+             + '<div class="ct-axis">' + ticks + '</div>'
+             + '</div>'
+             + rowsHtml
+             + '</div>';
+    }
+
+    const defaultMax = Math.min(10, Math.max(...allRows.map(r => r.times.length)));
+    const uid        = id;
+
+    window._ctBuilders = window._ctBuilders || {};
+    window._ctBuilders[uid] = build;
+
+    el.innerHTML = '<div class="ct-toolbar">'
+        + '<label>Max casts per ability&nbsp;'
+        + '<input type="range" min="1" max="100" value="' + defaultMax + '"'
+        + ' oninput="'
+        +   'document.getElementById(\'ct-val-' + uid + '\').textContent=sliderLabel(this.value);'
+        +   'document.getElementById(\'ct-body-' + uid + '\').innerHTML=window._ctBuilders[\'' + uid + '\'](this.value);">'
+        + '</label>'
+        + '<span id="ct-val-' + uid + '" class="ct-slider-val">' + sliderLabel(defaultMax) + '</span>'
+        + '<span class="ct-meta">' + fmt(durationMs) + ' fight &bull; ' + allRows.length + ' abilities</span>'
+        + '</div>'
+        + '<div class="ct-scroll"><div id="ct-body-' + uid + '"></div></div>';
+
+    document.getElementById('ct-body-' + uid).innerHTML = build(defaultMax);
+}
+
+const THEMES = {
+    dps: { accent: '#e84040', hover: '#ff5555', text: '#fff' },
+    hps: { accent: '#01C8AA', hover: '#02dfc0', text: '#0f1a18' },
+    playerscore: { accent: '#a335ee', hover: '#b85cff', text: '#fff' },
+};
+
+function setTheme(metric) {
+    const t    = THEMES[metric] || THEMES.dps;
+    const root = document.documentElement;
+    root.style.setProperty('--accent',       t.accent);
+    root.style.setProperty('--accent-hover', t.hover);
+    root.style.setProperty('--accent-text',  t.text);
+}
+
+// Injected by the server; see `templates::home`.
+const pageData       = JSON.parse(document.getElementById('page-data').textContent);
+const specsData      = pageData.specs;
+const encountersData = pageData.encounters;
+
+const regionSelect    = document.getElementById('region');
+const region2Select   = document.getElementById('region2');
+const modeSelect      = document.getElementById('mode');
+const zoneSelect      = document.getElementById('zone');
+const bracketSelect   = document.getElementById('bracket');
+const encounterSelect = document.getElementById('encounter');
+
+function encounterOption(enc) {
+    const option = document.createElement('option');
+    option.value = enc.id;
+    option.textContent = enc.name;
+    return option;
+}
+
+// Mythic+ lists the tier's dungeons; the raid modes list its bosses.
+function isDungeonMode() {
+    const option = modeSelect.selectedOptions[0];
+    return !!option && option.hasAttribute('data-dungeon');
+}
+
+// Boss options for the selected tier and mode, keeping the chosen boss if
+// it is still listed. Bosses are grouped by raid, in order of each raid's
+// first boss, unless the tier only has one. Mythic+ is disabled for tiers
+// without dungeons.
+function populateEncounters() {
+    const prevValue   = encounterSelect.value;
+    const tier        = encountersData.filter(enc => enc.season === zoneSelect.value);
+    const hasDungeons = tier.some(enc => enc.dungeon);
+    modeSelect.querySelectorAll('option[data-dungeon]').forEach(option => {
+        option.disabled = !hasDungeons;
+    });
+    if (!hasDungeons && isDungeonMode()) modeSelect.value = '';
+
+    const dungeon    = isDungeonMode();
+    const encounters = tier
+        .filter(enc => enc.dungeon === dungeon)
+        .sort((a, b) => a.order - b.order);
+    encounterSelect.innerHTML = '<option value="">Select Boss</option>';
+    // Encounter 0 asks for the whole tier's rankings.
+    if (encounters.length > 1) {
+        encounterSelect.appendChild(encounterOption({
+            id:   0,
+            name: dungeon ? 'All dungeons (tier overall)' : 'All bosses (tier overall)',
+        }));
+    }
+    const raids = [...new Set(encounters.map(enc => enc.raid || null))];
+    if (raids.length > 1) {
+        raids.forEach(raid => {
+            const optgroup = document.createElement('optgroup');
+            optgroup.label = raid || 'Other';
+            encounters
+                .filter(enc => (enc.raid || null) === raid)
+                .forEach(enc => optgroup.appendChild(encounterOption(enc)));
+            encounterSelect.appendChild(optgroup);
+        });
+    } else {
+        encounters.forEach(enc => encounterSelect.appendChild(encounterOption(enc)));
+    }
+    if (prevValue && [...encounterSelect.options].some(option => option.value === prevValue)) {
+        encounterSelect.value = prevValue;
+    }
+}
+
+// Filled while the page parses, so the browser can still restore the
+// previous selection.
+populateEncounters();
+const classSelect     = document.getElementById('class');
+const specSelect      = document.getElementById('spec');
+const class2Select    = document.getElementById('class2');
+const spec2Select     = document.getElementById('spec2');
+const submitBtn       = document.getElementById('submit-btn');
+const overviewBtn     = document.getElementById('overview-btn');
+const snapshotBtn     = document.getElementById('snapshot-btn');
+const compareBtn      = document.getElementById('compare-btn');
+const regionsBtn      = document.getElementById('regions-btn');
+const trendsBtn       = document.getElementById('trends-btn');
+const resultsDiv      = document.getElementById('results');
+const metricInput     = document.getElementById('metric-input');
+
+// Spec groups in the dropdown, in order. Specs without a role come last.
+const ROLE_GROUPS = [
+    { role: 'tank',   label: 'Tank' },
+    { role: 'healer', label: 'Healer' },
+    { role: 'dps',    label: 'Damage' },
+    { role: null,     label: 'Other' },
+];
+
+function specOption(spec) {
+    const option = document.createElement('option');
+    option.value = spec.name;
+    option.textContent = spec.name;
+    if (spec.role) option.dataset.role = spec.role;
+    return option;
+}
+
+// Populate spec options for a given class, optionally restoring a saved value.
+// Specs are grouped by role, unless the class only has one. `select` is
+// the spec dropdown to fill, the first one unless given.
+function populateSpecs(className, restoreValue, select = specSelect) {
+    const prevValue = restoreValue !== undefined ? restoreValue : select.value;
+    select.innerHTML = '<option value="">Select Spec</option>';
+    if (className && specsData[className]) {
+        const specs = specsData[className];
+        const roles = new Set(specs.map(spec => spec.role || null));
+        if (roles.size > 1) {
+            ROLE_GROUPS.forEach(group => {
+                const members = specs.filter(spec => (spec.role || null) === group.role);
+                if (members.length === 0) return;
+                const optgroup = document.createElement('optgroup');
+                optgroup.label = group.label;
+                members.forEach(spec => optgroup.appendChild(specOption(spec)));
+                select.appendChild(optgroup);
+            });
+        } else {
+            specs.forEach(spec => select.appendChild(specOption(spec)));
+        }
+        select.disabled = false;
+        // Restore previously selected spec if it exists in the new list
+        if (prevValue && specsData[className].some(spec => spec.name === prevValue)) {
+            select.value = prevValue;
+        }
+    } else {
+        select.disabled = true;
+    }
+}
+
+// Show the chosen class in its color, like its option in the list.
+function showClassColor(select = classSelect) {
+    const option = select.selectedOptions[0];
+    select.style.color = option && option.value ? option.style.color : '';
+}
+
+// On page load: restore spec list for whatever class the browser remembered,
+// restore metric button state and theme from the hidden input the browser remembered.
+document.addEventListener('DOMContentLoaded', () => {
+    // Restore spec dropdown
+    if (classSelect.value) {
+        populateSpecs(classSelect.value, specSelect.value);
+    }
+    if (class2Select.value) {
+        populateSpecs(class2Select.value, spec2Select.value, spec2Select);
+    }
+    showClassColor();
+    showClassColor(class2Select);
+
+    // Restore metric button active state + theme
+    selectMetric(metricInput.value || 'dps');
+    syncScoreMetric(false);
+    syncBracket();
+
+    updateSubmitButton();
+});
+
+function selectMetric(metric) {
+    document.querySelectorAll('.metric-btn').forEach(btn => {
+        btn.classList.toggle('active', btn.dataset.metric === metric);
+    });
+    metricInput.value = metric;
+    setTheme(metric);
+}
+
+// Score only ranks dungeon runs, so its button is shown for Mythic+ alone.
+// Entering Mythic+ switches to it; leaving it falls back to damage.
+function syncScoreMetric(switchMetric) {
+    const dungeon = isDungeonMode();
+    document.querySelector('.metric-btn[data-metric="playerscore"]').hidden = !dungeon;
+    if (dungeon && switchMetric) {
+        selectMetric('playerscore');
+    } else if (!dungeon && metricInput.value === 'playerscore') {
+        selectMetric('dps');
+    }
+}
+
+// The key level only narrows Mythic+ runs; otherwise it is hidden and, being
+// disabled, left out of the query.
+function syncBracket() {
+    bracketSelect.hidden = bracketSelect.disabled = !isDungeonMode();
+}
+
+// Metric toggle buttons
+document.querySelectorAll('.metric-btn').forEach(btn => {
+    btn.addEventListener('click', () => selectMetric(btn.dataset.metric));
+});
+
+regionSelect.addEventListener('change', updateSubmitButton);
+region2Select.addEventListener('change', updateSubmitButton);
+modeSelect.addEventListener('change', () => {
+    populateEncounters();
+    syncScoreMetric(true);
+    syncBracket();
+    updateSubmitButton();
+});
+encounterSelect.addEventListener('change', updateSubmitButton);
+
+zoneSelect.addEventListener('change', () => {
+    populateEncounters();
+    updateSubmitButton();
+});
+
+classSelect.addEventListener('change', (e) => {
+    populateSpecs(e.target.value);
+    showClassColor();
+    updateSubmitButton();
+});
+
+specSelect.addEventListener('change', updateSubmitButton);
+
+class2Select.addEventListener('change', (e) => {
+    populateSpecs(e.target.value, undefined, spec2Select);
+    showClassColor(class2Select);
+    updateSubmitButton();
+});
+
+spec2Select.addEventListener('change', updateSubmitButton);
+
+function updateSubmitButton() {
+    // The overview covers every boss, so it needs all but the boss.
+    overviewBtn.disabled = !(
+        regionSelect.value &&
+        modeSelect.value &&
+        classSelect.value &&
+        specSelect.value
+    );
+    submitBtn.disabled = overviewBtn.disabled || !encounterSelect.value;
+    compareBtn.disabled = submitBtn.disabled || !class2Select.value || !spec2Select.value;
+    trendsBtn.disabled  = submitBtn.disabled;
+    regionsBtn.disabled = submitBtn.disabled || !region2Select.value || region2Select.value === regionSelect.value;
+    // The snapshot covers every spec of one boss.
+    snapshotBtn.disabled = !(
+        regionSelect.value &&
+        modeSelect.value &&
+        encounterSelect.value &&
+        encounterSelect.value !== '0'
+    );
+}
+
+// Stream HTML sections into the results. A section with `data-order` is
+// placed by it, as they may arrive out of order; others are appended.
+function streamSections(url, heading, unit) {
+    resultsDiv.innerHTML = '<h2 id="results-heading"></h2><div id="talents-container"></div><div id="loading-spinner" class="spinner"></div><div id="progress-text" class="progress-text"></div>';
+    document.getElementById('results-heading').textContent = heading;
+    [submitBtn, overviewBtn, snapshotBtn, compareBtn, regionsBtn, trendsBtn].forEach(btn => { btn.disabled = true; });
+
+    const container   = document.getElementById('talents-container');
+    const eventSource = new EventSource(url);
+
+    eventSource.onmessage = (event) => {
+        const spinner = document.getElementById('loading-spinner');
+        if (spinner) spinner.remove();
+        const template = document.createElement('template');
+        template.innerHTML = event.data.trim();
+        const section = template.content.firstElementChild;
+        const order   = Number(section.dataset.order);
+        const next    = [...container.children].find(el => Number(el.dataset.order) > order);
+        container.insertBefore(section, next || null);
+    };
+
+    eventSource.addEventListener('progress', (event) => {
+        const progress = JSON.parse(event.data);
+        const text     = document.getElementById('progress-text');
+        if (text) text.textContent = 'Loaded ' + progress.resolved + '/' + progress.total + ' ' + unit;
+    });
+
+    eventSource.addEventListener('complete', () => {
+        eventSource.close();
+        ['loading-spinner', 'progress-text'].forEach(id => {
+            const el = document.getElementById(id);
+            if (el) el.remove();
+        });
+        updateSubmitButton();
+    });
+
+    eventSource.onerror = (event) => {
+        if (event.data) {
+            const err = JSON.parse(event.data);
+            if (err.fatal) {
+                const spinner = document.getElementById('loading-spinner');
+                if (spinner) spinner.remove();
+                container.insertAdjacentHTML('beforeend', err.html);
+            }
+            return;
+        }
+        eventSource.close();
+        resultsDiv.insertAdjacentHTML('beforeend', '<div class="error">Connection error. Please try again.</div>');
+        updateSubmitButton();
+    };
+}
+
+// The form as query parameters, less the second spec and region, which only
+// the comparisons read.
+function formParams() {
+    const params = new URLSearchParams(new FormData(document.getElementById('talent-form')));
+    ['class2', 'spec2', 'region2'].forEach(name => params.delete(name));
+    if (!params.get('bracket')) params.delete('bracket');
+    return params;
+}
+
+// Every boss of the tier for the chosen spec.
+overviewBtn.addEventListener('click', () => {
+    const params = formParams();
+    ['encounter', 'bracket'].forEach(name => params.delete(name));
+    const tier = zoneSelect.selectedOptions[0];
+    streamSections('/api/v1/talents/overview?' + params,
+        'Most Common Builds' + (tier ? ' — ' + tier.textContent : ''), 'bosses');
+});
+
+// Every spec on the chosen boss, one section per class.
+snapshotBtn.addEventListener('click', () => {
+    const params = formParams();
+    ['class', 'spec', 'metric', 'bracket'].forEach(name => params.delete(name));
+    const boss = encounterSelect.selectedOptions[0];
+    streamSections('/api/v1/talents/snapshot?' + params,
+        'Every Spec' + (boss ? ' — ' + boss.textContent : ''), 'specs');
+});
+
+// The dominant build of each day the selection was recorded.
+trendsBtn.addEventListener('click', () => {
+    streamSections('/api/v1/talents/trends?' + formParams(), 'Build History', 'days');
+});
+
+// The chosen spec's most common build in both regions.
+regionsBtn.addEventListener('click', () => {
+    const params = formParams();
+    params.set('region2', region2Select.value);
+    const regions = [regionSelect, region2Select].map(select => select.selectedOptions[0].textContent);
+    streamSections('/api/v1/talents/regions?' + params, regions.join(' vs '), 'regions');
+});
+
+// The chosen spec and the second one side by side, each in its own column.
+compareBtn.addEventListener('click', () => {
+    const params = formParams();
+    params.set('class2', class2Select.value);
+    params.set('spec2', spec2Select.value);
+    const label = (select, spec) =>
+        spec.value + ' ' + select.selectedOptions[0].textContent;
+
+    resultsDiv.innerHTML = '<h2 id="results-heading">Loading Talents…</h2><div class="compare"></div>';
+    [submitBtn, overviewBtn, snapshotBtn, compareBtn, regionsBtn, trendsBtn].forEach(btn => { btn.disabled = true; });
+    const columns = {};
+    [[1, label(classSelect, specSelect)], [2, label(class2Select, spec2Select)]].forEach(([side, name]) => {
+        const column = document.createElement('div');
+        column.className = 'compare-col';
+        column.innerHTML = '<h3></h3><div class="compare-entries"></div><div class="spinner"></div><div class="progress-text"></div>';
+        column.querySelector('h3').textContent = name;
+        resultsDiv.querySelector('.compare').appendChild(column);
+        columns[side] = column;
+    });
+    const settle = (side) => {
+        columns[side].querySelectorAll('.spinner, .progress-text').forEach(el => el.remove());
+    };
+
+    const eventSource = new EventSource('/api/v1/talents/compare?' + params);
+
+    eventSource.onmessage = (event) => {
+        const item   = JSON.parse(event.data);
+        const column = columns[item.side];
+        const spinner = column.querySelector('.spinner');
+        if (spinner) spinner.remove();
+        const template = document.createElement('template');
+        template.innerHTML = item.html.trim();
+        // Both columns have the same ranks, so element IDs get the side.
+        template.content.querySelectorAll('[id]').forEach(el => { el.id += '-side' + item.side; });
+        column.querySelector('.compare-entries').appendChild(template.content);
+    };
+
+    eventSource.addEventListener('meta', (event) => {
+        const meta = JSON.parse(event.data);
+        if (meta.side === 1) {
+            document.getElementById('results-heading').textContent =
+                meta.encounter ? 'Comparison — ' + meta.encounter : 'Comparison';
+        }
+        if (!meta.entries) settle(meta.side);
+    });
+
+    eventSource.addEventListener('progress', (event) => {
+        const progress = JSON.parse(event.data);
+        const text     = columns[progress.side].querySelector('.progress-text');
+        if (text) text.textContent = 'Resolved ' + progress.resolved + '/' + progress.total;
+        if (progress.resolved === progress.total) settle(progress.side);
+    });
+
+    eventSource.addEventListener('complete', () => {
+        eventSource.close();
+        [1, 2].forEach(settle);
+        const heading = document.getElementById('results-heading');
+        if (heading.textContent === 'Loading Talents…') heading.textContent = 'Comparison';
+        updateSubmitButton();
+    });
+
+    // A fatal error ends one side only; the other keeps streaming.
+    eventSource.onerror = (event) => {
+        if (event.data) {
+            const err = JSON.parse(event.data);
+            if (err.fatal) {
+                const sides = err.side ? [err.side] : [1, 2];
+                sides.forEach(side => {
+                    settle(side);
+                    columns[side].querySelector('.compare-entries').insertAdjacentHTML('beforeend', err.html);
+                });
+            }
+            return;
+        }
+        eventSource.close();
+        resultsDiv.insertAdjacentHTML('beforeend', '<div class="error">Connection error. Please try again.</div>');
+        updateSubmitButton();
+    };
+});
+
+document.getElementById('talent-form').addEventListener('submit', async (e) => {
+    e.preventDefault();
+    const params = formParams();
+
+    resultsDiv.innerHTML = '<h2 id="results-heading">Loading Talents…</h2><div id="talents-container"></div><div id="loading-spinner" class="spinner"></div><div id="progress-text" class="progress-text"></div>';
+    submitBtn.disabled = true;
+
+    const eventSource = new EventSource('/api/v1/talents?' + params);
+    let firstData = true;
+
+    eventSource.onmessage = (event) => {
+        // After a reconnect the server resumes past the last entry we
+        // saw, but never render the same rank twice.
+        if (event.lastEventId && document.getElementById('talent-entry-' + event.lastEventId)) {
+            return;
+        }
+        if (firstData) {
+            const spinner = document.getElementById('loading-spinner');
+            if (spinner) spinner.remove();
+            firstData = false;
+        }
+        document.getElementById('talents-container')
+            .insertAdjacentHTML('beforeend', event.data);
+    };
+
+    eventSource.addEventListener('progress', (event) => {
+        const progress = JSON.parse(event.data);
+        const text     = document.getElementById('progress-text');
+        if (text) {
+            text.textContent = 'Resolved ' + progress.resolved + '/' + progress.total;
+        }
+    });
+
+    eventSource.addEventListener('meta', (event) => {
+        const meta    = JSON.parse(event.data);
+        const heading = document.getElementById('results-heading');
+        if (heading) {
+            const title = meta.entries ? 'Top ' + meta.entries + ' Talents' : 'No Talents Found';
+            heading.textContent = meta.encounter ? title + ' — ' + meta.encounter : title;
+        }
+    });
+
+    eventSource.addEventListener('complete', (event) => {
+        eventSource.close();
+        const progressText = document.getElementById('progress-text');
+        if (progressText) progressText.remove();
+        let info = {};
+        try { info = JSON.parse(event.data); } catch (_) {}
+        if (info.failed_entries) {
+            resultsDiv.insertAdjacentHTML('beforeend',
+                '<div class="cache-note">' + info.failed_entries
+                + (info.failed_entries === 1 ? ' entry' : ' entries') + ' could not be loaded</div>');
+        }
+        if (info.cached) {
+            const mins = Math.floor((info.age_secs || 0) / 60);
+            resultsDiv.insertAdjacentHTML('beforeend',
+                '<div class="cache-note">Cached results from '
+                + (mins < 1 ? 'less than a minute' : mins + ' min') + ' ago</div>');
+        }
+        updateSubmitButton();
+    });
+
+    // Handles both our `error` events (which carry data) and the
+    // browser's own connection errors (which don't).
+    eventSource.onerror = (event) => {
+        if (event.data) {
+            const err = JSON.parse(event.data);
+            if (err.fatal) {
+                const spinner = document.getElementById('loading-spinner');
+                if (spinner) spinner.remove();
+                firstData = false;
+                document.getElementById('talents-container')
+                    .insertAdjacentHTML('beforeend', err.html);
+            }
+            return;
+        }
+        eventSource.close();
+        if (firstData) {
+            resultsDiv.innerHTML = '<div class="error">Connection error. Please try again.</div>';
+        }
+        updateSubmitButton();
+    };
+});